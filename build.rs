@@ -0,0 +1,27 @@
+use std::env;
+use std::fs::{read_dir, write};
+use std::path::Path;
+
+// Embeds every palette in ./palettes so the binary works without the folder next to it.
+fn main() {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let palette_dir = Path::new(&manifest_dir).join("palettes");
+    println!("cargo:rerun-if-changed={}", palette_dir.display());
+
+    let mut palettes: Vec<_> = read_dir(&palette_dir).expect("ERROR: UNABLE TO READ PALETTE DIRECTORY.")
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "hex"))
+        .collect();
+    palettes.sort();
+
+    let mut registry = String::from("pub static BUILTIN_PALETTES: &[(&str, &str)] = &[\n");
+    for path in palettes {
+        let name = path.file_stem().unwrap().to_str().unwrap();
+        registry.push_str(&format!("    ({:?}, include_str!({:?})),\n", name, path.display().to_string()));
+    }
+    registry.push_str("];\n");
+
+    let out_path = Path::new(&env::var("OUT_DIR").unwrap()).join("builtin_palettes.rs");
+    write(out_path, registry).expect("ERROR: UNABLE TO WRITE PALETTE REGISTRY.");
+}
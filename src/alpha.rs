@@ -0,0 +1,105 @@
+use image::{DynamicImage, GrayImage, Rgb, Rgba};
+
+use crate::ditherer::{bayer_threshold, generate_bayer_matrix};
+
+// Alpha at or above this counts as opaque for policies that need a binary alpha without a cutoff of their own.
+pub const DEFAULT_ALPHA_CUTOFF: u8 = 128;
+const ALPHA_DITHER_ORDER: u32 = 2;
+
+#[derive(Copy, Clone)]
+pub enum AlphaPolicy {
+    // Carry the alpha channel through unchanged.
+    KEEP,
+    // Fully opaque at or above the cutoff, fully transparent below it.
+    THRESHOLD(u8),
+    // Ordered dither of alpha to fully opaque or fully transparent.
+    DITHER,
+    // Binary alpha with every transparent pixel painted the palette colour at this index, so palette
+    // based outputs spend exactly one entry on transparency.
+    TRANSPARENTINDEX(usize),
+}
+
+// Splits off the alpha channel so colour operations can work on RGB only. None for opaque colour types.
+pub fn split_alpha(image: &DynamicImage) -> (DynamicImage, Option<GrayImage>) {
+    if !image.color().has_alpha() {
+        return (DynamicImage::ImageRgb8(image.to_rgb8()), None);
+    }
+
+    let rgba = image.to_rgba8();
+    let alpha = GrayImage::from_fn(rgba.width(), rgba.height(), |x, y| image::Luma([rgba.get_pixel(x, y)[3]]));
+    return (DynamicImage::ImageRgb8(image.to_rgb8()), Some(alpha));
+}
+
+pub fn restore_alpha(image: DynamicImage, alpha: Option<GrayImage>) -> DynamicImage {
+    let Some(alpha) = alpha else { return image };
+
+    let mut rgba = image.to_rgba8();
+    for (x, y, pixel) in rgba.enumerate_pixels_mut() {
+        pixel[3] = alpha.get_pixel(x, y)[0];
+    }
+    return DynamicImage::ImageRgba8(rgba);
+}
+
+// The palette is the one the image was just reduced to, only TRANSPARENTINDEX looks at it.
+pub fn apply_alpha_policy(image: &mut DynamicImage, policy: AlphaPolicy, palette: &[Rgb<u8>]) -> Result<(), String> {
+    if let AlphaPolicy::TRANSPARENTINDEX(index) = policy {
+        if index >= palette.len() {
+            return Err(format!("Transparent index {} is outside the {} colour palette", index, palette.len()));
+        }
+    }
+    if !image.color().has_alpha() {
+        return Ok(());
+    }
+
+    let mut rgba = image.to_rgba8();
+    match policy {
+        AlphaPolicy::KEEP => return Ok(()),
+        AlphaPolicy::THRESHOLD(cutoff) => {
+            for pixel in rgba.pixels_mut() {
+                pixel[3] = if pixel[3] >= cutoff { 255 } else { 0 };
+            }
+        },
+        AlphaPolicy::DITHER => {
+            let matrix = generate_bayer_matrix(ALPHA_DITHER_ORDER);
+            for (x, y, pixel) in rgba.enumerate_pixels_mut() {
+                pixel[3] = if pixel[3] as f32 > bayer_threshold(&matrix, ALPHA_DITHER_ORDER, x, y) { 255 } else { 0 };
+            }
+        },
+        AlphaPolicy::TRANSPARENTINDEX(index) => {
+            let key = palette[index];
+            for pixel in rgba.pixels_mut() {
+                *pixel = if pixel[3] >= DEFAULT_ALPHA_CUTOFF {
+                    Rgba([pixel[0], pixel[1], pixel[2], 255])
+                } else {
+                    Rgba([key[0], key[1], key[2], 0])
+                };
+            }
+        },
+    }
+    *image = DynamicImage::ImageRgba8(rgba);
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbaImage;
+
+    use super::*;
+
+    #[test]
+    fn transparent_index_paints_the_palette_entry() {
+        let palette = [Rgb([0, 0, 0]), Rgb([255, 0, 255])];
+        let mut image = DynamicImage::ImageRgba8(RgbaImage::from_fn(2, 1, |x, _| Rgba([10, 20, 30, if x == 0 { 0 } else { 200 }])));
+
+        apply_alpha_policy(&mut image, AlphaPolicy::TRANSPARENTINDEX(1), &palette).unwrap();
+        let rgba = image.to_rgba8();
+        assert_eq!(rgba.get_pixel(0, 0), &Rgba([255, 0, 255, 0]));
+        assert_eq!(rgba.get_pixel(1, 0), &Rgba([10, 20, 30, 255]));
+    }
+
+    #[test]
+    fn transparent_index_outside_the_palette_is_an_error() {
+        let mut image = DynamicImage::ImageRgba8(RgbaImage::new(1, 1));
+        assert!(apply_alpha_policy(&mut image, AlphaPolicy::TRANSPARENTINDEX(2), &[Rgb([0, 0, 0])]).is_err());
+    }
+}
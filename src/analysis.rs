@@ -0,0 +1,340 @@
+use image::Rgb;
+
+use crate::colour::{relative_luminance, rgb_to_lab, DistanceFunction};
+use crate::cvd::{simulate_colour, CvdModel, Deficiency};
+use crate::library::palette_stem;
+use crate::palette::Palette;
+
+// Pairs closer than this CIEDE2000 distance are hard to tell apart at pixel scale.
+pub const CONFUSABLE_DELTA_E: f32 = 10.0;
+pub const CONFUSABLE_PAIR_COUNT: usize = 5;
+pub const LIGHTNESS_BINS: usize = 10;
+
+pub const WCAG_AA: f32 = 4.5;
+pub const WCAG_AAA: f32 = 7.0;
+
+pub struct ColourPair {
+    pub first: Rgb<u8>,
+    pub second: Rgb<u8>,
+    pub value: f32,
+}
+
+pub struct CvdReport {
+    pub deficiency: Deficiency,
+    pub min_delta_e: f32,
+    // Pairs that are distinguishable with normal vision but fall under CONFUSABLE_DELTA_E once simulated.
+    pub lost_pairs: Vec<ColourPair>,
+}
+
+pub struct PaletteReport {
+    pub name: String,
+    pub colour_count: usize,
+    pub min_delta_e: f32,
+    pub mean_delta_e: f32,
+    pub confusable_pairs: Vec<ColourPair>,
+    pub lightness_range: (f32, f32),
+    pub lightness_coverage: f32,
+    pub hull_volume: f32,
+    pub aa_pairs: usize,
+    pub aaa_pairs: usize,
+    pub best_contrast: Option<ColourPair>,
+    pub cvd: Vec<CvdReport>,
+}
+
+impl PaletteReport {
+    pub fn analyse(palette: &Palette) -> PaletteReport {
+        return PaletteReport::analyse_with_model(palette, CvdModel::MACHADO);
+    }
+
+    pub fn analyse_with_model(palette: &Palette, model: CvdModel) -> PaletteReport {
+        let colours = &palette.colours;
+        let distance = DistanceFunction::CIEDE2000;
+        let mut confusable_pairs = pairwise(colours, |a, b| distance.distance(a, b));
+
+        let min_delta_e = confusable_pairs.iter().map(|p| p.value).fold(f32::INFINITY, f32::min);
+        let mean_delta_e = mean_pairwise_distance(colours, distance);
+
+        confusable_pairs.sort_by(|a, b| a.value.total_cmp(&b.value));
+        confusable_pairs.truncate(CONFUSABLE_PAIR_COUNT);
+
+        let lightness: Vec<f32> = colours.iter().map(|c| rgb_to_lab(c)[0]).collect();
+        let lightness_range = lightness.iter().fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), l| (min.min(*l), max.max(*l)));
+        let mut bins = [false; LIGHTNESS_BINS];
+        for l in &lightness {
+            bins[((l / 100f32 * LIGHTNESS_BINS as f32) as usize).min(LIGHTNESS_BINS - 1)] = true;
+        }
+        let lightness_coverage = bins.iter().filter(|b| **b).count() as f32 / LIGHTNESS_BINS as f32;
+
+        let labs: Vec<[f32; 3]> = colours.iter().map(rgb_to_lab).collect();
+        let hull_volume = convex_hull_volume(&labs);
+
+        let contrasts = pairwise(colours, contrast_ratio);
+        let aa_pairs = contrasts.iter().filter(|p| p.value >= WCAG_AA).count();
+        let aaa_pairs = contrasts.iter().filter(|p| p.value >= WCAG_AAA).count();
+        let best_contrast = contrasts.into_iter().max_by(|a, b| a.value.total_cmp(&b.value));
+
+        let cvd = Deficiency::all().into_iter()
+            .map(|deficiency| cvd_report(colours, deficiency, model))
+            .collect();
+
+        return PaletteReport {
+            name: palette_stem(&palette.name),
+            colour_count: colours.len(),
+            min_delta_e: if min_delta_e.is_finite() { min_delta_e } else { 0f32 },
+            mean_delta_e,
+            confusable_pairs,
+            lightness_range: if lightness.is_empty() { (0f32, 0f32) } else { lightness_range },
+            lightness_coverage,
+            hull_volume,
+            aa_pairs,
+            aaa_pairs,
+            best_contrast,
+            cvd,
+        };
+    }
+}
+
+pub fn cvd_report(colours: &[Rgb<u8>], deficiency: Deficiency, model: CvdModel) -> CvdReport {
+    let distance = DistanceFunction::CIEDE2000;
+    let simulated: Vec<Rgb<u8>> = colours.iter().map(|c| simulate_colour(c, deficiency, model)).collect();
+
+    let mut min_delta_e = f32::INFINITY;
+    let mut lost_pairs = Vec::new();
+    for i in 0..colours.len() {
+        for j in i + 1..colours.len() {
+            let seen = distance.distance(&simulated[i], &simulated[j]);
+            min_delta_e = min_delta_e.min(seen);
+            if seen < CONFUSABLE_DELTA_E && distance.distance(&colours[i], &colours[j]) >= CONFUSABLE_DELTA_E {
+                lost_pairs.push(ColourPair { first: colours[i], second: colours[j], value: seen });
+            }
+        }
+    }
+    lost_pairs.sort_by(|a, b| a.value.total_cmp(&b.value));
+
+    return CvdReport {
+        deficiency,
+        min_delta_e: if min_delta_e.is_finite() { min_delta_e } else { 0f32 },
+        lost_pairs,
+    };
+}
+
+// Mean over all unordered pairs, 0 for palettes with fewer than two colours.
+pub fn mean_pairwise_distance(colours: &[Rgb<u8>], distance: DistanceFunction) -> f32 {
+    let mut total = 0f64;
+    let mut count = 0u64;
+    for i in 0..colours.len() {
+        for j in i + 1..colours.len() {
+            total += distance.distance(&colours[i], &colours[j]) as f64;
+            count += 1;
+        }
+    }
+    if count == 0 {
+        return 0f32;
+    }
+    return (total / count as f64) as f32;
+}
+
+// WCAG 2 contrast ratio, from 1 for identical luminance up to 21 for black on white.
+pub fn contrast_ratio(colour1: &Rgb<u8>, colour2: &Rgb<u8>) -> f32 {
+    let l1 = relative_luminance(colour1);
+    let l2 = relative_luminance(colour2);
+    (l1.max(l2) + 0.05) / (l1.min(l2) + 0.05)
+}
+
+fn pairwise<F: Fn(&Rgb<u8>, &Rgb<u8>) -> f32>(colours: &[Rgb<u8>], f: F) -> Vec<ColourPair> {
+    let mut pairs = Vec::new();
+    for i in 0..colours.len() {
+        for j in i + 1..colours.len() {
+            pairs.push(ColourPair { first: colours[i], second: colours[j], value: f(&colours[i], &colours[j]) });
+        }
+    }
+    return pairs;
+}
+
+// Incremental convex hull, 0 when the points are coplanar or there are fewer than four of them.
+pub fn convex_hull_volume(points: &[[f32; 3]]) -> f32 {
+    const EPSILON: f64 = 1e-9;
+
+    let points: Vec<[f64; 3]> = points.iter().map(|p| p.map(|v| v as f64)).collect();
+    let Some(initial) = initial_tetrahedron(&points, EPSILON) else { return 0f32 };
+
+    let centroid = scale(add(add(points[initial[0]], points[initial[1]]), add(points[initial[2]], points[initial[3]])), 0.25);
+    let mut faces: Vec<[usize; 3]> = vec![
+        [initial[0], initial[1], initial[2]],
+        [initial[0], initial[1], initial[3]],
+        [initial[0], initial[2], initial[3]],
+        [initial[1], initial[2], initial[3]],
+    ];
+    for face in faces.iter_mut() {
+        if signed_distance(&points, face, centroid) > 0.0 {
+            face.swap(1, 2);
+        }
+    }
+
+    for (p, point) in points.iter().enumerate() {
+        if initial.contains(&p) {
+            continue;
+        }
+
+        let visible: Vec<bool> = faces.iter().map(|face| signed_distance(&points, face, *point) > EPSILON).collect();
+        if !visible.contains(&true) {
+            continue;
+        }
+
+        let mut horizon = Vec::new();
+        for (face, _) in faces.iter().zip(&visible).filter(|(_, v)| **v) {
+            for (a, b) in [(face[0], face[1]), (face[1], face[2]), (face[2], face[0])] {
+                let shared = faces.iter().zip(&visible)
+                    .filter(|(_, v)| **v)
+                    .any(|(other, _)| [(other[0], other[1]), (other[1], other[2]), (other[2], other[0])].contains(&(b, a)));
+                if !shared {
+                    horizon.push((a, b));
+                }
+            }
+        }
+
+        faces = faces.into_iter().zip(visible).filter(|(_, v)| !v).map(|(face, _)| face).collect();
+        faces.extend(horizon.into_iter().map(|(a, b)| [a, b, p]));
+    }
+
+    let volume: f64 = faces.iter()
+        .map(|face| dot(sub(points[face[0]], centroid), cross(sub(points[face[1]], centroid), sub(points[face[2]], centroid))).abs() / 6.0)
+        .sum();
+    return volume as f32;
+}
+
+fn initial_tetrahedron(points: &[[f64; 3]], epsilon: f64) -> Option<[usize; 4]> {
+    let a = 0;
+    let b = (1..points.len()).find(|i| length(sub(points[*i], points[a])) > epsilon)?;
+    let c = (1..points.len()).find(|i| length(cross(sub(points[b], points[a]), sub(points[*i], points[a]))) > epsilon)?;
+    let normal = cross(sub(points[b], points[a]), sub(points[c], points[a]));
+    let d = (1..points.len()).find(|i| dot(normal, sub(points[*i], points[a])).abs() > epsilon)?;
+    return Some([a, b, c, d]);
+}
+
+fn signed_distance(points: &[[f64; 3]], face: &[usize; 3], point: [f64; 3]) -> f64 {
+    let normal = cross(sub(points[face[1]], points[face[0]]), sub(points[face[2]], points[face[0]]));
+    dot(normal, sub(point, points[face[0]]))
+}
+
+fn add(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+fn sub(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn scale(a: [f64; 3], s: f64) -> [f64; 3] {
+    [a[0] * s, a[1] * s, a[2] * s]
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn length(a: [f64; 3]) -> f64 {
+    dot(a, a).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cube(side: f32) -> Vec<[f32; 3]> {
+        let mut corners = Vec::new();
+        for i in 0..8 {
+            corners.push([(i & 1) as f32 * side, ((i >> 1) & 1) as f32 * side, ((i >> 2) & 1) as f32 * side]);
+        }
+        return corners;
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        return (a - b).abs() < 1e-3;
+    }
+
+    #[test]
+    fn hull_volume_of_a_cube_ignores_interior_points() {
+        assert!(close(convex_hull_volume(&cube(1f32)), 1f32));
+        assert!(close(convex_hull_volume(&cube(2f32)), 8f32));
+
+        let mut with_centre = cube(1f32);
+        with_centre.insert(3, [0.5, 0.5, 0.5]);
+        with_centre.push([0.25, 0.75, 0.5]);
+        assert!(close(convex_hull_volume(&with_centre), 1f32));
+
+        let tetrahedron = [[0f32, 0f32, 0f32], [1f32, 0f32, 0f32], [0f32, 1f32, 0f32], [0f32, 0f32, 1f32]];
+        assert!(close(convex_hull_volume(&tetrahedron), 1f32 / 6f32));
+    }
+
+    #[test]
+    fn hull_volume_is_zero_for_flat_or_tiny_sets() {
+        let square = [[0f32, 0f32, 0f32], [1f32, 0f32, 0f32], [0f32, 1f32, 0f32], [1f32, 1f32, 0f32]];
+        assert_eq!(convex_hull_volume(&square), 0f32);
+        assert_eq!(convex_hull_volume(&cube(1f32)[..3]), 0f32);
+        assert_eq!(convex_hull_volume(&[]), 0f32);
+    }
+
+    #[test]
+    fn mean_pairwise_distance_averages_unordered_pairs() {
+        let black = Rgb([0, 0, 0]);
+        let white = Rgb([255, 255, 255]);
+        let red = Rgb([255, 0, 0]);
+
+        assert!(close(mean_pairwise_distance(&[black, white], DistanceFunction::EUCLIDEAN), 255f32 * 3f32.sqrt()));
+        assert!(close(mean_pairwise_distance(&[black, red], DistanceFunction::MANHATTAN), 255f32));
+        // black-red 255, black-white 441.67, red-white 360.62
+        let expected = (255f32 + 255f32 * 3f32.sqrt() + 255f32 * 2f32.sqrt()) / 3f32;
+        assert!(close(mean_pairwise_distance(&[black, red, white], DistanceFunction::EUCLIDEAN), expected));
+        assert_eq!(mean_pairwise_distance(&[white], DistanceFunction::EUCLIDEAN), 0f32);
+    }
+
+    #[test]
+    fn report_of_black_and_white() {
+        let palette = Palette { name: "mono.hex".to_string(), colours: vec![Rgb([0, 0, 0]), Rgb([255, 255, 255])] };
+        let report = PaletteReport::analyse(&palette);
+
+        assert_eq!(report.name, "mono");
+        assert_eq!(report.colour_count, 2);
+        assert!(close(report.min_delta_e, 100f32));
+        assert_eq!(report.min_delta_e, report.mean_delta_e);
+        assert_eq!(report.confusable_pairs.len(), 1);
+        assert!(close(report.lightness_range.0, 0f32) && close(report.lightness_range.1, 100f32));
+        assert_eq!(report.lightness_coverage, 0.2);
+        assert_eq!(report.hull_volume, 0f32);
+        assert_eq!((report.aa_pairs, report.aaa_pairs), (1, 1));
+        assert!(close(report.best_contrast.unwrap().value, 21f32));
+        assert_eq!(report.cvd.len(), 4);
+        assert!(report.cvd.iter().all(|cvd| cvd.lost_pairs.is_empty()));
+    }
+
+    #[test]
+    fn report_flags_confusable_and_colour_blind_pairs() {
+        let red = Rgb([200, 40, 40]);
+        let green = Rgb([90, 120, 40]);
+        let near_red = Rgb([202, 42, 40]);
+        let palette = Palette { name: "clash.hex".to_string(), colours: vec![red, green, near_red] };
+        let report = PaletteReport::analyse(&palette);
+
+        assert_eq!(report.confusable_pairs[0].first, red);
+        assert_eq!(report.confusable_pairs[0].second, near_red);
+        assert!(report.min_delta_e < CONFUSABLE_DELTA_E);
+        assert!(report.confusable_pairs.windows(2).all(|pair| pair[0].value <= pair[1].value));
+
+        let achromatopsia = report.cvd.iter().find(|cvd| matches!(cvd.deficiency, Deficiency::ACHROMATOPSIA)).unwrap();
+        assert!(achromatopsia.lost_pairs.iter().any(|pair| pair.first == red && pair.second == green));
+    }
+
+    #[test]
+    fn empty_palette_reports_zeroes() {
+        let report = PaletteReport::analyse(&Palette { name: "empty.hex".to_string(), colours: Vec::new() });
+        assert_eq!(report.colour_count, 0);
+        assert_eq!(report.min_delta_e, 0f32);
+        assert_eq!(report.lightness_range, (0f32, 0f32));
+        assert!(report.best_contrast.is_none());
+    }
+}
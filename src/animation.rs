@@ -0,0 +1,478 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::imageops::FilterType;
+use image::metadata::LoopCount;
+use image::{AnimationDecoder, DynamicImage, Rgb};
+
+use crate::alpha::{apply_alpha_policy, AlphaPolicy, DEFAULT_ALPHA_CUTOFF};
+use crate::colour::{select_colours, SelectionStrategy};
+use crate::ditherer::{DitherMode, Ditherer};
+use crate::image::{apply_palette, pixelate_image, Extension};
+use crate::indexed::{IndexedImage, IndexedPngOptions, MAX_INDEXED_COLOURS};
+use crate::palette::Palette;
+use crate::temporal::{delta_rectangle, dither_frames, Rectangle, TemporalDither};
+
+pub struct AnimationFrame {
+    // Full canvas, already composited over the previous frames.
+    pub image: DynamicImage,
+    pub delay_ms: u32,
+}
+
+pub struct Animation {
+    pub filename: String,
+    pub frames: Vec<AnimationFrame>,
+    // 0 loops forever.
+    pub loop_count: u32,
+    // Applied to the alpha channel of every frame after palettizing and dithering.
+    pub alpha_policy: AlphaPolicy,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum FramePalette {
+    // One palette generated across all frames.
+    GLOBAL,
+    // A palette generated for every frame on its own.
+    LOCAL,
+}
+
+#[derive(Copy, Clone)]
+pub struct AnimationSaveOptions {
+    // GIF or PNG (APNG), taken from the file extension when None.
+    pub format: Option<Extension>,
+    pub palette: FramePalette,
+    // Colours per palette, transparency takes one more entry when a frame needs it.
+    pub num_colours: usize,
+    pub strategy: SelectionStrategy,
+    // GIF frames after the first only store the rectangle that changed. Ignored for animations with
+    // transparency, which need every frame cleared and redrawn in full.
+    pub delta_frames: bool,
+}
+
+impl Default for AnimationSaveOptions {
+    fn default() -> Self {
+        AnimationSaveOptions {
+            format: None,
+            palette: FramePalette::GLOBAL,
+            num_colours: MAX_INDEXED_COLOURS - 1,
+            strategy: SelectionStrategy::Median,
+            delta_frames: true,
+        }
+    }
+}
+
+impl Animation {
+    // GIF, APNG and animated WebP load every frame, any other image loads as a single frame.
+    pub fn new(filepath: &str) -> Result<Animation, String> {
+        let filename = Path::new(filepath).file_stem().and_then(|s| s.to_str()).unwrap_or("animation").to_string();
+        let open = || File::open(filepath).map(BufReader::new).map_err(|err| format!("Could not open {}: {}", filepath, err));
+        let decode_error = |err: image::ImageError| format!("Could not decode {}: {}", filepath, err);
+
+        let (frames, loop_count) = match Extension::from_path(filepath) {
+            Some(Extension::GIF) => decode_frames(GifDecoder::new(open()?).map_err(decode_error)?)?,
+            Some(Extension::PNG) => {
+                let decoder = PngDecoder::new(open()?).map_err(decode_error)?;
+                if decoder.is_apng().map_err(decode_error)? {
+                    decode_frames(decoder.apng().map_err(decode_error)?)?
+                } else {
+                    still_frame(filepath)?
+                }
+            },
+            Some(Extension::WEBP) => {
+                let decoder = WebPDecoder::new(open()?).map_err(decode_error)?;
+                if decoder.has_animation() {
+                    decode_frames(decoder)?
+                } else {
+                    still_frame(filepath)?
+                }
+            },
+            _ => still_frame(filepath)?,
+        };
+
+        return Ok(Animation {
+            filename,
+            frames,
+            loop_count,
+            alpha_policy: AlphaPolicy::KEEP,
+        });
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.frames.first().map(|frame| (frame.image.width(), frame.image.height())).unwrap_or((0, 0))
+    }
+
+    pub fn pixelate(&mut self, scale: u32, filter: FilterType) {
+        for frame in self.frames.iter_mut() {
+            pixelate_image(&mut frame.image, scale, filter);
+        }
+    }
+
+    pub fn apply_palette(&mut self, palette: Palette) -> Result<(), String> {
+        for frame in self.frames.iter_mut() {
+            frame.image = apply_palette(frame.image.clone(), palette.clone());
+            apply_alpha_policy(&mut frame.image, self.alpha_policy, &palette.colours)?;
+        }
+        return Ok(());
+    }
+
+    pub fn dither(&mut self, mode: DitherMode) -> Result<(), String> {
+        let ditherer = Ditherer::new(mode);
+        let colours = match DitherMode::palette(&mode) {
+            Some(name) => Palette::new(name)?.colours,
+            None => Vec::new(),
+        };
+        for frame in self.frames.iter_mut() {
+            (ditherer.dither_fn)(&mut frame.image)?;
+            apply_alpha_policy(&mut frame.image, self.alpha_policy, &colours)?;
+        }
+        return Ok(());
+    }
+
+    // Dithers with thresholds or error that stay put in static regions, so the frames do not shimmer.
+    pub fn dither_stable(&mut self, palette: &Palette, mode: TemporalDither) -> Result<(), String> {
+        let mut images: Vec<DynamicImage> = self.frames.iter().map(|frame| frame.image.clone()).collect();
+        dither_frames(&mut images, palette, mode);
+        for (frame, mut image) in self.frames.iter_mut().zip(images) {
+            apply_alpha_policy(&mut image, self.alpha_policy, &palette.colours)?;
+            frame.image = image;
+        }
+        return Ok(());
+    }
+
+    // Palette over the opaque pixels of every frame, so all frames can share it.
+    pub fn generate_palette(&self, palettename: String, num_colours: usize, strategy: SelectionStrategy) -> Palette {
+        let images: Vec<&DynamicImage> = self.frames.iter().map(|frame| &frame.image).collect();
+        return Palette {
+            name: palettename,
+            colours: limited_palette(&images, num_colours, strategy),
+        };
+    }
+
+    pub fn save(&self, file_path: &str, options: &AnimationSaveOptions) -> Result<(), String> {
+        if self.frames.is_empty() {
+            return Err("Cannot save an animation without frames".to_string());
+        }
+
+        match options.format.or_else(|| Extension::from_path(file_path)) {
+            Some(Extension::GIF) => save_gif(self, file_path, options),
+            Some(Extension::PNG) => save_apng(self, file_path, options),
+            _ => Err(format!("Animations can only be saved as GIF or APNG, not {}", file_path)),
+        }
+    }
+}
+
+fn decode_frames<'a, D: AnimationDecoder<'a>>(decoder: D) -> Result<(Vec<AnimationFrame>, u32), String> {
+    let loop_count = match decoder.loop_count() {
+        LoopCount::Infinite => 0,
+        LoopCount::Finite(n) => n.get(),
+    };
+
+    let frames = decoder.into_frames().collect_frames().map_err(|err| format!("Could not decode frame: {}", err))?;
+    let frames = frames.into_iter()
+        .map(|frame| {
+            let (numerator, denominator) = frame.delay().numer_denom_ms();
+            AnimationFrame {
+                delay_ms: numerator / denominator.max(1),
+                image: DynamicImage::ImageRgba8(frame.into_buffer()),
+            }
+        })
+        .collect();
+
+    return Ok((frames, loop_count));
+}
+
+fn still_frame(filepath: &str) -> Result<(Vec<AnimationFrame>, u32), String> {
+    let image = image::open(filepath).map_err(|err| format!("Could not open {}: {}", filepath, err))?;
+    return Ok((vec![AnimationFrame { image, delay_ms: 0 }], 0));
+}
+
+fn has_transparency(image: &DynamicImage) -> bool {
+    image.color().has_alpha() && image.to_rgba8().pixels().any(|p| p[3] < DEFAULT_ALPHA_CUTOFF)
+}
+
+// Every opaque colour when they fit, otherwise num_colours picked with the selection strategy.
+pub(crate) fn limited_palette(images: &[&DynamicImage], num_colours: usize, strategy: SelectionStrategy) -> Vec<Rgb<u8>> {
+    let mut seen: HashSet<Rgb<u8>> = HashSet::new();
+    let mut colours = Vec::new();
+    for image in images {
+        for pixel in image.to_rgba8().pixels().filter(|p| p[3] >= DEFAULT_ALPHA_CUTOFF) {
+            let colour = Rgb([pixel[0], pixel[1], pixel[2]]);
+            if seen.insert(colour) {
+                colours.push(colour);
+            }
+        }
+    }
+
+    let num_colours = num_colours.clamp(1, MAX_INDEXED_COLOURS - 1);
+    if colours.is_empty() {
+        return vec![Rgb([0, 0, 0])];
+    }
+    if colours.len() <= num_colours {
+        return colours;
+    }
+
+    let mut selected: Vec<Rgb<u8>> = Vec::new();
+    for colour in select_colours(&colours, num_colours, strategy) {
+        if !selected.contains(&colour) {
+            selected.push(colour);
+        }
+    }
+    selected.truncate(num_colours);
+    return selected;
+}
+
+// Indexes a frame against the palette, with the transparent entry always after the palette colours.
+fn index_frame(image: &DynamicImage, palette: &[Rgb<u8>], with_transparency: bool) -> Result<IndexedImage, String> {
+    let palette = Palette { name: String::new(), colours: palette.to_vec() };
+    let mut indexed = IndexedImage::new(image, &palette, &IndexedPngOptions::default())?;
+    if with_transparency && indexed.transparent_index.is_none() {
+        indexed.colours.push(IndexedPngOptions::default().transparent_colour);
+        indexed.transparent_index = Some(palette.colours.len());
+    }
+    return Ok(indexed);
+}
+
+fn save_gif(animation: &Animation, file_path: &str, options: &AnimationSaveOptions) -> Result<(), String> {
+    let (width, height) = animation.dimensions();
+    if width > u16::MAX as u32 || height > u16::MAX as u32 {
+        return Err(format!("GIFs are limited to {}x{} pixels", u16::MAX, u16::MAX));
+    }
+    let gif_error = |err: gif::EncodingError| format!("Unable to save {}: {}", file_path, err);
+
+    let transparency = animation.frames.iter().any(|frame| has_transparency(&frame.image));
+    let images: Vec<&DynamicImage> = animation.frames.iter().map(|frame| &frame.image).collect();
+    let global = match options.palette {
+        FramePalette::GLOBAL => Some(limited_palette(&images, options.num_colours, options.strategy)),
+        FramePalette::LOCAL => None,
+    };
+
+    let file = File::create(file_path).map_err(|err| format!("Could not create {}: {}", file_path, err))?;
+    let mut global_table: Vec<u8> = Vec::new();
+    if let Some(colours) = &global {
+        global_table = index_frame(images[0], colours, transparency)?.colours.iter().flat_map(|c| c.0).collect();
+    }
+    let mut encoder = gif::Encoder::new(BufWriter::new(file), width as u16, height as u16, &global_table).map_err(gif_error)?;
+    let repeat = match animation.loop_count {
+        0 => gif::Repeat::Infinite,
+        n => gif::Repeat::Finite(n.min(u16::MAX as u32) as u16),
+    };
+    encoder.set_repeat(repeat).map_err(gif_error)?;
+
+    // Frames identical to the one before only extend its delay.
+    let delta = options.delta_frames && !transparency;
+    let mut parts: Vec<(usize, Rectangle, u32)> = Vec::new();
+    for (i, frame) in animation.frames.iter().enumerate() {
+        let rectangle = match (i, delta) {
+            (0, _) | (_, false) => Some((0, 0, width, height)),
+            _ => delta_rectangle(&animation.frames[i - 1].image.to_rgba8(), &frame.image.to_rgba8()),
+        };
+        match (rectangle, parts.last_mut()) {
+            (None, Some(last)) => last.2 += frame.delay_ms,
+            (rectangle, _) => parts.push((i, rectangle.unwrap_or((0, 0, width, height)), frame.delay_ms)),
+        }
+    }
+
+    for (i, (x, y, w, h), delay_ms) in parts {
+        let image = match delta {
+            true => animation.frames[i].image.crop_imm(x, y, w, h),
+            false => animation.frames[i].image.clone(),
+        };
+        let (indexed, local_table) = match &global {
+            Some(colours) => (index_frame(&image, colours, transparency)?, None),
+            None => {
+                let colours = limited_palette(&[&image], options.num_colours, options.strategy);
+                let indexed = index_frame(&image, &colours, has_transparency(&image))?;
+                let table: Vec<u8> = indexed.colours.iter().flat_map(|c| c.0).collect();
+                (indexed, Some(table))
+            },
+        };
+
+        // Full frames are cleared after display so transparent areas stay transparent, delta frames
+        // are kept so the next one can draw over them.
+        let gif_frame = gif::Frame {
+            delay: gif_delay(delay_ms) as u16,
+            dispose: if delta { gif::DisposalMethod::Keep } else { gif::DisposalMethod::Background },
+            transparent: indexed.transparent_index.map(|index| index as u8),
+            left: x as u16,
+            top: y as u16,
+            width: w as u16,
+            height: h as u16,
+            palette: local_table,
+            buffer: Cow::Owned(indexed.indices),
+            ..gif::Frame::default()
+        };
+        encoder.write_frame(&gif_frame).map_err(gif_error)?;
+    }
+
+    return Ok(());
+}
+
+// GIF delays are in hundredths of a second.
+fn gif_delay(delay_ms: u32) -> u32 {
+    ((delay_ms + 5) / 10).min(u16::MAX as u32)
+}
+
+// APNG has a single PLTE, so a global palette writes indexed frames while local palettes quantize each
+// frame on its own and write it as RGBA.
+fn save_apng(animation: &Animation, file_path: &str, options: &AnimationSaveOptions) -> Result<(), String> {
+    let (width, height) = animation.dimensions();
+    let png_error = |err: png::EncodingError| format!("Unable to save {}: {}", file_path, err);
+    let images: Vec<&DynamicImage> = animation.frames.iter().map(|frame| &frame.image).collect();
+
+    let mut data: Vec<Vec<u8>> = Vec::new();
+    let file = File::create(file_path).map_err(|err| format!("Could not create {}: {}", file_path, err))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+
+    match options.palette {
+        FramePalette::GLOBAL => {
+            let colours = limited_palette(&images, options.num_colours, options.strategy);
+            let transparency = images.iter().any(|image| has_transparency(image));
+            let mut indexed_frames = Vec::new();
+            for image in &images {
+                indexed_frames.push(index_frame(image, &colours, transparency)?);
+            }
+
+            let first = &indexed_frames[0];
+            encoder.set_color(png::ColorType::Indexed);
+            encoder.set_depth(first.bit_depth());
+            encoder.set_palette(first.colours.iter().flat_map(|c| c.0).collect::<Vec<u8>>());
+            if let Some(index) = first.transparent_index {
+                let mut trns = vec![255u8; index + 1];
+                trns[index] = 0;
+                encoder.set_trns(trns);
+            }
+            data.extend(indexed_frames.iter().map(|indexed| indexed.packed_rows()));
+        },
+        FramePalette::LOCAL => {
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            for image in &images {
+                let colours = limited_palette(&[image], options.num_colours, options.strategy);
+                let quantized = apply_palette((*image).clone(), Palette { name: String::new(), colours });
+                data.push(DynamicImage::ImageRgba8(quantized.to_rgba8()).into_bytes());
+            }
+        },
+    }
+
+    encoder.set_animated(data.len() as u32, animation.loop_count).map_err(png_error)?;
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    for (frame, bytes) in animation.frames.iter().zip(&data) {
+        writer.set_frame_delay(frame.delay_ms.min(u16::MAX as u32) as u16, 1000).map_err(png_error)?;
+        writer.write_image_data(bytes).map_err(png_error)?;
+    }
+    return writer.finish().map_err(png_error);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    const COLOURS: [[u8; 3]; 4] = [[200, 30, 40], [20, 120, 60], [240, 230, 220], [10, 10, 90]];
+
+    // A block of COLOURS[1] on COLOURS[0] that moves one pixel right per frame.
+    fn frame(offset: u32) -> DynamicImage {
+        return DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 6, |x, y| {
+            let [r, g, b] = if (offset..offset + 3).contains(&x) && (2..4).contains(&y) { COLOURS[1] } else { COLOURS[(y / 5 * 2) as usize] };
+            Rgba([r, g, b, 255])
+        }));
+    }
+
+    fn animation(frames: Vec<(DynamicImage, u32)>) -> Animation {
+        return Animation {
+            filename: "clip".to_string(),
+            frames: frames.into_iter().map(|(image, delay_ms)| AnimationFrame { image, delay_ms }).collect(),
+            loop_count: 3,
+            alpha_policy: AlphaPolicy::KEEP,
+        };
+    }
+
+    fn temp_path(name: &str, extension: &str) -> String {
+        return env::temp_dir().join(format!("pix-animation-{}-{}.{}", name, std::process::id(), extension)).to_str().unwrap().to_string();
+    }
+
+    fn round_trip(animation: &Animation, path: &str, options: &AnimationSaveOptions) -> Animation {
+        animation.save(path, options).unwrap();
+        return Animation::new(path).unwrap();
+    }
+
+    #[test]
+    fn gif_and_apng_keep_frames_and_delays() {
+        let clip = animation(vec![(frame(0), 100), (frame(2), 250), (frame(4), 40)]);
+
+        for extension in ["gif", "png"] {
+            for palette in [FramePalette::GLOBAL, FramePalette::LOCAL] {
+                let path = temp_path("trip", extension);
+                let options = AnimationSaveOptions { palette, ..AnimationSaveOptions::default() };
+                let loaded = round_trip(&clip, &path, &options);
+                fs::remove_file(&path).unwrap();
+
+                assert_eq!(loaded.frames.len(), 3, "{}", extension);
+                assert_eq!(loaded.frames.iter().map(|f| f.delay_ms).collect::<Vec<u32>>(), vec![100, 250, 40]);
+                for (saved, original) in loaded.frames.iter().zip(&clip.frames) {
+                    assert_eq!(saved.image.to_rgba8(), original.image.to_rgba8(), "{}", extension);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn identical_gif_frames_extend_the_delay_before_them() {
+        let clip = animation(vec![(frame(0), 100), (frame(0), 100), (frame(3), 50)]);
+        let path = temp_path("merge", "gif");
+        let loaded = round_trip(&clip, &path, &AnimationSaveOptions::default());
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.frames.len(), 2);
+        assert_eq!(loaded.frames.iter().map(|f| f.delay_ms).collect::<Vec<u32>>(), vec![200, 50]);
+        assert_eq!(loaded.frames[1].image.to_rgba8(), frame(3).to_rgba8());
+    }
+
+    #[test]
+    fn gif_palette_mode_picks_global_or_local_tables() {
+        let clip = animation(vec![(frame(0), 100), (frame(3), 100)]);
+
+        for (palette, global) in [(FramePalette::GLOBAL, true), (FramePalette::LOCAL, false)] {
+            let path = temp_path("table", "gif");
+            clip.save(&path, &AnimationSaveOptions { palette, ..AnimationSaveOptions::default() }).unwrap();
+
+            let mut decoder = gif::DecodeOptions::new().read_info(BufReader::new(File::open(&path).unwrap())).unwrap();
+            // The gif encoder always writes a global table, with local palettes it only holds padding.
+            let global_table = decoder.global_palette().unwrap().to_vec();
+            let mut local_tables = 0;
+            while let Some(frame) = decoder.read_next_frame().unwrap() {
+                local_tables += frame.palette.is_some() as usize;
+            }
+            fs::remove_file(&path).unwrap();
+
+            let in_global_table = [COLOURS[0], COLOURS[1], COLOURS[2]].iter().all(|c| global_table.chunks(3).any(|entry| entry == c));
+            assert_eq!(in_global_table, global);
+            assert_eq!(local_tables, if global { 0 } else { 2 });
+        }
+    }
+
+    #[test]
+    fn apng_palette_mode_picks_indexed_or_rgba() {
+        let clip = animation(vec![(frame(0), 100), (frame(3), 100)]);
+
+        for (palette, colour_type) in [(FramePalette::GLOBAL, png::ColorType::Indexed), (FramePalette::LOCAL, png::ColorType::Rgba)] {
+            let path = temp_path("apng", "png");
+            clip.save(&path, &AnimationSaveOptions { palette, ..AnimationSaveOptions::default() }).unwrap();
+
+            let reader = png::Decoder::new(BufReader::new(File::open(&path).unwrap())).read_info().unwrap();
+            let info = reader.info();
+            assert_eq!(info.color_type, colour_type);
+            assert_eq!(info.animation_control.map(|a| (a.num_frames, a.num_plays)), Some((2, 3)));
+            fs::remove_file(&path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,52 @@
+include!(concat!(env!("OUT_DIR"), "/builtin_palettes.rs"));
+
+pub fn builtin_palette(name: &str) -> Option<(&'static str, &'static str)> {
+    let wanted = name.to_lowercase();
+    let wanted = wanted.strip_suffix(".hex").unwrap_or(&wanted);
+
+    return BUILTIN_PALETTES.iter()
+        .find(|(builtin, _)| builtin.to_lowercase() == wanted)
+        .copied();
+}
+
+pub fn builtin_names() -> Vec<&'static str> {
+    BUILTIN_PALETTES.iter().map(|(name, _)| *name).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{read_dir, read_to_string};
+    use std::path::Path;
+
+    use crate::palette::Palette;
+
+    use super::*;
+
+    #[test]
+    fn registry_matches_the_palette_directory() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("palettes");
+        let mut files: Vec<(String, String)> = read_dir(dir).unwrap()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "hex"))
+            .map(|path| (path.file_stem().unwrap().to_str().unwrap().to_string(), read_to_string(&path).unwrap()))
+            .collect();
+        files.sort();
+
+        let registry: Vec<(String, String)> = BUILTIN_PALETTES.iter()
+            .map(|(name, contents)| (name.to_string(), contents.to_string()))
+            .collect();
+        assert_eq!(registry, files);
+    }
+
+    #[test]
+    fn names_resolve_case_insensitively_with_or_without_extension() {
+        for name in builtin_names() {
+            assert_eq!(builtin_palette(name).unwrap().0, name);
+            assert_eq!(builtin_palette(&format!("{}.hex", name.to_uppercase())).unwrap().0, name);
+            assert!(Palette::builtin(name).is_some_and(|palette| !palette.colours.is_empty()));
+        }
+        assert_eq!(builtin_palette("nyx8").unwrap().1.lines().count(), 8);
+        assert!(builtin_palette("not-a-builtin").is_none());
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::HashMap;
+
+use image::{DynamicImage, GenericImageView, Rgb, RgbaImage};
+
+use crate::alpha::DEFAULT_ALPHA_CUTOFF;
+use crate::palette::Palette;
+use crate::utils::find_closest_index;
+
+// Marks transparent pixels, which are never changed and never spread into their neighbours.
+const TRANSPARENT: usize = usize::MAX;
+const ORTHOGONAL: [(i64, i64); 4] = [(0, -1), (-1, 0), (0, 1), (1, 0)];
+const SURROUNDING: [(i64, i64); 8] = [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)];
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Connectivity {
+    // Pixels touching along a side belong to the same region.
+    FOUR,
+    // Diagonal neighbours count too, so one pixel wide diagonal lines hold together.
+    EIGHT,
+}
+
+impl Connectivity {
+    pub fn new(connectivity: &str) -> Result<Connectivity, &'static str> {
+        return match connectivity {
+            "4" => Ok(Connectivity::FOUR),
+            "8" => Ok(Connectivity::EIGHT),
+            _ => Err("Invalid connectivity"),
+        };
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct CleanupOptions {
+    // Regions of one colour with fewer pixels than this are merged into the neighbouring colour they
+    // share the most border with. 0 or 1 leaves every region alone.
+    pub min_region: usize,
+    pub connectivity: Connectivity,
+    // Fills one pixel bumps and notches along edges and removes the corner pixels that double up one
+    // pixel wide lines.
+    pub smooth_jaggies: bool,
+    // Radius of the mode filter that runs before everything else, 0 turns it off. Thin lines do not
+    // survive it, so it suits flat areas of photos more than line art.
+    pub mode_radius: u32,
+    // Times the whole pass runs, later passes catch what merging in the earlier ones uncovered.
+    pub passes: u32,
+}
+
+impl Default for CleanupOptions {
+    fn default() -> Self {
+        CleanupOptions {
+            min_region: 3,
+            connectivity: Connectivity::EIGHT,
+            smooth_jaggies: true,
+            mode_radius: 0,
+            passes: 1,
+        }
+    }
+}
+
+// Cleans up a palettized image the way a pixel artist would by hand. Pixels outside the palette are
+// matched to it first and every change copies a neighbouring palette colour, so the output never holds
+// a colour the palette does not. Alpha is carried through untouched.
+pub fn cleanup_image(img: &DynamicImage, palette: &Palette, options: &CleanupOptions) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    if palette.colours.is_empty() || width == 0 || height == 0 {
+        return img.clone();
+    }
+
+    let pixels = img.to_rgba8();
+    let mut cache: HashMap<Rgb<u8>, usize> = HashMap::new();
+    let mut indices: Vec<usize> = pixels.pixels()
+        .map(|p| match p[3] < DEFAULT_ALPHA_CUTOFF && img.color().has_alpha() {
+            true => TRANSPARENT,
+            false => {
+                let colour = Rgb([p[0], p[1], p[2]]);
+                *cache.entry(colour).or_insert_with(|| find_closest_index(&colour, &palette.colours))
+            },
+        })
+        .collect();
+
+    let grid = Grid { width: width as i64, height: height as i64 };
+    for _ in 0..options.passes.max(1) {
+        if options.mode_radius > 0 {
+            indices = mode_filter(&indices, &grid, options.mode_radius as i64);
+        }
+        if options.min_region > 1 {
+            merge_small_regions(&mut indices, &grid, options.min_region, options.connectivity);
+        }
+        if options.smooth_jaggies {
+            smooth_jaggies(&mut indices, &grid);
+        }
+    }
+
+    let output = RgbaImage::from_fn(width, height, |x, y| {
+        let pixel = *pixels.get_pixel(x, y);
+        match indices[(y * width + x) as usize] {
+            TRANSPARENT => pixel,
+            index => {
+                let colour = palette.colours[index];
+                image::Rgba([colour[0], colour[1], colour[2], pixel[3]])
+            },
+        }
+    });
+    return match img.color().has_alpha() {
+        true => DynamicImage::ImageRgba8(output),
+        false => DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(output).to_rgb8()),
+    };
+}
+
+struct Grid {
+    width: i64,
+    height: i64,
+}
+
+impl Grid {
+    // Index of the pixel, None outside the image.
+    fn at(&self, x: i64, y: i64) -> Option<usize> {
+        if x < 0 || y < 0 || x >= self.width || y >= self.height {
+            return None;
+        }
+        return Some((y * self.width + x) as usize);
+    }
+
+    fn position(&self, i: usize) -> (i64, i64) {
+        return (i as i64 % self.width, i as i64 / self.width);
+    }
+
+    fn len(&self) -> usize {
+        return (self.width * self.height) as usize;
+    }
+}
+
+// Most common colour in the square around each pixel. Ties keep the pixel's own colour.
+fn mode_filter(indices: &[usize], grid: &Grid, radius: i64) -> Vec<usize> {
+    let mut counts: HashMap<usize, usize> = HashMap::new();
+    return (0..grid.len())
+        .map(|i| {
+            if indices[i] == TRANSPARENT {
+                return TRANSPARENT;
+            }
+            let (x, y) = grid.position(i);
+            counts.clear();
+            for dy in -radius..=radius {
+                for dx in -radius..=radius {
+                    if let Some(n) = grid.at(x + dx, y + dy).filter(|n| indices[*n] != TRANSPARENT) {
+                        *counts.entry(indices[n]).or_default() += 1;
+                    }
+                }
+            }
+            let own = counts[&indices[i]];
+            counts.iter()
+                .filter(|(_, count)| **count > own)
+                .max_by_key(|(index, count)| (**count, std::cmp::Reverse(**index)))
+                .map(|(index, _)| *index)
+                .unwrap_or(indices[i])
+        })
+        .collect();
+}
+
+// Regions are found on the colours before any merge, so two small regions next to each other are
+// judged on their own sizes.
+fn merge_small_regions(indices: &mut [usize], grid: &Grid, min_region: usize, connectivity: Connectivity) {
+    let neighbours: &[(i64, i64)] = match connectivity {
+        Connectivity::FOUR => &ORTHOGONAL,
+        Connectivity::EIGHT => &SURROUNDING,
+    };
+
+    let mut region = vec![usize::MAX; grid.len()];
+    let mut replacements: Vec<(Vec<usize>, usize)> = Vec::new();
+    for start in 0..grid.len() {
+        if region[start] != usize::MAX || indices[start] == TRANSPARENT {
+            continue;
+        }
+
+        let colour = indices[start];
+        let mut members = vec![start];
+        let mut stack = vec![start];
+        region[start] = start;
+        while let Some(i) = stack.pop() {
+            let (x, y) = grid.position(i);
+            for (dx, dy) in neighbours {
+                if let Some(n) = grid.at(x + dx, y + dy).filter(|n| region[*n] == usize::MAX && indices[*n] == colour) {
+                    region[n] = start;
+                    members.push(n);
+                    stack.push(n);
+                }
+            }
+        }
+        if members.len() >= min_region {
+            continue;
+        }
+
+        // Border length shared with each other colour, counted along sides only.
+        let mut border: HashMap<usize, usize> = HashMap::new();
+        for i in &members {
+            let (x, y) = grid.position(*i);
+            for (dx, dy) in ORTHOGONAL {
+                if let Some(n) = grid.at(x + dx, y + dy).filter(|n| indices[*n] != colour && indices[*n] != TRANSPARENT) {
+                    *border.entry(indices[n]).or_default() += 1;
+                }
+            }
+        }
+        if let Some((replacement, _)) = border.into_iter().max_by_key(|(index, count)| (*count, std::cmp::Reverse(*index))) {
+            replacements.push((members, replacement));
+        }
+    }
+
+    for (members, replacement) in replacements {
+        for i in members {
+            indices[i] = replacement;
+        }
+    }
+}
+
+// Works in reading order on the image as it changes, so of two overlapping patterns only the first
+// is fixed.
+fn smooth_jaggies(indices: &mut [usize], grid: &Grid) {
+    for i in 0..grid.len() {
+        let colour = indices[i];
+        if colour == TRANSPARENT {
+            continue;
+        }
+        if let Some(replacement) = bump_or_notch(indices, grid, i).or_else(|| doubled_corner(indices, grid, i)) {
+            indices[i] = replacement;
+        }
+    }
+}
+
+// A pixel sticking out of an edge, or a one pixel dent in it: three sides share another colour while
+// the fourth side and both diagonals next to it keep the pixel's own. Ends of one pixel wide lines
+// have no diagonals of their own colour, so they are left alone.
+fn bump_or_notch(indices: &[usize], grid: &Grid, i: usize) -> Option<usize> {
+    let (x, y) = grid.position(i);
+    let colour_at = |dx: i64, dy: i64| grid.at(x + dx, y + dy).map(|n| indices[n]);
+    let colour = indices[i];
+
+    for (k, (dx, dy)) in ORTHOGONAL.iter().enumerate() {
+        let others: Vec<Option<usize>> = (0..4).filter(|j| *j != k).map(|j| colour_at(ORTHOGONAL[j].0, ORTHOGONAL[j].1)).collect();
+        let Some(surrounding) = others[0] else { continue };
+        if surrounding == colour || surrounding == TRANSPARENT || others.iter().any(|other| *other != Some(surrounding)) {
+            continue;
+        }
+
+        // Diagonals on the side that keeps the pixel's colour.
+        let (sx, sy) = (*dy, *dx);
+        let side = [colour_at(*dx, *dy), colour_at(dx + sx, dy + sy), colour_at(dx - sx, dy - sy)];
+        if side.iter().all(|c| *c == Some(colour)) {
+            return Some(surrounding);
+        }
+    }
+    return None;
+}
+
+// The corner pixel in an L of a one pixel wide line, which makes a diagonal step look doubled. It has
+// one neighbour of its colour to the side and one above or below, and the line stays connected
+// through the diagonal without it. It is only removed when at least one arm of the L is a single
+// pixel step, so the right angled corners of longer lines stay sharp.
+fn doubled_corner(indices: &[usize], grid: &Grid, i: usize) -> Option<usize> {
+    let (x, y) = grid.position(i);
+    let colour_at = |dx: i64, dy: i64| grid.at(x + dx, y + dy).map(|n| indices[n]);
+    let colour = indices[i];
+
+    let horizontal: Vec<i64> = [-1, 1].into_iter().filter(|dx| colour_at(*dx, 0) == Some(colour)).collect();
+    let vertical: Vec<i64> = [-1, 1].into_iter().filter(|dy| colour_at(0, *dy) == Some(colour)).collect();
+    if horizontal.len() != 1 || vertical.len() != 1 {
+        return None;
+    }
+    let (hx, vy) = (horizontal[0], vertical[0]);
+    if colour_at(hx, vy) == Some(colour) {
+        return None;
+    }
+    if colour_at(hx * 2, 0) == Some(colour) && colour_at(0, vy * 2) == Some(colour) {
+        return None;
+    }
+
+    // Every neighbour of the same colour has to stay reachable from the others without this pixel.
+    let same: Vec<(i64, i64)> = SURROUNDING.iter().copied().filter(|(dx, dy)| colour_at(*dx, *dy) == Some(colour)).collect();
+    let mut reached = vec![false; same.len()];
+    let mut stack = vec![0];
+    reached[0] = true;
+    while let Some(a) = stack.pop() {
+        for b in 0..same.len() {
+            if !reached[b] && (same[a].0 - same[b].0).abs() <= 1 && (same[a].1 - same[b].1).abs() <= 1 {
+                reached[b] = true;
+                stack.push(b);
+            }
+        }
+    }
+    if reached.iter().any(|r| !r) {
+        return None;
+    }
+
+    // The colour the rest of the neighbourhood is made of.
+    let mut counts: HashMap<usize, usize> = HashMap::new();
+    for (dx, dy) in SURROUNDING {
+        if let Some(c) = colour_at(dx, dy).filter(|c| *c != colour && *c != TRANSPARENT) {
+            *counts.entry(c).or_default() += 1;
+        }
+    }
+    return counts.into_iter().max_by_key(|(index, count)| (*count, std::cmp::Reverse(*index))).map(|(index, _)| index);
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbImage};
+
+    use super::*;
+
+    fn palette() -> Palette {
+        return Palette {
+            name: "test.hex".to_string(),
+            colours: vec![Rgb([0, 0, 0]), Rgb([255, 255, 255]), Rgb([200, 40, 40]), Rgb([40, 120, 200])],
+        };
+    }
+
+    // Noise over every colour, most of which are not in the palette.
+    fn noise(width: u32, height: u32) -> RgbaImage {
+        let mut state = 12345u32;
+        return RgbaImage::from_fn(width, height, |_, _| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            let [r, g, b, a] = state.to_be_bytes();
+            Rgba([r, g, b, if a < 64 { 0 } else { 255 }])
+        });
+    }
+
+    #[test]
+    fn output_stays_within_the_palette() {
+        let image = DynamicImage::ImageRgba8(noise(40, 30));
+        let options = [
+            CleanupOptions::default(),
+            CleanupOptions { connectivity: Connectivity::FOUR, min_region: 6, passes: 3, ..CleanupOptions::default() },
+            CleanupOptions { mode_radius: 2, smooth_jaggies: false, ..CleanupOptions::default() },
+        ];
+        for options in options {
+            let output = cleanup_image(&image, &palette(), &options).to_rgba8();
+            for (input, output) in image.to_rgba8().pixels().zip(output.pixels()) {
+                if input[3] < DEFAULT_ALPHA_CUTOFF {
+                    assert_eq!(input, output);
+                } else {
+                    assert_eq!(output[3], input[3]);
+                    assert!(palette().colours.contains(&Rgb([output[0], output[1], output[2]])));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn orphan_pixel_takes_the_surrounding_colour() {
+        let mut image = RgbImage::from_pixel(5, 5, Rgb([255, 255, 255]));
+        image.put_pixel(2, 2, Rgb([200, 40, 40]));
+        let output = cleanup_image(&DynamicImage::ImageRgb8(image), &palette(), &CleanupOptions::default()).to_rgb8();
+        assert!(output.pixels().all(|p| *p == Rgb([255, 255, 255])));
+    }
+
+    #[test]
+    fn one_pixel_diagonal_line_survives() {
+        let mut image = RgbImage::from_pixel(6, 6, Rgb([255, 255, 255]));
+        for i in 0..6 {
+            image.put_pixel(i, i, Rgb([0, 0, 0]));
+        }
+        let output = cleanup_image(&DynamicImage::ImageRgb8(image.clone()), &palette(), &CleanupOptions::default()).to_rgb8();
+        assert_eq!(output, image);
+    }
+}
@@ -0,0 +1,854 @@
+use std::fs::create_dir_all;
+use std::process::exit;
+
+use clap::parser::ValueSource;
+use clap::{arg, command, value_parser, Arg, ArgMatches, Command};
+use image::codecs::png::{CompressionType, FilterType as PngFilterType};
+use image::imageops::FilterType;
+use image::Rgb;
+
+use crate::alpha::AlphaPolicy;
+use crate::animation::{Animation, AnimationSaveOptions, FramePalette};
+use crate::analysis::{ColourPair, PaletteReport, CONFUSABLE_PAIR_COUNT, WCAG_AA, WCAG_AAA};
+use crate::cleanup::{CleanupOptions, Connectivity};
+use crate::cvd::{CvdModel, Deficiency};
+use crate::harmony::{generate_harmony, Harmony};
+use crate::image::{Extension, Image, SaveOptions};
+use crate::indexed::IndexedPngOptions;
+use crate::library::{ansi_swatch, palette_stem, PaletteFilter, PaletteInfo, PaletteLibrary};
+use crate::outline::{EdgeDetector, EdgeSignal, OutlineColour, OutlineMode, OutlineOptions};
+use crate::palette::Palette;
+use crate::pixelate::{BlockReduction, BlockSize, EdgeBlocks, GridAnchor, PixelateOptions};
+use crate::recolour::{ColourMapping, MappingStrategy};
+use crate::recommend::{recommend_palettes, save_previews, RankingMetric, RecommendOptions};
+use crate::ramp::{generate_ramp, generate_shade_ramps, RampOptions, RampSpace, SaturationCurve};
+use crate::builtin::BUILTIN_PALETTES;
+use crate::consts::DIFF_MAT_FLOYD_STEINBERG;
+use crate::resize::{ResizeMode, ResizeOptions, ResizeTarget};
+use crate::svg::SvgMode;
+use crate::temporal::TemporalDither;
+use crate::sequence::{ClipPalette, FrameSequence, SequenceOptions};
+use crate::search_path::{PaletteLocation, PaletteSearchPath, PaletteSource};
+use crate::upscale::Upscaler;
+use crate::utils::{hex_to_rgb, rgb_to_hex};
+
+pub fn build_cli() -> Command {
+    command!()
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommand(
+            Command::new("palettes")
+                .about("List, filter and search the palette library")
+                .arg(arg!(-s --search <QUERY> "Fuzzy search palette names"))
+                .arg(arg!(--"min-colours" <N> "Only show palettes with at least N colours").value_parser(value_parser!(usize)))
+                .arg(arg!(--"max-colours" <N> "Only show palettes with at most N colours").value_parser(value_parser!(usize)))
+                .arg(arg!(-c --contains <HEX> "Only show palettes containing this colour"))
+                .arg(arg!(--swatch "Print a colour swatch for each palette"))
+                .arg(arg!(-d --dir <DIR> "Only use palettes from this directory instead of the search path"))
+                .arg(arg!(--paths "Print the palette search path and exit"))
+                .arg(arg!(--which <NAME> "Print the file a palette name resolves to and exit"))
+        )
+        .subcommand(
+            Command::new("ramp")
+                .about("Generate colour ramps between anchors or shade/tint ramps from a palette")
+                .arg(arg!(-a --anchors <HEX> "Comma separated anchor colours").value_delimiter(','))
+                .arg(arg!(-p --palette <NAME> "Build a shade/tint ramp for every colour of this palette").conflicts_with("anchors"))
+                .arg(arg!(--steps <N> "Colours from one anchor to the next, counting both anchors").value_parser(value_parser!(usize)).default_value("8"))
+                .arg(arg!(--shades <N> "Shades per palette colour").value_parser(value_parser!(usize)).default_value("2"))
+                .arg(arg!(--tints <N> "Tints per palette colour").value_parser(value_parser!(usize)).default_value("2"))
+                .arg(arg!(--"hue-shift" <DEGREES> "Hue shift applied along the ramp").value_parser(value_parser!(f32)).default_value("0").allow_negative_numbers(true))
+                .arg(arg!(--peak <AMOUNT> "Boost saturation towards the middle of the ramp").value_parser(value_parser!(f32)))
+                .arg(arg!(--fade <AMOUNT> "Reduce saturation towards the ends of the ramp").value_parser(value_parser!(f32)).conflicts_with("peak"))
+                .arg(arg!(--space <SPACE> "Interpolation space").value_parser(["oklab", "oklch"]).default_value("oklch"))
+                .arg(arg!(-n --name <NAME> "Name of the generated palette").default_value("ramp.hex"))
+                .arg(arg!(-o --output <DIR> "Save the palette to this directory"))
+                .arg(arg!(--save "Save the palette to the default palette directory"))
+        )
+        .subcommand(
+            Command::new("analyse")
+                .about("Report colour distances, contrast and colour-blindness issues of a palette")
+                .arg(arg!(<PALETTE> "Palette name or file"))
+                .arg(arg!(--"cvd-model" <MODEL> "Colour-blindness simulation model").value_parser(["brettel", "vienot", "machado"]).default_value("machado"))
+        )
+        .subcommand(
+            Command::new("recommend")
+                .about("Rank the palette library by how well each palette reproduces an image")
+                .arg(arg!(<IMAGE> "Input image"))
+                .arg(arg!(-n --top <N> "Number of palettes to show").value_parser(value_parser!(usize)).default_value("5"))
+                .arg(arg!(-m --metric <METRIC> "Reconstruction error metric").value_parser(["deltae", "ssim"]).default_value("deltae"))
+                .arg(arg!(--"coverage-weight" <WEIGHT> "Penalty for palettes whose colours go unused").value_parser(value_parser!(f32)).default_value("10"))
+                .arg(arg!(--size <PIXELS> "Longest side of the thumbnail the palettes are tried on").value_parser(value_parser!(u32)).default_value("128"))
+                .arg(arg!(--previews <DIR> "Save a palettized thumbnail for each recommended palette"))
+        )
+        .subcommand(
+            Command::new("harmony")
+                .about("Synthesise a palette from seed colours using a colour harmony rule")
+                .arg(arg!(-s --seed <HEX> "Comma separated seed colours").value_delimiter(',').required(true))
+                .arg(arg!(-r --rule <RULE> "Harmony rule")
+                    .value_parser(["complementary", "analogous", "triadic", "tetradic", "split-complementary", "monochrome"])
+                    .default_value("complementary"))
+                .arg(arg!(-c --colours <N> "Number of colours to generate").value_parser(value_parser!(usize)).default_value("8"))
+                .arg(arg!(--spread <AMOUNT> "OKLCh lightness spread of each hue, from 0 to 1").value_parser(value_parser!(f32)).default_value("0.5"))
+                .arg(arg!(-n --name <NAME> "Name of the generated palette").default_value("harmony.hex"))
+                .arg(arg!(-o --output <DIR> "Save the palette to this directory"))
+                .arg(arg!(--save "Save the palette to the default palette directory"))
+        )
+        .subcommand(
+            Command::new("cvd")
+                .about("Simulate colour-blindness on an image or daltonize it")
+                .arg(arg!(<IMAGE> "Input image"))
+                .args(input_args())
+                .arg(arg!(-d --deficiency <DEFICIENCY> "Colour vision deficiency")
+                    .value_parser(["protanopia", "deuteranopia", "tritanopia", "achromatopsia"])
+                    .required(true))
+                .arg(arg!(-m --model <MODEL> "Simulation model").value_parser(["brettel", "vienot", "machado"]).default_value("machado"))
+                .arg(arg!(--daltonize "Correct the image for the deficiency instead of simulating it"))
+                .arg(arg!(-o --output <FILE> "Output image"))
+                .args(save_args())
+        )
+        .subcommand(
+            Command::new("palettize")
+                .about("Map every pixel of an image to its closest palette colour")
+                .arg(arg!(<IMAGE> "Input image"))
+                .args(input_args())
+                .arg(arg!(-p --palette <NAME> "Palette to map the image to").required(true))
+                .args(alpha_args())
+                .arg(arg!(-o --output <FILE> "Output image"))
+                .arg(arg!(--indexed "Write an indexed PNG with the palette in order, alpha below 128 becomes the one transparent entry, the --transparent-index one with --alpha index")
+                    .requires("output"))
+                .args(save_args())
+        )
+        .subcommand(
+            Command::new("pixelate")
+                .about("Reduce an image to blocks of one colour each")
+                .arg(arg!(<IMAGE> "Input image"))
+                .args(input_args())
+                .arg(arg!(-b --block <SIZE> "Block size in pixels, N or WxH, fractions allowed").value_parser(parse_block).default_value("4"))
+                .arg(arg!(-t --target <SIZE> "Number of blocks across and down, WxH").value_parser(parse_size).conflicts_with("block"))
+                .arg(arg!(-r --reduction <MODE> "How each block is reduced to one colour")
+                    .value_parser(["mode", "median", "kmeans", "edge", "nearest", "triangle", "lanczos"])
+                    .default_value("mode"))
+                .arg(arg!(-k --clusters <K> "Clusters per block for kmeans").value_parser(value_parser!(u32).range(1..=255)).default_value("2"))
+                .arg(arg!(--anchor <ANCHOR> "Where the grid lines up with the image")
+                    .value_parser(["topleft", "centre", "bottomright"])
+                    .default_value("topleft"))
+                .arg(arg!(--offset <XY> "Grid shift in pixels, X,Y").value_parser(parse_offset).allow_hyphen_values(true).default_value("0,0"))
+                .arg(arg!(--edges <MODE> "Blocks cut short by the image edges are kept, padded to full size or cropped")
+                    .value_parser(["partial", "pad", "crop"])
+                    .default_value("partial"))
+                .arg(arg!(--"low-res" "Write one pixel per block instead of scaling the blocks back up"))
+                .arg(arg!(-o --output <FILE> "Output image"))
+                .args(save_args())
+        )
+        .subcommand(
+            Command::new("outline")
+                .about("Draw one pixel outlines along the edges of pixel art")
+                .arg(arg!(<IMAGE> "Input image"))
+                .args(input_args())
+                .arg(arg!(-p --palette <NAME> "Palette the outline colours are taken from").required(true))
+                .arg(arg!(-m --mode <MODE> "Where outlines are drawn").value_parser(["inner", "outer", "selective"]).default_value("inner"))
+                .arg(arg!(-d --detector <DETECTOR> "Edge detector").value_parser(["sobel", "canny"]).default_value("sobel"))
+                .arg(arg!(--threshold <T> "Edge strength in CIELAB units, the high threshold for canny").value_parser(value_parser!(f32)).default_value("20"))
+                .arg(arg!(--signal <SIGNAL> "Measure edges on lightness or full colour difference")
+                    .value_parser(["luminance", "deltae"])
+                    .default_value("deltae"))
+                .arg(arg!(--darkest "Draw every outline in the darkest palette colour instead of darkened neighbours"))
+                .arg(arg!(--darken <AMOUNT> "Fraction of lightness taken off the neighbouring colour, from 0 to 1")
+                    .value_parser(value_parser!(f32))
+                    .default_value("0.35"))
+                .arg(arg!(-b --block <N> "Size of one art pixel in the image").value_parser(value_parser!(u32).range(1..)).default_value("1"))
+                .arg(arg!(-o --output <FILE> "Output image"))
+                .args(save_args())
+        )
+        .subcommand(
+            Command::new("cleanup")
+                .about("Remove orphan pixels, noisy clusters and jaggies from a palettized image")
+                .arg(arg!(<IMAGE> "Input image"))
+                .args(input_args())
+                .arg(arg!(-p --palette <NAME> "Palette the image is kept within").required(true))
+                .arg(arg!(--"min-region" <N> "Regions of one colour smaller than this are merged into their surroundings")
+                    .value_parser(value_parser!(usize))
+                    .default_value("3"))
+                .arg(arg!(--connectivity <N> "Whether diagonal neighbours join regions").value_parser(["4", "8"]).default_value("8"))
+                .arg(arg!(--"no-jaggies" "Leave bumps, notches and doubled line corners alone"))
+                .arg(arg!(--"mode-radius" <N> "Radius of the mode filter, 0 to turn it off").value_parser(value_parser!(u32)).default_value("0"))
+                .arg(arg!(--passes <N> "Times the cleanup runs").value_parser(value_parser!(u32).range(1..)).default_value("1"))
+                .arg(arg!(-o --output <FILE> "Output image"))
+                .args(save_args())
+        )
+        .subcommand(
+            Command::new("resize")
+                .about("Resize an image to a width, height, size or percentage")
+                .arg(arg!(<IMAGE> "Input image"))
+                .args(input_args())
+                .arg(arg!(-s --size <SIZE> "W, xH, WxH or a percentage such as 50%").required(true))
+                .arg(arg!(-m --mode <MODE> "How a WxH size is met, integer scales by whole factors with nearest neighbour")
+                    .value_parser(["fit", "fill", "exact", "integer"])
+                    .default_value("fit"))
+                .arg(arg!(-f --filter <FILTER> "Resampling filter")
+                    .value_parser(["nearest", "triangle", "catmullrom", "gaussian", "lanczos"])
+                    .default_value("nearest"))
+                .arg(arg!(-o --output <FILE> "Output image"))
+                .args(save_args())
+        )
+        .subcommand(
+            Command::new("upscale")
+                .about("Scale pixel art up without blurring it")
+                .arg(arg!(<IMAGE> "Input image"))
+                .args(input_args())
+                .arg(arg!(-a --algorithm <NAME> "Scaling algorithm, hqx, xbr and superxbr add colours outside the palette")
+                    .value_parser(["nearest", "epx", "scalex", "eagle", "hqx", "xbr", "superxbr"])
+                    .default_value("scalex"))
+                .arg(arg!(-f --factor <N> "Scale factor").value_parser(value_parser!(u32).range(1..)).default_value("2"))
+                .arg(arg!(-o --output <FILE> "Output image"))
+                .args(save_args())
+        )
+        .subcommand(
+            Command::new("animate")
+                .about("Pixelate and palettize every frame of an animated GIF, APNG or WebP")
+                .arg(arg!(<INPUT> "Input animation"))
+                .arg(arg!(-o --output <FILE> "Output GIF or APNG").required(true))
+                .arg(arg!(-p --palette <NAME> "Palettize every frame with this palette"))
+                .arg(arg!(--pixelate <SCALE> "Pixelate every frame by this factor").value_parser(value_parser!(u32).range(1..)))
+                .arg(arg!(-c --colours <N> "Colours in each generated palette").value_parser(value_parser!(usize)).default_value("255"))
+                .arg(arg!(--local "Generate a palette for every frame instead of one across all frames"))
+                .arg(arg!(--dither <MODE> "Dither to the palette without flicker between frames")
+                    .value_parser(["ordered", "bluenoise", "diffusion"])
+                    .requires("palette"))
+                .arg(arg!(--tolerance <T> "Channel difference below which a pixel counts as unchanged for diffusion")
+                    .value_parser(value_parser!(u8))
+                    .default_value("4"))
+                .arg(arg!(--"no-delta" "Store every GIF frame in full instead of only the changed rectangle"))
+                .args(alpha_args())
+        )
+        .subcommand(
+            Command::new("sequence")
+                .about("Process a directory of numbered frames as one clip")
+                .arg(arg!(<DIRECTORY> "Directory of frames, such as frame_0001.png, frame_0002.png..."))
+                .arg(arg!(-o --output <PATH> "Output directory, or a .gif or .png file for an animation").required(true))
+                .arg(arg!(-p --palette <NAME> "Palettize every frame with this palette instead of generating one"))
+                .arg(arg!(--scenes <THRESHOLD> "Generate a palette per scene, cutting where frames differ by more than this (0 to 1)")
+                    .value_parser(value_parser!(f32))
+                    .conflicts_with("palette"))
+                .arg(arg!(-c --colours <N> "Colours in each generated palette").value_parser(value_parser!(usize)).default_value("255"))
+                .arg(arg!(--pixelate <SCALE> "Pixelate every frame by this factor").value_parser(value_parser!(u32).range(1..)))
+                .arg(arg!(--dither <MODE> "Dither to the palette without flicker between frames")
+                    .value_parser(["ordered", "bluenoise", "diffusion"]))
+                .arg(arg!(--tolerance <T> "Channel difference below which a pixel counts as unchanged for diffusion")
+                    .value_parser(value_parser!(u8))
+                    .default_value("4"))
+                .arg(arg!(--fps <FPS> "Frame rate of an animation output").value_parser(value_parser!(f32)).default_value("24"))
+                .arg(arg!(--threads <N> "Frames processed at once, every available thread by default").value_parser(value_parser!(usize)))
+                .args(alpha_args())
+        )
+        .subcommand(
+            Command::new("recolour")
+                .about("Port an image from one palette to another through an explicit colour mapping")
+                .arg(arg!(<IMAGE> "Input image"))
+                .args(input_args())
+                .arg(arg!(-f --from <PALETTE> "Palette the image is drawn in").required_unless_present("mapping"))
+                .arg(arg!(-t --to <PALETTE> "Palette to port the image to").required_unless_present("mapping"))
+                .arg(arg!(-s --strategy <STRATEGY> "How source colours are paired with target colours")
+                    .value_parser(["index", "lightness", "optimal"])
+                    .default_value("optimal"))
+                .arg(arg!(-m --mapping <FILE> "Use a mapping table instead of building one").conflicts_with_all(["from", "to"]))
+                .arg(arg!(--table <FILE> "Export the mapping table to this file"))
+                .arg(arg!(-o --output <FILE> "Output image"))
+                .arg(arg!(--indexed "Write an indexed PNG with the target palette in order, alpha below 128 becomes the one transparent entry")
+                    .requires("output"))
+                .arg(arg!(--"transparent-index" <N> "Target palette entry that becomes the transparent one in indexed output, one is added by default")
+                    .value_parser(value_parser!(usize))
+                    .requires("indexed"))
+                .args(save_args())
+        )
+}
+
+fn input_args() -> Vec<Arg> {
+    vec![
+        arg!(--srgb "Convert from the embedded ICC profile to sRGB before processing"),
+    ]
+}
+
+fn save_args() -> Vec<Arg> {
+    vec![
+        arg!(--format <FORMAT> "Output format, taken from the output file extension by default")
+            .value_parser(["png", "jpg", "webp", "gif", "bmp", "qoi", "tiff", "avif", "svg"]),
+        arg!(--quality <QUALITY> "JPEG quality, WebP is always saved lossless").value_parser(value_parser!(u8).range(1..=100)),
+        arg!(--"png-compression" <LEVEL> "PNG compression: fast, default, best, none or a level from 1 to 9"),
+        arg!(--"png-filter" <FILTER> "PNG row filter").value_parser(["none", "sub", "up", "avg", "paeth", "adaptive"]),
+        arg!(--"svg-mode" <MODE> "How SVG output merges pixels").value_parser(["runs", "rectangles", "paths"]),
+        arg!(--"svg-scale" <N> "Size of one pixel in SVG output").value_parser(value_parser!(u32)),
+        arg!(--"no-crisp-edges" "Let SVG viewers anti-alias shape edges"),
+        arg!(--"no-metadata" "Leave EXIF, the ICC profile and PNG text chunks out of the output"),
+    ]
+}
+
+fn alpha_args() -> Vec<Arg> {
+    vec![
+        arg!(--alpha <POLICY> "What happens to alpha after palettizing: kept, cut at a threshold, dithered or keyed to one palette entry")
+            .value_parser(["keep", "threshold", "dither", "index"])
+            .default_value("keep"),
+        arg!(--"alpha-cutoff" <N> "Alpha at or above this is opaque for the threshold policy").value_parser(value_parser!(u8)).default_value("128"),
+        arg!(--"transparent-index" <N> "Palette entry transparent pixels are painted with for the index policy")
+            .value_parser(value_parser!(usize))
+            .default_value("0"),
+    ]
+}
+
+fn alpha_policy(matches: &ArgMatches) -> AlphaPolicy {
+    return match matches.get_one::<String>("alpha").unwrap().as_str() {
+        "threshold" => AlphaPolicy::THRESHOLD(*matches.get_one::<u8>("alpha-cutoff").unwrap()),
+        "dither" => AlphaPolicy::DITHER,
+        "index" => AlphaPolicy::TRANSPARENTINDEX(*matches.get_one::<usize>("transparent-index").unwrap()),
+        _ => AlphaPolicy::KEEP,
+    };
+}
+
+fn save_options(matches: &ArgMatches) -> SaveOptions {
+    let mut options = SaveOptions {
+        format: matches.get_one::<String>("format").map(|ext| Extension::new(ext).unwrap()),
+        ..SaveOptions::default()
+    };
+
+    if let Some(quality) = matches.get_one::<u8>("quality") {
+        options.jpeg_quality = *quality;
+    }
+    options.metadata = !matches.get_flag("no-metadata");
+    if let Some(level) = matches.get_one::<String>("png-compression") {
+        options.png_compression = match level.as_str() {
+            "fast" => CompressionType::Fast,
+            "default" => CompressionType::Default,
+            "best" => CompressionType::Best,
+            "none" => CompressionType::Uncompressed,
+            level => CompressionType::Level(level.parse::<u8>().ok().filter(|l| (1..=9).contains(l)).expect("ERROR: INVALID PNG COMPRESSION LEVEL")),
+        };
+    }
+    if let Some(mode) = matches.get_one::<String>("svg-mode") {
+        options.svg.mode = SvgMode::new(mode).unwrap();
+    }
+    if let Some(scale) = matches.get_one::<u32>("svg-scale") {
+        options.svg.scale = *scale;
+    }
+    options.svg.crisp_edges = !matches.get_flag("no-crisp-edges");
+    if let Some(filter) = matches.get_one::<String>("png-filter") {
+        options.png_filter = match filter.as_str() {
+            "none" => PngFilterType::NoFilter,
+            "sub" => PngFilterType::Sub,
+            "up" => PngFilterType::Up,
+            "avg" => PngFilterType::Avg,
+            "paeth" => PngFilterType::Paeth,
+            _ => PngFilterType::Adaptive,
+        };
+    }
+
+    return options;
+}
+
+fn open_image(matches: &ArgMatches) -> Image {
+    let mut image = Image::new(matches.get_one::<String>("IMAGE").unwrap());
+    if matches.get_flag("srgb") {
+        if let Err(err) = image.convert_to_srgb() {
+            fail(err);
+        }
+    }
+    return image;
+}
+
+fn save_output(image: &Image, matches: &ArgMatches) {
+    if let Err(err) = image.save_image(matches.get_one::<String>("output").map(|s| s.as_str()), &save_options(matches)) {
+        fail(err);
+    }
+}
+
+fn load_palette(name: &str) -> Palette {
+    return match Palette::new(name) {
+        Ok(palette) => palette,
+        Err(err) => fail(err),
+    };
+}
+
+fn hex_colour(hex: &str) -> Rgb<u8> {
+    return match hex_to_rgb(hex) {
+        Ok(colour) => colour,
+        Err(err) => fail(format!("{} is not a colour: {}", hex, err)),
+    };
+}
+
+// Reports the error and exits with a failure status, so scripts can tell the command did not work.
+fn fail(err: String) -> ! {
+    eprintln!("ERROR: {}", err);
+    exit(1);
+}
+
+pub fn run() {
+    let matches = build_cli().get_matches();
+
+    match matches.subcommand() {
+        Some(("palettes", sub)) => run_palettes(sub),
+        Some(("ramp", sub)) => run_ramp(sub),
+        Some(("harmony", sub)) => run_harmony(sub),
+        Some(("analyse", sub)) => run_analyse(sub),
+        Some(("recommend", sub)) => run_recommend(sub),
+        Some(("recolour", sub)) => run_recolour(sub),
+        Some(("cvd", sub)) => run_cvd(sub),
+        Some(("palettize", sub)) => run_palettize(sub),
+        Some(("pixelate", sub)) => run_pixelate(sub),
+        Some(("outline", sub)) => run_outline(sub),
+        Some(("cleanup", sub)) => run_cleanup(sub),
+        Some(("upscale", sub)) => run_upscale(sub),
+        Some(("resize", sub)) => run_resize(sub),
+        Some(("animate", sub)) => run_animate(sub),
+        Some(("sequence", sub)) => run_sequence(sub),
+        _ => unreachable!("ERROR: UNKNOWN SUBCOMMAND"),
+    }
+}
+
+fn run_palettes(matches: &ArgMatches) {
+    let search_path = match matches.get_one::<String>("dir") {
+        Some(dir) => PaletteSearchPath::from_dir(dir),
+        None => PaletteSearchPath::new(),
+    };
+
+    if matches.get_flag("paths") {
+        for (source, dir) in &search_path.dirs {
+            let status = if dir.is_dir() { "" } else { " (missing)" };
+            println!("{:<17} {}{}", PaletteSource::to_string(source), dir.display(), status);
+        }
+        if search_path.include_builtin {
+            println!("{:<17} {} palettes compiled into the binary", PaletteSource::to_string(&PaletteSource::BUILTIN), BUILTIN_PALETTES.len());
+        }
+        return;
+    }
+
+    if let Some(name) = matches.get_one::<String>("which") {
+        match search_path.resolve(name) {
+            Ok(location) => println!("{}", PaletteLocation::to_string(&location)),
+            Err(err) => fail(err),
+        }
+        return;
+    }
+
+    let library = PaletteLibrary::from_search_path(&search_path);
+
+    let filter = PaletteFilter {
+        min_colours: matches.get_one::<usize>("min-colours").copied(),
+        max_colours: matches.get_one::<usize>("max-colours").copied(),
+        contains: matches.get_one::<String>("contains").map(|hex| hex_colour(hex)),
+    };
+
+    let candidates: Vec<&Palette> = match matches.get_one::<String>("search") {
+        Some(query) => library.search(query),
+        None => library.palettes.iter().collect(),
+    };
+    let swatch = matches.get_flag("swatch");
+
+    println!("{:<28} {:>7} {:>11} {:>17}", "NAME", "COLOURS", "BRIGHTNESS", "CONTRAST");
+    for palette in candidates.into_iter().filter(|p| filter.matches(p)) {
+        let info = PaletteInfo::describe(palette);
+        println!(
+            "{:<28} {:>7} {:>10.1}% {:>5.1}-{:>5.1}% {:>4.1}:1",
+            info.name, info.colour_count, info.avg_brightness,
+            info.min_brightness, info.max_brightness, info.contrast_ratio()
+        );
+        if swatch {
+            println!("  {}", ansi_swatch(palette));
+        }
+    }
+}
+
+fn run_ramp(matches: &ArgMatches) {
+    let saturation_curve = match (matches.get_one::<f32>("peak"), matches.get_one::<f32>("fade")) {
+        (Some(amount), _) => SaturationCurve::PEAK(*amount),
+        (_, Some(amount)) => SaturationCurve::FADE(*amount),
+        _ => SaturationCurve::FLAT,
+    };
+    let space = match matches.get_one::<String>("space").unwrap().as_str() {
+        "oklab" => RampSpace::OKLAB,
+        _ => RampSpace::OKLCH,
+    };
+    let options = RampOptions {
+        steps: *matches.get_one::<usize>("steps").unwrap(),
+        hue_shift: *matches.get_one::<f32>("hue-shift").unwrap(),
+        saturation_curve,
+        space,
+    };
+
+    let mut palette = if let Some(name) = matches.get_one::<String>("palette") {
+        let shades = *matches.get_one::<usize>("shades").unwrap();
+        let tints = *matches.get_one::<usize>("tints").unwrap();
+        generate_shade_ramps(&load_palette(name), shades, tints, &options)
+    } else {
+        let anchors: Vec<Rgb<u8>> = matches.get_many::<String>("anchors")
+            .expect("ERROR: EITHER --anchors OR --palette IS REQUIRED")
+            .map(|hex| hex_colour(hex))
+            .collect();
+        generate_ramp(String::new(), &anchors, &options)
+    };
+    if palette.name.is_empty() || matches.value_source("name") == Some(ValueSource::CommandLine) {
+        palette.name = matches.get_one::<String>("name").unwrap().clone();
+    }
+
+    print_and_save_palette(&palette, matches);
+}
+
+fn run_harmony(matches: &ArgMatches) {
+    let seeds: Vec<Rgb<u8>> = matches.get_many::<String>("seed").unwrap()
+        .map(|hex| hex_colour(hex))
+        .collect();
+    let harmony = Harmony::new(matches.get_one::<String>("rule").unwrap()).unwrap();
+    let name = matches.get_one::<String>("name").unwrap().clone();
+    let num_colours = *matches.get_one::<usize>("colours").unwrap();
+    let spread = *matches.get_one::<f32>("spread").unwrap();
+
+    let palette = generate_harmony(name, &seeds, harmony, num_colours, spread);
+    print_and_save_palette(&palette, matches);
+}
+
+fn run_analyse(matches: &ArgMatches) {
+    let palette = load_palette(matches.get_one::<String>("PALETTE").unwrap());
+    let model = CvdModel::new(matches.get_one::<String>("cvd-model").unwrap()).unwrap();
+    let report = PaletteReport::analyse_with_model(&palette, model);
+    let pair = |p: &ColourPair| format!("{} / {}  {:.1}", rgb_to_hex(p.first), rgb_to_hex(p.second), p.value);
+
+    println!("{}  {}", report.name, ansi_swatch(&palette));
+    println!("colours            {}", report.colour_count);
+    println!("min ΔE00           {:.2}", report.min_delta_e);
+    println!("mean ΔE00          {:.2}", report.mean_delta_e);
+    println!("lightness (L*)     {:.1} - {:.1}, {:.0}% coverage", report.lightness_range.0, report.lightness_range.1, report.lightness_coverage * 100f32);
+    println!("gamut hull volume  {:.0} (CIELAB units³)", report.hull_volume);
+    println!("text contrast      {} pairs >= {}:1 (AA), {} pairs >= {}:1 (AAA)", report.aa_pairs, WCAG_AA, report.aaa_pairs, WCAG_AAA);
+    if let Some(best) = &report.best_contrast {
+        println!("best contrast      {}:1", pair(best));
+    }
+
+    println!("most confusable pairs (ΔE00)");
+    for p in &report.confusable_pairs {
+        println!("  {}", pair(p));
+    }
+
+    for cvd in &report.cvd {
+        println!("{:<18} min ΔE00 {:.2}, {} pairs become confusable", Deficiency::to_string(&cvd.deficiency), cvd.min_delta_e, cvd.lost_pairs.len());
+        for p in cvd.lost_pairs.iter().take(CONFUSABLE_PAIR_COUNT) {
+            println!("  {}", pair(p));
+        }
+    }
+}
+
+fn run_recommend(matches: &ArgMatches) {
+    let image = Image::new(matches.get_one::<String>("IMAGE").unwrap());
+    let options = RecommendOptions {
+        metric: match matches.get_one::<String>("metric").unwrap().as_str() {
+            "ssim" => RankingMetric::SSIM,
+            _ => RankingMetric::DELTAE,
+        },
+        coverage_weight: *matches.get_one::<f32>("coverage-weight").unwrap(),
+        top_n: *matches.get_one::<usize>("top").unwrap(),
+        thumbnail_size: *matches.get_one::<u32>("size").unwrap(),
+    };
+
+    let library = PaletteLibrary::from_search_path(&PaletteSearchPath::new());
+    let recommendations = recommend_palettes(&image.data, &library, &options);
+
+    println!("{:<4} {:<28} {:>8} {:>8} {:>7}", "RANK", "PALETTE", "SCORE", "ERROR", "UNUSED");
+    for (rank, recommendation) in recommendations.iter().enumerate() {
+        println!(
+            "{:<4} {:<28} {:>8.2} {:>8.2} {:>3}/{:<3}  {}",
+            rank + 1, palette_stem(&recommendation.palette.name), recommendation.score, recommendation.error,
+            recommendation.unused_colours, recommendation.palette.colours.len(), ansi_swatch(&recommendation.palette)
+        );
+    }
+
+    if let Some(dir) = matches.get_one::<String>("previews") {
+        create_dir_all(dir).expect("ERROR: COULD NOT CREATE PREVIEW DIRECTORY.");
+        if let Err(err) = save_previews(&recommendations, dir) {
+            fail(err);
+        }
+    }
+}
+
+fn run_recolour(matches: &ArgMatches) {
+    let mapping = match matches.get_one::<String>("mapping") {
+        Some(file) => ColourMapping::load_table(file),
+        None => {
+            let source = load_palette(matches.get_one::<String>("from").unwrap());
+            let target = load_palette(matches.get_one::<String>("to").unwrap());
+            let strategy = MappingStrategy::new(matches.get_one::<String>("strategy").unwrap()).unwrap();
+            ColourMapping::new(&source, &target, strategy)
+        }
+    };
+
+    for (source, target) in &mapping.entries {
+        println!("{} -> {}", rgb_to_hex(*source), rgb_to_hex(*target));
+    }
+    if let Some(file) = matches.get_one::<String>("table") {
+        mapping.save_table(file);
+    }
+
+    let mut image = open_image(matches);
+    let unmapped = image.recolour(&mapping);
+    if unmapped > 0 {
+        println!("INFO: {} pixels are not in the source palette and were left unchanged.", unmapped);
+    }
+
+    if matches.get_flag("indexed") {
+        let palette = match matches.get_one::<String>("to") {
+            Some(name) => load_palette(name),
+            None => Palette { name: String::new(), colours: mapping.target_colours() },
+        };
+        let output = matches.get_one::<String>("output").unwrap();
+        let options = IndexedPngOptions {
+            transparent_index: matches.get_one::<usize>("transparent-index").copied(),
+            ..IndexedPngOptions::default()
+        };
+        if let Err(err) = image.save_indexed_png(output, &palette, &options) {
+            fail(err);
+        }
+    } else {
+        save_output(&image, matches);
+    }
+}
+
+fn run_cvd(matches: &ArgMatches) {
+    let deficiency = Deficiency::new(matches.get_one::<String>("deficiency").unwrap()).unwrap();
+    let model = CvdModel::new(matches.get_one::<String>("model").unwrap()).unwrap();
+
+    let mut image = open_image(matches);
+    if matches.get_flag("daltonize") {
+        image.daltonize(deficiency, model);
+    } else {
+        image.simulate_cvd(deficiency, model);
+    }
+    save_output(&image, matches);
+}
+
+fn run_palettize(matches: &ArgMatches) {
+    let palette = load_palette(matches.get_one::<String>("palette").unwrap());
+    let mut image = open_image(matches);
+    image.alpha_policy = alpha_policy(matches);
+    if let Err(err) = image.apply_palette(palette.clone()) {
+        fail(err);
+    }
+
+    if matches.get_flag("indexed") {
+        let output = matches.get_one::<String>("output").unwrap();
+        // The index policy has already painted transparent pixels with that entry, so it becomes the tRNS one.
+        let options = IndexedPngOptions {
+            transparent_index: match image.alpha_policy {
+                AlphaPolicy::TRANSPARENTINDEX(index) => Some(index),
+                _ => None,
+            },
+            ..IndexedPngOptions::default()
+        };
+        if let Err(err) = image.save_indexed_png(output, &palette, &options) {
+            fail(err);
+        }
+    } else {
+        save_output(&image, matches);
+    }
+}
+
+fn run_pixelate(matches: &ArgMatches) {
+    let reduction = match BlockReduction::new(matches.get_one::<String>("reduction").unwrap()).unwrap() {
+        BlockReduction::KMEANS(_) => BlockReduction::KMEANS(*matches.get_one::<u32>("clusters").unwrap() as usize),
+        reduction => reduction,
+    };
+    let block = match matches.get_one::<(u32, u32)>("target") {
+        Some((width, height)) => BlockSize::TARGET(*width, *height),
+        None => {
+            let (width, height) = matches.get_one::<(f32, f32)>("block").unwrap();
+            BlockSize::SCALE(*width, *height)
+        },
+    };
+
+    let options = PixelateOptions {
+        block,
+        reduction,
+        anchor: GridAnchor::new(matches.get_one::<String>("anchor").unwrap()).unwrap(),
+        offset: *matches.get_one::<(i32, i32)>("offset").unwrap(),
+        edges: EdgeBlocks::new(matches.get_one::<String>("edges").unwrap()).unwrap(),
+        low_resolution: matches.get_flag("low-res"),
+    };
+    let mut image = open_image(matches);
+    image.pixelate_blocks(&options);
+    save_output(&image, matches);
+}
+
+fn run_outline(matches: &ArgMatches) {
+    let threshold = *matches.get_one::<f32>("threshold").unwrap();
+    let options = OutlineOptions {
+        detector: match matches.get_one::<String>("detector").unwrap().as_str() {
+            "canny" => EdgeDetector::CANNY(threshold / 2f32, threshold),
+            _ => EdgeDetector::SOBEL(threshold),
+        },
+        signal: match matches.get_one::<String>("signal").unwrap().as_str() {
+            "luminance" => EdgeSignal::LUMINANCE,
+            _ => EdgeSignal::DELTAE,
+        },
+        colour: match matches.get_flag("darkest") {
+            true => OutlineColour::DARKEST,
+            false => OutlineColour::DARKENED(*matches.get_one::<f32>("darken").unwrap()),
+        },
+        mode: match matches.get_one::<String>("mode").unwrap().as_str() {
+            "outer" => OutlineMode::OUTER,
+            "selective" => OutlineMode::SELECTIVE,
+            _ => OutlineMode::INNER,
+        },
+        block: *matches.get_one::<u32>("block").unwrap(),
+    };
+
+    let mut image = open_image(matches);
+    image.outline(&load_palette(matches.get_one::<String>("palette").unwrap()), &options);
+    save_output(&image, matches);
+}
+
+fn run_cleanup(matches: &ArgMatches) {
+    let options = CleanupOptions {
+        min_region: *matches.get_one::<usize>("min-region").unwrap(),
+        connectivity: Connectivity::new(matches.get_one::<String>("connectivity").unwrap()).unwrap(),
+        smooth_jaggies: !matches.get_flag("no-jaggies"),
+        mode_radius: *matches.get_one::<u32>("mode-radius").unwrap(),
+        passes: *matches.get_one::<u32>("passes").unwrap(),
+    };
+
+    let mut image = open_image(matches);
+    image.cleanup(&load_palette(matches.get_one::<String>("palette").unwrap()), &options);
+    save_output(&image, matches);
+}
+
+fn run_resize(matches: &ArgMatches) {
+    let target = match ResizeTarget::new(matches.get_one::<String>("size").unwrap()) {
+        Ok(target) => target,
+        Err(err) => fail(err),
+    };
+    let options = ResizeOptions {
+        target,
+        mode: ResizeMode::new(matches.get_one::<String>("mode").unwrap()).unwrap(),
+        filter: match matches.get_one::<String>("filter").unwrap().as_str() {
+            "triangle" => FilterType::Triangle,
+            "catmullrom" => FilterType::CatmullRom,
+            "gaussian" => FilterType::Gaussian,
+            "lanczos" => FilterType::Lanczos3,
+            _ => FilterType::Nearest,
+        },
+    };
+
+    let mut image = open_image(matches);
+    if let Err(err) = image.resize(&options) {
+        fail(err);
+    }
+    println!("INFO: Resized to {}x{}", image.data.width(), image.data.height());
+    save_output(&image, matches);
+}
+
+fn run_upscale(matches: &ArgMatches) {
+    let upscaler = Upscaler::new(matches.get_one::<String>("algorithm").unwrap()).unwrap();
+    let mut image = open_image(matches);
+    if let Err(err) = image.upscale(upscaler, *matches.get_one::<u32>("factor").unwrap()) {
+        fail(err);
+    }
+    save_output(&image, matches);
+}
+
+// N or WxH, both sides at least 1 and possibly fractional.
+fn parse_block(value: &str) -> Result<(f32, f32), String> {
+    let parse = |side: &str| match side.trim().parse::<f32>() {
+        Ok(n) if n >= 1f32 && n.is_finite() => Ok(n),
+        _ => Err(format!("{} is not a block size, use N or WxH with sides of at least 1", value)),
+    };
+    return match value.to_lowercase().split_once('x') {
+        Some((width, height)) => Ok((parse(width)?, parse(height)?)),
+        None => parse(value).map(|n| (n, n)),
+    };
+}
+
+fn parse_offset(value: &str) -> Result<(i32, i32), String> {
+    let error = || format!("{} is not an offset, use X,Y", value);
+    let (x, y) = value.split_once(',').ok_or_else(error)?;
+    return Ok((x.trim().parse().map_err(|_| error())?, y.trim().parse().map_err(|_| error())?));
+}
+
+// N or WxH, both sides at least 1.
+fn parse_size(value: &str) -> Result<(u32, u32), String> {
+    let parse = |side: &str| match side.trim().parse::<u32>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("{} is not a size, use N or WxH", value)),
+    };
+    return match value.to_lowercase().split_once('x') {
+        Some((width, height)) => Ok((parse(width)?, parse(height)?)),
+        None => parse(value).map(|n| (n, n)),
+    };
+}
+
+fn run_animate(matches: &ArgMatches) {
+    let mut animation = Animation::new(matches.get_one::<String>("INPUT").unwrap()).expect("ERROR: UNABLE TO OPEN ANIMATION");
+    animation.alpha_policy = alpha_policy(matches);
+    println!("INFO: {} frames, {}x{}", animation.frames.len(), animation.dimensions().0, animation.dimensions().1);
+
+    if let Some(scale) = matches.get_one::<u32>("pixelate") {
+        animation.pixelate(*scale, FilterType::Nearest);
+    }
+    if let Some(name) = matches.get_one::<String>("palette") {
+        let palettized = match temporal_dither(matches) {
+            Some(mode) => animation.dither_stable(&load_palette(name), mode),
+            None => animation.apply_palette(load_palette(name)),
+        };
+        if let Err(err) = palettized {
+            fail(err);
+        }
+    }
+
+    let options = AnimationSaveOptions {
+        palette: if matches.get_flag("local") { FramePalette::LOCAL } else { FramePalette::GLOBAL },
+        num_colours: *matches.get_one::<usize>("colours").unwrap(),
+        delta_frames: !matches.get_flag("no-delta"),
+        ..AnimationSaveOptions::default()
+    };
+    if let Err(err) = animation.save(matches.get_one::<String>("output").unwrap(), &options) {
+        fail(err);
+    }
+}
+
+fn run_sequence(matches: &ArgMatches) {
+    let sequence = FrameSequence::new(matches.get_one::<String>("DIRECTORY").unwrap()).expect("ERROR: UNABLE TO READ FRAME SEQUENCE");
+
+    let palette = match (matches.get_one::<String>("palette"), matches.get_one::<f32>("scenes")) {
+        (Some(name), _) => ClipPalette::FIXED(load_palette(name)),
+        (None, Some(threshold)) => ClipPalette::SCENES(*threshold),
+        (None, None) => ClipPalette::GLOBAL,
+    };
+    let options = SequenceOptions {
+        palette,
+        num_colours: *matches.get_one::<usize>("colours").unwrap(),
+        pixelate: matches.get_one::<u32>("pixelate").copied(),
+        dither: temporal_dither(matches),
+        threads: matches.get_one::<usize>("threads").copied().unwrap_or(0),
+        alpha_policy: alpha_policy(matches),
+        ..SequenceOptions::default()
+    };
+
+    let output = matches.get_one::<String>("output").unwrap();
+    match sequence.process(output, *matches.get_one::<f32>("fps").unwrap(), &options) {
+        Ok(()) => println!("INFO: Saved {}", output),
+        Err(err) => fail(err),
+    }
+}
+
+fn temporal_dither(matches: &ArgMatches) -> Option<TemporalDither> {
+    let tolerance = *matches.get_one::<u8>("tolerance").unwrap();
+    match matches.get_one::<String>("dither")?.as_str() {
+        "ordered" => Some(TemporalDither::ORDERED(2)),
+        "bluenoise" => Some(TemporalDither::BLUENOISE),
+        _ => Some(TemporalDither::DIFFUSION(tolerance, &DIFF_MAT_FLOYD_STEINBERG)),
+    }
+}
+
+fn print_and_save_palette(palette: &Palette, matches: &ArgMatches) {
+    println!("{}", ansi_swatch(palette));
+    println!("{}", palette.colours.iter().map(|c| rgb_to_hex(*c)).collect::<Vec<String>>().join(" "));
+
+    if let Some(dir) = matches.get_one::<String>("output") {
+        palette.save_palette(Some(dir));
+    } else if matches.get_flag("save") {
+        palette.save_palette(None);
+    }
+}
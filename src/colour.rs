@@ -1,5 +1,3 @@
-use std::collections::HashSet;
-
 use image::Rgb;
 use rand::prelude::IteratorRandom;
 
@@ -14,23 +12,260 @@ pub fn euclidean_distance(color1: &Rgb<u8>, color2: &Rgb<u8>) -> f32 {
     return dist;
 }
 
-pub fn calculate_avg_distance_in_palette(pal: &HashSet<Rgb<u8>>) -> f32 {
-    let mut total_distance = 0.0;
-    let color_count = pal.len();
+pub fn srgb_to_linear(channel: u8) -> f32 {
+    let c = channel as f32 / 255f32;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// Relative luminance as defined by WCAG, in the range 0-1.
+pub fn relative_luminance(colour: &Rgb<u8>) -> f32 {
+    0.2126 * srgb_to_linear(colour[0]) + 0.7152 * srgb_to_linear(colour[1]) + 0.0722 * srgb_to_linear(colour[2])
+}
+
+pub fn linear_to_srgb(channel: f32) -> u8 {
+    let c = channel.clamp(0f32, 1f32);
+    let srgb = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1f32 / 2.4) - 0.055
+    };
+    (srgb * 255f32).round() as u8
+}
+
+// OKLab as [L, a, b] with L in the range 0-1.
+pub fn rgb_to_oklab(colour: &Rgb<u8>) -> [f32; 3] {
+    let r = srgb_to_linear(colour[0]);
+    let g = srgb_to_linear(colour[1]);
+    let b = srgb_to_linear(colour[2]);
+
+    let l = (0.41222147 * r + 0.53633254 * g + 0.051445993 * b).cbrt();
+    let m = (0.2119035 * r + 0.6806995 * g + 0.10739696 * b).cbrt();
+    let s = (0.08830246 * r + 0.28171884 * g + 0.6299787 * b).cbrt();
+
+    [
+        0.21045426 * l + 0.7936178 * m - 0.004072047 * s,
+        1.9779985 * l - 2.4285922 * m + 0.4505937 * s,
+        0.025904037 * l + 0.78277177 * m - 0.80867577 * s,
+    ]
+}
+
+pub fn oklab_to_linear(lab: [f32; 3]) -> [f32; 3] {
+    let l = (lab[0] + 0.39633778 * lab[1] + 0.21580376 * lab[2]).powi(3);
+    let m = (lab[0] - 0.105561346 * lab[1] - 0.06385417 * lab[2]).powi(3);
+    let s = (lab[0] - 0.08948418 * lab[1] - 1.2914855 * lab[2]).powi(3);
+
+    [
+        4.0767417 * l - 3.3077116 * m + 0.23096993 * s,
+        -1.268438 * l + 2.6097574 * m - 0.3413194 * s,
+        -0.0041960863 * l - 0.7034186 * m + 1.7076147 * s,
+    ]
+}
+
+pub fn oklab_to_rgb(lab: [f32; 3]) -> Rgb<u8> {
+    let linear = oklab_to_linear(lab);
+    Rgb(linear.map(linear_to_srgb))
+}
+
+// Reduces chroma until the colour fits in sRGB instead of clipping channels, which would shift the hue.
+pub fn oklch_to_rgb_clipped(lch: [f32; 3]) -> Rgb<u8> {
+    let in_gamut = |c: f32| oklab_to_linear(oklch_to_oklab([lch[0], c, lch[2]])).iter().all(|v| (-0.0001..=1.0001).contains(v));
+    if in_gamut(lch[1]) {
+        return oklab_to_rgb(oklch_to_oklab(lch));
+    }
+
+    let (mut low, mut high) = (0f32, lch[1]);
+    for _ in 0..20 {
+        let mid = (low + high) / 2f32;
+        if in_gamut(mid) { low = mid } else { high = mid }
+    }
+    return oklab_to_rgb(oklch_to_oklab([lch[0], low, lch[2]]));
+}
+
+// OKLCh as [L, C, h] with the hue in degrees.
+pub fn oklab_to_oklch(lab: [f32; 3]) -> [f32; 3] {
+    let c = (lab[1] * lab[1] + lab[2] * lab[2]).sqrt();
+    let h = lab[2].atan2(lab[1]).to_degrees().rem_euclid(360f32);
+    [lab[0], c, h]
+}
+
+pub fn oklch_to_oklab(lch: [f32; 3]) -> [f32; 3] {
+    let h = lch[2].to_radians();
+    [lch[0], lch[1] * h.cos(), lch[1] * h.sin()]
+}
+
+pub fn oklab_distance(lab1: [f32; 3], lab2: [f32; 3]) -> f32 {
+    ((lab1[0] - lab2[0]).powi(2) + (lab1[1] - lab2[1]).powi(2) + (lab1[2] - lab2[2]).powi(2)).sqrt()
+}
+
+// CIELAB as [L*, a*, b*] relative to the D65 white point.
+pub fn rgb_to_lab(colour: &Rgb<u8>) -> [f32; 3] {
+    let r = srgb_to_linear(colour[0]);
+    let g = srgb_to_linear(colour[1]);
+    let b = srgb_to_linear(colour[2]);
+
+    let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+    let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+    let z = (0.0193339 * r + 0.119192 * g + 0.9503041 * b) / 1.08883;
+
+    let f = |t: f32| if t > 0.008856 { t.cbrt() } else { 7.787 * t + 16f32 / 116f32 };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+
+    [116f32 * fy - 16f32, 500f32 * (fx - fy), 200f32 * (fy - fz)]
+}
+
+#[derive(Copy, Clone)]
+pub enum DistanceFunction {
+    EUCLIDEAN,
+    MANHATTAN,
+    CHEBYSHEV,
+    CIE76,
+    CIE94,
+    CIEDE2000,
+    CMC,
+    OKLAB,
+}
+
+impl DistanceFunction {
+    pub fn new(name: &str) -> Result<DistanceFunction, &'static str> {
+        let distance = match name.to_lowercase().as_str() {
+            "euclidean" => DistanceFunction::EUCLIDEAN,
+            "manhattan" => DistanceFunction::MANHATTAN,
+            "chebyshev" => DistanceFunction::CHEBYSHEV,
+            "cie76" => DistanceFunction::CIE76,
+            "cie94" => DistanceFunction::CIE94,
+            "ciede2000" => DistanceFunction::CIEDE2000,
+            "cmc" => DistanceFunction::CMC,
+            "oklab" => DistanceFunction::OKLAB,
 
-    if color_count == 0 {
-        return 0.0;
+            _ => return Err("Unknown distance function")
+        };
+
+        return Ok(distance);
     }
 
-    for (i, color1) in pal.iter().enumerate() {
-        for color2 in pal.iter().skip(i + 1) {
-            total_distance += euclidean_distance(color1, color2);
+    // RGB based distances are in channel units, the CIE ones in ΔE and OKLAB in OKLab units.
+    pub fn distance(&self, color1: &Rgb<u8>, color2: &Rgb<u8>) -> f32 {
+        match self {
+            DistanceFunction::EUCLIDEAN => euclidean_distance(color1, color2),
+            DistanceFunction::MANHATTAN => (0..3).map(|i| (color1[i] as f32 - color2[i] as f32).abs()).sum(),
+            DistanceFunction::CHEBYSHEV => (0..3).map(|i| (color1[i] as f32 - color2[i] as f32).abs()).fold(0f32, f32::max),
+            DistanceFunction::CIE76 => cie76(rgb_to_lab(color1), rgb_to_lab(color2)),
+            DistanceFunction::CIE94 => cie94(rgb_to_lab(color1), rgb_to_lab(color2)),
+            DistanceFunction::CIEDE2000 => ciede2000(rgb_to_lab(color1), rgb_to_lab(color2)),
+            DistanceFunction::CMC => cmc(rgb_to_lab(color1), rgb_to_lab(color2), 1f32, 1f32),
+            DistanceFunction::OKLAB => oklab_distance(rgb_to_oklab(color1), rgb_to_oklab(color2)),
         }
     }
+}
 
-    total_distance / color_count as f32
+pub fn cie76(lab1: [f32; 3], lab2: [f32; 3]) -> f32 {
+    ((lab1[0] - lab2[0]).powi(2) + (lab1[1] - lab2[1]).powi(2) + (lab1[2] - lab2[2]).powi(2)).sqrt()
 }
 
+// Graphic arts weighting (kL = 1, K1 = 0.045, K2 = 0.015).
+pub fn cie94(lab1: [f32; 3], lab2: [f32; 3]) -> f32 {
+    let c1 = (lab1[1].powi(2) + lab1[2].powi(2)).sqrt();
+    let c2 = (lab2[1].powi(2) + lab2[2].powi(2)).sqrt();
+    let dl = lab1[0] - lab2[0];
+    let dc = c1 - c2;
+    let da = lab1[1] - lab2[1];
+    let db = lab1[2] - lab2[2];
+    let dh2 = (da * da + db * db - dc * dc).max(0f32);
+
+    let sc = 1f32 + 0.045 * c1;
+    let sh = 1f32 + 0.015 * c1;
+
+    (dl.powi(2) + (dc / sc).powi(2) + dh2 / sh.powi(2)).sqrt()
+}
+
+pub fn ciede2000(lab1: [f32; 3], lab2: [f32; 3]) -> f32 {
+    let [l1, a1, b1] = lab1.map(|v| v as f64);
+    let [l2, a2, b2] = lab2.map(|v| v as f64);
+
+    let c1 = (a1 * a1 + b1 * b1).sqrt();
+    let c2 = (a2 * a2 + b2 * b2).sqrt();
+    let c_bar7 = ((c1 + c2) / 2.0).powi(7);
+    let g = 0.5 * (1.0 - (c_bar7 / (c_bar7 + 25f64.powi(7))).sqrt());
+
+    let a1p = a1 * (1.0 + g);
+    let a2p = a2 * (1.0 + g);
+    let c1p = (a1p * a1p + b1 * b1).sqrt();
+    let c2p = (a2p * a2p + b2 * b2).sqrt();
+    let hue = |b: f64, a: f64| if a == 0.0 && b == 0.0 { 0.0 } else { b.atan2(a).to_degrees().rem_euclid(360.0) };
+    let h1p = hue(b1, a1p);
+    let h2p = hue(b2, a2p);
+
+    let dlp = l2 - l1;
+    let dcp = c2p - c1p;
+    let dhp = if c1p * c2p == 0.0 {
+        0.0
+    } else if (h2p - h1p).abs() <= 180.0 {
+        h2p - h1p
+    } else if h2p - h1p > 180.0 {
+        h2p - h1p - 360.0
+    } else {
+        h2p - h1p + 360.0
+    };
+    let dhp_big = 2.0 * (c1p * c2p).sqrt() * (dhp.to_radians() / 2.0).sin();
+
+    let l_bar = (l1 + l2) / 2.0;
+    let c_bar = (c1p + c2p) / 2.0;
+    let h_bar = if c1p * c2p == 0.0 {
+        h1p + h2p
+    } else if (h1p - h2p).abs() <= 180.0 {
+        (h1p + h2p) / 2.0
+    } else if h1p + h2p < 360.0 {
+        (h1p + h2p + 360.0) / 2.0
+    } else {
+        (h1p + h2p - 360.0) / 2.0
+    };
+
+    let t = 1.0 - 0.17 * (h_bar - 30.0).to_radians().cos() + 0.24 * (2.0 * h_bar).to_radians().cos()
+        + 0.32 * (3.0 * h_bar + 6.0).to_radians().cos() - 0.20 * (4.0 * h_bar - 63.0).to_radians().cos();
+    let d_theta = 30.0 * (-((h_bar - 275.0) / 25.0).powi(2)).exp();
+    let c_bar7 = c_bar.powi(7);
+    let rc = 2.0 * (c_bar7 / (c_bar7 + 25f64.powi(7))).sqrt();
+    let sl = 1.0 + (0.015 * (l_bar - 50.0).powi(2)) / (20.0 + (l_bar - 50.0).powi(2)).sqrt();
+    let sc = 1.0 + 0.045 * c_bar;
+    let sh = 1.0 + 0.015 * c_bar * t;
+    let rt = -(2.0 * d_theta).to_radians().sin() * rc;
+
+    let dl = dlp / sl;
+    let dc = dcp / sc;
+    let dh = dhp_big / sh;
+
+    (dl * dl + dc * dc + dh * dh + rt * dc * dh).sqrt() as f32
+}
+
+pub fn cmc(lab1: [f32; 3], lab2: [f32; 3], l: f32, c: f32) -> f32 {
+    let c1 = (lab1[1].powi(2) + lab1[2].powi(2)).sqrt();
+    let c2 = (lab2[1].powi(2) + lab2[2].powi(2)).sqrt();
+    let dl = lab1[0] - lab2[0];
+    let dc = c1 - c2;
+    let da = lab1[1] - lab2[1];
+    let db = lab1[2] - lab2[2];
+    let dh2 = (da * da + db * db - dc * dc).max(0f32);
+
+    let h1 = lab1[2].atan2(lab1[1]).to_degrees().rem_euclid(360f32);
+    let t = if (164f32..=345f32).contains(&h1) {
+        0.56 + (0.2 * (h1 + 168f32).to_radians().cos()).abs()
+    } else {
+        0.36 + (0.4 * (h1 + 35f32).to_radians().cos()).abs()
+    };
+    let f = (c1.powi(4) / (c1.powi(4) + 1900f32)).sqrt();
+
+    let sl = if lab1[0] < 16f32 { 0.511 } else { 0.040975 * lab1[0] / (1f32 + 0.01765 * lab1[0]) };
+    let sc = 0.0638 * c1 / (1f32 + 0.0131 * c1) + 0.638;
+    let sh = sc * (f * t + 1f32 - f);
+
+    ((dl / (l * sl)).powi(2) + (dc / (c * sc)).powi(2) + dh2 / sh.powi(2)).sqrt()
+}
+
+#[derive(Copy, Clone)]
 pub enum SelectionStrategy {
     Random,
     Average,
@@ -38,8 +273,17 @@ pub enum SelectionStrategy {
     Median,
 }
 
-pub fn select_randomly(colors: &Vec<Rgb<u8>>, num_colours: usize) -> Vec<Rgb<u8>> {
-    let mut rng = rand::thread_rng();
+pub fn select_colours(colors: &[Rgb<u8>], num_colours: usize, selection_strategy: SelectionStrategy) -> Vec<Rgb<u8>> {
+    match selection_strategy {
+        SelectionStrategy::Average => select_average(colors, num_colours),
+        SelectionStrategy::Random => select_randomly(colors, num_colours),
+        SelectionStrategy::KMeans => select_kmeans(colors, num_colours),
+        SelectionStrategy::Median => select_median(colors, num_colours)
+    }
+}
+
+pub fn select_randomly(colors: &[Rgb<u8>], num_colours: usize) -> Vec<Rgb<u8>> {
+    let mut rng = rand::rng();
     colors.iter().choose_multiple(&mut rng, num_colours)
         .into_iter()
         .cloned()
@@ -47,7 +291,7 @@ pub fn select_randomly(colors: &Vec<Rgb<u8>>, num_colours: usize) -> Vec<Rgb<u8>
 }
 
 //broken
-pub fn select_average(colors: &Vec<Rgb<u8>>, num_colours: usize) -> Vec<Rgb<u8>> {
+pub fn select_average(colors: &[Rgb<u8>], num_colours: usize) -> Vec<Rgb<u8>> {
     let chunk_size = (colors.len() / num_colours).max(1);
     colors.chunks(chunk_size)
         .map(|chunk| {
@@ -62,8 +306,8 @@ pub fn select_average(colors: &Vec<Rgb<u8>>, num_colours: usize) -> Vec<Rgb<u8>>
         .collect()
 }
 
-pub fn select_kmeans(colors: &Vec<Rgb<u8>>, num_colours: usize) -> Vec<Rgb<u8>> {
-    let mut rng = rand::thread_rng();
+pub fn select_kmeans(colors: &[Rgb<u8>], num_colours: usize) -> Vec<Rgb<u8>> {
+    let mut rng = rand::rng();
 
     let mut centroids: Vec<Rgb<u8>> = colors.iter()
         .choose_multiple(&mut rng, num_colours)
@@ -119,7 +363,7 @@ pub fn select_kmeans(colors: &Vec<Rgb<u8>>, num_colours: usize) -> Vec<Rgb<u8>>
     centroids
 }
 
-pub fn select_median(colors: &Vec<Rgb<u8>>, num_colours: usize) -> Vec<Rgb<u8>> {
+pub fn select_median(colors: &[Rgb<u8>], num_colours: usize) -> Vec<Rgb<u8>> {
     let mut boxes = vec![colors.to_vec()];
     while boxes.len() < num_colours {
         let mut new_boxes = vec![];
@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+
+use image::{DynamicImage, Rgb, Rgba};
+
+use crate::analysis::cvd_report;
+use crate::colour::{linear_to_srgb, relative_luminance, srgb_to_linear};
+use crate::utils::generate_raw_palette;
+
+// Images with more distinct colours than this are daltonized colour by colour without the palette check.
+pub const DALTONIZE_PALETTE_LIMIT: usize = 256;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Deficiency {
+    PROTANOPIA,
+    DEUTERANOPIA,
+    TRITANOPIA,
+    ACHROMATOPSIA,
+}
+
+impl Deficiency {
+    pub fn new(name: &str) -> Result<Deficiency, &'static str> {
+        let deficiency = match name.to_lowercase().as_str() {
+            "protanopia" | "protan" => Deficiency::PROTANOPIA,
+            "deuteranopia" | "deutan" => Deficiency::DEUTERANOPIA,
+            "tritanopia" | "tritan" => Deficiency::TRITANOPIA,
+            "achromatopsia" | "achroma" => Deficiency::ACHROMATOPSIA,
+
+            _ => return Err("Unknown colour vision deficiency")
+        };
+
+        return Ok(deficiency);
+    }
+
+    pub fn all() -> [Deficiency; 4] {
+        [Deficiency::PROTANOPIA, Deficiency::DEUTERANOPIA, Deficiency::TRITANOPIA, Deficiency::ACHROMATOPSIA]
+    }
+
+    pub fn to_string(deficiency: &Deficiency) -> String {
+        let name = match deficiency {
+            Deficiency::PROTANOPIA => "protanopia",
+            Deficiency::DEUTERANOPIA => "deuteranopia",
+            Deficiency::TRITANOPIA => "tritanopia",
+            Deficiency::ACHROMATOPSIA => "achromatopsia",
+        };
+
+        return name.to_string();
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CvdModel {
+    BRETTEL,
+    VIENOT,
+    MACHADO,
+}
+
+impl CvdModel {
+    pub fn new(name: &str) -> Result<CvdModel, &'static str> {
+        let model = match name.to_lowercase().as_str() {
+            "brettel" => CvdModel::BRETTEL,
+            "vienot" => CvdModel::VIENOT,
+            "machado" => CvdModel::MACHADO,
+
+            _ => return Err("Unknown colour vision deficiency model")
+        };
+
+        return Ok(model);
+    }
+
+    pub fn to_string(model: &CvdModel) -> String {
+        let name = match model {
+            CvdModel::BRETTEL => "brettel",
+            CvdModel::VIENOT => "vienot",
+            CvdModel::MACHADO => "machado",
+        };
+
+        return name.to_string();
+    }
+}
+
+// All matrices act on linear RGB and keep white fixed.
+
+// Machado, Oliveira and Fernandes (2009) at full severity.
+const MACHADO_PROTANOPIA: [[f32; 3]; 3] = [
+    [0.152286, 1.052583, -0.204868],
+    [0.114503, 0.786281, 0.099216],
+    [-0.003882, -0.048116, 1.051998],
+];
+
+const MACHADO_DEUTERANOPIA: [[f32; 3]; 3] = [
+    [0.367322, 0.860646, -0.227968],
+    [0.280085, 0.672501, 0.047413],
+    [-0.011820, 0.042940, 0.968881],
+];
+
+const MACHADO_TRITANOPIA: [[f32; 3]; 3] = [
+    [1.255528, -0.076749, -0.178779],
+    [-0.078411, 0.930809, 0.147602],
+    [0.004733, 0.691367, 0.303900],
+];
+
+// Viénot, Brettel and Mollon (1999), a single projection plane through black, white and blue
+// (red for tritanopia, which the original paper does not cover).
+const VIENOT_PROTANOPIA: [[f32; 3]; 3] = [
+    [0.11238, 0.88762, 0.0],
+    [0.11238, 0.88762, 0.0],
+    [0.00401, -0.00401, 1.0],
+];
+
+const VIENOT_DEUTERANOPIA: [[f32; 3]; 3] = [
+    [0.29275, 0.70725, 0.0],
+    [0.29275, 0.70725, 0.0],
+    [-0.02234, 0.02234, 1.0],
+];
+
+const VIENOT_TRITANOPIA: [[f32; 3]; 3] = [
+    [1.0, 0.14461, -0.14461],
+    [0.0, 0.85924, 0.14076],
+    [0.0, 0.85924, 0.14076],
+];
+
+// Brettel, Viénot and Mollon (1997), two half planes meeting at the neutral axis. The normal picks the
+// half plane: colours with a non-negative dot product use the first matrix, the rest the second.
+struct BrettelParams {
+    first: [[f32; 3]; 3],
+    second: [[f32; 3]; 3],
+    normal: [f32; 3],
+}
+
+const BRETTEL_PROTANOPIA: BrettelParams = BrettelParams {
+    first: [
+        [0.14980, 1.19548, -0.34528],
+        [0.10764, 0.84864, 0.04372],
+        [0.00384, -0.00540, 1.00156],
+    ],
+    second: [
+        [0.14570, 1.16172, -0.30742],
+        [0.10816, 0.85291, 0.03892],
+        [0.00386, -0.00524, 1.00139],
+    ],
+    normal: [0.00048, 0.00393, -0.00441],
+};
+
+const BRETTEL_DEUTERANOPIA: BrettelParams = BrettelParams {
+    first: [
+        [0.36477, 0.86381, -0.22858],
+        [0.26294, 0.64245, 0.09462],
+        [-0.02006, 0.02728, 0.99278],
+    ],
+    second: [
+        [0.37298, 0.88166, -0.25464],
+        [0.25954, 0.63506, 0.10540],
+        [-0.01980, 0.02784, 0.99196],
+    ],
+    normal: [-0.00281, -0.00611, 0.00892],
+};
+
+const BRETTEL_TRITANOPIA: BrettelParams = BrettelParams {
+    first: [
+        [1.01277, 0.13548, -0.14826],
+        [-0.01243, 0.86812, 0.14431],
+        [0.07589, 0.80500, 0.11911],
+    ],
+    second: [
+        [0.93678, 0.18979, -0.12657],
+        [0.06154, 0.81526, 0.12320],
+        [-0.37562, 1.12767, 0.24796],
+    ],
+    normal: [0.03901, -0.02788, -0.01113],
+};
+
+// Fidaner, Lin and Ozguven (2005), moves the error the viewer cannot see into the channels they can.
+const DALTONIZE_RED_GREEN: [[f32; 3]; 3] = [
+    [0.0, 0.0, 0.0],
+    [0.7, 1.0, 0.0],
+    [0.7, 0.0, 1.0],
+];
+
+const DALTONIZE_BLUE_YELLOW: [[f32; 3]; 3] = [
+    [1.0, 0.0, 0.7],
+    [0.0, 1.0, 0.7],
+    [0.0, 0.0, 0.0],
+];
+
+pub fn simulate_colour(colour: &Rgb<u8>, deficiency: Deficiency, model: CvdModel) -> Rgb<u8> {
+    return Rgb(simulate_linear(colour, deficiency, model).map(linear_to_srgb));
+}
+
+// Achromatopsia is modelled as seeing luminance only, whichever model is picked.
+fn simulate_linear(colour: &Rgb<u8>, deficiency: Deficiency, model: CvdModel) -> [f32; 3] {
+    let linear = colour.0.map(srgb_to_linear);
+
+    let matrix = match (deficiency, model) {
+        (Deficiency::ACHROMATOPSIA, _) => return [relative_luminance(colour); 3],
+        (Deficiency::PROTANOPIA, CvdModel::BRETTEL) => brettel_matrix(&BRETTEL_PROTANOPIA, linear),
+        (Deficiency::DEUTERANOPIA, CvdModel::BRETTEL) => brettel_matrix(&BRETTEL_DEUTERANOPIA, linear),
+        (Deficiency::TRITANOPIA, CvdModel::BRETTEL) => brettel_matrix(&BRETTEL_TRITANOPIA, linear),
+        (Deficiency::PROTANOPIA, CvdModel::VIENOT) => &VIENOT_PROTANOPIA,
+        (Deficiency::DEUTERANOPIA, CvdModel::VIENOT) => &VIENOT_DEUTERANOPIA,
+        (Deficiency::TRITANOPIA, CvdModel::VIENOT) => &VIENOT_TRITANOPIA,
+        (Deficiency::PROTANOPIA, CvdModel::MACHADO) => &MACHADO_PROTANOPIA,
+        (Deficiency::DEUTERANOPIA, CvdModel::MACHADO) => &MACHADO_DEUTERANOPIA,
+        (Deficiency::TRITANOPIA, CvdModel::MACHADO) => &MACHADO_TRITANOPIA,
+    };
+
+    return mat_mul(matrix, linear);
+}
+
+fn brettel_matrix(params: &BrettelParams, linear: [f32; 3]) -> &[[f32; 3]; 3] {
+    let side = params.normal[0] * linear[0] + params.normal[1] * linear[1] + params.normal[2] * linear[2];
+    if side >= 0f32 { &params.first } else { &params.second }
+}
+
+// Achromatopsia leaves no chromatic channel to move the error into, so those colours come back unchanged.
+pub fn daltonize_colour(colour: &Rgb<u8>, deficiency: Deficiency, model: CvdModel) -> Rgb<u8> {
+    let correction = match deficiency {
+        Deficiency::PROTANOPIA | Deficiency::DEUTERANOPIA => &DALTONIZE_RED_GREEN,
+        Deficiency::TRITANOPIA => &DALTONIZE_BLUE_YELLOW,
+        Deficiency::ACHROMATOPSIA => return *colour,
+    };
+
+    let linear = colour.0.map(srgb_to_linear);
+    let simulated = simulate_linear(colour, deficiency, model);
+    let error = [linear[0] - simulated[0], linear[1] - simulated[1], linear[2] - simulated[2]];
+    let shift = mat_mul(correction, error);
+
+    return Rgb([
+        linear_to_srgb(linear[0] + shift[0]),
+        linear_to_srgb(linear[1] + shift[1]),
+        linear_to_srgb(linear[2] + shift[2]),
+    ]);
+}
+
+// A palette that loses no pair to the deficiency is already readable and comes back unchanged.
+pub fn daltonize_palette(colours: &[Rgb<u8>], deficiency: Deficiency, model: CvdModel) -> Vec<Rgb<u8>> {
+    if cvd_report(colours, deficiency, model).lost_pairs.is_empty() {
+        return colours.to_vec();
+    }
+    return colours.iter().map(|colour| daltonize_colour(colour, deficiency, model)).collect();
+}
+
+pub fn simulate_image(image: &DynamicImage, deficiency: Deficiency, model: CvdModel) -> DynamicImage {
+    map_colours(image, |colour| simulate_colour(colour, deficiency, model))
+}
+
+pub fn daltonize_image(image: &DynamicImage, deficiency: Deficiency, model: CvdModel) -> DynamicImage {
+    let colours: Vec<Rgb<u8>> = generate_raw_palette(image).into_iter().collect();
+    if colours.len() > DALTONIZE_PALETTE_LIMIT {
+        return map_colours(image, |colour| daltonize_colour(colour, deficiency, model));
+    }
+
+    let corrected: HashMap<Rgb<u8>, Rgb<u8>> = colours.iter().copied()
+        .zip(daltonize_palette(&colours, deficiency, model))
+        .collect();
+    return map_colours(image, |colour| corrected[colour]);
+}
+
+// Pixel art reuses a handful of colours, so each distinct colour is only converted once. Alpha is kept.
+fn map_colours<F: Fn(&Rgb<u8>) -> Rgb<u8>>(image: &DynamicImage, f: F) -> DynamicImage {
+    let mut cache: HashMap<Rgb<u8>, Rgb<u8>> = HashMap::new();
+    let mut pixels = image.to_rgba8();
+
+    for pixel in pixels.pixels_mut() {
+        let colour = Rgb([pixel[0], pixel[1], pixel[2]]);
+        let mapped = *cache.entry(colour).or_insert_with(|| f(&colour));
+        *pixel = Rgba([mapped[0], mapped[1], mapped[2], pixel[3]]);
+    }
+
+    if image.color().has_alpha() {
+        return DynamicImage::ImageRgba8(pixels);
+    }
+    return DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(pixels).to_rgb8());
+}
+
+pub fn mat_mul(matrix: &[[f32; 3]; 3], v: [f32; 3]) -> [f32; 3] {
+    [
+        matrix[0][0] * v[0] + matrix[0][1] * v[1] + matrix[0][2] * v[2],
+        matrix[1][0] * v[0] + matrix[1][1] * v[1] + matrix[1][2] * v[2],
+        matrix[2][0] * v[0] + matrix[2][1] * v[1] + matrix[2][2] * v[2],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    const MODELS: [CvdModel; 3] = [CvdModel::BRETTEL, CvdModel::VIENOT, CvdModel::MACHADO];
+
+    fn near(a: Rgb<u8>, b: Rgb<u8>) -> bool {
+        return a.0.iter().zip(b.0.iter()).all(|(x, y)| x.abs_diff(*y) <= 1);
+    }
+
+    #[test]
+    fn every_model_leaves_greys_unchanged() {
+        for model in MODELS {
+            for deficiency in Deficiency::all() {
+                for level in [0u8, 1, 37, 128, 200, 254, 255] {
+                    let grey = Rgb([level; 3]);
+                    let simulated = simulate_colour(&grey, deficiency, model);
+                    assert!(near(simulated, grey), "{:?} {:?} {:?} -> {:?}", model, deficiency, grey, simulated);
+                }
+            }
+        }
+    }
+
+    // The columns of the published matrices for pure red, green and blue, encoded back to sRGB.
+    #[test]
+    fn primaries_match_the_published_matrices() {
+        let expected = [
+            (CvdModel::MACHADO, Deficiency::PROTANOPIA, [[109, 95, 0], [255, 229, 0], [0, 89, 255]]),
+            (CvdModel::MACHADO, Deficiency::DEUTERANOPIA, [[163, 144, 0], [239, 214, 58], [0, 61, 251]]),
+            (CvdModel::MACHADO, Deficiency::TRITANOPIA, [[255, 0, 15], [0, 247, 217], [0, 107, 150]]),
+            (CvdModel::VIENOT, Deficiency::PROTANOPIA, [[94, 94, 13], [242, 242, 0], [0, 0, 255]]),
+            (CvdModel::VIENOT, Deficiency::DEUTERANOPIA, [[147, 147, 0], [219, 219, 41], [0, 0, 255]]),
+            (CvdModel::VIENOT, Deficiency::TRITANOPIA, [[255, 0, 0], [106, 239, 239], [0, 105, 105]]),
+            (CvdModel::BRETTEL, Deficiency::PROTANOPIA, [[108, 92, 12], [255, 237, 0], [0, 56, 255]]),
+            (CvdModel::BRETTEL, Deficiency::DEUTERANOPIA, [[164, 139, 0], [241, 209, 46], [0, 87, 254]]),
+            (CvdModel::BRETTEL, Deficiency::TRITANOPIA, [[255, 0, 78], [121, 233, 255], [0, 98, 136]]),
+        ];
+        let primaries = [Rgb([255, 0, 0]), Rgb([0, 255, 0]), Rgb([0, 0, 255])];
+
+        for (model, deficiency, colours) in expected {
+            for (primary, colour) in primaries.iter().zip(colours) {
+                let simulated = simulate_colour(primary, deficiency, model);
+                assert!(near(simulated, Rgb(colour)), "{:?} {:?} {:?} -> {:?}", model, deficiency, primary, simulated);
+            }
+        }
+    }
+
+    #[test]
+    fn achromatopsia_sees_luminance_only() {
+        for model in MODELS {
+            let simulated = simulate_colour(&Rgb([255, 0, 0]), Deficiency::ACHROMATOPSIA, model);
+            assert_eq!(simulated, Rgb([127, 127, 127]));
+        }
+    }
+
+    #[test]
+    fn daltonize_leaves_a_readable_palette_alone() {
+        let readable = [Rgb([0, 0, 0]), Rgb([255, 255, 255]), Rgb([30, 60, 220]), Rgb([250, 220, 40])];
+        for model in MODELS {
+            assert_eq!(daltonize_palette(&readable, Deficiency::PROTANOPIA, model), readable.to_vec());
+            assert_eq!(daltonize_palette(&readable, Deficiency::DEUTERANOPIA, model), readable.to_vec());
+        }
+
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(4, 1, |x, _| readable[x as usize]));
+        assert_eq!(daltonize_image(&image, Deficiency::PROTANOPIA, CvdModel::MACHADO).to_rgb8(), image.to_rgb8());
+    }
+
+    #[test]
+    fn daltonize_separates_a_confused_pair() {
+        let red = Rgb([200, 40, 40]);
+        let green = Rgb([90, 120, 40]);
+        let corrected = daltonize_palette(&[red, green], Deficiency::DEUTERANOPIA, CvdModel::MACHADO);
+        assert_ne!(corrected, vec![red, green]);
+
+        let distance = crate::colour::DistanceFunction::CIEDE2000;
+        let before = distance.distance(
+            &simulate_colour(&red, Deficiency::DEUTERANOPIA, CvdModel::MACHADO),
+            &simulate_colour(&green, Deficiency::DEUTERANOPIA, CvdModel::MACHADO),
+        );
+        let after = distance.distance(
+            &simulate_colour(&corrected[0], Deficiency::DEUTERANOPIA, CvdModel::MACHADO),
+            &simulate_colour(&corrected[1], Deficiency::DEUTERANOPIA, CvdModel::MACHADO),
+        );
+        assert!(after > before);
+    }
+}
@@ -1,7 +1,9 @@
-use image::{DynamicImage, GenericImage, GenericImageView, Rgb, Rgba};
+use image::{DynamicImage, GenericImage, GenericImageView, GrayImage, Rgb, Rgba};
 use rand::Rng;
 
-use crate::colour::calculate_avg_distance_in_palette;
+use crate::alpha::{restore_alpha, split_alpha};
+use crate::analysis::mean_pairwise_distance;
+use crate::colour::DistanceFunction;
 use crate::consts::{
     DIFF_MAT_ATKINSON, DIFF_MAT_BURKES, DIFF_MAT_FAN, DIFF_MAT_FLOYD_STEINBERG,
     DIFF_MAT_IMPROVED_STUCKI, DIFF_MAT_JARVIS_JUDICE_NINKE, DIFF_MAT_K3M,
@@ -10,7 +12,7 @@ use crate::consts::{
     DIFF_MAT_TWO_ROW_SIERRA
 };
 use crate::palette::Palette;
-use crate::utils::{calculate_error, diffuse_error, find_closest_color, gen_blue_noise_threshold, generate_raw_palette};
+use crate::utils::{calculate_error, clamp, diffuse_error, find_closest_color, gen_blue_noise_threshold, generate_raw_palette};
 
 #[derive(Copy, Clone)]
 pub enum BlueNoiseThreshold {
@@ -40,14 +42,63 @@ pub enum DitherMode {
     IMPROVEDSTUCKI(&'static str),
 }
 
+impl DitherMode {
+    pub fn to_string(mode: &DitherMode) -> String {
+        let (name, palette) = match mode {
+            DitherMode::BAYER(order) => return format!("bayer order={}", order),
+            DitherMode::BLUENOISE(threshold, palette) => {
+                let level = match threshold {
+                    BlueNoiseThreshold::LOW => "low",
+                    BlueNoiseThreshold::MEDIUM => "medium",
+                    BlueNoiseThreshold::HIGH => "high",
+                };
+                return format!("bluenoise threshold={} palette={}", level, palette);
+            },
+            DitherMode::FLOYDSTEINBERG(palette) => ("floyd-steinberg", palette),
+            DitherMode::ATKINSON(palette) => ("atkinson", palette),
+            DitherMode::JARVISJUDICENINKE(palette) => ("jarvis-judice-ninke", palette),
+            DitherMode::SIERRA(palette) => ("sierra", palette),
+            DitherMode::STUCKI(palette) => ("stucki", palette),
+            DitherMode::BURKES(palette) => ("burkes", palette),
+            DitherMode::STEVENSONARCE(palette) => ("stevenson-arce", palette),
+            DitherMode::SIERRA2(palette) => ("sierra-2", palette),
+            DitherMode::SIERRALITE(palette) => ("sierra-lite", palette),
+            DitherMode::FAN(palette) => ("fan", palette),
+            DitherMode::K3M(palette) => ("k3m", palette),
+            DitherMode::LIWAN(palette) => ("li-wan", palette),
+            DitherMode::PJARRI(palette) => ("pjarri", palette),
+            DitherMode::SHIAUFAN(palette) => ("shiau-fan", palette),
+            DitherMode::IMPROVEDSTUCKI(palette) => ("improved-stucki", palette),
+        };
+
+        return format!("{} palette={}", name, palette);
+    }
+
+    // Name of the palette the mode dithers to, None for Bayer which keeps the image's own colours.
+    pub fn palette(mode: &DitherMode) -> Option<&'static str> {
+        return match mode {
+            DitherMode::BAYER(_) => None,
+            DitherMode::BLUENOISE(_, palette) => Some(palette),
+            DitherMode::FLOYDSTEINBERG(palette) | DitherMode::ATKINSON(palette) | DitherMode::JARVISJUDICENINKE(palette)
+            | DitherMode::SIERRA(palette) | DitherMode::STUCKI(palette) | DitherMode::BURKES(palette)
+            | DitherMode::STEVENSONARCE(palette) | DitherMode::SIERRA2(palette) | DitherMode::SIERRALITE(palette)
+            | DitherMode::FAN(palette) | DitherMode::K3M(palette) | DitherMode::LIWAN(palette)
+            | DitherMode::PJARRI(palette) | DitherMode::SHIAUFAN(palette) | DitherMode::IMPROVEDSTUCKI(palette) => Some(palette),
+        };
+    }
+}
+
+// Fails when the mode's palette cannot be loaded.
+pub type DitherFn = Box<dyn Fn(&mut DynamicImage) -> Result<(), String>>;
+
 pub struct Ditherer {
     pub dither_mode: DitherMode,
-    pub dither_fn: Box<dyn Fn(&mut DynamicImage)>,
+    pub dither_fn: DitherFn,
 }
 
 impl Ditherer {
     pub fn new(dither_mode: DitherMode) -> Ditherer {
-        let mode = dither_mode.clone();
+        let mode = dither_mode;
         let dither_fn = Self::get_dither_fn(mode);
         return Ditherer {
             dither_mode,
@@ -55,11 +106,12 @@ impl Ditherer {
         };
     }
 
-    fn get_dither_fn(mode: DitherMode) -> Box<dyn Fn(&mut DynamicImage) + 'static> {
+    fn get_dither_fn(mode: DitherMode) -> DitherFn {
         match mode {
             DitherMode::BAYER(order) => {
                 Box::new(move |image: &mut DynamicImage| {
-                    bayer_dithering(image, order)
+                    bayer_dithering(image, order);
+                    Ok(())
                 })
             },
             DitherMode::BLUENOISE(threshold, palette) => {
@@ -148,8 +200,9 @@ impl Ditherer {
 
 fn bayer_dithering(image: &mut DynamicImage, order: u32) {
     let (width, height) = image.dimensions();
-    let pal = generate_raw_palette(image);
-    let avg = calculate_avg_distance_in_palette(&pal);
+    let pal: Vec<Rgb<u8>> = generate_raw_palette(image).into_iter().collect();
+    // Summed pairwise distance over the colour count, as Bayer has always been scaled.
+    let avg = mean_pairwise_distance(&pal, DistanceFunction::EUCLIDEAN) * pal.len().saturating_sub(1) as f32 / 2f32;
     let mat = generate_bayer_matrix(order);
 
     for y in 0..height {
@@ -158,9 +211,9 @@ fn bayer_dithering(image: &mut DynamicImage, order: u32) {
             let threshold = bayer_threshold(&mat, order, x, y);
 
             let new_pixel = [
-                if pixel[0] as f32 > threshold { pixel[0] + (avg * threshold) as u8 } else { 0 },
-                if pixel[1] as f32 > threshold { pixel[1] + (avg * threshold) as u8 } else { 0 },
-                if pixel[2] as f32 > threshold { pixel[2] + (avg * threshold) as u8 } else { 0 },
+                if pixel[0] as f32 > threshold { pixel[0].saturating_add((avg * threshold) as u8) } else { 0 },
+                if pixel[1] as f32 > threshold { pixel[1].saturating_add((avg * threshold) as u8) } else { 0 },
+                if pixel[2] as f32 > threshold { pixel[2].saturating_add((avg * threshold) as u8) } else { 0 },
                 pixel[3],
             ];
 
@@ -169,7 +222,7 @@ fn bayer_dithering(image: &mut DynamicImage, order: u32) {
     }
 }
 
-fn generate_bayer_matrix(order: u32) -> Vec<Vec<f32>> {
+pub fn generate_bayer_matrix(order: u32) -> Vec<Vec<f32>> {
     if order == 0 {
         return vec![vec![0f32]];
     }
@@ -192,58 +245,71 @@ fn generate_bayer_matrix(order: u32) -> Vec<Vec<f32>> {
     matrix
 }
 
-fn bayer_threshold(matrix: &Vec<Vec<f32>>, order: u32, x: u32, y: u32) -> f32 {
+pub fn bayer_threshold(matrix: &[Vec<f32>], order: u32, x: u32, y: u32) -> f32 {
     let size = matrix.len() as u32;
     let value = matrix[(y % size) as usize][(x % size) as usize];
     let max_value = (1 << (2 * order)) as f32; // equivalent to 2^(2*order)
     (value / max_value) * 255.0
 }
 
-fn generic_error_diffusion_dither(image: &mut DynamicImage, palette: &str, diff_mat: &[((i32, i32), f32)]) {
+fn generic_error_diffusion_dither(image: &mut DynamicImage, palette: &str, diff_mat: &[((i32, i32), f32)]) -> Result<(), String> {
     let (width, height) = image.dimensions();
-    let pal = Palette::new(palette).colours;
-    let mut pixels = image.to_rgb8();
+    let pal = Palette::new(palette)?.colours;
+    let (rgb, alpha) = split_alpha(image);
+    let mut pixels = rgb.to_rgb8();
 
     for y in 0..height {
         for x in 0..width {
-            let old_color = pixels.get_pixel(x, y).clone();
+            let old_color = *pixels.get_pixel(x, y);
             let new_color = find_closest_color(&old_color, &pal);
             pixels.put_pixel(x, y, new_color);
             let error = calculate_error(&old_color, &new_color);
 
-            diffuse_error(x, y, diff_mat, error, &mut pixels);
+            if !is_transparent(&alpha, x, y) {
+                diffuse_error(x, y, diff_mat, error, &mut pixels);
+            }
         }
     }
-    *image = DynamicImage::ImageRgb8(pixels);
+    *image = restore_alpha(DynamicImage::ImageRgb8(pixels), alpha);
+    return Ok(());
 }
 
-fn blue_noise_dither(image: &mut DynamicImage, threshold: BlueNoiseThreshold, palette: &str) {
-    let pal = Palette::new(palette).colours;
+fn blue_noise_dither(image: &mut DynamicImage, threshold: BlueNoiseThreshold, palette: &str) -> Result<(), String> {
+    let pal = Palette::new(palette)?.colours;
     let (width, height) = image.dimensions();
-    let mut pixels = image.to_rgb8();
-    let mut rng = rand::thread_rng();
+    let (rgb, alpha) = split_alpha(image);
+    let mut pixels = rgb.to_rgb8();
+    let mut rng = rand::rng();
     let noise_threshold = gen_blue_noise_threshold(threshold);
 
     for y in 0..height {
         for x in 0..width {
             let old_color = pixels.get_pixel(x, y);
             let new_color = find_closest_color(old_color, &pal);
-            let error = calculate_error(&old_color, &new_color);
+            let error = calculate_error(old_color, &new_color);
 
-            let noise_value: u8 = rng.gen();
+            let noise_value: u8 = rng.random();
             if noise_value > noise_threshold {
                 let new_color = Rgb([
-                    (new_color[0] as i16 + error[0] / 4) as u8,
-                    (new_color[1] as i16 + error[1] / 4) as u8,
-                    (new_color[2] as i16 + error[2] / 4) as u8,
+                    clamp(new_color[0] as i16 + error[0] / 4, 0, 255) as u8,
+                    clamp(new_color[1] as i16 + error[1] / 4, 0, 255) as u8,
+                    clamp(new_color[2] as i16 + error[2] / 4, 0, 255) as u8,
                 ]);
                 pixels.put_pixel(x, y, new_color);
             } else {
                 pixels.put_pixel(x, y, new_color);
             }
 
-            diffuse_error(x, y, &DIFF_MAT_FLOYD_STEINBERG, error, &mut pixels);
+            if !is_transparent(&alpha, x, y) {
+                diffuse_error(x, y, &DIFF_MAT_FLOYD_STEINBERG, error, &mut pixels);
+            }
         }
     }
-    *image = DynamicImage::ImageRgb8(pixels);
+    *image = restore_alpha(DynamicImage::ImageRgb8(pixels), alpha);
+    return Ok(());
+}
+
+// Fully transparent pixels hold arbitrary colours, so their error is not spread onto visible neighbours.
+fn is_transparent(alpha: &Option<GrayImage>, x: u32, y: u32) -> bool {
+    alpha.as_ref().is_some_and(|alpha| alpha.get_pixel(x, y)[0] == 0)
 }
\ No newline at end of file
@@ -0,0 +1,165 @@
+use image::Rgb;
+
+use crate::colour::{oklab_to_oklch, oklch_to_rgb_clipped, rgb_to_oklab};
+use crate::palette::Palette;
+
+const MIN_LIGHTNESS: f32 = 0.05;
+const MAX_LIGHTNESS: f32 = 0.98;
+
+#[derive(Copy, Clone)]
+pub enum Harmony {
+    COMPLEMENTARY,
+    ANALOGOUS,
+    TRIADIC,
+    TETRADIC,
+    SPLITCOMPLEMENTARY,
+    MONOCHROME,
+}
+
+impl Harmony {
+    pub fn new(name: &str) -> Result<Harmony, &'static str> {
+        let harmony = match name.to_lowercase().as_str() {
+            "complementary" => Harmony::COMPLEMENTARY,
+            "analogous" => Harmony::ANALOGOUS,
+            "triadic" => Harmony::TRIADIC,
+            "tetradic" => Harmony::TETRADIC,
+            "split-complementary" => Harmony::SPLITCOMPLEMENTARY,
+            "monochrome" => Harmony::MONOCHROME,
+
+            _ => return Err("Unknown harmony rule")
+        };
+
+        return Ok(harmony);
+    }
+
+    // Hue offsets in degrees from the seed hue, measured on the OKLCh hue circle.
+    pub fn hue_offsets(&self) -> Vec<f32> {
+        match self {
+            Harmony::COMPLEMENTARY => vec![0f32, 180f32],
+            Harmony::ANALOGOUS => vec![0f32, -30f32, 30f32],
+            Harmony::TRIADIC => vec![0f32, 120f32, 240f32],
+            Harmony::TETRADIC => vec![0f32, 90f32, 180f32, 270f32],
+            Harmony::SPLITCOMPLEMENTARY => vec![0f32, 150f32, 210f32],
+            Harmony::MONOCHROME => vec![0f32],
+        }
+    }
+}
+
+// Colours are shared out round robin between the hues of every seed, each hue gets a lightness ramp of
+// width lightness_spread centred on its seed. The seeds themselves are always part of the result.
+pub fn generate_harmony(palettename: String, seeds: &[Rgb<u8>], harmony: Harmony, num_colours: usize, lightness_spread: f32) -> Palette {
+    if seeds.is_empty() {
+        return Palette {
+            name: palettename,
+            colours: Vec::new(),
+        };
+    }
+
+    let offsets = harmony.hue_offsets();
+    let mut slots: Vec<([f32; 3], f32, bool)> = Vec::new();
+    for seed in seeds {
+        let lch = oklab_to_oklch(rgb_to_oklab(seed));
+        for (i, offset) in offsets.iter().enumerate() {
+            slots.push((lch, (lch[2] + offset).rem_euclid(360f32), i == 0));
+        }
+    }
+
+    // The seed hues are first in the round robin, so every seed has a slot even when there are only as
+    // many colours as seeds.
+    let num_colours = num_colours.max(seeds.len());
+    let order: Vec<usize> = (0..slots.len()).filter(|i| slots[*i].2)
+        .chain((0..slots.len()).filter(|i| !slots[*i].2))
+        .collect();
+    let mut counts = vec![0usize; slots.len()];
+    for i in 0..num_colours {
+        counts[order[i % order.len()]] += 1;
+    }
+
+    let mut colours = Vec::with_capacity(num_colours);
+    for (slot, ((seed_lch, hue, is_seed), count)) in slots.iter().zip(counts).enumerate() {
+        if count == 0 {
+            continue;
+        }
+
+        let mut ramp: Vec<Rgb<u8>> = lightness_steps(seed_lch[0], lightness_spread, count).into_iter()
+            .map(|lightness| oklch_to_rgb_clipped([lightness, seed_lch[1], *hue]))
+            .collect();
+
+        if *is_seed {
+            let seed = &seeds[slot / offsets.len()];
+            let closest = (0..ramp.len())
+                .min_by(|a, b| {
+                    let la = (rgb_to_oklab(&ramp[*a])[0] - seed_lch[0]).abs();
+                    let lb = (rgb_to_oklab(&ramp[*b])[0] - seed_lch[0]).abs();
+                    la.total_cmp(&lb)
+                })
+                .unwrap();
+            ramp[closest] = *seed;
+        }
+
+        colours.extend(ramp);
+    }
+
+    return Palette {
+        name: palettename,
+        colours,
+    };
+}
+
+fn lightness_steps(centre: f32, spread: f32, count: usize) -> Vec<f32> {
+    if count == 1 {
+        return vec![centre];
+    }
+
+    // Shift the window rather than clamp it so the steps stay evenly spaced near black and white.
+    let half = (spread / 2f32).min((MAX_LIGHTNESS - MIN_LIGHTNESS) / 2f32);
+    let low = (centre - half).clamp(MIN_LIGHTNESS, MAX_LIGHTNESS - 2f32 * half);
+    return (0..count)
+        .map(|i| low + 2f32 * half * i as f32 / (count - 1) as f32)
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: [Harmony; 6] = [
+        Harmony::COMPLEMENTARY,
+        Harmony::ANALOGOUS,
+        Harmony::TRIADIC,
+        Harmony::TETRADIC,
+        Harmony::SPLITCOMPLEMENTARY,
+        Harmony::MONOCHROME,
+    ];
+
+    #[test]
+    fn every_seed_is_part_of_the_result() {
+        let seeds = [Rgb([200, 30, 30]), Rgb([30, 160, 60]), Rgb([40, 40, 220])];
+        for harmony in RULES {
+            for count in 1..=seeds.len() {
+                for num_colours in [0, 1, 2, 3, 5, 8, 16] {
+                    let palette = generate_harmony("test.hex".to_string(), &seeds[..count], harmony, num_colours, 0.5);
+                    assert_eq!(palette.colours.len(), num_colours.max(count));
+                    for seed in &seeds[..count] {
+                        assert!(palette.colours.contains(seed));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn complementary_hue_follows_the_seed() {
+        let seed = Rgb([200, 30, 30]);
+        let palette = generate_harmony("test.hex".to_string(), &[seed], Harmony::COMPLEMENTARY, 2, 0.5);
+        let seed_hue = oklab_to_oklch(rgb_to_oklab(&seed))[2];
+        let hue = oklab_to_oklch(rgb_to_oklab(&palette.colours[1]))[2];
+        let difference = (hue - seed_hue).rem_euclid(360f32);
+        assert!((difference - 180f32).abs() < 10f32, "hue difference {}", difference);
+    }
+
+    #[test]
+    fn no_seeds_give_an_empty_palette() {
+        assert!(generate_harmony("test.hex".to_string(), &[], Harmony::TRIADIC, 8, 0.5).colours.is_empty());
+    }
+}
@@ -1,16 +1,33 @@
 use std::fmt::Error;
+use std::fs::{create_dir_all, write};
+use std::io::Cursor;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
-use image::{DynamicImage, ExtendedColorType, GenericImage, GenericImageView, ImageFormat, Pixel, save_buffer_with_format};
+use image::{ColorType, DynamicImage, GenericImage, GenericImageView, ImageFormat, Pixel};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType as PngFilterType, PngEncoder};
+use image::codecs::webp::WebPEncoder;
 use image::imageops::{FilterType};
 
+use crate::alpha::{apply_alpha_policy, restore_alpha, split_alpha, AlphaPolicy};
+use crate::cleanup::{cleanup_image, CleanupOptions, Connectivity};
 use crate::colour::euclidean_distance;
+use crate::cvd::{daltonize_image, simulate_image, CvdModel, Deficiency};
 use crate::ditherer::{Ditherer, DitherMode};
+use crate::indexed::{save_indexed_png, IndexedPngOptions};
+use crate::metadata::{convert_to_srgb, insert_png_text, open_with_metadata, Metadata};
+use crate::outline::{outline_image, EdgeDetector, OutlineColour, OutlineOptions};
 use crate::palette::Palette;
+use crate::pixelate::{pixelate_blocks, BlockReduction, BlockSize, PixelateOptions};
+use crate::recolour::ColourMapping;
+use crate::resize::{resize_image, ResizeMode, ResizeOptions, ResizeTarget};
+use crate::svg::{save_svg, SvgOptions};
+use crate::upscale::{upscale_image, Upscaler};
 use crate::utils::{available_threads, hex_to_rgb, rgb_to_hex};
 
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Extension {
     PNG,
     JPG,
@@ -25,7 +42,7 @@ pub enum Extension {
 
 impl Extension {
     pub fn new(ext: &str) -> Result<Extension, Error> {
-        let extension = match ext {
+        let extension = match ext.to_lowercase().as_str() {
             "png" => Extension::PNG,
             "jpg" => Extension::JPG,
             "jpeg" => Extension::JPG,
@@ -36,6 +53,7 @@ impl Extension {
             "webp" => Extension::WEBP,
             "avif" => Extension::AVIF,
             "tiff" => Extension::TIFF,
+            "tif" => Extension::TIFF,
 
             _ => return Err(Error)
         };
@@ -43,6 +61,11 @@ impl Extension {
         return Ok(extension);
     }
 
+    pub fn from_path(file_path: &str) -> Option<Extension> {
+        let ext = Path::new(file_path).extension()?.to_str()?;
+        return Extension::new(ext).ok();
+    }
+
     pub fn to_string(extension: &Extension) -> String {
         let ext = match extension {
             Extension::PNG => "png".to_string(),
@@ -59,6 +82,23 @@ impl Extension {
         return ext;
     }
 
+    // None for SVG, which is written by the vector exporter rather than the image crate.
+    pub fn image_format(&self) -> Option<ImageFormat> {
+        let format = match self {
+            Extension::PNG => ImageFormat::Png,
+            Extension::JPG => ImageFormat::Jpeg,
+            Extension::QOI => ImageFormat::Qoi,
+            Extension::GIF => ImageFormat::Gif,
+            Extension::BMP => ImageFormat::Bmp,
+            Extension::WEBP => ImageFormat::WebP,
+            Extension::AVIF => ImageFormat::Avif,
+            Extension::TIFF => ImageFormat::Tiff,
+            Extension::SVG => return None,
+        };
+
+        return Some(format);
+    }
+
     pub fn change_file_extension(file_path: &str, new_extension: Extension) -> String {
         let path = Path::new(file_path);
         let mut new_path = PathBuf::from(path);
@@ -71,10 +111,39 @@ impl Extension {
     }
 }
 
+#[derive(Copy, Clone)]
+pub struct SaveOptions {
+    // Output format, taken from the file extension when None.
+    pub format: Option<Extension>,
+    // From 1 to 100. JPEG only, WebP is always saved lossless.
+    pub jpeg_quality: u8,
+    pub png_compression: CompressionType,
+    pub png_filter: PngFilterType,
+    pub svg: SvgOptions,
+    // Write EXIF, the ICC profile and PNG text chunks where the format can hold them.
+    pub metadata: bool,
+}
+
+impl Default for SaveOptions {
+    fn default() -> Self {
+        SaveOptions {
+            format: None,
+            jpeg_quality: 90,
+            png_compression: CompressionType::Default,
+            png_filter: PngFilterType::Adaptive,
+            svg: SvgOptions::default(),
+            metadata: true,
+        }
+    }
+}
+
 pub struct Image {
     pub filename: String,
     pub extension: Extension,
     pub data: DynamicImage,
+    // Applied to the alpha channel after palettizing and dithering.
+    pub alpha_policy: AlphaPolicy,
+    pub metadata: Metadata,
 }
 
 impl Image {
@@ -83,51 +152,163 @@ impl Image {
         let name = path.file_stem().unwrap().to_str().unwrap().to_string();
         let extstr = path.extension().unwrap().to_str().unwrap();
         let ext = Extension::new(extstr).expect("ERROR: UNSUPPORTED EXTENSION");
-        let data = load_image(filepath);
+        let (data, metadata) = open_with_metadata(filepath).expect("ERROR: Unable to open image");
 
         return Image {
             filename: name,
             extension: ext,
             data,
+            alpha_policy: AlphaPolicy::KEEP,
+            metadata,
         };
     }
 
+    // Converts the pixels from the embedded ICC profile to sRGB and drops the profile, does nothing
+    // for images without one.
+    pub fn convert_to_srgb(&mut self) -> Result<(), String> {
+        if let Some(icc_profile) = &self.metadata.icc_profile {
+            self.data = convert_to_srgb(&self.data, icc_profile)?;
+            self.metadata.icc_profile = None;
+            self.metadata.provenance.push("convert-to-srgb".to_string());
+        }
+        return Ok(());
+    }
+
+    // Palette colours are sRGB, so pixels about to be mapped to them are converted from the embedded
+    // profile first. A profile that cannot be converted is dropped, it would not describe the output.
+    fn prepare_palette_mapping(&mut self) {
+        if let Err(err) = self.convert_to_srgb() {
+            println!("INFO: The ICC profile was dropped, palette colours are sRGB: {}", err);
+            self.metadata.icc_profile = None;
+        }
+    }
+
     pub fn pixelate(&mut self, scale: u32, filter: FilterType) {
-        pixelate_image(&mut self.data, scale, filter)
+        pixelate_image(&mut self.data, scale, filter);
+        self.metadata.provenance.push(format!("pixelate scale={} filter={:?}", scale, filter));
     }
 
-    pub fn apply_palette(&mut self, palette: Palette) {
-        self.data = apply_palette(self.data.clone(), palette)
+    pub fn pixelate_blocks(&mut self, options: &PixelateOptions) {
+        self.data = pixelate_blocks(&self.data, options);
+        let block = match options.block {
+            BlockSize::SCALE(width, height) => format!("block={}x{}", width, height),
+            BlockSize::TARGET(width, height) => format!("target={}x{}", width, height),
+        };
+        self.metadata.provenance.push(format!(
+            "pixelate {} reduction={} offset={}x{} low-resolution={}",
+            block, BlockReduction::to_string(&options.reduction), options.offset.0, options.offset.1, options.low_resolution
+        ));
     }
 
-    // Currently only jpeg, png, ico, pnm, bmp, exr and tiff files are supported.
-    pub fn save_image(&self, file_path: Option<&str>) {
-        let ext = Extension::to_string(&self.extension);
-        let extstr = ext.as_str();
-        let path = file_path.unwrap_or_else(|| "./output");
-        if path.eq("./output") {
-            let mut fullpath = Path::new(path).join(&self.filename);
-            fullpath.set_extension(extstr);
-            let new = fullpath.to_str().unwrap();
-            save_image(&self.data, extstr, &new)
-        } else {
-            save_image(&self.data, extstr, path)
+    // Outline colours come from the palette, so a palettized image stays within it.
+    pub fn outline(&mut self, palette: &Palette, options: &OutlineOptions) {
+        self.prepare_palette_mapping();
+        self.data = outline_image(&self.data, palette, options);
+        let detector = match options.detector {
+            EdgeDetector::SOBEL(threshold) => format!("sobel threshold={}", threshold),
+            EdgeDetector::CANNY(low, high) => format!("canny low={} high={}", low, high),
+        };
+        let colour = match options.colour {
+            OutlineColour::DARKEST => "darkest".to_string(),
+            OutlineColour::DARKENED(amount) => format!("darkened amount={}", amount),
+        };
+        self.metadata.provenance.push(format!("outline palette={} detector={} colour={} block={}", palette.name, detector, colour, options.block));
+    }
+
+    pub fn cleanup(&mut self, palette: &Palette, options: &CleanupOptions) {
+        self.prepare_palette_mapping();
+        self.data = cleanup_image(&self.data, palette, options);
+        let connectivity = if options.connectivity == Connectivity::FOUR { 4 } else { 8 };
+        self.metadata.provenance.push(format!(
+            "cleanup palette={} min-region={} connectivity={} jaggies={} mode-radius={} passes={}",
+            palette.name, options.min_region, connectivity, options.smooth_jaggies, options.mode_radius, options.passes
+        ));
+    }
+
+    pub fn upscale(&mut self, upscaler: Upscaler, factor: u32) -> Result<(), String> {
+        self.data = upscale_image(&self.data, upscaler, factor)?;
+        self.metadata.provenance.push(format!("upscale algorithm={} factor={}", Upscaler::to_string(&upscaler), factor));
+        return Ok(());
+    }
+
+    pub fn apply_palette(&mut self, palette: Palette) -> Result<(), String> {
+        self.prepare_palette_mapping();
+        self.metadata.provenance.push(format!("palette name={} colours={}", palette.name, palette.colours.len()));
+        let colours = palette.colours.clone();
+        self.data = apply_palette(self.data.clone(), palette);
+        return apply_alpha_policy(&mut self.data, self.alpha_policy, &colours);
+    }
+
+    // Returns the number of pixels whose colour is not in the mapping's source palette. Source colours
+    // are matched exactly, so the pixels are not converted, but the profile no longer describes the
+    // sRGB target colours and is dropped.
+    pub fn recolour(&mut self, mapping: &ColourMapping) -> usize {
+        self.metadata.icc_profile = None;
+        self.metadata.provenance.push(format!("recolour entries={}", mapping.entries.len()));
+        mapping.apply(&mut self.data)
+    }
+
+    // Saves to ./output/<filename> when no path is given, in the format from the options, the path or the input.
+    pub fn save_image(&self, file_path: Option<&str>, options: &SaveOptions) -> Result<(), String> {
+        let format = options.format
+            .or_else(|| file_path.and_then(Extension::from_path))
+            .unwrap_or(self.extension);
+        let options = SaveOptions { format: Some(format), ..*options };
+
+        match file_path {
+            Some(path) => save_image_with_metadata(&self.data, path, &options, &self.metadata),
+            None => {
+                let mut fullpath = Path::new("./output").join(&self.filename);
+                fullpath.set_extension(Extension::to_string(&format));
+                create_dir_all("./output").map_err(|err| format!("Could not create ./output: {}", err))?;
+                save_image_with_metadata(&self.data, fullpath.to_str().unwrap(), &options, &self.metadata)
+            }
         }
     }
 
-    pub fn resize(infilepath: &str, outfilepath: Option<&str>, n_width: u32, n_height: u32, filter: FilterType) {
-        let img = load_image(infilepath);
-        let path = outfilepath.unwrap_or_else(|| "./output");
-        img.resize(n_width, n_height, filter);
+    pub fn save_indexed_png(&self, file_path: &str, palette: &Palette, options: &IndexedPngOptions) -> Result<(), String> {
+        save_indexed_png(&self.data, palette, file_path, options, &self.metadata.output_text())
+    }
+
+    pub fn resize(&mut self, options: &ResizeOptions) -> Result<(), String> {
+        self.data = resize_image(&self.data, options)?;
+        self.metadata.provenance.push(format!(
+            "resize target={} mode={} filter={:?} size={}x{}",
+            ResizeTarget::to_string(&options.target), ResizeMode::to_string(&options.mode), options.filter, self.data.width(), self.data.height()
+        ));
+        return Ok(());
+    }
+
+    pub fn simulate_cvd(&mut self, deficiency: Deficiency, model: CvdModel) {
+        self.data = simulate_image(&self.data, deficiency, model);
+        self.metadata.provenance.push(format!(
+            "simulate-cvd deficiency={} model={}", Deficiency::to_string(&deficiency), CvdModel::to_string(&model)
+        ));
     }
 
-    pub fn dither(&mut self, mode: DitherMode) {
+    pub fn daltonize(&mut self, deficiency: Deficiency, model: CvdModel) {
+        self.data = daltonize_image(&self.data, deficiency, model);
+        self.metadata.provenance.push(format!(
+            "daltonize deficiency={} model={}", Deficiency::to_string(&deficiency), CvdModel::to_string(&model)
+        ));
+    }
+
+    pub fn dither(&mut self, mode: DitherMode) -> Result<(), String> {
+        if DitherMode::palette(&mode).is_some() {
+            self.prepare_palette_mapping();
+        }
+        self.metadata.provenance.push(format!("dither {}", DitherMode::to_string(&mode)));
         let ditherer = Ditherer::new(mode);
-        (ditherer.dither_fn)(&mut self.data)
+        (ditherer.dither_fn)(&mut self.data)?;
+        let colours = match DitherMode::palette(&mode) {
+            Some(name) => Palette::new(name)?.colours,
+            None => Vec::new(),
+        };
+        return apply_alpha_policy(&mut self.data, self.alpha_policy, &colours);
     }
 
     pub fn convert_to_grayscale_in_place(&mut self) {
-        self.data.to_luma8();
+        self.data = DynamicImage::ImageLuma8(self.data.to_luma8());
     }
 
     pub fn convert_to_grayscale(&self) -> DynamicImage {
@@ -142,7 +323,7 @@ impl Image {
 
         for y in 0..height {
             for x in 0..width {
-                let pix = mask.get_pixel(x, y).clone();
+                let pix = *mask.get_pixel(x, y);
                 if !colours.contains(&rgb_to_hex(pix)) {
                     mask.put_pixel(x, y, back)
                 }
@@ -158,11 +339,11 @@ impl Image {
 
         for y in 0..height {
             for x in 0..width {
-                let mPixel = rgb_mask.get_pixel(x, y).clone();
-                let hex = rgb_to_hex(mPixel);
+                let m_pixel = *rgb_mask.get_pixel(x, y);
+                let hex = rgb_to_hex(m_pixel);
 
-                if !(hex == background) {
-                    self.data.put_pixel(x, y, mPixel.to_rgba())
+                if hex != background {
+                    self.data.put_pixel(x, y, m_pixel.to_rgba())
                 }
             }
         }
@@ -170,31 +351,110 @@ impl Image {
 
 }
 
-fn load_image(file_path: &str) -> DynamicImage {
-    let img = image::open(file_path).expect("ERROR: Unable to open image");
-    return img;
+// Encodes with the image's own colour type where the format supports it, otherwise the encoder converts it.
+pub fn save_image(img: &DynamicImage, file_path: &str, options: &SaveOptions) -> Result<(), String> {
+    return save_image_with_metadata(img, file_path, options, &Metadata::default());
 }
 
-pub fn save_image(img: &DynamicImage, extension: &str, file_path: &str) {
-    let imgbuf = img.as_bytes();
-    let width = img.width();
-    let height = img.height();
-    let colour = ExtendedColorType::Rgb8;
-    let format = ImageFormat::from_extension(extension);
-    save_buffer_with_format(file_path, imgbuf, width, height, colour, format.unwrap()).expect("ERROR: Unable to save image");
-}
+// The image is encoded in memory first, so a failed save never leaves a partial file behind.
+pub fn save_image_with_metadata(img: &DynamicImage, file_path: &str, options: &SaveOptions, metadata: &Metadata) -> Result<(), String> {
+    let format = options.format
+        .or_else(|| Extension::from_path(file_path))
+        .ok_or(format!("Cannot tell the output format of {}", file_path))?;
 
-fn pixelate_image(img: &mut DynamicImage, scale: u32, filter: FilterType) {
-    let (width, height) = img.dimensions();
+    if format == Extension::SVG {
+        return save_svg(img, file_path, &options.svg);
+    }
 
-    let new_width = width / scale;
-    let new_height = height / scale;
+    let converted = to_encodable(img, format);
+    let img = converted.as_ref().unwrap_or(img);
+    let metadata = if options.metadata { metadata.clone() } else { Metadata::default() };
+
+    let mut bytes = Cursor::new(Vec::new());
+    let result = match format {
+        Extension::JPG => {
+            let mut encoder = JpegEncoder::new_with_quality(&mut bytes, options.jpeg_quality.clamp(1, 100));
+            metadata.apply_to_encoder(&mut encoder);
+            img.write_with_encoder(encoder)
+        },
+        Extension::PNG => {
+            let mut encoder = PngEncoder::new_with_quality(&mut bytes, options.png_compression, options.png_filter);
+            metadata.apply_to_encoder(&mut encoder);
+            img.write_with_encoder(encoder)
+        },
+        // The image crate only ships a lossless WebP encoder, so there is no quality setting for WebP.
+        Extension::WEBP => {
+            let mut encoder = WebPEncoder::new_lossless(&mut bytes);
+            metadata.apply_to_encoder(&mut encoder);
+            img.write_with_encoder(encoder)
+        },
+        _ => {
+            if metadata.exif.is_some() || metadata.icc_profile.is_some() {
+                println!("INFO: EXIF and ICC profiles are not written to {}, they were left out.", Extension::to_string(&format));
+            }
+            img.write_to(&mut bytes, format.image_format().unwrap())
+        },
+    };
+    result.map_err(|err| format!("Unable to save {}: {}", file_path, err))?;
+
+    let mut bytes = bytes.into_inner();
+    if format == Extension::PNG && options.metadata {
+        bytes = insert_png_text(&bytes, &metadata.output_text());
+    }
+    return write(file_path, bytes).map_err(|err| format!("Unable to save {}: {}", file_path, err));
+}
 
-    let resized_down = img.resize_exact(new_width, new_height, filter);
+// Converts to the nearest colour type the format's encoder takes. Only PNG and TIFF take more than 8 bits
+// per channel, JPEG has no alpha, GIF and QOI have no grey and TIFF and BMP have no grey with alpha.
+fn to_encodable(img: &DynamicImage, format: Extension) -> Option<DynamicImage> {
+    let colour = img.color();
+    let alpha = colour.has_alpha() && format != Extension::JPG;
+    let grey = !colour.has_color() && match format {
+        Extension::GIF | Extension::QOI => false,
+        Extension::TIFF | Extension::BMP => !alpha,
+        _ => true,
+    };
+    let wide = colour.bytes_per_pixel() != colour.channel_count() && matches!(format, Extension::PNG | Extension::TIFF);
+
+    if format == Extension::TIFF && matches!(colour, ColorType::Rgb32F | ColorType::Rgba32F) {
+        return None;
+    }
 
-    let pixelated = resized_down.resize_exact(width, height, filter);
+    let target = match (grey, alpha, wide) {
+        (true, false, false) => ColorType::L8,
+        (true, true, false) => ColorType::La8,
+        (false, false, false) => ColorType::Rgb8,
+        (false, true, false) => ColorType::Rgba8,
+        (true, false, true) => ColorType::L16,
+        (true, true, true) => ColorType::La16,
+        (false, false, true) => ColorType::Rgb16,
+        (false, true, true) => ColorType::Rgba16,
+    };
+    if target == colour {
+        return None;
+    }
 
-    *img = pixelated;
+    let converted = match target {
+        ColorType::L8 => DynamicImage::ImageLuma8(img.to_luma8()),
+        ColorType::La8 => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+        ColorType::Rgb8 => DynamicImage::ImageRgb8(img.to_rgb8()),
+        ColorType::L16 => DynamicImage::ImageLuma16(img.to_luma16()),
+        ColorType::La16 => DynamicImage::ImageLumaA16(img.to_luma_alpha16()),
+        ColorType::Rgb16 => DynamicImage::ImageRgb16(img.to_rgb16()),
+        ColorType::Rgba16 => DynamicImage::ImageRgba16(img.to_rgba16()),
+        _ => DynamicImage::ImageRgba8(img.to_rgba8()),
+    };
+    return Some(converted);
+}
+
+// Resamples to one pixel per scale x scale block with the filter, then fills each block with it.
+pub fn pixelate_image(img: &mut DynamicImage, scale: u32, filter: FilterType) {
+    let options = PixelateOptions {
+        block: BlockSize::SCALE(scale as f32, scale as f32),
+        reduction: BlockReduction::FILTER(filter),
+        ..PixelateOptions::default()
+    };
+    *img = pixelate_blocks(img, &options);
 }
 
 fn apply_palette_partial(image: &mut DynamicImage, palette: &Palette, start_row: u32, end_row: u32) {
@@ -203,12 +463,12 @@ fn apply_palette_partial(image: &mut DynamicImage, palette: &Palette, start_row:
 
     for y in start_row..end_row.min(height) {
         for x in 0..width {
-            let mut pixel = rgb_image.get_pixel(x, y);
+            let pixel = rgb_image.get_pixel(x, y);
             let mut min_distance = f64::MAX;
             let mut best_match = *pixel;
 
             for color in &palette.colours {
-                let distance = euclidean_distance(pixel, &color);
+                let distance = euclidean_distance(pixel, color);
                 if distance < min_distance as f32 {
                     min_distance = distance as f64;
                     best_match = *color;
@@ -222,10 +482,12 @@ fn apply_palette_partial(image: &mut DynamicImage, palette: &Palette, start_row:
     *image = DynamicImage::ImageRgb8(rgb_image);
 }
 
-fn apply_palette(image: DynamicImage, palette: Palette) -> DynamicImage {
+// Alpha is carried through untouched, only the colour channels are matched against the palette.
+pub fn apply_palette(image: DynamicImage, palette: Palette) -> DynamicImage {
+    let (image, alpha) = split_alpha(&image);
     let num_threads = available_threads();
-    let (width, height) = image.dimensions();
-    let rows_per_thread = (height as usize + num_threads - 1) / num_threads;
+    let (_, height) = image.dimensions();
+    let rows_per_thread = (height as usize).div_ceil(num_threads);
 
     let image = Arc::new(Mutex::new(image));
     let palette = Arc::new(palette);
@@ -250,8 +512,164 @@ fn apply_palette(image: DynamicImage, palette: Palette) -> DynamicImage {
         handle.join().unwrap();
     }
 
-    return Arc::try_unwrap(image).unwrap().into_inner().unwrap();
+    let image = Arc::try_unwrap(image).unwrap().into_inner().unwrap();
+    return restore_alpha(image, alpha);
 }
 
 
 
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use image::{Rgb, Rgba, RgbaImage};
+
+    use super::*;
+    use crate::ditherer::BlueNoiseThreshold;
+
+    const PALETTE: &str = "ammo-8";
+
+    // Alpha runs 0-255 across the image over a colour gradient.
+    fn translucent() -> Image {
+        let data = RgbaImage::from_fn(16, 16, |x, y| Rgba([(x * 16) as u8, (y * 16) as u8, 90, (x * 16 + y) as u8]));
+        return Image {
+            filename: "translucent".to_string(),
+            extension: Extension::PNG,
+            data: DynamicImage::ImageRgba8(data),
+            alpha_policy: AlphaPolicy::KEEP,
+            metadata: Metadata::default(),
+        };
+    }
+
+    fn check_alpha(policy: AlphaPolicy, before: &RgbaImage, after: &RgbaImage, palette: &[Rgb<u8>]) {
+        for (old, new) in before.pixels().zip(after.pixels()) {
+            match policy {
+                AlphaPolicy::KEEP => assert_eq!(new[3], old[3]),
+                AlphaPolicy::THRESHOLD(cutoff) => assert_eq!(new[3], if old[3] >= cutoff { 255 } else { 0 }),
+                AlphaPolicy::DITHER => assert!(new[3] == 0 || new[3] == 255),
+                AlphaPolicy::TRANSPARENTINDEX(index) => {
+                    assert!(new[3] == 0 || new[3] == 255);
+                    if new[3] == 0 {
+                        assert_eq!(Rgb([new[0], new[1], new[2]]), palette[index]);
+                    }
+                },
+            }
+            if old[3] == 0 {
+                assert_eq!(new[3], 0);
+            }
+        }
+        if let AlphaPolicy::DITHER = policy {
+            let opaque = after.pixels().filter(|p| p[3] == 255).count();
+            assert!((96..=160).contains(&opaque), "{} opaque pixels", opaque);
+        }
+    }
+
+    #[test]
+    fn alpha_survives_palettize_for_each_policy() {
+        let palette = Palette::new(PALETTE).unwrap();
+        for policy in [AlphaPolicy::KEEP, AlphaPolicy::THRESHOLD(100), AlphaPolicy::DITHER, AlphaPolicy::TRANSPARENTINDEX(2)] {
+            let mut image = translucent();
+            let before = image.data.to_rgba8();
+            image.alpha_policy = policy;
+            image.apply_palette(palette.clone()).unwrap();
+
+            let after = image.data.to_rgba8();
+            check_alpha(policy, &before, &after, &palette.colours);
+            assert!(after.pixels().all(|p| palette.colours.contains(&Rgb([p[0], p[1], p[2]]))));
+        }
+    }
+
+    #[test]
+    fn alpha_survives_dither_for_each_policy() {
+        let palette = Palette::new(PALETTE).unwrap();
+        let modes = [
+            DitherMode::FLOYDSTEINBERG(PALETTE),
+            DitherMode::ATKINSON(PALETTE),
+            DitherMode::BLUENOISE(BlueNoiseThreshold::MEDIUM, PALETTE),
+        ];
+        for mode in modes {
+            for policy in [AlphaPolicy::KEEP, AlphaPolicy::THRESHOLD(100), AlphaPolicy::DITHER, AlphaPolicy::TRANSPARENTINDEX(5)] {
+                let mut image = translucent();
+                let before = image.data.to_rgba8();
+                image.alpha_policy = policy;
+                image.dither(mode).unwrap();
+                check_alpha(policy, &before, &image.data.to_rgba8(), &palette.colours);
+            }
+        }
+
+        let mut bayer = translucent();
+        let before = bayer.data.to_rgba8();
+        bayer.dither(DitherMode::BAYER(2)).unwrap();
+        check_alpha(AlphaPolicy::KEEP, &before, &bayer.data.to_rgba8(), &[]);
+    }
+
+    // Four colours so GIF keeps them exactly, alpha either opaque or half transparent.
+    fn source() -> DynamicImage {
+        let colours = [[200u8, 30, 40], [20, 120, 60], [240, 230, 220], [10, 10, 90]];
+        return DynamicImage::ImageRgba8(RgbaImage::from_fn(6, 4, |x, y| {
+            let [r, g, b] = colours[((x + y) % 4) as usize];
+            Rgba([r, g, b, if x < 3 { 255 } else { 128 }])
+        }));
+    }
+
+    #[test]
+    fn every_format_saves_every_colour_type() {
+        let source = source();
+        let colour_types = [
+            DynamicImage::ImageLuma8(source.to_luma8()),
+            DynamicImage::ImageLumaA8(source.to_luma_alpha8()),
+            DynamicImage::ImageRgb8(source.to_rgb8()),
+            DynamicImage::ImageRgba8(source.to_rgba8()),
+            DynamicImage::ImageLuma16(source.to_luma16()),
+            DynamicImage::ImageLumaA16(source.to_luma_alpha16()),
+            DynamicImage::ImageRgb16(source.to_rgb16()),
+            DynamicImage::ImageRgba16(source.to_rgba16()),
+            DynamicImage::ImageRgb32F(source.to_rgb32f()),
+            DynamicImage::ImageRgba32F(source.to_rgba32f()),
+        ];
+        let formats = [
+            Extension::PNG, Extension::JPG, Extension::SVG, Extension::AVIF, Extension::BMP,
+            Extension::GIF, Extension::QOI, Extension::TIFF, Extension::WEBP,
+        ];
+
+        for format in formats {
+            for image in &colour_types {
+                let path = env::temp_dir().join(format!(
+                    "pix-save-{:?}-{}.{}", image.color(), std::process::id(), Extension::to_string(&format)
+                ));
+                let path = path.to_str().unwrap();
+                save_image(image, path, &SaveOptions::default())
+                    .unwrap_or_else(|err| panic!("{:?} as {}: {}", image.color(), Extension::to_string(&format), err));
+
+                if format == Extension::SVG {
+                    assert!(fs::read_to_string(path).unwrap().contains("<svg"));
+                    fs::remove_file(path).unwrap();
+                    continue;
+                }
+                // The image crate encodes AVIF but cannot decode it without dav1d.
+                if format == Extension::AVIF {
+                    assert_eq!(&fs::read(path).unwrap()[4..8], b"ftyp");
+                    fs::remove_file(path).unwrap();
+                    continue;
+                }
+
+                let saved = image::open(path).unwrap();
+                fs::remove_file(path).unwrap();
+                assert_eq!(saved.dimensions(), (6, 4));
+
+                let expected = image.to_rgba8();
+                let saved = saved.to_rgba8();
+                match format {
+                    Extension::JPG => {},
+                    Extension::GIF => {
+                        for (old, new) in expected.pixels().zip(saved.pixels()).filter(|(old, _)| old[3] == 255) {
+                            assert_eq!(old, new, "{:?} as gif", image.color());
+                        }
+                    },
+                    _ => assert_eq!(expected, saved, "{:?} as {}", image.color(), Extension::to_string(&format)),
+                }
+            }
+        }
+    }
+}
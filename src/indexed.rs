@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+
+use image::{DynamicImage, Rgb};
+use png::{BitDepth, ColorType, Compression, Encoder};
+
+use crate::alpha::DEFAULT_ALPHA_CUTOFF;
+use crate::palette::Palette;
+use crate::utils::find_closest_index;
+
+pub const MAX_INDEXED_COLOURS: usize = 256;
+
+#[derive(Copy, Clone)]
+pub struct IndexedPngOptions {
+    // Palette entry that becomes the transparent one. When None an entry is added after the palette
+    // colours, or an entry no opaque pixel uses is taken when the palette is already full.
+    pub transparent_index: Option<usize>,
+    // PLTE colour of an added transparent entry, shown by viewers that ignore tRNS.
+    pub transparent_colour: Rgb<u8>,
+    pub compression: Compression,
+}
+
+impl Default for IndexedPngOptions {
+    fn default() -> Self {
+        IndexedPngOptions {
+            transparent_index: None,
+            transparent_colour: Rgb([0, 0, 0]),
+            compression: Compression::Balanced,
+        }
+    }
+}
+
+pub struct IndexedImage {
+    pub width: u32,
+    pub height: u32,
+    pub colours: Vec<Rgb<u8>>,
+    pub transparent_index: Option<usize>,
+    // One palette index per pixel, row by row.
+    pub indices: Vec<u8>,
+}
+
+impl IndexedImage {
+    // Colours keep the palette order. Pixels not in the palette take the nearest entry and pixels below
+    // DEFAULT_ALPHA_CUTOFF take the transparent entry, which only exists when the image has such pixels.
+    // Opaque pixels never take the transparent entry, even when their colour is the one it holds.
+    // Alpha is binary: pixels at or above the cutoff are written fully opaque, so partial transparency is
+    // lost. Run THRESHOLD or DITHER from AlphaPolicy first to choose how it is reduced.
+    pub fn new(image: &DynamicImage, palette: &Palette, options: &IndexedPngOptions) -> Result<IndexedImage, String> {
+        if palette.colours.is_empty() {
+            return Err("Cannot write an indexed image without palette colours".to_string());
+        }
+        if palette.colours.len() > MAX_INDEXED_COLOURS {
+            return Err(format!("Indexed PNGs hold at most {} colours, got {}", MAX_INDEXED_COLOURS, palette.colours.len()));
+        }
+        if let Some(index) = options.transparent_index {
+            if index >= palette.colours.len() {
+                return Err(format!("Transparent index {} is outside the {} colour palette", index, palette.colours.len()));
+            }
+        }
+
+        let rgba = image.to_rgba8();
+        let has_transparency = image.color().has_alpha() && rgba.pixels().any(|p| p[3] < DEFAULT_ALPHA_CUTOFF);
+        let reserved = if has_transparency { options.transparent_index } else { None };
+
+        let candidates: Vec<usize> = (0..palette.colours.len()).filter(|i| Some(*i) != reserved).collect();
+        let candidate_colours: Vec<Rgb<u8>> = candidates.iter().map(|i| palette.colours[*i]).collect();
+        let mut lookup: HashMap<Rgb<u8>, u8> = HashMap::new();
+        for index in candidates.iter().rev() {
+            lookup.insert(palette.colours[*index], *index as u8);
+        }
+
+        let mut opaque_indices = Vec::with_capacity(rgba.len() / 4);
+        for pixel in rgba.pixels() {
+            if has_transparency && pixel[3] < DEFAULT_ALPHA_CUTOFF {
+                opaque_indices.push(None);
+                continue;
+            }
+            if candidates.is_empty() {
+                return Err("The only palette colour is the transparent one, opaque pixels have no entry left".to_string());
+            }
+            let colour = Rgb([pixel[0], pixel[1], pixel[2]]);
+            let index = *lookup.entry(colour).or_insert_with(|| candidates[find_closest_index(&colour, &candidate_colours)] as u8);
+            opaque_indices.push(Some(index));
+        }
+
+        let mut colours = palette.colours.clone();
+        let transparent_index = match (has_transparency, options.transparent_index) {
+            (false, _) => None,
+            (true, Some(index)) => Some(index),
+            (true, None) if colours.len() < MAX_INDEXED_COLOURS => {
+                colours.push(options.transparent_colour);
+                Some(colours.len() - 1)
+            },
+            (true, None) => {
+                let mut used = vec![false; colours.len()];
+                for index in opaque_indices.iter().flatten() {
+                    used[*index as usize] = true;
+                }
+                match used.iter().position(|u| !u) {
+                    Some(index) => Some(index),
+                    None => return Err(format!("All {} palette entries are in use, pick one as the transparent index", MAX_INDEXED_COLOURS)),
+                }
+            },
+        };
+
+        let indices = opaque_indices.into_iter()
+            .map(|index| index.unwrap_or_else(|| transparent_index.unwrap() as u8))
+            .collect();
+
+        return Ok(IndexedImage {
+            width: rgba.width(),
+            height: rgba.height(),
+            colours,
+            transparent_index,
+            indices,
+        });
+    }
+
+    // Smallest PNG palette bit depth that can address every colour.
+    pub fn bit_depth(&self) -> BitDepth {
+        match self.colours.len() {
+            0..=2 => BitDepth::One,
+            3..=4 => BitDepth::Two,
+            5..=16 => BitDepth::Four,
+            _ => BitDepth::Eight,
+        }
+    }
+
+    // Rows packed most significant bits first, each row padded to a whole byte.
+    pub fn packed_rows(&self) -> Vec<u8> {
+        let bits = self.bit_depth() as usize;
+        let per_byte = 8 / bits;
+        let row_bytes = (self.width as usize).div_ceil(per_byte);
+        let mut packed = vec![0u8; row_bytes * self.height as usize];
+
+        for (y, row) in self.indices.chunks(self.width.max(1) as usize).enumerate() {
+            for (x, index) in row.iter().enumerate() {
+                let shift = 8 - bits * (x % per_byte + 1);
+                packed[y * row_bytes + x / per_byte] |= index << shift;
+            }
+        }
+        return packed;
+    }
+
+    // Text chunks go in as tEXt, or iTXt when the text does not fit Latin-1.
+    pub fn save(&self, file_path: &str, compression: Compression, text: &[(String, String)]) -> Result<(), String> {
+        let file = File::create(file_path).map_err(|err| format!("Could not create {}: {}", file_path, err))?;
+        let mut encoder = Encoder::new(BufWriter::new(file), self.width, self.height);
+        encoder.set_color(ColorType::Indexed);
+        encoder.set_depth(self.bit_depth());
+        encoder.set_compression(compression);
+        encoder.set_palette(self.colours.iter().flat_map(|c| c.0).collect::<Vec<u8>>());
+
+        // tRNS only needs to run up to the transparent entry, the rest default to opaque.
+        if let Some(index) = self.transparent_index {
+            let mut trns = vec![255u8; index + 1];
+            trns[index] = 0;
+            encoder.set_trns(trns);
+        }
+        for (keyword, value) in text {
+            let added = match value.chars().all(|c| (c as u32) < 256) {
+                true => encoder.add_text_chunk(keyword.clone(), value.clone()),
+                false => encoder.add_itxt_chunk(keyword.clone(), value.clone()),
+            };
+            added.map_err(|err| format!("Unable to save {}: {}", file_path, err))?;
+        }
+
+        let mut writer = encoder.write_header().map_err(|err| format!("Unable to save {}: {}", file_path, err))?;
+        writer.write_image_data(&self.packed_rows()).map_err(|err| format!("Unable to save {}: {}", file_path, err))?;
+        return writer.finish().map_err(|err| format!("Unable to save {}: {}", file_path, err));
+    }
+}
+
+pub fn save_indexed_png(
+    image: &DynamicImage,
+    palette: &Palette,
+    file_path: &str,
+    options: &IndexedPngOptions,
+    text: &[(String, String)],
+) -> Result<(), String> {
+    let indexed = IndexedImage::new(image, palette, options)?;
+    return indexed.save(file_path, options.compression, text);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    fn palette(count: usize) -> Palette {
+        let colours = (0..count).map(|i| Rgb([i as u8, (i * 7 % 256) as u8, 255 - i as u8])).collect();
+        return Palette { name: String::new(), colours };
+    }
+
+    // Pixel i takes palette colour i, the first one is transparent.
+    fn image(palette: &Palette) -> DynamicImage {
+        let count = palette.colours.len() as u32;
+        return DynamicImage::ImageRgba8(RgbaImage::from_fn(count, 1, |x, _| {
+            let colour = palette.colours[x as usize];
+            Rgba([colour[0], colour[1], colour[2], if x == 0 { 0 } else { 255 }])
+        }));
+    }
+
+    // PLTE length in entries, tRNS and bit depth as written to disk.
+    fn read_back(indexed: &IndexedImage, name: &str) -> (usize, Option<Vec<u8>>, BitDepth) {
+        let path = env::temp_dir().join(format!("pix-indexed-{}-{}.png", name, std::process::id()));
+        indexed.save(path.to_str().unwrap(), Compression::Fast, &[]).unwrap();
+        let decoder = png::Decoder::new(std::io::BufReader::new(File::open(&path).unwrap()));
+        let reader = decoder.read_info().unwrap();
+        let info = reader.info();
+        let result = (info.palette.as_ref().unwrap().len() / 3, info.trns.as_ref().map(|t| t.to_vec()), info.bit_depth);
+        std::fs::remove_file(&path).unwrap();
+        return result;
+    }
+
+    #[test]
+    fn transparent_index_reuses_the_palette_entry() {
+        let palette = palette(4);
+        let options = IndexedPngOptions { transparent_index: Some(3), ..IndexedPngOptions::default() };
+        let indexed = IndexedImage::new(&image(&palette), &palette, &options).unwrap();
+
+        assert_eq!(indexed.colours, palette.colours);
+        assert_eq!(indexed.transparent_index, Some(3));
+        // Pixel 3 is opaque and holds the transparent entry's colour, so it takes its nearest other entry.
+        assert_eq!(indexed.indices, vec![3, 1, 2, 2]);
+        assert_eq!(read_back(&indexed, "index"), (4, Some(vec![255, 255, 255, 0]), BitDepth::Two));
+    }
+
+    #[test]
+    fn transparent_entry_is_added_after_the_palette_by_default() {
+        let palette = palette(4);
+        let indexed = IndexedImage::new(&image(&palette), &palette, &IndexedPngOptions::default()).unwrap();
+
+        assert_eq!(indexed.colours.len(), 5);
+        assert_eq!(indexed.indices, vec![4, 1, 2, 3]);
+        assert_eq!(read_back(&indexed, "added"), (5, Some(vec![255, 255, 255, 255, 0]), BitDepth::Four));
+    }
+
+    #[test]
+    fn opaque_images_have_no_transparent_entry() {
+        let palette = palette(2);
+        let opaque = DynamicImage::ImageRgb8(image(&palette).to_rgb8());
+        let options = IndexedPngOptions { transparent_index: Some(0), ..IndexedPngOptions::default() };
+        let indexed = IndexedImage::new(&opaque, &palette, &options).unwrap();
+
+        assert_eq!(indexed.transparent_index, None);
+        assert_eq!(indexed.indices, vec![0, 1]);
+        assert_eq!(read_back(&indexed, "opaque"), (2, None, BitDepth::One));
+    }
+
+    #[test]
+    fn full_palette_with_transparency_still_saves() {
+        let palette = palette(MAX_INDEXED_COLOURS);
+        let options = IndexedPngOptions { transparent_index: Some(0), ..IndexedPngOptions::default() };
+        let indexed = IndexedImage::new(&image(&palette), &palette, &options).unwrap();
+        let (entries, trns, depth) = read_back(&indexed, "full-index");
+        assert_eq!((entries, trns.unwrap(), depth), (256, vec![0], BitDepth::Eight));
+
+        // Without an index the entry of the transparent pixel's colour is the only one no opaque pixel uses.
+        let indexed = IndexedImage::new(&image(&palette), &palette, &IndexedPngOptions::default()).unwrap();
+        assert_eq!(indexed.transparent_index, Some(0));
+        assert_eq!(read_back(&indexed, "full-unused").0, 256);
+
+        // Every entry in use by an opaque pixel and one transparent pixel after them leaves nothing to take.
+        let every_entry = DynamicImage::ImageRgba8(RgbaImage::from_fn(MAX_INDEXED_COLOURS as u32 + 1, 1, |x, _| {
+            match palette.colours.get(x as usize) {
+                Some(colour) => Rgba([colour[0], colour[1], colour[2], 255]),
+                None => Rgba([0, 0, 0, 0]),
+            }
+        }));
+        assert!(IndexedImage::new(&every_entry, &palette, &IndexedPngOptions::default()).is_err());
+    }
+
+    #[test]
+    fn transparent_index_outside_the_palette_is_an_error() {
+        let palette = palette(4);
+        let options = IndexedPngOptions { transparent_index: Some(4), ..IndexedPngOptions::default() };
+        assert!(IndexedImage::new(&image(&palette), &palette, &options).is_err());
+    }
+}
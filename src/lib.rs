@@ -0,0 +1,30 @@
+#![allow(clippy::needless_return, clippy::upper_case_acronyms)]
+
+pub mod palette;
+pub mod colour;
+pub mod image;
+pub mod pixelate;
+pub mod outline;
+pub mod cleanup;
+pub mod upscale;
+pub mod resize;
+pub mod alpha;
+pub mod indexed;
+pub mod metadata;
+pub mod svg;
+pub mod animation;
+pub mod temporal;
+pub mod sequence;
+pub mod utils;
+pub mod ditherer;
+pub mod consts;
+pub mod library;
+pub mod search_path;
+pub mod builtin;
+pub mod ramp;
+pub mod harmony;
+pub mod analysis;
+pub mod cvd;
+pub mod recommend;
+pub mod recolour;
+pub mod cli;
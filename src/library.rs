@@ -0,0 +1,248 @@
+use std::path::Path;
+
+use image::Rgb;
+
+use crate::colour::relative_luminance;
+use crate::palette::Palette;
+use crate::search_path::PaletteSearchPath;
+
+pub struct PaletteInfo {
+    pub name: String,
+    pub colour_count: usize,
+    pub avg_brightness: f32,
+    pub min_brightness: f32,
+    pub max_brightness: f32,
+}
+
+impl PaletteInfo {
+    pub fn describe(palette: &Palette) -> PaletteInfo {
+        let brightness: Vec<f32> = palette.colours.iter()
+            .map(|colour| relative_luminance(colour) * 100f32)
+            .collect();
+
+        let (min, max, sum) = brightness.iter().fold((f32::MAX, 0f32, 0f32), |(min, max, sum), b| {
+            (min.min(*b), max.max(*b), sum + b)
+        });
+
+        let count = brightness.len();
+        if count == 0 {
+            return PaletteInfo {
+                name: palette_stem(&palette.name),
+                colour_count: 0,
+                avg_brightness: 0.0,
+                min_brightness: 0.0,
+                max_brightness: 0.0,
+            };
+        }
+
+        return PaletteInfo {
+            name: palette_stem(&palette.name),
+            colour_count: count,
+            avg_brightness: sum / count as f32,
+            min_brightness: min,
+            max_brightness: max,
+        };
+    }
+
+    // WCAG style ratio between the darkest and lightest colour of the palette.
+    pub fn contrast_ratio(&self) -> f32 {
+        (self.max_brightness / 100f32 + 0.05) / (self.min_brightness / 100f32 + 0.05)
+    }
+}
+
+#[derive(Default)]
+pub struct PaletteFilter {
+    pub min_colours: Option<usize>,
+    pub max_colours: Option<usize>,
+    pub contains: Option<Rgb<u8>>,
+}
+
+impl PaletteFilter {
+    pub fn matches(&self, palette: &Palette) -> bool {
+        let count = palette.colours.len();
+        if self.min_colours.is_some_and(|min| count < min) {
+            return false;
+        }
+        if self.max_colours.is_some_and(|max| count > max) {
+            return false;
+        }
+        if let Some(colour) = self.contains {
+            return palette.colours.contains(&colour);
+        }
+        return true;
+    }
+}
+
+pub struct PaletteLibrary {
+    pub palettes: Vec<Palette>,
+}
+
+impl PaletteLibrary {
+    pub fn load(dir: &str) -> PaletteLibrary {
+        Self::from_search_path(&PaletteSearchPath::from_dir(dir))
+    }
+
+    pub fn from_search_path(search_path: &PaletteSearchPath) -> PaletteLibrary {
+        // One unreadable file should not hide the rest of the library.
+        let mut palettes: Vec<Palette> = search_path.palettes().iter()
+            .filter_map(|location| match Palette::from_location(location) {
+                Ok(palette) => Some(palette),
+                Err(err) => {
+                    println!("INFO: Skipping palette, {}", err);
+                    None
+                },
+            })
+            .collect();
+
+        palettes.sort_by(|a, b| a.name.cmp(&b.name));
+        return PaletteLibrary { palettes };
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.palettes.iter().map(|p| palette_stem(&p.name)).collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Palette> {
+        self.palettes.iter().find(|p| palette_stem(&p.name) == palette_stem(name))
+    }
+
+    pub fn describe(&self) -> Vec<PaletteInfo> {
+        self.palettes.iter().map(PaletteInfo::describe).collect()
+    }
+
+    pub fn filter(&self, filter: &PaletteFilter) -> Vec<&Palette> {
+        self.palettes.iter().filter(|p| filter.matches(p)).collect()
+    }
+
+    // Best matches first, palettes whose name does not contain the query as a subsequence are dropped.
+    pub fn search(&self, query: &str) -> Vec<&Palette> {
+        let mut scored: Vec<(i32, &Palette)> = self.palettes.iter()
+            .filter_map(|p| fuzzy_score(query, &palette_stem(&p.name)).map(|score| (score, p)))
+            .collect();
+
+        scored.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.name.cmp(&b.1.name)));
+        return scored.into_iter().map(|(_, p)| p).collect();
+    }
+}
+
+pub fn palette_stem(name: &str) -> String {
+    let path = Path::new(name);
+    return path.file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| name.to_string());
+}
+
+pub fn fuzzy_score(query: &str, candidate: &str) -> Option<i32> {
+    let query = query.to_lowercase();
+    let candidate = candidate.to_lowercase();
+
+    if query.is_empty() {
+        return Some(0);
+    }
+    if candidate == query {
+        return Some(1000);
+    }
+    if let Some(pos) = candidate.find(&query) {
+        return Some(500 - pos as i32 - candidate.len() as i32);
+    }
+
+    let mut score = 0;
+    let mut last_match: Option<usize> = None;
+    let mut chars = candidate.char_indices();
+
+    for q in query.chars() {
+        let (idx, _) = chars.find(|(_, c)| *c == q)?;
+        score += match last_match {
+            Some(last) if idx == last + 1 => 10,
+            _ => 1,
+        };
+        last_match = Some(idx);
+    }
+
+    return Some(score - candidate.len() as i32);
+}
+
+pub fn ansi_swatch(palette: &Palette) -> String {
+    let mut swatch = String::new();
+    for colour in &palette.colours {
+        swatch.push_str(&format!("\x1b[48;2;{};{};{}m  ", colour[0], colour[1], colour[2]));
+    }
+    swatch.push_str("\x1b[0m");
+    return swatch;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn palette(name: &str, colours: Vec<Rgb<u8>>) -> Palette {
+        return Palette {
+            name: name.to_string(),
+            colours,
+        };
+    }
+
+    #[test]
+    fn fuzzy_score_ranks_exact_then_substring_then_subsequence() {
+        assert_eq!(fuzzy_score("nyx8", "NYX8"), Some(1000));
+        assert_eq!(fuzzy_score("", "anything"), Some(0));
+        assert_eq!(fuzzy_score("xyz", "nyx8"), None);
+
+        let substring = fuzzy_score("pico", "pico-8").unwrap();
+        let later_substring = fuzzy_score("pico", "my-pico-8").unwrap();
+        let subsequence = fuzzy_score("pc8", "pico-8").unwrap();
+        assert!(substring > later_substring);
+        assert!(later_substring > subsequence);
+
+        // Consecutive characters score more than scattered ones in a candidate of the same length.
+        assert!(fuzzy_score("ab", "xabx").unwrap() > fuzzy_score("ab", "axxb").unwrap());
+    }
+
+    #[test]
+    fn filter_checks_size_and_required_colour() {
+        let red = Rgb([255, 0, 0]);
+        let small = palette("small.hex", vec![red, Rgb([0, 0, 0])]);
+        let large = palette("large.hex", vec![Rgb([0, 0, 0]); 8]);
+
+        let at_least_four = PaletteFilter { min_colours: Some(4), ..PaletteFilter::default() };
+        assert!(!at_least_four.matches(&small));
+        assert!(at_least_four.matches(&large));
+
+        let at_most_two = PaletteFilter { max_colours: Some(2), ..PaletteFilter::default() };
+        assert!(at_most_two.matches(&small));
+        assert!(!at_most_two.matches(&large));
+
+        let with_red = PaletteFilter { contains: Some(red), ..PaletteFilter::default() };
+        assert!(with_red.matches(&small));
+        assert!(!with_red.matches(&large));
+        assert!(PaletteFilter::default().matches(&palette("empty.hex", Vec::new())));
+    }
+
+    #[test]
+    fn info_reports_brightness_and_contrast() {
+        let info = PaletteInfo::describe(&palette("mono.hex", vec![Rgb([0, 0, 0]), Rgb([255, 255, 255])]));
+        assert_eq!(info.name, "mono");
+        assert_eq!(info.colour_count, 2);
+        assert!((info.avg_brightness - 50f32).abs() < 1e-3);
+        assert!(info.min_brightness.abs() < 1e-3);
+        assert!((info.max_brightness - 100f32).abs() < 1e-3);
+        assert!((info.contrast_ratio() - 21f32).abs() < 1e-3);
+
+        let empty = PaletteInfo::describe(&palette("empty.hex", Vec::new()));
+        assert_eq!(empty.colour_count, 0);
+        assert_eq!(empty.avg_brightness, 0f32);
+    }
+
+    #[test]
+    fn search_orders_best_matches_first() {
+        let library = PaletteLibrary {
+            palettes: vec![
+                palette("endesga-32.hex", Vec::new()),
+                palette("nyx8.hex", Vec::new()),
+                palette("nyx8-extended.hex", Vec::new()),
+            ],
+        };
+        let names: Vec<&str> = library.search("nyx8").iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["nyx8.hex", "nyx8-extended.hex"]);
+    }
+}
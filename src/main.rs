@@ -1,10 +1,5 @@
-mod palette;
-mod colour;
-mod image;
-mod utils;
-mod ditherer;
-mod consts;
+use pix::cli;
 
 fn main() {
-
+    cli::run();
 }
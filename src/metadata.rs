@@ -0,0 +1,297 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageEncoder, ImageReader};
+use moxcms::{ColorProfile, DataColorSpace, Layout, TransformOptions};
+
+pub const PROVENANCE_KEYWORD: &str = "Provenance";
+pub const SOFTWARE_KEYWORD: &str = "Software";
+
+// Everything besides the pixels that is carried from the input to the output.
+#[derive(Clone, Default)]
+pub struct Metadata {
+    // Raw EXIF block, with the orientation reset once it has been applied to the pixels.
+    pub exif: Option<Vec<u8>>,
+    pub icc_profile: Option<Vec<u8>>,
+    // Keyword and text of the tEXt, zTXt and iTXt chunks of PNG input.
+    pub text: Vec<(String, String)>,
+    // Every step applied to the image, in order, written to PNG output as a text chunk.
+    pub provenance: Vec<String>,
+}
+
+impl Metadata {
+    // Input text chunks followed by the Pix ones, which replace any from an earlier run.
+    pub fn output_text(&self) -> Vec<(String, String)> {
+        let mut text: Vec<(String, String)> = self.text.iter()
+            .filter(|(keyword, _)| keyword != SOFTWARE_KEYWORD && keyword != PROVENANCE_KEYWORD)
+            .cloned()
+            .collect();
+        text.push((SOFTWARE_KEYWORD.to_string(), format!("Pix {}", env!("CARGO_PKG_VERSION"))));
+        if !self.provenance.is_empty() {
+            text.push((PROVENANCE_KEYWORD.to_string(), self.provenance.join("; ")));
+        }
+        return text;
+    }
+
+    // Formats without support for a block leave it out, which is reported rather than failing the save.
+    pub fn apply_to_encoder<E: ImageEncoder>(&self, encoder: &mut E) {
+        if let Some(icc) = &self.icc_profile {
+            if let Err(err) = encoder.set_icc_profile(icc.clone()) {
+                println!("INFO: The ICC profile was left out: {}", err);
+            }
+        }
+        if let Some(exif) = &self.exif {
+            if let Err(err) = encoder.set_exif_metadata(exif.clone()) {
+                println!("INFO: The EXIF data was left out: {}", err);
+            }
+        }
+    }
+}
+
+// Decodes the pixels with the EXIF orientation applied, and the metadata alongside them.
+pub fn open_with_metadata(file_path: &str) -> Result<(DynamicImage, Metadata), String> {
+    let open_error = |err: String| format!("Could not open {}: {}", file_path, err);
+    let reader = ImageReader::open(file_path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(|err| open_error(err.to_string()))?;
+    let format = reader.format();
+    let mut decoder = reader.into_decoder().map_err(|err| open_error(err.to_string()))?;
+
+    let icc_profile = decoder.icc_profile().ok().flatten();
+    let mut exif = decoder.exif_metadata().ok().flatten();
+    let orientation = exif.as_mut()
+        .and_then(|exif| Orientation::remove_from_exif_chunk(exif))
+        .unwrap_or(Orientation::NoTransforms);
+
+    let mut image = DynamicImage::from_decoder(decoder).map_err(|err| open_error(err.to_string()))?;
+    image.apply_orientation(orientation);
+
+    let text = match format {
+        Some(image::ImageFormat::Png) => read_png_text(file_path),
+        _ => Vec::new(),
+    };
+
+    return Ok((image, Metadata { exif, icc_profile, text, provenance: Vec::new() }));
+}
+
+// Text chunks before the image data, chunks that fail to decode are skipped.
+fn read_png_text(file_path: &str) -> Vec<(String, String)> {
+    let Ok(file) = File::open(file_path) else {
+        return Vec::new();
+    };
+    let mut decoder = png::Decoder::new(BufReader::new(file));
+    decoder.set_ignore_text_chunk(false);
+    let Ok(reader) = decoder.read_info() else {
+        return Vec::new();
+    };
+
+    let info = reader.info();
+    let mut text: Vec<(String, String)> = info.uncompressed_latin1_text.iter()
+        .map(|chunk| (chunk.keyword.clone(), chunk.text.clone()))
+        .collect();
+    for chunk in &info.compressed_latin1_text {
+        let mut chunk = chunk.clone();
+        if let Ok(value) = chunk.decompress_text().and_then(|_| chunk.get_text()) {
+            text.push((chunk.keyword.clone(), value));
+        }
+    }
+    for chunk in &info.utf8_text {
+        let mut chunk = chunk.clone();
+        if let Ok(value) = chunk.decompress_text().and_then(|_| chunk.get_text()) {
+            text.push((chunk.keyword.clone(), value));
+        }
+    }
+    return text;
+}
+
+// Converts RGB pixels from the embedded profile to sRGB. Alpha is untouched and the result is 8-bit.
+pub fn convert_to_srgb(image: &DynamicImage, icc_profile: &[u8]) -> Result<DynamicImage, String> {
+    let source = ColorProfile::new_from_slice(icc_profile).map_err(|err| format!("Unreadable ICC profile: {}", err))?;
+    if source.color_space != DataColorSpace::Rgb {
+        return Err("Only RGB ICC profiles can be converted to sRGB".to_string());
+    }
+
+    let transform = source
+        .create_transform_8bit(Layout::Rgba, &ColorProfile::new_srgb(), Layout::Rgba, TransformOptions::default())
+        .map_err(|err| format!("Unable to convert to sRGB: {}", err))?;
+    let pixels = image.to_rgba8();
+    let mut converted = pixels.clone();
+    transform.transform(pixels.as_raw(), &mut converted).map_err(|err| format!("Unable to convert to sRGB: {}", err))?;
+
+    let converted = DynamicImage::ImageRgba8(converted);
+    if image.color().has_alpha() {
+        return Ok(converted);
+    }
+    return Ok(DynamicImage::ImageRgb8(converted.to_rgb8()));
+}
+
+// Inserts tEXt chunks, or iTXt chunks for text outside Latin-1, right after the IHDR of an encoded PNG.
+pub fn insert_png_text(png: &[u8], text: &[(String, String)]) -> Vec<u8> {
+    // 8 byte signature, then the IHDR chunk: 4 length, 4 type, 13 data and 4 CRC bytes.
+    let header_end = 8 + 4 + 4 + 13 + 4;
+    if png.len() < header_end || &png[12..16] != b"IHDR" {
+        return png.to_vec();
+    }
+
+    let mut output = png[..header_end].to_vec();
+    for (keyword, value) in text {
+        let keyword: String = keyword.chars().filter(|c| (*c as u32) < 256 && !c.is_control()).take(79).collect();
+        if keyword.is_empty() {
+            continue;
+        }
+
+        let latin1 = |s: &str| s.chars().map(|c| c as u8).collect::<Vec<u8>>();
+        if value.chars().all(|c| (c as u32) < 256) {
+            let mut data = latin1(&keyword);
+            data.push(0);
+            data.extend(latin1(value));
+            write_chunk(&mut output, b"tEXt", &data);
+        } else {
+            // Keyword, then compression flag, compression method, empty language tag and empty
+            // translated keyword, each text field ending in a null.
+            let mut data = latin1(&keyword);
+            data.extend([0, 0, 0, 0, 0]);
+            data.extend(value.as_bytes());
+            write_chunk(&mut output, b"iTXt", &data);
+        }
+    }
+    output.extend_from_slice(&png[header_end..]);
+    return output;
+}
+
+fn write_chunk(output: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    output.extend((data.len() as u32).to_be_bytes());
+    output.extend(chunk_type);
+    output.extend(data);
+    let crc = crc32(chunk_type.iter().chain(data));
+    output.extend(crc.to_be_bytes());
+}
+
+// The CRC-32 PNG chunks end in, over the chunk type and data.
+fn crc32<'a, I: Iterator<Item = &'a u8>>(bytes: I) -> u32 {
+    let mut crc = u32::MAX;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    return !crc;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::remove_file;
+
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    use super::*;
+    use crate::image::{save_image_with_metadata, Image, SaveOptions};
+    use crate::palette::Palette;
+
+    fn temp_path(name: &str) -> String {
+        return env::temp_dir().join(format!("pix-metadata-{}-{}.png", name, std::process::id())).to_str().unwrap().to_string();
+    }
+
+    // Little endian TIFF header with one IFD entry, Orientation (0x0112) as a SHORT.
+    fn exif_with_orientation(orientation: u8) -> Vec<u8> {
+        let mut exif = b"II*\0".to_vec();
+        exif.extend(8u32.to_le_bytes());
+        exif.extend(1u16.to_le_bytes());
+        exif.extend([0x12, 0x01, 3, 0, 1, 0, 0, 0, orientation, 0, 0, 0]);
+        exif.extend(0u32.to_le_bytes());
+        return exif;
+    }
+
+    fn marked() -> DynamicImage {
+        return DynamicImage::ImageRgb8(RgbImage::from_fn(3, 2, |x, y| if (x, y) == (0, 0) { Rgb([255, 0, 0]) } else { Rgb([0, 0, 255]) }));
+    }
+
+    #[test]
+    fn exif_orientation_is_applied_and_reset() {
+        let path = temp_path("orientation");
+        let metadata = Metadata { exif: Some(exif_with_orientation(6)), ..Metadata::default() };
+        save_image_with_metadata(&marked(), &path, &SaveOptions::default(), &metadata).unwrap();
+
+        let (image, metadata) = open_with_metadata(&path).unwrap();
+        remove_file(&path).unwrap();
+
+        // 6 is a quarter turn clockwise, the top left corner ends up top right.
+        assert_eq!((image.width(), image.height()), (2, 3));
+        assert_eq!(image.to_rgb8().get_pixel(1, 0), &Rgb([255, 0, 0]));
+        let mut exif = metadata.exif.unwrap();
+        assert_eq!(Orientation::remove_from_exif_chunk(&mut exif), Some(Orientation::NoTransforms));
+    }
+
+    #[test]
+    fn text_chunks_round_trip_as_text_and_itxt() {
+        let path = temp_path("text");
+        let text = vec![
+            ("Title".to_string(), "Sprite sheet".to_string()),
+            ("Comment".to_string(), "snow ☃ and café".to_string()),
+            (SOFTWARE_KEYWORD.to_string(), "Something else".to_string()),
+        ];
+        let metadata = Metadata { text, provenance: vec!["palette name=ammo-8".to_string()], ..Metadata::default() };
+        save_image_with_metadata(&marked(), &path, &SaveOptions::default(), &metadata).unwrap();
+
+        let (_, loaded) = open_with_metadata(&path).unwrap();
+        remove_file(&path).unwrap();
+
+        let get = |keyword: &str| loaded.text.iter().filter(|(k, _)| k == keyword).map(|(_, v)| v.clone()).collect::<Vec<String>>();
+        assert_eq!(get("Title"), vec!["Sprite sheet"]);
+        assert_eq!(get("Comment"), vec!["snow ☃ and café"]);
+        assert_eq!(get(SOFTWARE_KEYWORD), vec![format!("Pix {}", env!("CARGO_PKG_VERSION"))]);
+        assert_eq!(get(PROVENANCE_KEYWORD), vec!["palette name=ammo-8"]);
+    }
+
+    #[test]
+    fn srgb_profile_leaves_pixels_alone() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_fn(4, 1, |x, _| Rgba([x as u8 * 60, 128, 255 - x as u8 * 60, 100 + x as u8])));
+        let profile = ColorProfile::new_srgb().encode().unwrap();
+        let converted = convert_to_srgb(&image, &profile).unwrap();
+
+        for (a, b) in image.to_rgba8().pixels().zip(converted.to_rgba8().pixels()) {
+            assert!((0..3).all(|i| a[i].abs_diff(b[i]) <= 1), "{:?} {:?}", a, b);
+            assert_eq!(a[3], b[3]);
+        }
+    }
+
+    #[test]
+    fn display_p3_gets_more_saturated_in_srgb() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(1, 1, Rgb([200, 100, 60])));
+        let profile = ColorProfile::new_display_p3().encode().unwrap();
+        let converted = convert_to_srgb(&image, &profile).unwrap();
+
+        assert!(!converted.color().has_alpha());
+        let pixel = converted.to_rgb8().get_pixel(0, 0).0;
+        assert!(pixel[0] > 200 && pixel[2] < 60, "{:?}", pixel);
+
+        let grey = ColorProfile::new_gray_with_gamma(2.2).encode().unwrap();
+        assert!(convert_to_srgb(&image, &grey).is_err());
+        assert!(convert_to_srgb(&image, b"not a profile").is_err());
+    }
+
+    #[test]
+    fn palette_mapping_converts_and_drops_the_profile() {
+        let mut image = Image {
+            filename: "wide".to_string(),
+            extension: crate::image::Extension::PNG,
+            data: DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([200, 100, 60]))),
+            alpha_policy: crate::alpha::AlphaPolicy::KEEP,
+            metadata: Metadata { icc_profile: Some(ColorProfile::new_display_p3().encode().unwrap()), ..Metadata::default() },
+        };
+        image.pixelate(1, image::imageops::FilterType::Nearest);
+        assert!(image.metadata.icc_profile.is_some());
+
+        image.apply_palette(Palette { name: "pair".to_string(), colours: vec![Rgb([200, 100, 60]), Rgb([216, 92, 46])] }).unwrap();
+        assert!(image.metadata.icc_profile.is_none());
+        // Converted to sRGB first, the pixels are closer to the more saturated entry.
+        assert_eq!(image.data.to_rgb8().get_pixel(0, 0), &Rgb([216, 92, 46]));
+
+        image.metadata.icc_profile = Some(b"unreadable".to_vec());
+        image.apply_palette(Palette { name: "one".to_string(), colours: vec![Rgb([0, 0, 0])] }).unwrap();
+        assert!(image.metadata.icc_profile.is_none());
+    }
+}
@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+
+use image::{DynamicImage, GenericImageView, Rgb, Rgba, RgbaImage};
+
+use crate::alpha::DEFAULT_ALPHA_CUTOFF;
+use crate::colour::{cie76, oklab_distance, oklab_to_rgb, rgb_to_lab, rgb_to_oklab};
+use crate::palette::Palette;
+
+// CIE76 difference up to which a pixel still counts as the background colour, so gradients and
+// noise in the background stay out of the silhouette.
+const BACKGROUND_TOLERANCE: f32 = 6f32;
+
+#[derive(Copy, Clone)]
+pub enum EdgeDetector {
+    // Pixels whose gradient magnitude reaches the threshold, lines come out two pixels wide and are
+    // thinned to the darker side.
+    SOBEL(f32),
+    // Low and high hysteresis thresholds, lines come out one pixel wide.
+    CANNY(f32, f32),
+}
+
+// What the gradient is measured on. Both are in CIELAB units, so the same thresholds work for either.
+#[derive(Copy, Clone, PartialEq)]
+pub enum EdgeSignal {
+    // L* only, edges between colours of equal lightness are missed.
+    LUMINANCE,
+    // L*, a* and b* together, the gradient of the CIE76 colour difference.
+    DELTAE,
+}
+
+#[derive(Copy, Clone)]
+pub enum OutlineColour {
+    // The darkest palette colour everywhere.
+    DARKEST,
+    // The adjacent colour with its OKLab lightness lowered by the given fraction, snapped to the
+    // nearest darker palette colour.
+    DARKENED(f32),
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum OutlineMode {
+    // Outlines inside the shapes, which keep their size.
+    INNER,
+    // Outlines around the shapes, which grow by a pixel.
+    OUTER,
+    // Inner outlines left out on the sides that face the light from the top left, the selective
+    // outlining pixel artists use to keep lit edges soft.
+    SELECTIVE,
+}
+
+#[derive(Copy, Clone)]
+pub struct OutlineOptions {
+    pub detector: EdgeDetector,
+    pub signal: EdgeSignal,
+    pub colour: OutlineColour,
+    pub mode: OutlineMode,
+    // Size of one art pixel, edges are found and drawn on a grid of blocks this size.
+    pub block: u32,
+}
+
+impl Default for OutlineOptions {
+    fn default() -> Self {
+        OutlineOptions {
+            detector: EdgeDetector::SOBEL(20f32),
+            signal: EdgeSignal::DELTAE,
+            colour: OutlineColour::DARKENED(0.35),
+            mode: OutlineMode::INNER,
+            block: 1,
+        }
+    }
+}
+
+// Draws one pixel outlines along the silhouette and the edges inside it. The silhouette is the
+// opaque pixels of images with transparency, otherwise everything but the most common border colour.
+pub fn outline_image(img: &DynamicImage, palette: &Palette, options: &OutlineOptions) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    let block = options.block.clamp(1, width.max(height).max(1));
+    if width == 0 || height == 0 {
+        return img.clone();
+    }
+
+    // One sample from the middle of every block.
+    let (small_width, small_height) = (width.div_ceil(block), height.div_ceil(block));
+    let pixels = img.to_rgba8();
+    let small = RgbaImage::from_fn(small_width, small_height, |x, y| {
+        *pixels.get_pixel((x * block + block / 2).min(width - 1), (y * block + block / 2).min(height - 1))
+    });
+
+    let from_alpha = img.color().has_alpha() && small.pixels().any(|p| p[3] < DEFAULT_ALPHA_CUTOFF);
+    let mask = silhouette(&small, from_alpha);
+
+    // Transparent pixels take the colour of the nearest opaque one, so the colour hidden under them,
+    // usually black, never shows up as an edge.
+    let colours: Vec<Rgb<u8>> = small.pixels().map(|p| Rgb([p[0], p[1], p[2]])).collect();
+    let colours = match from_alpha {
+        true => fill_transparent(&colours, &mask, small_width, small_height),
+        false => colours,
+    };
+    let lab: Vec<[f32; 3]> = colours.iter().map(rgb_to_lab).collect();
+    let signal: Vec<Vec<f32>> = lab.iter()
+        .map(|lab| match options.signal {
+            EdgeSignal::LUMINANCE => vec![lab[0]],
+            EdgeSignal::DELTAE => lab.to_vec(),
+        })
+        .collect();
+    let lightness: Vec<f32> = lab.iter().map(|lab| lab[0]).collect();
+    let edges = match options.detector {
+        EdgeDetector::SOBEL(threshold) => sobel_edges(&signal, &lightness, small_width, small_height, threshold),
+        EdgeDetector::CANNY(low, high) => canny_edges(&signal, small_width, small_height, low, high),
+    };
+
+    let outline = outline_pixels(&mask, &edges, small_width, small_height, options.mode);
+    let darkest = palette.colours.iter()
+        .min_by(|a, b| rgb_to_lab(a)[0].total_cmp(&rgb_to_lab(b)[0]))
+        .copied()
+        .unwrap_or(Rgb([0, 0, 0]));
+
+    let mut output = pixels.clone();
+    let mut darkened: HashMap<Rgb<u8>, Rgb<u8>> = HashMap::new();
+    for (index, adjacent) in outline {
+        let (x, y) = (index as u32 % small_width, index as u32 / small_width);
+        let colour = match options.colour {
+            OutlineColour::DARKEST => darkest,
+            OutlineColour::DARKENED(amount) => {
+                let base = colours[adjacent];
+                *darkened.entry(base).or_insert_with(|| darken(&base, amount, &palette.colours, darkest))
+            },
+        };
+
+        // Pixels already as dark as the outline, such as an existing line, are left alone. Pixels
+        // outside the silhouette are always drawn, whatever colour they hold.
+        if mask[index] && lightness[index] <= rgb_to_lab(&colour)[0] {
+            continue;
+        }
+
+        for by in y * block..((y + 1) * block).min(height) {
+            for bx in x * block..((x + 1) * block).min(width) {
+                output.put_pixel(bx, by, Rgba([colour[0], colour[1], colour[2], 255]));
+            }
+        }
+    }
+
+    return match img.color().has_alpha() {
+        true => DynamicImage::ImageRgba8(output),
+        false => DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(output).to_rgb8()),
+    };
+}
+
+fn silhouette(small: &RgbaImage, from_alpha: bool) -> Vec<bool> {
+    if from_alpha {
+        return small.pixels().map(|p| p[3] >= DEFAULT_ALPHA_CUTOFF).collect();
+    }
+
+    let (width, height) = small.dimensions();
+    let mut counts: HashMap<Rgba<u8>, usize> = HashMap::new();
+    for (x, y, pixel) in small.enumerate_pixels() {
+        if x == 0 || y == 0 || x == width - 1 || y == height - 1 {
+            *counts.entry(*pixel).or_default() += 1;
+        }
+    }
+    let Some((background, _)) = counts.into_iter().max_by_key(|(colour, count)| (*count, colour.0)) else {
+        return vec![true; small.len() / 4];
+    };
+    let background = rgb_to_lab(&Rgb([background[0], background[1], background[2]]));
+    return small.pixels().map(|p| cie76(rgb_to_lab(&Rgb([p[0], p[1], p[2]])), background) > BACKGROUND_TOLERANCE).collect();
+}
+
+// Spreads the colours of the silhouette outwards one ring at a time over the pixels outside it.
+fn fill_transparent(colours: &[Rgb<u8>], mask: &[bool], width: u32, height: u32) -> Vec<Rgb<u8>> {
+    let (width, height) = (width as i64, height as i64);
+    let mut filled = colours.to_vec();
+    let mut done = mask.to_vec();
+    let mut ring: Vec<usize> = (0..mask.len()).filter(|i| mask[*i]).collect();
+    while !ring.is_empty() {
+        let mut next = Vec::new();
+        for i in ring {
+            let (x, y) = (i as i64 % width, i as i64 / width);
+            for (dx, dy) in [(0, -1), (-1, 0), (0, 1), (1, 0)] {
+                let (nx, ny) = (x + dx, y + dy);
+                if nx >= 0 && ny >= 0 && nx < width && ny < height && !done[(ny * width + nx) as usize] {
+                    let n = (ny * width + nx) as usize;
+                    done[n] = true;
+                    filled[n] = filled[i];
+                    next.push(n);
+                }
+            }
+        }
+        ring = next;
+    }
+    return filled;
+}
+
+// Pixels to draw, each with the pixel whose colour the outline is based on.
+fn outline_pixels(mask: &[bool], edges: &[bool], width: u32, height: u32, mode: OutlineMode) -> Vec<(usize, usize)> {
+    let (width, height) = (width as i64, height as i64);
+    let index = |x: i64, y: i64| (y * width + x) as usize;
+    let in_image = |x: i64, y: i64| x >= 0 && y >= 0 && x < width && y < height;
+    let inside = |x: i64, y: i64| in_image(x, y) && mask[index(x, y)];
+    // Up, left, down, right. The first two face the light.
+    let neighbours = [(0, -1), (-1, 0), (0, 1), (1, 0)];
+
+    let mut outline = Vec::new();
+    for y in 0..height {
+        for x in 0..width {
+            let i = index(x, y);
+            if mode == OutlineMode::OUTER {
+                if !mask[i] {
+                    if let Some((dx, dy)) = neighbours.iter().find(|(dx, dy)| inside(x + dx, y + dy)) {
+                        outline.push((i, index(x + dx, y + dy)));
+                    }
+                } else if edges[i] {
+                    outline.push((i, i));
+                }
+                continue;
+            }
+
+            if !mask[i] {
+                continue;
+            }
+            // The image border does not count as an open side.
+            let open_sides: Vec<usize> = (0..4)
+                .filter(|k| {
+                    let (nx, ny) = (x + neighbours[*k].0, y + neighbours[*k].1);
+                    in_image(nx, ny) && !mask[index(nx, ny)]
+                })
+                .collect();
+            let lit_only = !open_sides.is_empty() && open_sides.iter().all(|k| *k < 2);
+            if mode == OutlineMode::SELECTIVE && lit_only {
+                continue;
+            }
+            if !open_sides.is_empty() || edges[i] {
+                outline.push((i, i));
+            }
+        }
+    }
+    return outline;
+}
+
+// Lowers the OKLab lightness, then takes the nearest palette colour darker than the original.
+fn darken(colour: &Rgb<u8>, amount: f32, palette: &[Rgb<u8>], darkest: Rgb<u8>) -> Rgb<u8> {
+    let lab = rgb_to_oklab(colour);
+    let target = [lab[0] * (1f32 - amount.clamp(0f32, 1f32)), lab[1], lab[2]];
+    if palette.is_empty() {
+        return oklab_to_rgb(target);
+    }
+
+    return palette.iter()
+        .map(|c| (c, rgb_to_oklab(c)))
+        .filter(|(_, c)| c[0] < lab[0])
+        .min_by(|(_, a), (_, b)| oklab_distance(*a, target).total_cmp(&oklab_distance(*b, target)))
+        .map(|(c, _)| *c)
+        .unwrap_or(darkest);
+}
+
+// Gradient of every channel with the Sobel kernels, normalised so a step of d gives a magnitude of d.
+// Borders repeat the edge pixels.
+fn gradients(signal: &[Vec<f32>], width: u32, height: u32) -> Vec<Vec<(f32, f32)>> {
+    let (width, height) = (width as i64, height as i64);
+    let at = |x: i64, y: i64, c: usize| signal[(y.clamp(0, height - 1) * width + x.clamp(0, width - 1)) as usize][c];
+    let channels = signal.first().map(|s| s.len()).unwrap_or(0);
+
+    let mut result = Vec::with_capacity((width * height) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            result.push((0..channels).map(|c| {
+                let gx = at(x + 1, y - 1, c) + 2f32 * at(x + 1, y, c) + at(x + 1, y + 1, c)
+                    - at(x - 1, y - 1, c) - 2f32 * at(x - 1, y, c) - at(x - 1, y + 1, c);
+                let gy = at(x - 1, y + 1, c) + 2f32 * at(x, y + 1, c) + at(x + 1, y + 1, c)
+                    - at(x - 1, y - 1, c) - 2f32 * at(x, y - 1, c) - at(x + 1, y - 1, c);
+                (gx / 4f32, gy / 4f32)
+            }).collect());
+        }
+    }
+    return result;
+}
+
+fn magnitude(gradient: &[(f32, f32)]) -> f32 {
+    gradient.iter().map(|(gx, gy)| gx * gx + gy * gy).sum::<f32>().sqrt()
+}
+
+// Edge pixels that are darker than a neighbour across the edge, so each edge is drawn once.
+fn sobel_edges(signal: &[Vec<f32>], lightness: &[f32], width: u32, height: u32, threshold: f32) -> Vec<bool> {
+    let gradients = gradients(signal, width, height);
+    let (w, h) = (width as i64, height as i64);
+    return (0..(w * h))
+        .map(|i| {
+            let (x, y) = (i % w, i / w);
+            magnitude(&gradients[i as usize]) >= threshold
+                && [(0, -1), (-1, 0), (0, 1), (1, 0)].iter().any(|(dx, dy)| {
+                    let (nx, ny) = (x + dx, y + dy);
+                    nx >= 0 && ny >= 0 && nx < w && ny < h && lightness[(ny * w + nx) as usize] > lightness[i as usize] + 1f32
+                })
+        })
+        .collect();
+}
+
+fn canny_edges(signal: &[Vec<f32>], width: u32, height: u32, low: f32, high: f32) -> Vec<bool> {
+    let (w, h) = (width as i64, height as i64);
+    let at = |x: i64, y: i64| (y.clamp(0, h - 1) * w + x.clamp(0, w - 1)) as usize;
+
+    // 3x3 binomial blur.
+    let weights = [(-1, -1, 1f32), (0, -1, 2f32), (1, -1, 1f32), (-1, 0, 2f32), (0, 0, 4f32), (1, 0, 2f32), (-1, 1, 1f32), (0, 1, 2f32), (1, 1, 1f32)];
+    let blurred: Vec<Vec<f32>> = (0..(w * h))
+        .map(|i| {
+            let (x, y) = (i % w, i / w);
+            (0..signal[0].len())
+                .map(|c| weights.iter().map(|(dx, dy, weight)| signal[at(x + dx, y + dy)][c] * weight).sum::<f32>() / 16f32)
+                .collect()
+        })
+        .collect();
+
+    let gradients = gradients(&blurred, width, height);
+    let magnitudes: Vec<f32> = gradients.iter().map(|g| magnitude(g)).collect();
+
+    // Keeps local maxima along the gradient of the strongest channel, rounded to one of four directions.
+    let mut strength = vec![0u8; (w * h) as usize];
+    for i in 0..(w * h) {
+        let (x, y) = (i % w, i / w);
+        let m = magnitudes[i as usize];
+        if m < low {
+            continue;
+        }
+        let (gx, gy) = gradients[i as usize].iter().copied().max_by(|a, b| (a.0.hypot(a.1)).total_cmp(&b.0.hypot(b.1))).unwrap();
+        let angle = gy.atan2(gx).to_degrees().rem_euclid(180f32);
+        let (dx, dy) = match angle {
+            a if !(22.5..157.5).contains(&a) => (1, 0),
+            a if a < 67.5 => (1, 1),
+            a if a < 112.5 => (0, 1),
+            _ => (-1, 1),
+        };
+        let inside = |x: i64, y: i64| x >= 0 && y >= 0 && x < w && y < h;
+        let before = if inside(x - dx, y - dy) { magnitudes[at(x - dx, y - dy)] } else { 0f32 };
+        let after = if inside(x + dx, y + dy) { magnitudes[at(x + dx, y + dy)] } else { 0f32 };
+        if m >= before && m > after {
+            strength[i as usize] = if m >= high { 2 } else { 1 };
+        }
+    }
+
+    // Hysteresis, weak pixels survive when connected to a strong one.
+    let mut edges = vec![false; (w * h) as usize];
+    let mut stack: Vec<i64> = (0..(w * h)).filter(|i| strength[*i as usize] == 2).collect();
+    while let Some(i) = stack.pop() {
+        if edges[i as usize] {
+            continue;
+        }
+        edges[i as usize] = true;
+        let (x, y) = (i % w, i / w);
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                let (nx, ny) = (x + dx, y + dy);
+                if nx >= 0 && ny >= 0 && nx < w && ny < h && strength[(ny * w + nx) as usize] > 0 && !edges[(ny * w + nx) as usize] {
+                    stack.push(ny * w + nx);
+                }
+            }
+        }
+    }
+    return edges;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 4x4 red square in the middle of an 8x8 image, on transparent pixels of the given colour.
+    fn square(hidden: Rgb<u8>) -> DynamicImage {
+        return DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 8, |x, y| match (2..6).contains(&x) && (2..6).contains(&y) {
+            true => Rgba([200, 30, 30, 255]),
+            false => Rgba([hidden[0], hidden[1], hidden[2], 0]),
+        }));
+    }
+
+    fn opaque(image: &DynamicImage) -> usize {
+        return image.to_rgba8().pixels().filter(|p| p[3] == 255).count();
+    }
+
+    #[test]
+    fn outer_outline_ignores_the_colour_under_transparent_pixels() {
+        let palette = Palette { name: "test.hex".to_string(), colours: vec![Rgb([0, 0, 0]), Rgb([200, 30, 30])] };
+        let options = OutlineOptions { mode: OutlineMode::OUTER, ..OutlineOptions::default() };
+        for hidden in [Rgb([0, 0, 0]), Rgb([255, 255, 255])] {
+            // The square and a ring of 16 pixels around it, without the corners.
+            assert_eq!(opaque(&outline_image(&square(hidden), &palette, &options)), 32);
+        }
+    }
+
+    #[test]
+    fn inner_outline_stays_inside_the_silhouette() {
+        let palette = Palette { name: "test.hex".to_string(), colours: vec![Rgb([0, 0, 0]), Rgb([200, 30, 30])] };
+        for hidden in [Rgb([0, 0, 0]), Rgb([255, 255, 255])] {
+            let output = outline_image(&square(hidden), &palette, &OutlineOptions::default()).to_rgba8();
+            assert_eq!(output.pixels().filter(|p| p[3] == 255).count(), 16);
+            // The border of the square is outlined and its middle is left alone.
+            assert_eq!(output.get_pixel(2, 2), &Rgba([0, 0, 0, 255]));
+            assert_eq!(output.get_pixel(3, 3), &Rgba([200, 30, 30, 255]));
+        }
+    }
+}
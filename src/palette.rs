@@ -1,13 +1,25 @@
-use std::fs::{File, read_dir, read_to_string};
-use std::io::Read;
+use std::collections::HashMap;
+use std::fs::{create_dir_all, File, read_to_string};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
-use image::Rgb;
+use image::{DynamicImage, Rgb};
 
-use crate::colour::{select_average, select_kmeans, select_median, select_randomly, SelectionStrategy};
+use crate::colour::{euclidean_distance, oklab_distance, oklab_to_oklch, relative_luminance, rgb_to_oklab, select_colours, DistanceFunction, SelectionStrategy};
 use crate::image::Image;
-use crate::utils::{generate_raw_palette, hex_to_rgb, rgb_to_hex};
+use crate::library::PaletteLibrary;
+use crate::builtin::builtin_palette;
+use crate::search_path::{PaletteLocation, PaletteSearchPath};
+use crate::utils::{find_closest_index, generate_raw_palette, hex_to_rgb, rgb_to_hex};
+
+#[derive(Copy, Clone)]
+pub enum PaletteOrder {
+    LUMINANCE,
+    HUE,
+    LIGHTNESS,
+    CHROMA,
+    CHAIN,
+}
 
 #[derive(Clone)]
 pub struct Palette {
@@ -16,12 +28,34 @@ pub struct Palette {
 }
 
 impl Palette {
-    pub fn new(filename: &str) -> Palette {
-        let colors = load_palette(filename);
-        return Palette {
-            name: filename.to_string(),
-            colours: colors,
-        };
+    // Looks the name up on the palette search path, unknown and ambiguous names are errors.
+    pub fn new(filename: &str) -> Result<Palette, String> {
+        let location = PaletteSearchPath::new().resolve(filename)?;
+        return Palette::from_location(&location);
+    }
+
+    pub fn from_file(path: &Path) -> Result<Palette, String> {
+        let name = path.file_name().unwrap().to_str().unwrap().to_string();
+        let contents = read_to_string(path).map_err(|err| format!("Unable to read palette {}: {}", path.display(), err))?;
+        return Ok(Palette {
+            colours: parse_palette(&contents).map_err(|err| format!("Palette {} is invalid: {}", path.display(), err))?,
+            name,
+        });
+    }
+
+    pub fn builtin(name: &str) -> Option<Palette> {
+        let (builtin, contents) = builtin_palette(name)?;
+        return Some(Palette {
+            name: format!("{}.hex", builtin),
+            colours: parse_palette(contents).expect("ERROR: INVALID BUILT-IN PALETTE"),
+        });
+    }
+
+    pub fn from_location(location: &PaletteLocation) -> Result<Palette, String> {
+        match location {
+            PaletteLocation::File(path) => Palette::from_file(path),
+            PaletteLocation::Builtin(name) => Palette::builtin(name).ok_or(format!("No built-in palette named {}", name)),
+        }
     }
 
     pub fn generate_palette(imagefilepath: &str, palettename: String, numcolours: usize, selection_strategy: SelectionStrategy) -> Palette {
@@ -29,12 +63,7 @@ impl Palette {
         let raw_pal = generate_raw_palette(&img.data);
         let raw_vec: Vec<Rgb<u8>> = raw_pal.into_iter().collect();
 
-        let gen_pal = match selection_strategy {
-            SelectionStrategy::Average => select_average(&raw_vec, numcolours),
-            SelectionStrategy::Random => select_randomly(&raw_vec, numcolours),
-            SelectionStrategy::KMeans => select_kmeans(&raw_vec, numcolours),
-            SelectionStrategy::Median => select_median(&raw_vec, numcolours)
-        };
+        let gen_pal = select_colours(&raw_vec, numcolours, selection_strategy);
 
         return Palette {
             name: palettename,
@@ -43,8 +72,12 @@ impl Palette {
     }
 
     pub fn save_palette(&self, filepath: Option<&str>) {
-        let pathstr = filepath.unwrap_or_else(|| "./palettes");
-        let path = Path::new(pathstr).join(&self.name);
+        let dir = match filepath {
+            Some(pathstr) => PathBuf::from(pathstr),
+            None => PaletteSearchPath::new().save_dir(),
+        };
+        create_dir_all(&dir).expect("ERROR: COULD NOT CREATE PALETTE DIRECTORY.");
+        let path = dir.join(&self.name);
         let mut file = File::create(path).expect("ERROR: COULD NOT CREATE PALETTE FILE.");
 
         for colour in &self.colours {
@@ -54,30 +87,340 @@ impl Palette {
         println!("INFO: Palette Saved Successfully.");
     }
 
+    // The order is kept by save_palette, so sorting before saving produces an ordered file.
+    pub fn sort(&mut self, order: PaletteOrder) {
+        match order {
+            PaletteOrder::LUMINANCE => sort_by_f32_key(&mut self.colours, relative_luminance),
+            PaletteOrder::LIGHTNESS => sort_by_f32_key(&mut self.colours, |c| rgb_to_oklab(c)[0]),
+            PaletteOrder::CHROMA => sort_by_f32_key(&mut self.colours, |c| oklab_to_oklch(rgb_to_oklab(c))[1]),
+            PaletteOrder::HUE => sort_by_hue(&mut self.colours),
+            PaletteOrder::CHAIN => self.colours = nearest_neighbour_chain(&self.colours),
+        }
+    }
+
+    // Most used colour first, pixels are counted against their closest palette colour.
+    pub fn sort_by_usage(&mut self, image: &DynamicImage) {
+        let usage = colour_usage(&self.colours, image);
+        let mut indexed: Vec<(u64, Rgb<u8>)> = usage.into_iter().zip(self.colours.iter().copied()).collect();
+        indexed.sort_by_key(|(count, _)| std::cmp::Reverse(*count));
+        self.colours = indexed.into_iter().map(|(_, colour)| colour).collect();
+    }
+
+    // The set operations return every colour once, in the order it first appears in self and then other.
+    pub fn union(&self, other: &Palette) -> Palette {
+        let mut colours = self.colours.clone();
+        colours.extend_from_slice(&other.colours);
+
+        return Palette {
+            name: self.name.clone(),
+            colours: unique_colours(&colours),
+        };
+    }
+
+    pub fn intersection(&self, other: &Palette) -> Palette {
+        let mut result = self.clone();
+        result.colours.retain(|colour| other.colours.contains(colour));
+        result.colours = unique_colours(&result.colours);
+        return result;
+    }
+
+    pub fn difference(&self, other: &Palette) -> Palette {
+        let mut result = self.clone();
+        result.colours.retain(|colour| !other.colours.contains(colour));
+        result.colours = unique_colours(&result.colours);
+        return result;
+    }
+
+    // Keeps the first colour of every group closer than tolerance, so the original order is preserved.
+    pub fn dedupe(&mut self, tolerance: f32, distance: DistanceFunction) {
+        let mut kept: Vec<Rgb<u8>> = Vec::with_capacity(self.colours.len());
+        for colour in &self.colours {
+            if !kept.iter().any(|k| distance.distance(k, colour) <= tolerance) {
+                kept.push(*colour);
+            }
+        }
+        self.colours = kept;
+    }
+
+    // Re-quantizes the palette's own colours down to num_colours. The result always holds exactly
+    // min(num_colours, unique colours) colours. The strategies can return a few more or fewer than asked
+    // for, so entries no palette colour maps to are dropped, the closest of any remaining pair goes while
+    // there are too many and the palette colours farthest from those chosen make up any shortfall.
+    pub fn reduce(&self, num_colours: usize, selection_strategy: SelectionStrategy) -> Palette {
+        let unique = unique_colours(&self.colours);
+        if num_colours >= unique.len() || num_colours == 0 {
+            return Palette {
+                name: self.name.clone(),
+                colours: if num_colours == 0 { Vec::new() } else { unique },
+            };
+        }
+
+        let selected = unique_colours(&select_colours(&unique, num_colours, selection_strategy));
+        let used: Vec<usize> = unique.iter().map(|colour| find_closest_index(colour, &selected)).collect();
+        let mut colours: Vec<Rgb<u8>> = selected.iter()
+            .enumerate()
+            .filter(|(i, _)| used.contains(i))
+            .map(|(_, colour)| *colour)
+            .collect();
+
+        let nearest = |colour: &Rgb<u8>, others: &[Rgb<u8>]| {
+            others.iter().filter(|k| *k != colour).map(|k| euclidean_distance(k, colour)).fold(f32::MAX, f32::min)
+        };
+        while colours.len() > num_colours {
+            let closest = (0..colours.len())
+                .min_by(|a, b| nearest(&colours[*a], &colours).total_cmp(&nearest(&colours[*b], &colours)))
+                .unwrap();
+            colours.remove(closest);
+        }
+        while colours.len() < num_colours {
+            let farthest = unique.iter()
+                .filter(|colour| !colours.contains(colour))
+                .max_by(|a, b| nearest(a, &colours).total_cmp(&nearest(b, &colours)))
+                .copied()
+                .unwrap();
+            colours.push(farthest);
+        }
+
+        return Palette {
+            name: self.name.clone(),
+            colours,
+        };
+    }
+
     pub fn list_palettes() {
-        let palette_dir = Path::new("./palettes");
-        let files = read_dir(palette_dir).unwrap();
-        for pals in files {
-            println!("{:?}", pals.unwrap().file_name())
+        let library = PaletteLibrary::from_search_path(&PaletteSearchPath::new());
+        for name in library.names() {
+            println!("{}", name)
         }
     }
 }
 
-fn load_palette(path: &str) -> Vec<Rgb<u8>> {
-    let file_path = if Path::new(path).is_absolute() {
-        PathBuf::from(path)
-    } else {
-        Path::new("./palettes").join(path)
-    };
+fn parse_palette(contents: &str) -> Result<Vec<Rgb<u8>>, String> {
     let mut palette = Vec::new();
 
-    for colour in read_to_string(file_path).unwrap().lines() {
-        let rgb = hex_to_rgb(colour).unwrap();
+    for colour in contents.lines().filter(|line| !line.trim().is_empty()) {
+        let rgb = hex_to_rgb(colour.trim()).map_err(|err| format!("{}: {}", colour, err))?;
         palette.push(rgb)
     }
-    return palette;
+    return Ok(palette);
+}
+
+fn unique_colours(colours: &[Rgb<u8>]) -> Vec<Rgb<u8>> {
+    let mut unique = Vec::with_capacity(colours.len());
+    for colour in colours {
+        if !unique.contains(colour) {
+            unique.push(*colour);
+        }
+    }
+    return unique;
+}
+
+fn sort_by_f32_key<F: Fn(&Rgb<u8>) -> f32>(colours: &mut [Rgb<u8>], key: F) {
+    colours.sort_by(|a, b| key(a).total_cmp(&key(b)));
 }
 
+// Greys have no meaningful hue so they are kept together at the start, ordered by lightness.
+fn sort_by_hue(colours: &mut [Rgb<u8>]) {
+    const ACHROMATIC_CHROMA: f32 = 0.02;
 
+    colours.sort_by(|a, b| {
+        let lch_a = oklab_to_oklch(rgb_to_oklab(a));
+        let lch_b = oklab_to_oklch(rgb_to_oklab(b));
+        let grey_a = lch_a[1] < ACHROMATIC_CHROMA;
+        let grey_b = lch_b[1] < ACHROMATIC_CHROMA;
 
+        grey_b.cmp(&grey_a)
+            .then_with(|| if grey_a { lch_a[0].total_cmp(&lch_b[0]) } else { lch_a[2].total_cmp(&lch_b[2]) })
+            .then_with(|| lch_a[0].total_cmp(&lch_b[0]))
+    });
+}
+
+// Greedy nearest neighbour path from the darkest colour, then untangled with 2-opt so the strip reads smoothly.
+fn nearest_neighbour_chain(colours: &[Rgb<u8>]) -> Vec<Rgb<u8>> {
+    if colours.len() < 3 {
+        let mut chain = colours.to_vec();
+        sort_by_f32_key(&mut chain, |c| rgb_to_oklab(c)[0]);
+        return chain;
+    }
 
+    let labs: Vec<[f32; 3]> = colours.iter().map(rgb_to_oklab).collect();
+    let start = (0..labs.len()).min_by(|a, b| labs[*a][0].total_cmp(&labs[*b][0])).unwrap();
+
+    let mut visited = vec![false; labs.len()];
+    let mut path = vec![start];
+    visited[start] = true;
+
+    while path.len() < labs.len() {
+        let last = labs[*path.last().unwrap()];
+        let next = (0..labs.len())
+            .filter(|i| !visited[*i])
+            .min_by(|a, b| oklab_distance(last, labs[*a]).total_cmp(&oklab_distance(last, labs[*b])))
+            .unwrap();
+        visited[next] = true;
+        path.push(next);
+    }
+
+    let mut improved = true;
+    while improved {
+        improved = false;
+        for i in 0..path.len() - 2 {
+            for j in i + 2..path.len() {
+                let before = oklab_distance(labs[path[i]], labs[path[i + 1]])
+                    + if j + 1 < path.len() { oklab_distance(labs[path[j]], labs[path[j + 1]]) } else { 0f32 };
+                let after = oklab_distance(labs[path[i]], labs[path[j]])
+                    + if j + 1 < path.len() { oklab_distance(labs[path[i + 1]], labs[path[j + 1]]) } else { 0f32 };
+
+                if after + 1e-6 < before {
+                    path[i + 1..=j].reverse();
+                    improved = true;
+                }
+            }
+        }
+    }
+
+    return path.into_iter().map(|i| colours[i]).collect();
+}
+
+fn colour_usage(colours: &[Rgb<u8>], image: &DynamicImage) -> Vec<u64> {
+    // There is nothing to count against, and find_closest_index would point past the end.
+    if colours.is_empty() {
+        return Vec::new();
+    }
+    let mut counts: HashMap<Rgb<u8>, u64> = HashMap::new();
+    for pixel in image.to_rgb8().pixels() {
+        *counts.entry(*pixel).or_insert(0) += 1;
+    }
+
+    let mut usage = vec![0u64; colours.len()];
+    for (colour, count) in counts {
+        usage[find_closest_index(&colour, colours)] += count;
+    }
+    return usage;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn palette(colours: Vec<Rgb<u8>>) -> Palette {
+        return Palette {
+            name: "test.hex".to_string(),
+            colours,
+        };
+    }
+
+    // 64 distinct colours spread over the whole cube.
+    fn spread() -> Palette {
+        return palette((0..64u8).map(|i| Rgb([(i % 4) * 85, (i / 4 % 4) * 85, (i / 16) * 85])).collect());
+    }
+
+    const STRATEGIES: [SelectionStrategy; 4] = [
+        SelectionStrategy::Random,
+        SelectionStrategy::Average,
+        SelectionStrategy::KMeans,
+        SelectionStrategy::Median,
+    ];
+
+    #[test]
+    fn reduce_returns_exactly_the_requested_number_of_colours() {
+        let source = spread();
+        for strategy in STRATEGIES {
+            for num_colours in [0, 1, 3, 20, 33, 63, 64, 100] {
+                let reduced = source.reduce(num_colours, strategy);
+                assert_eq!(reduced.colours.len(), num_colours.min(64));
+                assert_eq!(unique_colours(&reduced.colours).len(), reduced.colours.len());
+            }
+        }
+    }
+
+    #[test]
+    fn reduce_counts_duplicates_once() {
+        let mut colours = spread().colours;
+        colours.extend(spread().colours);
+        let source = palette(colours);
+        for strategy in STRATEGIES {
+            assert_eq!(source.reduce(100, strategy).colours.len(), 64);
+            assert_eq!(source.reduce(20, strategy).colours.len(), 20);
+        }
+    }
+
+    #[test]
+    fn reduce_never_invents_black() {
+        let source = palette(vec![Rgb([250, 250, 250]), Rgb([240, 240, 240]), Rgb([200, 0, 0]), Rgb([0, 0, 200])]);
+        for _ in 0..20 {
+            assert!(!source.reduce(3, SelectionStrategy::KMeans).colours.contains(&Rgb([0, 0, 0])));
+        }
+    }
+
+    #[test]
+    fn set_operations_return_each_colour_once() {
+        let (red, green, blue) = (Rgb([255, 0, 0]), Rgb([0, 255, 0]), Rgb([0, 0, 255]));
+        let a = palette(vec![red, red, green, blue, green]);
+        let b = palette(vec![blue, blue, red]);
+
+        assert_eq!(a.union(&b).colours, vec![red, green, blue]);
+        assert_eq!(a.intersection(&b).colours, vec![red, blue]);
+        assert_eq!(a.difference(&b).colours, vec![green]);
+        assert_eq!(b.union(&a).colours, vec![blue, red, green]);
+    }
+
+    #[test]
+    fn unknown_palettes_and_bad_colours_are_errors() {
+        assert!(matches!(Palette::new("no-such-palette-anywhere"), Err(err) if err.contains("not found")));
+        assert_eq!(Palette::new("nyx8").unwrap().colours.len(), 8);
+        assert!(parse_palette("FF0000\nnot-a-colour\n").unwrap_err().contains("not-a-colour"));
+        assert_eq!(parse_palette("FF0000\n\n 00FF00 \n").unwrap(), vec![Rgb([255, 0, 0]), Rgb([0, 255, 0])]);
+    }
+
+    fn sorted(colours: &[Rgb<u8>], order: PaletteOrder) -> Vec<Rgb<u8>> {
+        let mut palette = palette(colours.to_vec());
+        palette.sort(order);
+        return palette.colours;
+    }
+
+    const BLACK: Rgb<u8> = Rgb([0, 0, 0]);
+    const GREY: Rgb<u8> = Rgb([128, 128, 128]);
+    const WHITE: Rgb<u8> = Rgb([255, 255, 255]);
+    const RED: Rgb<u8> = Rgb([255, 0, 0]);
+    const YELLOW: Rgb<u8> = Rgb([255, 255, 0]);
+    const GREEN: Rgb<u8> = Rgb([0, 255, 0]);
+    const BLUE: Rgb<u8> = Rgb([0, 0, 255]);
+
+    #[test]
+    fn sort_orders_by_each_key() {
+        assert_eq!(sorted(&[WHITE, GREEN, BLACK, RED, BLUE], PaletteOrder::LUMINANCE), vec![BLACK, BLUE, RED, GREEN, WHITE]);
+        assert_eq!(sorted(&[WHITE, BLACK, GREY], PaletteOrder::LIGHTNESS), vec![BLACK, GREY, WHITE]);
+        assert_eq!(sorted(&[RED, GREY, Rgb([200, 150, 150])], PaletteOrder::CHROMA), vec![GREY, Rgb([200, 150, 150]), RED]);
+        // Greys lead in lightness order, then hue angle from red round to blue.
+        assert_eq!(
+            sorted(&[BLUE, WHITE, GREEN, BLACK, YELLOW, GREY, RED], PaletteOrder::HUE),
+            vec![BLACK, GREY, WHITE, RED, YELLOW, GREEN, BLUE]
+        );
+    }
+
+    #[test]
+    fn chain_walks_from_the_darkest_colour_to_its_neighbours() {
+        let ramp: Vec<Rgb<u8>> = [0u8, 64, 128, 192, 255].iter().map(|v| Rgb([*v, *v, *v])).collect();
+        let shuffled = [ramp[2], ramp[4], ramp[0], ramp[3], ramp[1]];
+        assert_eq!(sorted(&shuffled, PaletteOrder::CHAIN), ramp);
+        assert_eq!(sorted(&[WHITE, BLACK], PaletteOrder::CHAIN), vec![BLACK, WHITE]);
+        assert_eq!(sorted(&[], PaletteOrder::CHAIN), Vec::<Rgb<u8>>::new());
+    }
+
+    #[test]
+    fn usage_puts_the_most_used_colour_first_and_keeps_ties_in_order() {
+        let image = |pixels: &[Rgb<u8>]| DynamicImage::ImageRgb8(image::RgbImage::from_fn(pixels.len() as u32, 1, |x, _| pixels[x as usize]));
+
+        let mut used = palette(vec![GREEN, BLUE, RED]);
+        used.sort_by_usage(&image(&[RED, Rgb([200, 10, 10]), RED, BLUE]));
+        assert_eq!(used.colours, vec![RED, BLUE, GREEN]);
+
+        let mut tied = palette(vec![GREEN, RED, BLUE]);
+        tied.sort_by_usage(&image(&[BLUE, RED, BLUE, RED]));
+        assert_eq!(tied.colours, vec![RED, BLUE, GREEN]);
+
+        let mut empty = palette(Vec::new());
+        empty.sort_by_usage(&image(&[RED]));
+        assert!(empty.colours.is_empty());
+    }
+}
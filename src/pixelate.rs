@@ -0,0 +1,483 @@
+use std::collections::HashMap;
+
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+
+// Blocks whose luminance range is below this have no outline worth keeping.
+const EDGE_CONTRAST: f32 = 48f32;
+const KMEANS_ITERATIONS: usize = 8;
+
+#[derive(Copy, Clone)]
+pub enum BlockReduction {
+    // Resample each block to one pixel with the filter, the old pixelate behaviour.
+    FILTER(FilterType),
+    // Most common colour in the block.
+    MODE,
+    // Median of each channel on its own.
+    MEDIAN,
+    // Centre of the largest of k clusters, so two regions meeting in a block are not blended.
+    KMEANS(usize),
+    // Darkest colours when they form a line through a high contrast block, otherwise the mode. Keeps
+    // thin outlines that the other modes would average or vote away.
+    EDGE,
+}
+
+impl BlockReduction {
+    pub fn new(name: &str) -> Result<BlockReduction, &'static str> {
+        let reduction = match name.to_lowercase().as_str() {
+            "nearest" => BlockReduction::FILTER(FilterType::Nearest),
+            "triangle" => BlockReduction::FILTER(FilterType::Triangle),
+            "lanczos" => BlockReduction::FILTER(FilterType::Lanczos3),
+            "mode" => BlockReduction::MODE,
+            "median" => BlockReduction::MEDIAN,
+            "kmeans" => BlockReduction::KMEANS(2),
+            "edge" => BlockReduction::EDGE,
+
+            _ => return Err("Unknown block reduction")
+        };
+
+        return Ok(reduction);
+    }
+
+    pub fn to_string(reduction: &BlockReduction) -> String {
+        let name = match reduction {
+            BlockReduction::FILTER(filter) => format!("{:?}", filter).to_lowercase(),
+            BlockReduction::MODE => "mode".to_string(),
+            BlockReduction::MEDIAN => "median".to_string(),
+            BlockReduction::KMEANS(k) => format!("kmeans k={}", k),
+            BlockReduction::EDGE => "edge".to_string(),
+        };
+
+        return name;
+    }
+}
+
+#[derive(Copy, Clone)]
+pub enum BlockSize {
+    // Block width and height in pixels, fractional sizes alternate between the neighbouring whole sizes.
+    SCALE(f32, f32),
+    // Number of block columns and rows, spread evenly over the image.
+    TARGET(u32, u32),
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum GridAnchor {
+    // Grid starts at the top left corner, partial blocks end up at the right and bottom.
+    TOPLEFT,
+    // Partial blocks are split evenly between both sides.
+    CENTRE,
+    // Grid ends at the bottom right corner, partial blocks end up at the left and top.
+    BOTTOMRIGHT,
+}
+
+impl GridAnchor {
+    pub fn new(name: &str) -> Result<GridAnchor, &'static str> {
+        let anchor = match name.to_lowercase().as_str() {
+            "topleft" => GridAnchor::TOPLEFT,
+            "centre" | "center" => GridAnchor::CENTRE,
+            "bottomright" => GridAnchor::BOTTOMRIGHT,
+
+            _ => return Err("Unknown grid anchor")
+        };
+
+        return Ok(anchor);
+    }
+}
+
+// What happens to blocks the image edges cut short.
+#[derive(Copy, Clone, PartialEq)]
+pub enum EdgeBlocks {
+    // Kept at the size that fits, the output has the input size.
+    PARTIAL,
+    // Grown to whole blocks, the output grows with them.
+    PAD,
+    // Dropped, unless no whole block would be left.
+    CROP,
+}
+
+impl EdgeBlocks {
+    pub fn new(name: &str) -> Result<EdgeBlocks, &'static str> {
+        let edges = match name.to_lowercase().as_str() {
+            "partial" => EdgeBlocks::PARTIAL,
+            "pad" => EdgeBlocks::PAD,
+            "crop" => EdgeBlocks::CROP,
+
+            _ => return Err("Unknown edge handling")
+        };
+
+        return Ok(edges);
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct PixelateOptions {
+    pub block: BlockSize,
+    pub reduction: BlockReduction,
+    pub anchor: GridAnchor,
+    // Grid shift in pixels, applied after the anchor.
+    pub offset: (i32, i32),
+    pub edges: EdgeBlocks,
+    // One pixel per block instead of blocks at full size, the size sprite assets are drawn at.
+    pub low_resolution: bool,
+}
+
+impl Default for PixelateOptions {
+    fn default() -> Self {
+        PixelateOptions {
+            block: BlockSize::SCALE(4f32, 4f32),
+            reduction: BlockReduction::MODE,
+            anchor: GridAnchor::TOPLEFT,
+            offset: (0, 0),
+            edges: EdgeBlocks::PARTIAL,
+            low_resolution: false,
+        }
+    }
+}
+
+// Blocks along one axis: the source pixels each block covers and where it lands in the output.
+struct AxisGrid {
+    source: Vec<(u32, u32)>,
+    output: Vec<(u32, u32)>,
+}
+
+// Reduces every block to one colour and fills the block with it. Never fails, an empty image comes
+// back unchanged and a grid without whole blocks still has its partial ones.
+pub fn pixelate_blocks(img: &DynamicImage, options: &PixelateOptions) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 {
+        return img.clone();
+    }
+
+    let (step_x, step_y) = match options.block {
+        BlockSize::SCALE(block_width, block_height) => (block_width, block_height),
+        BlockSize::TARGET(columns, rows) => (width as f32 / columns.clamp(1, width) as f32, height as f32 / rows.clamp(1, height) as f32),
+    };
+    let columns = axis_grid(width, step_x, options.offset.0, options);
+    let rows = axis_grid(height, step_y, options.offset.1, options);
+
+    let pixels = img.to_rgba8();
+    let (output_width, output_height) = (columns.output.last().unwrap().1, rows.output.last().unwrap().1);
+    let mut output = RgbaImage::new(output_width, output_height);
+    for (source_y, output_y) in rows.source.iter().zip(&rows.output) {
+        for (source_x, output_x) in columns.source.iter().zip(&columns.output) {
+            let block = pixels.view(source_x.0, source_y.0, source_x.1 - source_x.0, source_y.1 - source_y.0).to_image();
+            let longest_side = block.width().max(block.height());
+            let colour = reduce_block(&block, longest_side, options.reduction);
+            for y in output_y.0..output_y.1 {
+                for x in output_x.0..output_x.1 {
+                    output.put_pixel(x, y, colour);
+                }
+            }
+        }
+    }
+
+    return match img.color().has_alpha() {
+        true => DynamicImage::ImageRgba8(output),
+        false => DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(output).to_rgb8()),
+    };
+}
+
+fn axis_grid(length: u32, step: f32, offset: i32, options: &PixelateOptions) -> AxisGrid {
+    let step = if step.is_finite() { step.clamp(1f32, length as f32) } else { length as f32 };
+    let whole_blocks = (length as f32 / step).floor();
+    let leftover = length as f32 - whole_blocks * step;
+    let anchored = match options.anchor {
+        GridAnchor::TOPLEFT => 0f32,
+        GridAnchor::CENTRE => leftover / 2f32,
+        GridAnchor::BOTTOMRIGHT => leftover,
+    };
+    let origin = (anchored + offset as f32).rem_euclid(step);
+
+    // Grid lines before the origin belong to the partial block at the start.
+    let mut lines = vec![0f32];
+    let mut line = origin;
+    while line < length as f32 {
+        if line > 0f32 {
+            lines.push(line);
+        }
+        line += step;
+    }
+    lines.push(length as f32);
+
+    let mut source: Vec<(u32, u32)> = Vec::new();
+    let mut whole: Vec<bool> = Vec::new();
+    for pair in lines.windows(2) {
+        let (start, end) = (pair[0].round() as u32, pair[1].round() as u32);
+        if end > start {
+            source.push((start, end));
+            whole.push(pair[1] - pair[0] >= step - 0.5);
+        }
+    }
+
+    if options.edges == EdgeBlocks::CROP && whole.iter().any(|w| *w) {
+        source = source.into_iter().zip(&whole).filter(|(_, w)| **w).map(|(block, _)| block).collect();
+    }
+
+    let mut output = Vec::with_capacity(source.len());
+    let mut position = 0u32;
+    let mut exact = 0f32;
+    for block in &source {
+        let size = match (options.low_resolution, options.edges) {
+            (true, _) => 1,
+            // Padded sizes follow the running total, so fractional steps keep their average.
+            (false, EdgeBlocks::PAD) => {
+                exact += step;
+                exact.round() as u32 - position
+            },
+            (false, _) => block.1 - block.0,
+        };
+        output.push((position, position + size));
+        position += size;
+    }
+
+    return AxisGrid { source, output };
+}
+
+fn reduce_block(block: &RgbaImage, longest_side: u32, reduction: BlockReduction) -> Rgba<u8> {
+    let pixels: Vec<Rgba<u8>> = block.pixels().copied().collect();
+    return match reduction {
+        BlockReduction::FILTER(filter) => *DynamicImage::ImageRgba8(block.clone()).resize_exact(1, 1, filter).to_rgba8().get_pixel(0, 0),
+        BlockReduction::MODE => mode_colour(&pixels),
+        BlockReduction::MEDIAN => median_colour(&pixels),
+        BlockReduction::KMEANS(k) => kmeans_colour(&pixels, k),
+        BlockReduction::EDGE => edge_colour(&pixels, longest_side),
+    };
+}
+
+// Ties go to the colour seen first, so the result does not depend on hash order.
+fn mode_colour(block: &[Rgba<u8>]) -> Rgba<u8> {
+    let mut counts: HashMap<Rgba<u8>, (usize, usize)> = HashMap::new();
+    for (i, pixel) in block.iter().enumerate() {
+        counts.entry(*pixel).or_insert((0, i)).0 += 1;
+    }
+    return counts.into_iter()
+        .max_by(|(_, (count_a, first_a)), (_, (count_b, first_b))| count_a.cmp(count_b).then(first_b.cmp(first_a)))
+        .map(|(colour, _)| colour)
+        .unwrap_or(Rgba([0, 0, 0, 0]));
+}
+
+fn median_colour(block: &[Rgba<u8>]) -> Rgba<u8> {
+    let mut median = [0u8; 4];
+    for (channel, value) in median.iter_mut().enumerate() {
+        let mut values: Vec<u8> = block.iter().map(|p| p[channel]).collect();
+        values.sort_unstable();
+        *value = values[values.len() / 2];
+    }
+    return Rgba(median);
+}
+
+fn kmeans_colour(block: &[Rgba<u8>], k: usize) -> Rgba<u8> {
+    let points: Vec<[f32; 4]> = block.iter().map(|p| p.0.map(|v| v as f32)).collect();
+    let k = k.clamp(1, points.len());
+
+    // Starting centres spread evenly through the pixels sorted by luminance.
+    let mut sorted = points.clone();
+    sorted.sort_by(|a, b| luminance(a).total_cmp(&luminance(b)));
+    let mut centres: Vec<[f32; 4]> = (0..k).map(|i| sorted[i * (sorted.len() - 1) / (k.max(2) - 1)]).collect();
+
+    let mut assignment = vec![0usize; points.len()];
+    for _ in 0..KMEANS_ITERATIONS {
+        for (point, cluster) in points.iter().zip(assignment.iter_mut()) {
+            let distance = |c: &[f32; 4]| (0..4).map(|i| (point[i] - c[i]).powi(2)).sum::<f32>();
+            *cluster = (0..k).min_by(|a, b| distance(&centres[*a]).total_cmp(&distance(&centres[*b]))).unwrap();
+        }
+        for (cluster, centre) in centres.iter_mut().enumerate() {
+            let members: Vec<&[f32; 4]> = points.iter().zip(&assignment).filter(|(_, c)| **c == cluster).map(|(p, _)| p).collect();
+            if !members.is_empty() {
+                *centre = [0, 1, 2, 3].map(|i| members.iter().map(|p| p[i]).sum::<f32>() / members.len() as f32);
+            }
+        }
+    }
+
+    let largest = (0..k).max_by_key(|cluster| assignment.iter().filter(|c| *c == cluster).count()).unwrap();
+    return Rgba(centres[largest].map(|v| v.round().clamp(0f32, 255f32) as u8));
+}
+
+// Dark pixels win when there are at least half a block side of them, about what a one pixel outline
+// crossing the block leaves behind.
+fn edge_colour(block: &[Rgba<u8>], longest_side: u32) -> Rgba<u8> {
+    let opaque: Vec<Rgba<u8>> = block.iter().filter(|p| p[3] > 0).copied().collect();
+    if opaque.is_empty() {
+        return mode_colour(block);
+    }
+
+    let lightness = |p: &Rgba<u8>| luminance(&p.0.map(|v| v as f32));
+    let darkest = opaque.iter().map(lightness).fold(f32::INFINITY, f32::min);
+    let lightest = opaque.iter().map(lightness).fold(f32::NEG_INFINITY, f32::max);
+    if lightest - darkest < EDGE_CONTRAST {
+        return mode_colour(block);
+    }
+
+    let cutoff = darkest + (lightest - darkest) / 4f32;
+    let dark: Vec<Rgba<u8>> = opaque.iter().filter(|p| lightness(p) <= cutoff).copied().collect();
+    if dark.len() * 2 >= longest_side as usize {
+        return mode_colour(&dark);
+    }
+    return mode_colour(block);
+}
+
+fn luminance(colour: &[f32; 4]) -> f32 {
+    0.2126 * colour[0] + 0.7152 * colour[1] + 0.0722 * colour[2]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid(length: u32, step: f32, offset: i32, anchor: GridAnchor, edges: EdgeBlocks) -> AxisGrid {
+        let options = PixelateOptions { anchor, edges, ..PixelateOptions::default() };
+        return axis_grid(length, step, offset, &options);
+    }
+
+    #[test]
+    fn anchor_decides_where_the_partial_blocks_go() {
+        assert_eq!(grid(10, 4f32, 0, GridAnchor::TOPLEFT, EdgeBlocks::PARTIAL).source, vec![(0, 4), (4, 8), (8, 10)]);
+        assert_eq!(grid(10, 4f32, 0, GridAnchor::CENTRE, EdgeBlocks::PARTIAL).source, vec![(0, 1), (1, 5), (5, 9), (9, 10)]);
+        assert_eq!(grid(10, 4f32, 0, GridAnchor::BOTTOMRIGHT, EdgeBlocks::PARTIAL).source, vec![(0, 2), (2, 6), (6, 10)]);
+    }
+
+    #[test]
+    fn offset_wraps_around_the_step() {
+        assert_eq!(grid(10, 4f32, -1, GridAnchor::TOPLEFT, EdgeBlocks::PARTIAL).source, vec![(0, 3), (3, 7), (7, 10)]);
+        assert_eq!(grid(10, 4f32, 5, GridAnchor::TOPLEFT, EdgeBlocks::PARTIAL).source, vec![(0, 1), (1, 5), (5, 9), (9, 10)]);
+    }
+
+    #[test]
+    fn partial_blocks_cover_the_whole_axis() {
+        for step in [1f32, 1.5, 2.5, 3f32, 4.75, 7f32, 100f32] {
+            for offset in [-3, 0, 2] {
+                let axis = grid(23, step, offset, GridAnchor::CENTRE, EdgeBlocks::PARTIAL);
+                assert_eq!(axis.source.first().unwrap().0, 0);
+                assert_eq!(axis.source.last().unwrap().1, 23);
+                assert!(axis.source.windows(2).all(|pair| pair[0].1 == pair[1].0));
+                assert_eq!(axis.output, axis.source);
+            }
+        }
+    }
+
+    #[test]
+    fn crop_and_pad_change_the_edge_blocks() {
+        let cropped = grid(10, 4f32, 0, GridAnchor::CENTRE, EdgeBlocks::CROP);
+        assert_eq!(cropped.source, vec![(1, 5), (5, 9)]);
+        assert_eq!(cropped.output, vec![(0, 4), (4, 8)]);
+
+        let padded = grid(10, 4f32, 0, GridAnchor::TOPLEFT, EdgeBlocks::PAD);
+        assert_eq!(padded.source, vec![(0, 4), (4, 8), (8, 10)]);
+        assert_eq!(padded.output, vec![(0, 4), (4, 8), (8, 12)]);
+    }
+
+    #[test]
+    fn fractional_steps_keep_their_average_size() {
+        let axis = grid(10, 2.5, 0, GridAnchor::TOPLEFT, EdgeBlocks::PARTIAL);
+        assert_eq!(axis.source.len(), 4);
+        assert!(axis.source.iter().all(|(start, end)| end - start == 2 || end - start == 3));
+
+        let padded = grid(9, 2.5, 0, GridAnchor::TOPLEFT, EdgeBlocks::PAD);
+        assert_eq!(padded.output.last().unwrap().1, 10);
+    }
+
+    #[test]
+    fn output_size_follows_the_grid() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::new(10, 7));
+        let size = |options: PixelateOptions| pixelate_blocks(&image, &options).dimensions();
+
+        assert_eq!(size(PixelateOptions::default()), (10, 7));
+        assert_eq!(size(PixelateOptions { edges: EdgeBlocks::PAD, ..PixelateOptions::default() }), (12, 8));
+        assert_eq!(size(PixelateOptions { edges: EdgeBlocks::CROP, ..PixelateOptions::default() }), (8, 4));
+        assert_eq!(size(PixelateOptions { low_resolution: true, ..PixelateOptions::default() }), (3, 2));
+        assert_eq!(size(PixelateOptions { block: BlockSize::TARGET(5, 7), low_resolution: true, ..PixelateOptions::default() }), (5, 7));
+    }
+
+    const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+    const BLACK: Rgba<u8> = Rgba([0, 0, 0, 255]);
+    const RED: Rgba<u8> = Rgba([200, 30, 30, 255]);
+
+    // A 4x4 block from a row major list of pixels.
+    fn block(pixels: &[Rgba<u8>]) -> RgbaImage {
+        return RgbaImage::from_fn(4, 4, |x, y| pixels[(y * 4 + x) as usize]);
+    }
+
+    fn reduce(pixels: &[Rgba<u8>], reduction: BlockReduction) -> Rgba<u8> {
+        return reduce_block(&block(pixels), 4, reduction);
+    }
+
+    #[test]
+    fn mode_picks_the_most_common_colour() {
+        let mut pixels = vec![WHITE; 9];
+        pixels.extend([BLACK; 7]);
+        assert_eq!(reduce(&pixels, BlockReduction::MODE), WHITE);
+
+        // On a tie the colour seen first wins.
+        let mut tied = vec![RED; 8];
+        tied.extend([BLACK; 8]);
+        assert_eq!(reduce(&tied, BlockReduction::MODE), RED);
+        tied.reverse();
+        assert_eq!(reduce(&tied, BlockReduction::MODE), BLACK);
+    }
+
+    #[test]
+    fn median_works_on_each_channel() {
+        let pixels: Vec<Rgba<u8>> = (0..16u8).map(|i| Rgba([i * 10, 150 - i * 10, if i % 2 == 0 { 0 } else { 255 }, 255])).collect();
+        // Each channel sorted on its own, the upper middle of 16 values.
+        assert_eq!(reduce(&pixels, BlockReduction::MEDIAN), Rgba([80, 80, 255, 255]));
+
+        let mut outlier = vec![Rgba([100, 100, 100, 255]); 15];
+        outlier.push(WHITE);
+        assert_eq!(reduce(&outlier, BlockReduction::MEDIAN), Rgba([100, 100, 100, 255]));
+    }
+
+    #[test]
+    fn kmeans_keeps_the_larger_region_unblended() {
+        let mut pixels = vec![RED; 10];
+        pixels.extend([Rgba([20, 20, 220, 255]); 6]);
+        assert_eq!(reduce(&pixels, BlockReduction::KMEANS(2)), RED);
+
+        // Two shades of the larger region average within their cluster.
+        let mut shades = vec![Rgba([200, 30, 30, 255]); 5];
+        shades.extend([Rgba([210, 40, 40, 255]); 5]);
+        shades.extend([BLACK; 6]);
+        assert_eq!(reduce(&shades, BlockReduction::KMEANS(2)), Rgba([205, 35, 35, 255]));
+
+        // One cluster is the plain mean.
+        let mut halves = vec![WHITE; 8];
+        halves.extend([BLACK; 8]);
+        assert_eq!(reduce(&halves, BlockReduction::KMEANS(1)), Rgba([128, 128, 128, 255]));
+    }
+
+    #[test]
+    fn edge_keeps_thin_dark_lines() {
+        // A one pixel diagonal outline through a light block.
+        let diagonal: Vec<Rgba<u8>> = (0..16).map(|i| if i % 5 == 0 { BLACK } else { WHITE }).collect();
+        assert_eq!(reduce(&diagonal, BlockReduction::EDGE), BLACK);
+        assert_eq!(reduce(&diagonal, BlockReduction::MODE), WHITE);
+
+        // A single dark pixel is noise, not a line.
+        let mut speck = vec![WHITE; 16];
+        speck[5] = BLACK;
+        assert_eq!(reduce(&speck, BlockReduction::EDGE), WHITE);
+
+        // Low contrast blocks fall back to the mode.
+        let soft: Vec<Rgba<u8>> = (0..16).map(|i| if i % 5 == 0 { Rgba([220, 220, 220, 255]) } else { WHITE }).collect();
+        assert_eq!(reduce(&soft, BlockReduction::EDGE), WHITE);
+
+        // Transparent pixels do not count as dark.
+        let hidden: Vec<Rgba<u8>> = (0..16).map(|i| if i % 5 == 0 { Rgba([0, 0, 0, 0]) } else { WHITE }).collect();
+        assert_eq!(reduce(&hidden, BlockReduction::EDGE), WHITE);
+    }
+
+    #[test]
+    fn each_block_is_reduced_on_its_own() {
+        let mut pixels = RgbaImage::from_pixel(8, 4, WHITE);
+        for i in 0..4 {
+            pixels.put_pixel(i, i, BLACK);
+            pixels.put_pixel(4 + i, 0, RED);
+        }
+        let image = DynamicImage::ImageRgba8(pixels);
+        let options = PixelateOptions { reduction: BlockReduction::EDGE, low_resolution: true, ..PixelateOptions::default() };
+        let output = pixelate_blocks(&image, &options).to_rgba8();
+
+        assert_eq!(output.dimensions(), (2, 1));
+        assert_eq!(output.get_pixel(0, 0), &BLACK);
+        assert_eq!(output.get_pixel(1, 0), &RED);
+    }
+}
@@ -0,0 +1,221 @@
+use std::f32::consts::PI;
+
+use image::Rgb;
+
+use crate::colour::{oklab_to_oklch, oklch_to_rgb_clipped, rgb_to_oklab};
+use crate::library::palette_stem;
+use crate::palette::Palette;
+
+// Lightness range used for the darkest shade and the lightest tint.
+const SHADE_LIGHTNESS: f32 = 0.12;
+const TINT_LIGHTNESS: f32 = 0.97;
+
+#[derive(Copy, Clone)]
+pub enum RampSpace {
+    OKLAB,
+    OKLCH,
+}
+
+#[derive(Copy, Clone)]
+pub enum SaturationCurve {
+    FLAT,
+    PEAK(f32), // boosts chroma towards the middle of the ramp
+    FADE(f32), // drops chroma towards both ends of the ramp
+}
+
+impl SaturationCurve {
+    // position runs from 0 at the dark end of the ramp to 1 at the light end.
+    pub fn apply(&self, chroma: f32, position: f32) -> f32 {
+        let scale = match self {
+            SaturationCurve::FLAT => 1f32,
+            SaturationCurve::PEAK(amount) => 1f32 + amount * (PI * position).sin(),
+            SaturationCurve::FADE(amount) => 1f32 - amount * (2f32 * position - 1f32).abs(),
+        };
+        (chroma * scale).max(0f32)
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct RampOptions {
+    pub steps: usize,
+    pub hue_shift: f32,
+    pub saturation_curve: SaturationCurve,
+    pub space: RampSpace,
+}
+
+impl Default for RampOptions {
+    fn default() -> Self {
+        RampOptions {
+            steps: 8,
+            hue_shift: 0f32,
+            saturation_curve: SaturationCurve::FLAT,
+            space: RampSpace::OKLCH,
+        }
+    }
+}
+
+// steps colours per pair of anchors, neighbouring segments share their anchor so every anchor appears exactly once.
+// The hue shift bends the middle of each segment by up to hue_shift degrees and leaves the anchors untouched.
+pub fn generate_ramp(palettename: String, anchors: &[Rgb<u8>], options: &RampOptions) -> Palette {
+    if anchors.len() < 2 || options.steps < 2 {
+        return Palette {
+            name: palettename,
+            colours: anchors.to_vec(),
+        };
+    }
+
+    let segments = anchors.len() - 1;
+    let mut colours = vec![anchors[0]];
+
+    for (i, pair) in anchors.windows(2).enumerate() {
+        let start = rgb_to_oklab(&pair[0]);
+        let end = rgb_to_oklab(&pair[1]);
+
+        for step in 1..options.steps {
+            let t = step as f32 / (options.steps - 1) as f32;
+            if step == options.steps - 1 {
+                colours.push(pair[1]);
+                continue;
+            }
+
+            let mut lch = match options.space {
+                RampSpace::OKLAB => oklab_to_oklch(lerp3(start, end, t)),
+                RampSpace::OKLCH => lerp_oklch(oklab_to_oklch(start), oklab_to_oklch(end), t),
+            };
+
+            let position = (i as f32 + t) / segments as f32;
+            lch[1] = options.saturation_curve.apply(lch[1], position);
+            lch[2] = (lch[2] + options.hue_shift * (PI * t).sin()).rem_euclid(360f32);
+            colours.push(oklch_to_rgb_clipped(lch));
+        }
+    }
+
+    return Palette {
+        name: palettename,
+        colours,
+    };
+}
+
+// Shades darken towards SHADE_LIGHTNESS while shifting hue by -hue_shift, tints lighten and shift by +hue_shift.
+pub fn generate_shade_ramp(base: &Rgb<u8>, shades: usize, tints: usize, options: &RampOptions) -> Vec<Rgb<u8>> {
+    let [l, c, h] = oklab_to_oklch(rgb_to_oklab(base));
+    let span = shades + tints;
+    let mut ramp = Vec::with_capacity(span + 1);
+
+    for k in (1..=shades).rev() {
+        let amount = k as f32 / (shades + 1) as f32;
+        let lightness = l - (l - SHADE_LIGHTNESS).max(0f32) * amount;
+        let position = (shades - k) as f32 / span.max(1) as f32;
+        let chroma = options.saturation_curve.apply(c, position);
+        ramp.push(oklch_to_rgb_clipped([lightness, chroma, h - options.hue_shift * amount]));
+    }
+
+    ramp.push(*base);
+
+    for k in 1..=tints {
+        let amount = k as f32 / (tints + 1) as f32;
+        let lightness = l + (TINT_LIGHTNESS - l).max(0f32) * amount;
+        let position = (shades + k) as f32 / span.max(1) as f32;
+        let chroma = options.saturation_curve.apply(c, position);
+        ramp.push(oklch_to_rgb_clipped([lightness, chroma, h + options.hue_shift * amount]));
+    }
+
+    return ramp;
+}
+
+// One shade/tint ramp per palette entry, concatenated in palette order.
+pub fn generate_shade_ramps(palette: &Palette, shades: usize, tints: usize, options: &RampOptions) -> Palette {
+    let colours = palette.colours.iter()
+        .flat_map(|colour| generate_shade_ramp(colour, shades, tints, options))
+        .collect();
+
+    return Palette {
+        name: format!("{}-ramps.hex", palette_stem(&palette.name)),
+        colours,
+    };
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+fn lerp3(a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 3] {
+    [lerp(a[0], b[0], t), lerp(a[1], b[1], t), lerp(a[2], b[2], t)]
+}
+
+// Interpolates hue along the shorter arc, an achromatic end takes the hue of the other end.
+fn lerp_oklch(a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 3] {
+    const ACHROMATIC_CHROMA: f32 = 0.002;
+
+    let hue_a = if a[1] < ACHROMATIC_CHROMA { b[2] } else { a[2] };
+    let hue_b = if b[1] < ACHROMATIC_CHROMA { hue_a } else { b[2] };
+    let mut delta = hue_b - hue_a;
+    if delta > 180f32 {
+        delta -= 360f32;
+    } else if delta < -180f32 {
+        delta += 360f32;
+    }
+
+    [lerp(a[0], b[0], t), lerp(a[1], b[1], t), (hue_a + delta * t).rem_euclid(360f32)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lightness(colours: &[Rgb<u8>]) -> Vec<f32> {
+        return colours.iter().map(|c| rgb_to_oklab(c)[0]).collect();
+    }
+
+    fn increasing(values: &[f32]) -> bool {
+        return values.windows(2).all(|pair| pair[1] > pair[0]);
+    }
+
+    const ANCHORS: [Rgb<u8>; 3] = [Rgb([20, 24, 60]), Rgb([150, 60, 70]), Rgb([250, 230, 170])];
+
+    #[test]
+    fn ramp_has_steps_per_segment_and_keeps_its_anchors() {
+        for space in [RampSpace::OKLAB, RampSpace::OKLCH] {
+            let options = RampOptions { steps: 5, hue_shift: 20f32, space, ..RampOptions::default() };
+            let ramp = generate_ramp("ramp.hex".to_string(), &ANCHORS, &options).colours;
+
+            assert_eq!(ramp.len(), 1 + 2 * 4);
+            assert_eq!(ramp[0], ANCHORS[0]);
+            assert_eq!(ramp[4], ANCHORS[1]);
+            assert_eq!(ramp[8], ANCHORS[2]);
+            assert!(increasing(&lightness(&ramp)));
+        }
+
+        let too_few = RampOptions { steps: 1, ..RampOptions::default() };
+        assert_eq!(generate_ramp(String::new(), &ANCHORS, &too_few).colours, ANCHORS.to_vec());
+        assert_eq!(generate_ramp(String::new(), &ANCHORS[..1], &RampOptions::default()).colours, vec![ANCHORS[0]]);
+    }
+
+    #[test]
+    fn saturation_curves_leave_the_lightness_order_alone() {
+        for curve in [SaturationCurve::PEAK(0.5), SaturationCurve::FADE(0.5)] {
+            let options = RampOptions { steps: 6, saturation_curve: curve, ..RampOptions::default() };
+            assert!(increasing(&lightness(&generate_ramp(String::new(), &ANCHORS, &options).colours)));
+        }
+        assert_eq!(SaturationCurve::PEAK(0.5).apply(0.1, 0.5), 0.15);
+        assert_eq!(SaturationCurve::FADE(0.5).apply(0.1, 0f32), 0.05);
+        assert_eq!(SaturationCurve::FLAT.apply(0.1, 0.3), 0.1);
+    }
+
+    #[test]
+    fn shade_ramp_darkens_then_lightens_around_the_base() {
+        let base = Rgb([90, 140, 200]);
+        let options = RampOptions { hue_shift: 15f32, ..RampOptions::default() };
+        let ramp = generate_shade_ramp(&base, 3, 2, &options);
+
+        assert_eq!(ramp.len(), 6);
+        assert_eq!(ramp[3], base);
+        assert!(increasing(&lightness(&ramp)));
+
+        let palette = Palette { name: "pair.hex".to_string(), colours: vec![base, ANCHORS[1]] };
+        let ramps = generate_shade_ramps(&palette, 3, 2, &options);
+        assert_eq!(ramps.name, "pair-ramps.hex");
+        assert_eq!(ramps.colours.len(), 12);
+        assert_eq!(ramps.colours[9], ANCHORS[1]);
+    }
+}
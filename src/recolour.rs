@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::fs::{read_to_string, File};
+use std::io::Write;
+
+use image::{DynamicImage, GenericImageView, Rgb, Rgba};
+
+use crate::colour::{rgb_to_oklab, DistanceFunction};
+use crate::palette::Palette;
+use crate::utils::{hex_to_rgb, rgb_to_hex};
+
+#[derive(Copy, Clone)]
+pub enum MappingStrategy {
+    INDEX,
+    LIGHTNESS,
+    OPTIMAL,
+}
+
+impl MappingStrategy {
+    pub fn new(name: &str) -> Result<MappingStrategy, &'static str> {
+        let strategy = match name.to_lowercase().as_str() {
+            "index" => MappingStrategy::INDEX,
+            "lightness" => MappingStrategy::LIGHTNESS,
+            "optimal" => MappingStrategy::OPTIMAL,
+
+            _ => return Err("Unknown mapping strategy")
+        };
+
+        return Ok(strategy);
+    }
+}
+
+pub struct ColourMapping {
+    pub entries: Vec<(Rgb<u8>, Rgb<u8>)>,
+}
+
+impl ColourMapping {
+    pub fn new(source: &Palette, target: &Palette, strategy: MappingStrategy) -> ColourMapping {
+        if source.colours.is_empty() || target.colours.is_empty() {
+            return ColourMapping { entries: Vec::new() };
+        }
+
+        let targets = match strategy {
+            MappingStrategy::INDEX => map_by_index(&source.colours, &target.colours),
+            MappingStrategy::LIGHTNESS => map_by_lightness(&source.colours, &target.colours),
+            MappingStrategy::OPTIMAL => map_optimal(&source.colours, &target.colours),
+        };
+
+        return ColourMapping {
+            entries: source.colours.iter().copied().zip(targets).collect(),
+        };
+    }
+
+    pub fn get(&self, colour: &Rgb<u8>) -> Option<Rgb<u8>> {
+        self.entries.iter().find(|(source, _)| source == colour).map(|(_, target)| *target)
+    }
+
+    // Distinct target colours in the order they first appear in the mapping.
+    pub fn target_colours(&self) -> Vec<Rgb<u8>> {
+        let mut colours: Vec<Rgb<u8>> = Vec::new();
+        for (_, target) in &self.entries {
+            if !colours.contains(target) {
+                colours.push(*target);
+            }
+        }
+        return colours;
+    }
+
+    // Rewrites every pixel whose colour is in the source palette and keeps its alpha.
+    // Returns the number of pixels left untouched because their colour is not part of the mapping.
+    pub fn apply(&self, image: &mut DynamicImage) -> usize {
+        // A source colour listed more than once keeps its first target, the same as get.
+        let mut lookup: HashMap<Rgb<u8>, Rgb<u8>> = HashMap::new();
+        for (source, target) in &self.entries {
+            lookup.entry(*source).or_insert(*target);
+        }
+        let (width, height) = image.dimensions();
+        let mut pixels = image.to_rgba8();
+        let mut unmapped = 0;
+
+        for y in 0..height {
+            for x in 0..width {
+                let pixel = *pixels.get_pixel(x, y);
+                match lookup.get(&Rgb([pixel[0], pixel[1], pixel[2]])) {
+                    Some(target) => pixels.put_pixel(x, y, Rgba([target[0], target[1], target[2], pixel[3]])),
+                    None => unmapped += 1,
+                }
+            }
+        }
+
+        *image = if image.color().has_alpha() {
+            DynamicImage::ImageRgba8(pixels)
+        } else {
+            DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(pixels).to_rgb8())
+        };
+        return unmapped;
+    }
+
+    // One "SOURCE TARGET" hex pair per line, in source palette order.
+    pub fn to_table(&self) -> String {
+        self.entries.iter()
+            .map(|(source, target)| format!("{} {}\n", rgb_to_hex(*source), rgb_to_hex(*target)))
+            .collect()
+    }
+
+    pub fn from_table(table: &str) -> Result<ColourMapping, &'static str> {
+        let mut entries = Vec::new();
+        for line in table.lines().filter(|line| !line.trim().is_empty()) {
+            let mut columns = line.split_whitespace();
+            let source = hex_to_rgb(columns.next().ok_or("Missing source colour")?)?;
+            let target = hex_to_rgb(columns.next().ok_or("Missing target colour")?)?;
+            entries.push((source, target));
+        }
+        return Ok(ColourMapping { entries });
+    }
+
+    pub fn save_table(&self, filepath: &str) {
+        let mut file = File::create(filepath).expect("ERROR: COULD NOT CREATE MAPPING FILE.");
+        write!(file, "{}", self.to_table()).expect("ERROR: UNABLE TO WRITE TO FILE.");
+    }
+
+    pub fn load_table(filepath: &str) -> ColourMapping {
+        let table = read_to_string(filepath).expect("ERROR: UNABLE TO READ MAPPING FILE.");
+        return ColourMapping::from_table(&table).expect("ERROR: INVALID MAPPING FILE");
+    }
+}
+
+fn map_by_index(source: &[Rgb<u8>], target: &[Rgb<u8>]) -> Vec<Rgb<u8>> {
+    (0..source.len()).map(|i| target[i % target.len()]).collect()
+}
+
+// The n-th darkest source colour goes to the proportionally placed target colour in lightness order.
+fn map_by_lightness(source: &[Rgb<u8>], target: &[Rgb<u8>]) -> Vec<Rgb<u8>> {
+    let lightness_order = |colours: &[Rgb<u8>]| {
+        let mut order: Vec<usize> = (0..colours.len()).collect();
+        order.sort_by(|a, b| rgb_to_oklab(&colours[*a])[0].total_cmp(&rgb_to_oklab(&colours[*b])[0]));
+        order
+    };
+    let source_order = lightness_order(source);
+    let target_order = lightness_order(target);
+
+    let mut mapped = vec![target[0]; source.len()];
+    for (rank, index) in source_order.into_iter().enumerate() {
+        let target_rank = if source.len() == 1 {
+            0
+        } else {
+            (rank as f32 * (target.len() - 1) as f32 / (source.len() - 1) as f32).round() as usize
+        };
+        mapped[index] = target[target_order[target_rank]];
+    }
+    return mapped;
+}
+
+// Minimum total CIEDE2000 assignment. When the source has more colours than the target,
+// the target is repeated so its colours can be shared while staying as balanced as possible.
+fn map_optimal(source: &[Rgb<u8>], target: &[Rgb<u8>]) -> Vec<Rgb<u8>> {
+    let distance = DistanceFunction::CIEDE2000;
+    let copies = source.len().div_ceil(target.len());
+    let columns: Vec<Rgb<u8>> = (0..copies).flat_map(|_| target.iter().copied()).collect();
+
+    let cost: Vec<Vec<f64>> = source.iter()
+        .map(|s| columns.iter().map(|t| distance.distance(s, t) as f64).collect())
+        .collect();
+
+    return hungarian(&cost).into_iter().map(|column| columns[column]).collect();
+}
+
+// Hungarian algorithm with potentials for a rows <= columns cost matrix, returns the column of each row.
+pub fn hungarian(cost: &[Vec<f64>]) -> Vec<usize> {
+    let rows = cost.len();
+    if rows == 0 {
+        return Vec::new();
+    }
+    let columns = cost[0].len();
+
+    let mut u = vec![0f64; rows + 1];
+    let mut v = vec![0f64; columns + 1];
+    let mut assigned_row = vec![0usize; columns + 1];
+    let mut way = vec![0usize; columns + 1];
+
+    for row in 1..=rows {
+        assigned_row[0] = row;
+        let mut column = 0;
+        let mut min_value = vec![f64::INFINITY; columns + 1];
+        let mut used = vec![false; columns + 1];
+
+        loop {
+            used[column] = true;
+            let current_row = assigned_row[column];
+            let mut delta = f64::INFINITY;
+            let mut next_column = 0;
+
+            for j in 1..=columns {
+                if used[j] {
+                    continue;
+                }
+                let reduced = cost[current_row - 1][j - 1] - u[current_row] - v[j];
+                if reduced < min_value[j] {
+                    min_value[j] = reduced;
+                    way[j] = column;
+                }
+                if min_value[j] < delta {
+                    delta = min_value[j];
+                    next_column = j;
+                }
+            }
+
+            for j in 0..=columns {
+                if used[j] {
+                    u[assigned_row[j]] += delta;
+                    v[j] -= delta;
+                } else {
+                    min_value[j] -= delta;
+                }
+            }
+
+            column = next_column;
+            if assigned_row[column] == 0 {
+                break;
+            }
+        }
+
+        loop {
+            let previous = way[column];
+            assigned_row[column] = assigned_row[previous];
+            column = previous;
+            if column == 0 {
+                break;
+            }
+        }
+    }
+
+    let mut assignment = vec![0usize; rows];
+    for j in 1..=columns {
+        if assigned_row[j] != 0 {
+            assignment[assigned_row[j] - 1] = j - 1;
+        }
+    }
+    return assignment;
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbImage;
+
+    use super::*;
+
+    #[test]
+    fn duplicate_sources_map_to_their_first_target_everywhere() {
+        let mapping = ColourMapping::from_table("FF0000 00FF00\nFF0000 0000FF\n").unwrap();
+        let mut image = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([255, 0, 0])));
+
+        assert_eq!(mapping.apply(&mut image), 0);
+        assert_eq!(mapping.get(&Rgb([255, 0, 0])), Some(Rgb([0, 255, 0])));
+        assert_eq!(image.to_rgb8().get_pixel(1, 1), &Rgb([0, 255, 0]));
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use image::{DynamicImage, GrayImage, Rgb};
+
+use crate::colour::DistanceFunction;
+use crate::image::{apply_palette, save_image, SaveOptions};
+use crate::library::{palette_stem, PaletteLibrary};
+use crate::palette::Palette;
+
+pub const SSIM_WINDOW: u32 = 8;
+
+#[derive(Copy, Clone)]
+pub enum RankingMetric {
+    DELTAE,
+    SSIM,
+}
+
+pub struct Recommendation {
+    pub palette: Palette,
+    pub preview: DynamicImage,
+    // Mean CIEDE2000 for DELTAE, (1 - SSIM) * 100 for SSIM so both read as "lower is better".
+    pub error: f32,
+    pub unused_colours: usize,
+    pub score: f32,
+}
+
+pub struct RecommendOptions {
+    pub metric: RankingMetric,
+    // Score added when none of the palette's colours are used, scaled by the unused fraction.
+    pub coverage_weight: f32,
+    pub top_n: usize,
+    pub thumbnail_size: u32,
+}
+
+impl Default for RecommendOptions {
+    fn default() -> Self {
+        RecommendOptions {
+            metric: RankingMetric::DELTAE,
+            coverage_weight: 10f32,
+            top_n: 5,
+            thumbnail_size: 128,
+        }
+    }
+}
+
+// Palettizes a thumbnail of the image with every palette in the library and returns the best top_n.
+pub fn recommend_palettes(image: &DynamicImage, library: &PaletteLibrary, options: &RecommendOptions) -> Vec<Recommendation> {
+    let thumbnail = DynamicImage::ImageRgb8(image.thumbnail(options.thumbnail_size, options.thumbnail_size).to_rgb8());
+
+    let mut ranked: Vec<Recommendation> = library.palettes.iter()
+        .filter(|palette| !palette.colours.is_empty())
+        .map(|palette| score_palette(&thumbnail, palette, options))
+        .collect();
+
+    ranked.sort_by(|a, b| a.score.total_cmp(&b.score));
+    ranked.truncate(options.top_n);
+    return ranked;
+}
+
+pub fn score_palette(image: &DynamicImage, palette: &Palette, options: &RecommendOptions) -> Recommendation {
+    let preview = apply_palette(image.clone(), palette.clone());
+
+    let error = match options.metric {
+        RankingMetric::DELTAE => mean_delta_e(image, &preview),
+        RankingMetric::SSIM => (1f32 - ssim(&image.to_luma8(), &preview.to_luma8())) * 100f32,
+    };
+
+    let used: HashSet<Rgb<u8>> = preview.to_rgb8().pixels().copied().collect();
+    let unused_colours = palette.colours.iter().filter(|c| !used.contains(c)).count();
+    let unused_fraction = unused_colours as f32 / palette.colours.len() as f32;
+
+    return Recommendation {
+        palette: palette.clone(),
+        preview,
+        error,
+        unused_colours,
+        score: error + options.coverage_weight * unused_fraction,
+    };
+}
+
+pub fn save_previews(recommendations: &[Recommendation], dir: &str) -> Result<(), String> {
+    for (rank, recommendation) in recommendations.iter().enumerate() {
+        let filename = format!("{:02}-{}.png", rank + 1, palette_stem(&recommendation.palette.name));
+        let path = Path::new(dir).join(filename);
+        save_image(&recommendation.preview, path.to_str().unwrap(), &SaveOptions::default())?;
+    }
+    return Ok(());
+}
+
+pub fn mean_delta_e(original: &DynamicImage, palettized: &DynamicImage) -> f32 {
+    let distance = DistanceFunction::CIEDE2000;
+    let original = original.to_rgb8();
+    let palettized = palettized.to_rgb8();
+
+    let total: f32 = original.pixels().zip(palettized.pixels())
+        .map(|(a, b)| distance.distance(a, b))
+        .sum();
+    return total / (original.width() * original.height()).max(1) as f32;
+}
+
+// Mean SSIM over SSIM_WINDOW sized windows with a half window stride.
+pub fn ssim(a: &GrayImage, b: &GrayImage) -> f32 {
+    const C1: f32 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f32 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let (width, height) = a.dimensions();
+    if width == 0 || height == 0 {
+        return 1f32;
+    }
+    let window = SSIM_WINDOW.min(width).min(height).max(1);
+    let stride = (window / 2).max(1);
+
+    let mut total = 0f32;
+    let mut windows = 0;
+    for y in (0..=height - window).step_by(stride as usize) {
+        for x in (0..=width - window).step_by(stride as usize) {
+            let mut sum = [0f32; 5];
+            for wy in y..y + window {
+                for wx in x..x + window {
+                    let pa = a.get_pixel(wx, wy)[0] as f32;
+                    let pb = b.get_pixel(wx, wy)[0] as f32;
+                    sum[0] += pa;
+                    sum[1] += pb;
+                    sum[2] += pa * pa;
+                    sum[3] += pb * pb;
+                    sum[4] += pa * pb;
+                }
+            }
+
+            let n = (window * window) as f32;
+            let (mean_a, mean_b) = (sum[0] / n, sum[1] / n);
+            let var_a = sum[2] / n - mean_a * mean_a;
+            let var_b = sum[3] / n - mean_b * mean_b;
+            let covariance = sum[4] / n - mean_a * mean_b;
+
+            total += ((2f32 * mean_a * mean_b + C1) * (2f32 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            windows += 1;
+        }
+    }
+
+    return if windows == 0 { 1f32 } else { total / windows as f32 };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    const COLOURS: [Rgb<u8>; 4] = [Rgb([200, 40, 40]), Rgb([40, 160, 60]), Rgb([30, 60, 200]), Rgb([240, 220, 120])];
+
+    // Four 8x8 quadrants, one per colour.
+    fn quadrants() -> DynamicImage {
+        return DynamicImage::ImageRgb8(RgbImage::from_fn(16, 16, |x, y| COLOURS[(x / 8 + 2 * (y / 8)) as usize]));
+    }
+
+    fn palette(name: &str, colours: &[Rgb<u8>]) -> Palette {
+        return Palette { name: name.to_string(), colours: colours.to_vec() };
+    }
+
+    #[test]
+    fn ssim_of_an_image_with_itself_is_one() {
+        let image = quadrants().to_luma8();
+        assert!((ssim(&image, &image) - 1f32).abs() < 1e-6);
+
+        let inverted = GrayImage::from_fn(16, 16, |x, y| image::Luma([255 - image.get_pixel(x, y)[0]]));
+        assert!(ssim(&image, &inverted) < 0.5);
+    }
+
+    #[test]
+    fn exact_palette_has_no_delta_e() {
+        let image = quadrants();
+        let preview = apply_palette(image.clone(), palette("exact.hex", &COLOURS));
+        assert_eq!(mean_delta_e(&image, &preview), 0f32);
+
+        let grey = apply_palette(image.clone(), palette("grey.hex", &[Rgb([128, 128, 128])]));
+        assert!(mean_delta_e(&image, &grey) > 10f32);
+    }
+
+    #[test]
+    fn ranking_is_ordered_and_truncated() {
+        let mut with_spare = COLOURS.to_vec();
+        with_spare.push(Rgb([0, 0, 0]));
+        let library = PaletteLibrary { palettes: vec![
+            palette("grey.hex", &[Rgb([60, 60, 60]), Rgb([190, 190, 190])]),
+            palette("spare.hex", &with_spare),
+            palette("empty.hex", &[]),
+            palette("exact.hex", &COLOURS),
+            palette("warm.hex", &[COLOURS[0], COLOURS[3]]),
+        ] };
+
+        for metric in [RankingMetric::DELTAE, RankingMetric::SSIM] {
+            let options = RecommendOptions { metric, top_n: 10, thumbnail_size: 16, ..RecommendOptions::default() };
+            let ranked = recommend_palettes(&quadrants(), &library, &options);
+
+            let names: Vec<&str> = ranked.iter().map(|r| r.palette.name.as_str()).collect();
+            assert_eq!(names.len(), 4);
+            assert_eq!(names[..2], ["exact.hex", "spare.hex"]);
+            assert!(ranked.windows(2).all(|pair| pair[0].score <= pair[1].score));
+
+            assert!(ranked[0].error.abs() < 1e-3);
+            assert_eq!(ranked[1].unused_colours, 1);
+            assert!((ranked[1].score - ranked[1].error - 2f32).abs() < 1e-3);
+        }
+
+        let top = recommend_palettes(&quadrants(), &library, &RecommendOptions { top_n: 2, thumbnail_size: 16, ..RecommendOptions::default() });
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].palette.name, "exact.hex");
+    }
+}
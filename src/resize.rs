@@ -0,0 +1,181 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView};
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum ResizeMode {
+    // As large as fits inside the target, keeping the aspect ratio.
+    FIT,
+    // Covers the target keeping the aspect ratio, the overflow is cropped evenly from both sides.
+    FILL,
+    // Exactly the target size, stretching the image.
+    EXACT,
+    // The largest whole multiple of the size that fits the target, or the smallest whole division
+    // when the image is already larger. Always nearest neighbour, so pixel art stays sharp.
+    INTEGER,
+}
+
+impl ResizeMode {
+    pub fn new(mode: &str) -> Result<ResizeMode, &'static str> {
+        return match mode {
+            "fit" => Ok(ResizeMode::FIT),
+            "fill" => Ok(ResizeMode::FILL),
+            "exact" => Ok(ResizeMode::EXACT),
+            "integer" => Ok(ResizeMode::INTEGER),
+            _ => Err("Invalid resize mode"),
+        };
+    }
+
+    pub fn to_string(mode: &ResizeMode) -> String {
+        return match mode {
+            ResizeMode::FIT => "fit",
+            ResizeMode::FILL => "fill",
+            ResizeMode::EXACT => "exact",
+            ResizeMode::INTEGER => "integer",
+        }.to_string();
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum ResizeTarget {
+    // Width, the height follows the aspect ratio.
+    WIDTH(u32),
+    // Height, the width follows the aspect ratio.
+    HEIGHT(u32),
+    SIZE(u32, u32),
+    // Both sides scaled by the percentage.
+    PERCENT(f32),
+}
+
+impl ResizeTarget {
+    // W, Wx, xH, WxH or P%.
+    pub fn new(target: &str) -> Result<ResizeTarget, String> {
+        let error = || format!("{} is not a size, use W, WxH, xH or a percentage such as 50%", target);
+        let side = |side: &str| side.trim().parse::<u32>().ok().filter(|n| *n > 0).ok_or_else(error);
+
+        let target_lower = target.trim().to_lowercase();
+        if let Some(percent) = target_lower.strip_suffix('%') {
+            return match percent.trim().parse::<f32>() {
+                Ok(p) if p > 0f32 && p.is_finite() => Ok(ResizeTarget::PERCENT(p)),
+                _ => Err(error()),
+            };
+        }
+        return match target_lower.split_once('x') {
+            Some((width, "")) => Ok(ResizeTarget::WIDTH(side(width)?)),
+            Some(("", height)) => Ok(ResizeTarget::HEIGHT(side(height)?)),
+            Some((width, height)) => Ok(ResizeTarget::SIZE(side(width)?, side(height)?)),
+            None => Ok(ResizeTarget::WIDTH(side(&target_lower)?)),
+        };
+    }
+
+    pub fn to_string(target: &ResizeTarget) -> String {
+        return match target {
+            ResizeTarget::WIDTH(width) => format!("{}x", width),
+            ResizeTarget::HEIGHT(height) => format!("x{}", height),
+            ResizeTarget::SIZE(width, height) => format!("{}x{}", width, height),
+            ResizeTarget::PERCENT(percent) => format!("{}%", percent),
+        };
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct ResizeOptions {
+    pub target: ResizeTarget,
+    // Only matters for targets with both sides, a single side or a percentage keeps the aspect ratio.
+    pub mode: ResizeMode,
+    pub filter: FilterType,
+}
+
+impl Default for ResizeOptions {
+    fn default() -> Self {
+        ResizeOptions {
+            target: ResizeTarget::PERCENT(100f32),
+            mode: ResizeMode::FIT,
+            filter: FilterType::Nearest,
+        }
+    }
+}
+
+pub fn resize_image(img: &DynamicImage, options: &ResizeOptions) -> Result<DynamicImage, String> {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 {
+        return Err("Cannot resize an empty image".to_string());
+    }
+
+    // Both sides of the target, a missing side is filled in from the aspect ratio.
+    let aspect = |side: u32, from: u32, to: u32| ((side as f64 * to as f64 / from as f64).round() as u32).max(1);
+    let (target_width, target_height) = match options.target {
+        ResizeTarget::WIDTH(w) => (w, aspect(height, width, w)),
+        ResizeTarget::HEIGHT(h) => (aspect(width, height, h), h),
+        ResizeTarget::SIZE(w, h) => (w, h),
+        ResizeTarget::PERCENT(p) => (
+            ((width as f64 * p as f64 / 100f64).round() as u32).max(1),
+            ((height as f64 * p as f64 / 100f64).round() as u32).max(1),
+        ),
+    };
+    if (target_width as u64) * (target_height as u64) > u32::MAX as u64 {
+        return Err(format!("{}x{} is too large", target_width, target_height));
+    }
+
+    let single_side = !matches!(options.target, ResizeTarget::SIZE(..));
+    return Ok(match options.mode {
+        ResizeMode::INTEGER => {
+            let (new_width, new_height) = integer_size(width, height, target_width, target_height);
+            img.resize_exact(new_width, new_height, FilterType::Nearest)
+        },
+        _ if single_side => img.resize_exact(target_width, target_height, options.filter),
+        ResizeMode::FIT => img.resize(target_width, target_height, options.filter),
+        ResizeMode::FILL => img.resize_to_fill(target_width, target_height, options.filter),
+        ResizeMode::EXACT => img.resize_exact(target_width, target_height, options.filter),
+    });
+}
+
+// Size after the largest whole factor that fits the target, or the smallest whole divisor when even
+// the original size does not fit.
+fn integer_size(width: u32, height: u32, target_width: u32, target_height: u32) -> (u32, u32) {
+    let factor = (target_width / width).min(target_height / height);
+    if factor >= 1 {
+        return (width * factor, height * factor);
+    }
+    let divisor = width.div_ceil(target_width).max(height.div_ceil(target_height));
+    return ((width / divisor).max(1), (height / divisor).max(1));
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbImage;
+
+    use super::*;
+
+    #[test]
+    fn targets_parse_every_form() {
+        assert!(ResizeTarget::new("64") == Ok(ResizeTarget::WIDTH(64)));
+        assert!(ResizeTarget::new("64x") == Ok(ResizeTarget::WIDTH(64)));
+        assert!(ResizeTarget::new("x48") == Ok(ResizeTarget::HEIGHT(48)));
+        assert!(ResizeTarget::new("64X48") == Ok(ResizeTarget::SIZE(64, 48)));
+        assert!(ResizeTarget::new("50%") == Ok(ResizeTarget::PERCENT(50f32)));
+        for invalid in ["", "0", "x", "64x0", "-5%", "abc", "10x10x10"] {
+            assert!(ResizeTarget::new(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn integer_mode_scales_by_whole_factors() {
+        assert_eq!(integer_size(16, 8, 100, 100), (96, 48));
+        assert_eq!(integer_size(16, 8, 40, 16), (32, 16));
+        assert_eq!(integer_size(100, 50, 40, 40), (33, 16));
+    }
+
+    #[test]
+    fn modes_meet_the_target() {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(40, 20));
+        let size = |target: ResizeTarget, mode: ResizeMode| {
+            resize_image(&image, &ResizeOptions { target, mode, ..ResizeOptions::default() }).unwrap().dimensions()
+        };
+        assert_eq!(size(ResizeTarget::SIZE(30, 30), ResizeMode::FIT), (30, 15));
+        assert_eq!(size(ResizeTarget::SIZE(30, 30), ResizeMode::FILL), (30, 30));
+        assert_eq!(size(ResizeTarget::SIZE(30, 30), ResizeMode::EXACT), (30, 30));
+        assert_eq!(size(ResizeTarget::SIZE(130, 70), ResizeMode::INTEGER), (120, 60));
+        assert_eq!(size(ResizeTarget::HEIGHT(10), ResizeMode::FIT), (20, 10));
+        assert_eq!(size(ResizeTarget::PERCENT(250f32), ResizeMode::FIT), (100, 50));
+    }
+}
@@ -0,0 +1,301 @@
+use std::collections::HashSet;
+use std::env;
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+
+use crate::builtin::{builtin_names, builtin_palette};
+
+pub const PALETTE_PATH_ENV: &str = "PIX_PALETTE_PATH";
+pub const PALETTE_EXTENSIONS: [&str; 1] = ["hex"];
+pub const PROJECT_PALETTE_DIR: &str = ".pix/palettes";
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PaletteSource {
+    ENV,
+    USER,
+    PROJECT,
+    BUILTIN,
+}
+
+impl PaletteSource {
+    pub fn to_string(source: &PaletteSource) -> String {
+        let name = match source {
+            PaletteSource::ENV => PALETTE_PATH_ENV,
+            PaletteSource::USER => "user",
+            PaletteSource::PROJECT => "project",
+            PaletteSource::BUILTIN => "builtin",
+        };
+
+        return name.to_string();
+    }
+}
+
+#[derive(Debug)]
+pub enum PaletteLocation {
+    File(PathBuf),
+    Builtin(&'static str),
+}
+
+impl PaletteLocation {
+    pub fn to_string(location: &PaletteLocation) -> String {
+        match location {
+            PaletteLocation::File(path) => path.display().to_string(),
+            PaletteLocation::Builtin(name) => format!("builtin:{}", name),
+        }
+    }
+}
+
+pub struct PaletteSearchPath {
+    pub dirs: Vec<(PaletteSource, PathBuf)>,
+    pub include_builtin: bool,
+}
+
+impl PaletteSearchPath {
+    // Search order: PIX_PALETTE_PATH, the XDG user data dir, the nearest .pix/palettes and finally the built-in set.
+    pub fn new() -> PaletteSearchPath {
+        let mut dirs = Vec::new();
+
+        if let Some(paths) = env::var_os(PALETTE_PATH_ENV) {
+            for dir in env::split_paths(&paths).filter(|p| !p.as_os_str().is_empty()) {
+                dirs.push((PaletteSource::ENV, dir));
+            }
+        }
+        if let Some(dir) = user_palette_dir() {
+            dirs.push((PaletteSource::USER, dir));
+        }
+        if let Some(dir) = project_palette_dir() {
+            dirs.push((PaletteSource::PROJECT, dir));
+        }
+
+        return PaletteSearchPath {
+            dirs,
+            include_builtin: true,
+        };
+    }
+
+    pub fn from_dir(dir: &str) -> PaletteSearchPath {
+        return PaletteSearchPath {
+            dirs: vec![(PaletteSource::PROJECT, PathBuf::from(dir))],
+            include_builtin: false,
+        };
+    }
+
+    // Paths are used as is, bare names are looked up in every directory and then in the built-in set.
+    // A name found more than once, in one directory or across several, is reported as ambiguous.
+    pub fn resolve(&self, name: &str) -> Result<PaletteLocation, String> {
+        let path = Path::new(name);
+        if path.is_absolute() || path.components().count() > 1 {
+            return if path.is_file() {
+                Ok(PaletteLocation::File(path.to_path_buf()))
+            } else {
+                Err(format!("Palette file {} does not exist", name))
+            };
+        }
+
+        // A directory listed twice on the search path does not make its palettes ambiguous.
+        let mut seen = HashSet::new();
+        let matches: Vec<PathBuf> = self.dirs.iter()
+            .flat_map(|(_, dir)| find_in_dir(dir, name))
+            .filter(|m| seen.insert(m.canonicalize().unwrap_or_else(|_| m.clone())))
+            .collect();
+        match matches.len() {
+            0 => {},
+            1 => return Ok(PaletteLocation::File(matches[0].clone())),
+            _ => {
+                let candidates: Vec<String> = matches.iter().map(|m| m.display().to_string()).collect();
+                return Err(format!("Palette name {} is ambiguous, candidates: {}", name, candidates.join(", ")));
+            }
+        }
+
+        if self.include_builtin {
+            if let Some((builtin, _)) = builtin_palette(name) {
+                return Ok(PaletteLocation::Builtin(builtin));
+            }
+        }
+
+        return Err(format!("Palette {} not found in search path", name));
+    }
+
+    // Every palette on the search path, a name in several directories is listed once from the first of
+    // them and files shadow built-ins.
+    pub fn palettes(&self) -> Vec<PaletteLocation> {
+        let mut seen = HashSet::new();
+        let mut files = Vec::new();
+
+        for (_, dir) in &self.dirs {
+            let Ok(entries) = read_dir(dir) else { continue };
+            let mut dir_files: Vec<PathBuf> = entries.flatten()
+                .map(|entry| entry.path())
+                .filter(|path| is_palette_file(path))
+                .collect();
+            dir_files.sort();
+
+            for file in dir_files {
+                let stem = file.file_stem().unwrap().to_string_lossy().to_lowercase();
+                if seen.insert(stem) {
+                    files.push(PaletteLocation::File(file));
+                }
+            }
+        }
+
+        if self.include_builtin {
+            for name in builtin_names() {
+                if seen.insert(name.to_lowercase()) {
+                    files.push(PaletteLocation::Builtin(name));
+                }
+            }
+        }
+
+        return files;
+    }
+
+    // The first user writable directory, used when saving palettes without an explicit path. Without
+    // one, such as when HOME is unset, the project directory is used so the palette can still be found.
+    pub fn save_dir(&self) -> PathBuf {
+        for (source, dir) in &self.dirs {
+            if *source == PaletteSource::ENV || *source == PaletteSource::USER {
+                return dir.clone();
+            }
+        }
+        for (source, dir) in &self.dirs {
+            if *source == PaletteSource::PROJECT {
+                return dir.clone();
+            }
+        }
+        return PathBuf::from(PROJECT_PALETTE_DIR);
+    }
+}
+
+impl Default for PaletteSearchPath {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn find_in_dir(dir: &Path, name: &str) -> Vec<PathBuf> {
+    let Ok(entries) = read_dir(dir) else { return Vec::new() };
+    let wanted = name.to_lowercase();
+
+    let mut matches: Vec<PathBuf> = entries.flatten()
+        .map(|entry| entry.path())
+        .filter(|path| is_palette_file(path))
+        .filter(|path| {
+            let file_name = path.file_name().unwrap().to_string_lossy().to_lowercase();
+            let stem = path.file_stem().unwrap().to_string_lossy().to_lowercase();
+            file_name == wanted || stem == wanted
+        })
+        .collect();
+
+    // An exact file name match is never ambiguous.
+    if let Some(exact) = matches.iter().find(|path| path.file_name().is_some_and(|f| f == name)) {
+        return vec![exact.clone()];
+    }
+
+    matches.sort();
+    return matches;
+}
+
+fn is_palette_file(path: &Path) -> bool {
+    path.is_file() && path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| PALETTE_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+fn user_palette_dir() -> Option<PathBuf> {
+    let data_home = match env::var_os("XDG_DATA_HOME").filter(|v| !v.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".local/share"),
+    };
+    return Some(data_home.join("pix/palettes"));
+}
+
+fn project_palette_dir() -> Option<PathBuf> {
+    let cwd = env::current_dir().ok()?;
+    return cwd.ancestors()
+        .map(|dir| dir.join(PROJECT_PALETTE_DIR))
+        .find(|dir| dir.is_dir());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    use super::*;
+
+    // A fresh directory per test and process, so parallel runs do not see each other's files.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("pix-search-path-{}-{}", name, std::process::id()));
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        return dir;
+    }
+
+    fn search_path(dirs: Vec<(PaletteSource, PathBuf)>) -> PaletteSearchPath {
+        return PaletteSearchPath {
+            dirs,
+            include_builtin: true,
+        };
+    }
+
+    #[test]
+    fn bare_names_resolve_to_files_before_builtins() {
+        let user = scratch_dir("precedence");
+        write(user.join("warm.hex"), "FF0000\n").unwrap();
+        write(user.join("nyx8.hex"), "000000\n").unwrap();
+        let path = search_path(vec![(PaletteSource::ENV, user.join("missing")), (PaletteSource::USER, user.clone())]);
+
+        assert!(matches!(path.resolve("warm"), Ok(PaletteLocation::File(file)) if file == user.join("warm.hex")));
+        assert!(matches!(path.resolve("WARM.hex"), Ok(PaletteLocation::File(_))));
+        assert!(matches!(path.resolve("nyx8"), Ok(PaletteLocation::File(file)) if file == user.join("nyx8.hex")));
+        assert!(matches!(path.resolve("endesga-32"), Ok(PaletteLocation::Builtin("endesga-32"))));
+        assert!(path.resolve("no-such-palette").unwrap_err().contains("not found"));
+
+        let listed: Vec<String> = path.palettes().iter().map(PaletteLocation::to_string).collect();
+        assert_eq!(listed.iter().filter(|l| l.ends_with("nyx8") || l.ends_with("nyx8.hex")).count(), 1);
+        assert!(listed.contains(&user.join("nyx8.hex").display().to_string()));
+        remove_dir_all(user).unwrap();
+    }
+
+    #[test]
+    fn names_in_several_places_are_ambiguous() {
+        let root = scratch_dir("ambiguous");
+        let (env_dir, project) = (root.join("env"), root.join("project"));
+        create_dir_all(&env_dir).unwrap();
+        create_dir_all(&project).unwrap();
+        write(env_dir.join("warm.hex"), "FF0000\n").unwrap();
+        write(project.join("warm.hex"), "FF8800\n").unwrap();
+
+        let path = search_path(vec![(PaletteSource::ENV, env_dir.clone()), (PaletteSource::PROJECT, project.clone())]);
+        let err = path.resolve("warm").unwrap_err();
+        assert!(err.contains("ambiguous"));
+        assert!(err.contains(&env_dir.join("warm.hex").display().to_string()));
+        assert!(err.contains(&project.join("warm.hex").display().to_string()));
+
+        // The same directory twice is still one file.
+        let twice = search_path(vec![(PaletteSource::ENV, project.clone()), (PaletteSource::PROJECT, project.clone())]);
+        assert!(twice.resolve("warm").is_ok());
+
+        // Within one directory only a case-insensitive clash is ambiguous, the exact file name is not.
+        write(project.join("Cool.hex"), "0000FF\n").unwrap();
+        write(project.join("cool.HEX"), "0088FF\n").unwrap();
+        let project_only = search_path(vec![(PaletteSource::PROJECT, project.clone())]);
+        assert!(project_only.resolve("cool").unwrap_err().contains("ambiguous"));
+        assert!(matches!(project_only.resolve("Cool.hex"), Ok(PaletteLocation::File(file)) if file == project.join("Cool.hex")));
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn save_dir_prefers_user_writable_directories() {
+        let (env_dir, user, project) = (PathBuf::from("env"), PathBuf::from("user"), PathBuf::from("project"));
+
+        let all = search_path(vec![
+            (PaletteSource::PROJECT, project.clone()),
+            (PaletteSource::USER, user.clone()),
+            (PaletteSource::ENV, env_dir.clone()),
+        ]);
+        assert_eq!(all.save_dir(), user);
+        assert_eq!(search_path(vec![(PaletteSource::ENV, env_dir.clone()), (PaletteSource::USER, user)]).save_dir(), env_dir);
+        assert_eq!(search_path(vec![(PaletteSource::PROJECT, project.clone())]).save_dir(), project);
+        assert_eq!(search_path(Vec::new()).save_dir(), PathBuf::from(PROJECT_PALETTE_DIR));
+    }
+}
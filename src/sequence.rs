@@ -0,0 +1,338 @@
+use std::fs::{canonicalize, create_dir_all, read_dir};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView};
+
+use crate::alpha::{apply_alpha_policy, AlphaPolicy};
+use crate::animation::{limited_palette, Animation, AnimationFrame, AnimationSaveOptions, FramePalette};
+use crate::colour::SelectionStrategy;
+use crate::image::{apply_palette, pixelate_image, save_image, Extension, SaveOptions};
+use crate::palette::Palette;
+use crate::temporal::{dither_frames, TemporalDither};
+use crate::utils::available_threads;
+
+// Frames are shrunk to this size before they are compared for scene changes.
+const SCENE_THUMBNAIL_SIZE: u32 = 32;
+// Longest side of the copies the clip palettes are generated from.
+const PALETTE_SAMPLE_SIZE: u32 = 128;
+
+pub enum ClipPalette {
+    // The same existing palette for every frame.
+    FIXED(Palette),
+    // One palette generated across the whole clip.
+    GLOBAL,
+    // A palette generated per scene, a new scene starts where the mean channel difference between
+    // consecutive frames, from 0 to 1, exceeds the threshold.
+    SCENES(f32),
+}
+
+pub struct SequenceOptions {
+    pub palette: ClipPalette,
+    // Colours in each generated palette.
+    pub num_colours: usize,
+    pub strategy: SelectionStrategy,
+    pub pixelate: Option<u32>,
+    pub dither: Option<TemporalDither>,
+    pub alpha_policy: AlphaPolicy,
+    // 0 uses every available thread.
+    pub threads: usize,
+}
+
+impl Default for SequenceOptions {
+    fn default() -> Self {
+        SequenceOptions {
+            palette: ClipPalette::GLOBAL,
+            num_colours: 255,
+            strategy: SelectionStrategy::Median,
+            pixelate: None,
+            dither: None,
+            alpha_policy: AlphaPolicy::KEEP,
+            threads: 0,
+        }
+    }
+}
+
+// A clip stored as one image per frame, such as the numbered frames ffmpeg extracts.
+pub struct FrameSequence {
+    pub directory: PathBuf,
+    pub frames: Vec<PathBuf>,
+}
+
+impl FrameSequence {
+    // Every readable image in the directory, in natural order so frame_10 follows frame_9.
+    pub fn new(directory: &str) -> Result<FrameSequence, String> {
+        let entries = read_dir(directory).map_err(|err| format!("Could not read {}: {}", directory, err))?;
+        let mut frames: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file() && path.to_str().and_then(Extension::from_path).is_some_and(|ext| ext != Extension::SVG))
+            .collect();
+        frames.sort_by_key(|path| frame_sort_key(path));
+
+        if frames.is_empty() {
+            return Err(format!("No frames found in {}", directory));
+        }
+        return Ok(FrameSequence { directory: PathBuf::from(directory), frames });
+    }
+
+    // Ranges of frames between scene changes.
+    pub fn detect_scenes(&self, threshold: f32, threads: usize) -> Result<Vec<Range<usize>>, String> {
+        let thumbnails = parallel_map(self.frames.len(), threads, |i| {
+            let image = open_frame(&self.frames[i])?;
+            return Ok(image.resize_exact(SCENE_THUMBNAIL_SIZE, SCENE_THUMBNAIL_SIZE, FilterType::Triangle).to_rgb8());
+        })?;
+
+        let mut scenes = Vec::new();
+        let mut start = 0;
+        for i in 1..thumbnails.len() {
+            let total: u64 = thumbnails[i].as_raw().iter()
+                .zip(thumbnails[i - 1].as_raw())
+                .map(|(a, b)| a.abs_diff(*b) as u64)
+                .sum();
+            let difference = total as f32 / (thumbnails[i].as_raw().len() as f32 * 255f32);
+            if difference > threshold {
+                scenes.push(start..i);
+                start = i;
+            }
+        }
+        scenes.push(start..thumbnails.len());
+        return Ok(scenes);
+    }
+
+    // Pixelates, palettizes and dithers every frame. Output ending in .gif or .png is written as an
+    // animation at the given frame rate, anything else is a directory the frames are written to under
+    // their own names.
+    pub fn process(&self, output: &str, fps: f32, options: &SequenceOptions) -> Result<(), String> {
+        let as_animation = matches!(Extension::from_path(output), Some(Extension::GIF) | Some(Extension::PNG));
+        if !as_animation {
+            self.check_output_directory(output)?;
+        }
+        let threads = if options.threads == 0 { available_threads() } else { options.threads };
+        let scenes = match options.palette {
+            ClipPalette::SCENES(threshold) => self.detect_scenes(threshold, threads)?,
+            _ => std::iter::once(0..self.frames.len()).collect(),
+        };
+
+        let palettes: Vec<Palette> = match &options.palette {
+            ClipPalette::FIXED(palette) => vec![palette.clone()],
+            _ => scenes.iter().map(|scene| self.generate_palette(scene.clone(), options, threads)).collect::<Result<_, _>>()?,
+        };
+        println!("INFO: {} frames, {} scenes", self.frames.len(), scenes.len());
+
+        // Diffusion carries state from frame to frame, so each scene is dithered in order on one
+        // thread. Every other mode handles frames on their own.
+        let units: Vec<(Range<usize>, usize)> = match options.dither {
+            Some(TemporalDither::DIFFUSION(..)) => scenes.iter().cloned().enumerate().map(|(s, scene)| (scene, s)).collect(),
+            _ => scenes.iter().enumerate().flat_map(|(s, scene)| scene.clone().map(move |i| (i..i + 1, s))).collect(),
+        };
+
+        if !as_animation {
+            create_dir_all(output).map_err(|err| format!("Could not create {}: {}", output, err))?;
+        }
+
+        let processed: Mutex<Vec<(usize, DynamicImage)>> = Mutex::new(Vec::new());
+        parallel_map(units.len(), threads, |u| {
+            let (range, scene) = &units[u];
+            let palette = &palettes[(*scene).min(palettes.len() - 1)];
+            let mut images = range.clone().map(|i| open_frame(&self.frames[i])).collect::<Result<Vec<_>, _>>()?;
+
+            for image in images.iter_mut() {
+                if let Some(scale) = options.pixelate {
+                    pixelate_image(image, scale, FilterType::Nearest);
+                }
+            }
+            match options.dither {
+                Some(mode) => dither_frames(&mut images, palette, mode),
+                None => images = images.into_iter().map(|image| apply_palette(image, palette.clone())).collect(),
+            }
+
+            for (i, mut image) in range.clone().zip(images) {
+                apply_alpha_policy(&mut image, options.alpha_policy, &palette.colours)?;
+                if as_animation {
+                    processed.lock().unwrap().push((i, image));
+                } else {
+                    let name = self.frames[i].file_name().unwrap().to_string_lossy();
+                    save_image(&image, &Path::new(output).join(name.as_ref()).to_string_lossy(), &SaveOptions::default())?;
+                }
+            }
+            return Ok(());
+        })?;
+
+        if !as_animation {
+            return Ok(());
+        }
+        let mut processed = processed.into_inner().unwrap();
+        processed.sort_by_key(|(i, _)| *i);
+        let delay_ms = (1000f32 / fps.max(0.01)).round() as u32;
+        let animation = Animation {
+            filename: output.to_string(),
+            frames: processed.into_iter().map(|(_, image)| AnimationFrame { image, delay_ms }).collect(),
+            loop_count: 0,
+            alpha_policy: options.alpha_policy,
+        };
+        // Scene palettes together can run past what one GIF palette holds.
+        let save_options = AnimationSaveOptions {
+            palette: if palettes.len() > 1 { FramePalette::LOCAL } else { FramePalette::GLOBAL },
+            ..AnimationSaveOptions::default()
+        };
+        return animation.save(output, &save_options);
+    }
+
+    // Frames are written under their own names, so writing them back to the input directory would
+    // overwrite the clip.
+    fn check_output_directory(&self, output: &str) -> Result<(), String> {
+        let same = match (canonicalize(output), canonicalize(&self.directory)) {
+            (Ok(output), Ok(directory)) => output == directory,
+            _ => false,
+        };
+        if same {
+            return Err(format!("The output directory {} is the input directory, its frames would be overwritten", output));
+        }
+        return Ok(());
+    }
+
+    // Palette over downsampled copies of the frames in the range, nearest neighbour keeps their colours exact.
+    fn generate_palette(&self, range: Range<usize>, options: &SequenceOptions, threads: usize) -> Result<Palette, String> {
+        let samples = parallel_map(range.len(), threads, |i| {
+            let mut image = open_frame(&self.frames[range.start + i])?;
+            if let Some(scale) = options.pixelate {
+                pixelate_image(&mut image, scale, FilterType::Nearest);
+            }
+            let (width, height) = image.dimensions();
+            if width.max(height) > PALETTE_SAMPLE_SIZE {
+                image = image.resize(PALETTE_SAMPLE_SIZE, PALETTE_SAMPLE_SIZE, FilterType::Nearest);
+            }
+            return Ok(image);
+        })?;
+
+        let images: Vec<&DynamicImage> = samples.iter().collect();
+        return Ok(Palette {
+            name: format!("scene-{}", range.start),
+            colours: limited_palette(&images, options.num_colours, options.strategy),
+        });
+    }
+}
+
+fn open_frame(path: &Path) -> Result<DynamicImage, String> {
+    return image::open(path).map_err(|err| format!("Could not open {}: {}", path.display(), err));
+}
+
+// Name with its last run of digits compared as a number.
+fn frame_sort_key(path: &Path) -> (String, u64, String) {
+    let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    let digits_start = stem.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+    let number = stem[digits_start..].parse::<u64>().unwrap_or(0);
+    return (stem[..digits_start].to_string(), number, name);
+}
+
+// Runs f for 0..count on up to the given number of threads, results come back in order and the first
+// error stops the remaining work.
+fn parallel_map<T: Send, F: Fn(usize) -> Result<T, String> + Sync>(count: usize, threads: usize, f: F) -> Result<Vec<T>, String> {
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<T>>> = Mutex::new((0..count).map(|_| None).collect());
+    let error: Mutex<Option<String>> = Mutex::new(None);
+
+    thread::scope(|scope| {
+        for _ in 0..threads.clamp(1, count.max(1)) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= count || error.lock().unwrap().is_some() {
+                    break;
+                }
+                match f(i) {
+                    Ok(result) => results.lock().unwrap()[i] = Some(result),
+                    Err(err) => *error.lock().unwrap() = Some(err),
+                }
+            });
+        }
+    });
+
+    if let Some(err) = error.into_inner().unwrap() {
+        return Err(err);
+    }
+    return Ok(results.into_inner().unwrap().into_iter().map(|result| result.unwrap()).collect());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{remove_dir_all, write};
+    use std::time::Duration;
+
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    // A fresh directory holding one flat frame per level, named frame_<index>.png.
+    fn clip(name: &str, levels: &[u8]) -> PathBuf {
+        let directory = env::temp_dir().join(format!("pix-sequence-{}-{}", name, std::process::id()));
+        let _ = remove_dir_all(&directory);
+        create_dir_all(&directory).unwrap();
+        for (i, level) in levels.iter().enumerate() {
+            RgbImage::from_pixel(8, 8, Rgb([*level; 3])).save(directory.join(format!("frame_{}.png", i))).unwrap();
+        }
+        return directory;
+    }
+
+    #[test]
+    fn frames_sort_by_the_number_in_their_name() {
+        let mut names = vec!["frame_10.png", "frame_9.png", "frame_100.png", "frame_1.png", "a.png", "frame_02.png"];
+        names.sort_by_key(|name| frame_sort_key(Path::new(name)));
+        assert_eq!(names, vec!["a.png", "frame_1.png", "frame_02.png", "frame_9.png", "frame_10.png", "frame_100.png"]);
+    }
+
+    #[test]
+    fn sequence_skips_files_that_are_not_frames() {
+        let directory = clip("listing", &[0; 11]);
+        write(directory.join("notes.txt"), "").unwrap();
+        write(directory.join("frame_3.svg"), "").unwrap();
+        let sequence = FrameSequence::new(directory.to_str().unwrap()).unwrap();
+        remove_dir_all(&directory).unwrap();
+
+        let names: Vec<String> = sequence.frames.iter().map(|f| f.file_name().unwrap().to_string_lossy().to_string()).collect();
+        assert_eq!(names.len(), 11);
+        assert_eq!(names[..3], ["frame_0.png", "frame_1.png", "frame_2.png"]);
+        assert_eq!(names[10], "frame_10.png");
+    }
+
+    #[test]
+    fn scenes_split_where_frames_jump() {
+        let directory = clip("scenes", &[0, 5, 10, 250, 245, 20, 20]);
+        let sequence = FrameSequence::new(directory.to_str().unwrap()).unwrap();
+        let scenes = sequence.detect_scenes(0.5, 2).unwrap();
+        remove_dir_all(&directory).unwrap();
+
+        assert_eq!(scenes, vec![0..3, 3..5, 5..7]);
+    }
+
+    #[test]
+    fn parallel_map_keeps_the_order_and_reports_errors() {
+        let results = parallel_map(12, 4, |i| {
+            thread::sleep(Duration::from_millis(((12 - i) * 2) as u64));
+            return Ok(i * i);
+        });
+        assert_eq!(results.unwrap(), (0..12).map(|i| i * i).collect::<Vec<usize>>());
+
+        let failed = parallel_map(12, 4, |i| if i == 7 { Err(format!("frame {}", i)) } else { Ok(i) });
+        assert_eq!(failed.err(), Some("frame 7".to_string()));
+        assert!(parallel_map(0, 4, Ok).unwrap().is_empty());
+    }
+
+    #[test]
+    fn output_directory_cannot_be_the_input_directory() {
+        let directory = clip("overwrite", &[0, 255]);
+        let sequence = FrameSequence::new(directory.to_str().unwrap()).unwrap();
+        let same = directory.join(".");
+        let result = sequence.process(same.to_str().unwrap(), 12f32, &SequenceOptions::default());
+        let written = read_dir(&directory).unwrap().count();
+        remove_dir_all(&directory).unwrap();
+
+        assert!(result.is_err());
+        assert_eq!(written, 2);
+    }
+}
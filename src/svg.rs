@@ -0,0 +1,363 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as FmtWrite;
+use std::fs::write;
+
+use image::{DynamicImage, Rgba, RgbaImage};
+
+use crate::utils::rgb_to_hex;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum SvgMode {
+    // Maximal horizontal runs of one colour.
+    RUNS,
+    // Greedy rectangles, runs grown downwards while the rows below match.
+    RECTANGLES,
+    // Outlines traced around every same-coloured region.
+    PATHS,
+}
+
+impl SvgMode {
+    pub fn new(name: &str) -> Result<SvgMode, &'static str> {
+        let mode = match name.to_lowercase().as_str() {
+            "runs" => SvgMode::RUNS,
+            "rectangles" => SvgMode::RECTANGLES,
+            "paths" => SvgMode::PATHS,
+
+            _ => return Err("Unknown SVG mode")
+        };
+
+        return Ok(mode);
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct SvgOptions {
+    pub mode: SvgMode,
+    pub crisp_edges: bool,
+    // Output size of one pixel, the coordinates inside the viewBox stay in pixels.
+    pub scale: u32,
+}
+
+impl Default for SvgOptions {
+    fn default() -> Self {
+        SvgOptions {
+            mode: SvgMode::PATHS,
+            crisp_edges: true,
+            scale: 1,
+        }
+    }
+}
+
+// One <path> per colour with a class fill from <defs>, fully transparent pixels are left out.
+pub fn image_to_svg(img: &DynamicImage, options: &SvgOptions) -> String {
+    let pixels = img.to_rgba8();
+    let (width, height) = pixels.dimensions();
+    let colours = distinct_colours(&pixels);
+
+    let mut svg = String::new();
+    let rendering = if options.crisp_edges { " shape-rendering=\"crispEdges\"" } else { "" };
+    let _ = writeln!(
+        svg,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\"{}>",
+        width * options.scale.max(1), height * options.scale.max(1), width, height, rendering
+    );
+
+    svg.push_str("<defs><style>");
+    for (index, colour) in colours.iter().enumerate() {
+        let _ = write!(svg, ".c{}{{fill:#{}", index, rgb_to_hex(image::Rgb([colour[0], colour[1], colour[2]])));
+        if colour[3] < 255 {
+            let _ = write!(svg, ";fill-opacity:{:.3}", colour[3] as f32 / 255f32);
+        }
+        svg.push('}');
+    }
+    svg.push_str("</style></defs>\n");
+
+    let index_of: HashMap<Rgba<u8>, usize> = colours.iter().enumerate().map(|(i, c)| (*c, i)).collect();
+    let mut shapes: Vec<String> = vec![String::new(); colours.len()];
+    match options.mode {
+        SvgMode::RUNS => {
+            for (x, y, w, h, colour) in runs(&pixels) {
+                let _ = write!(shapes[index_of[&colour]], "M{} {}h{}v{}h-{}z", x, y, w, h, w);
+            }
+        },
+        SvgMode::RECTANGLES => {
+            for (x, y, w, h, colour) in rectangles(&pixels) {
+                let _ = write!(shapes[index_of[&colour]], "M{} {}h{}v{}h-{}z", x, y, w, h, w);
+            }
+        },
+        SvgMode::PATHS => {
+            for (index, colour) in colours.iter().enumerate() {
+                shapes[index] = trace_outlines(&pixels, *colour);
+            }
+        },
+    }
+
+    for (index, shape) in shapes.iter().enumerate() {
+        let _ = writeln!(svg, "<path class=\"c{}\" d=\"{}\"/>", index, shape);
+    }
+    svg.push_str("</svg>\n");
+
+    return svg;
+}
+
+pub fn save_svg(img: &DynamicImage, file_path: &str, options: &SvgOptions) -> Result<(), String> {
+    return write(file_path, image_to_svg(img, options)).map_err(|err| format!("Unable to save {}: {}", file_path, err));
+}
+
+fn distinct_colours(pixels: &RgbaImage) -> Vec<Rgba<u8>> {
+    let mut seen: HashSet<Rgba<u8>> = HashSet::new();
+    let mut colours = Vec::new();
+    for pixel in pixels.pixels().filter(|p| p[3] > 0) {
+        if seen.insert(*pixel) {
+            colours.push(*pixel);
+        }
+    }
+    return colours;
+}
+
+fn runs(pixels: &RgbaImage) -> Vec<(u32, u32, u32, u32, Rgba<u8>)> {
+    let (width, height) = pixels.dimensions();
+    let mut result = Vec::new();
+
+    for y in 0..height {
+        let mut x = 0;
+        while x < width {
+            let colour = *pixels.get_pixel(x, y);
+            let mut end = x + 1;
+            while end < width && *pixels.get_pixel(end, y) == colour {
+                end += 1;
+            }
+            if colour[3] > 0 {
+                result.push((x, y, end - x, 1, colour));
+            }
+            x = end;
+        }
+    }
+    return result;
+}
+
+fn rectangles(pixels: &RgbaImage) -> Vec<(u32, u32, u32, u32, Rgba<u8>)> {
+    let (width, height) = pixels.dimensions();
+    let mut covered = vec![false; (width * height) as usize];
+    let mut result = Vec::new();
+    let free = |covered: &[bool], x: u32, y: u32, colour: Rgba<u8>| {
+        !covered[(y * width + x) as usize] && *pixels.get_pixel(x, y) == colour
+    };
+
+    for y in 0..height {
+        for x in 0..width {
+            let colour = *pixels.get_pixel(x, y);
+            if covered[(y * width + x) as usize] || colour[3] == 0 {
+                continue;
+            }
+
+            let mut w = 1;
+            while x + w < width && free(&covered, x + w, y, colour) {
+                w += 1;
+            }
+            let mut h = 1;
+            while y + h < height && (x..x + w).all(|rx| free(&covered, rx, y + h, colour)) {
+                h += 1;
+            }
+
+            for ry in y..y + h {
+                for rx in x..x + w {
+                    covered[(ry * width + rx) as usize] = true;
+                }
+            }
+            result.push((x, y, w, h, colour));
+        }
+    }
+    return result;
+}
+
+// Edges run clockwise around each pixel and are only kept where the neighbour has another colour, so
+// they chain into outer outlines and holes with opposite winding that fill correctly under nonzero.
+fn trace_outlines(pixels: &RgbaImage, colour: Rgba<u8>) -> String {
+    let (width, height) = pixels.dimensions();
+    let same = |x: i64, y: i64| {
+        x >= 0 && y >= 0 && x < width as i64 && y < height as i64 && *pixels.get_pixel(x as u32, y as u32) == colour
+    };
+
+    let mut edges: HashMap<(i64, i64), Vec<(i64, i64)>> = HashMap::new();
+    for y in 0..height as i64 {
+        for x in 0..width as i64 {
+            if !same(x, y) {
+                continue;
+            }
+            let mut add = |from: (i64, i64), to: (i64, i64)| edges.entry(from).or_default().push(to);
+            if !same(x, y - 1) { add((x, y), (x + 1, y)); }
+            if !same(x + 1, y) { add((x + 1, y), (x + 1, y + 1)); }
+            if !same(x, y + 1) { add((x + 1, y + 1), (x, y + 1)); }
+            if !same(x - 1, y) { add((x, y + 1), (x, y)); }
+        }
+    }
+
+    let mut starts: Vec<(i64, i64)> = edges.keys().copied().collect();
+    starts.sort_by_key(|(x, y)| (*y, *x));
+
+    let mut d = String::new();
+    for start in starts {
+        while edges.get(&start).is_some_and(|ends| !ends.is_empty()) {
+            let mut outline = vec![start];
+            let mut current = start;
+            loop {
+                let next = edges.get_mut(&current).and_then(|ends| ends.pop()).unwrap();
+                if next == start {
+                    break;
+                }
+                outline.push(next);
+                current = next;
+            }
+            write_outline(&mut d, &outline);
+        }
+    }
+    return d;
+}
+
+// Drops the points in the middle of straight segments and writes the rest as H/V commands.
+fn write_outline(d: &mut String, outline: &[(i64, i64)]) {
+    let n = outline.len();
+    let corners: Vec<(i64, i64)> = (0..n)
+        .filter(|i| {
+            let prev = outline[(i + n - 1) % n];
+            let next = outline[(i + 1) % n];
+            let point = outline[*i];
+            !(prev.0 == point.0 && point.0 == next.0 || prev.1 == point.1 && point.1 == next.1)
+        })
+        .map(|i| outline[i])
+        .collect();
+    if corners.is_empty() {
+        return;
+    }
+
+    let _ = write!(d, "M{} {}", corners[0].0, corners[0].1);
+    for pair in corners.windows(2) {
+        if pair[0].0 == pair[1].0 {
+            let _ = write!(d, "V{}", pair[1].1);
+        } else {
+            let _ = write!(d, "H{}", pair[1].0);
+        }
+    }
+    d.push('z');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: Rgba<u8> = Rgba([200, 30, 40, 255]);
+    const B: Rgba<u8> = Rgba([20, 120, 60, 255]);
+    const CLEAR: Rgba<u8> = Rgba([0, 0, 0, 0]);
+
+    fn image(rows: &[&[Rgba<u8>]]) -> DynamicImage {
+        return DynamicImage::ImageRgba8(RgbaImage::from_fn(rows[0].len() as u32, rows.len() as u32, |x, y| rows[y as usize][x as usize]));
+    }
+
+    // The d attribute of every <path>, in class order.
+    fn paths(svg: &str) -> Vec<String> {
+        return svg.lines()
+            .filter_map(|line| line.strip_prefix("<path class=\"c"))
+            .map(|rest| rest.split("d=\"").nth(1).unwrap().trim_end_matches("\"/>").to_string())
+            .collect();
+    }
+
+    // Signed area of each subpath, positive for clockwise outlines in image coordinates.
+    fn subpath_areas(d: &str) -> Vec<i64> {
+        let mut areas = Vec::new();
+        let mut points: Vec<(i64, i64)> = Vec::new();
+        let mut chars = d.chars().peekable();
+        let number = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+            while chars.peek() == Some(&' ') {
+                chars.next();
+            }
+            let mut text = String::new();
+            while chars.peek().is_some_and(|c| c.is_ascii_digit() || *c == '-') {
+                text.push(chars.next().unwrap());
+            }
+            text.parse::<i64>().unwrap()
+        };
+        while let Some(command) = chars.next() {
+            let (x, y) = points.last().copied().unwrap_or((0, 0));
+            match command {
+                'M' => { let x = number(&mut chars); let y = number(&mut chars); points = vec![(x, y)]; },
+                'H' => { let x = number(&mut chars); points.push((x, y)); },
+                'V' => { let y = number(&mut chars); points.push((x, y)); },
+                'h' => { let dx = number(&mut chars); points.push((x + dx, y)); },
+                'v' => { let dy = number(&mut chars); points.push((x, y + dy)); },
+                'z' => {
+                    let n = points.len();
+                    let twice: i64 = (0..n).map(|i| points[i].0 * points[(i + 1) % n].1 - points[(i + 1) % n].0 * points[i].1).sum();
+                    areas.push(twice / 2);
+                },
+                _ => panic!("unexpected command {}", command),
+            }
+        }
+        return areas;
+    }
+
+    fn svg(img: &DynamicImage, mode: SvgMode) -> String {
+        return image_to_svg(img, &SvgOptions { mode, ..SvgOptions::default() });
+    }
+
+    #[test]
+    fn runs_are_one_row_high() {
+        let img = image(&[&[A, A, B], &[A, A, A]]);
+        assert_eq!(paths(&svg(&img, SvgMode::RUNS)), vec!["M0 0h2v1h-2zM0 1h3v1h-3z", "M2 0h1v1h-1z"]);
+    }
+
+    #[test]
+    fn rectangles_grow_down_over_matching_rows() {
+        let img = image(&[&[A, A, B], &[A, A, B], &[A, B, B]]);
+        assert_eq!(paths(&svg(&img, SvgMode::RECTANGLES)), vec!["M0 0h2v2h-2zM0 2h1v1h-1z", "M2 0h1v3h-1zM1 2h1v1h-1z"]);
+    }
+
+    #[test]
+    fn paths_merge_a_region_into_one_outline() {
+        let img = image(&[&[A, A, B], &[A, A, B]]);
+        assert_eq!(paths(&svg(&img, SvgMode::PATHS)), vec!["M0 0H2V2H0z", "M2 0H3V2H2z"]);
+    }
+
+    #[test]
+    fn holes_wind_the_other_way() {
+        let img = image(&[&[A, A, A], &[A, B, A], &[A, A, A]]);
+        let shapes = paths(&svg(&img, SvgMode::PATHS));
+
+        let mut ring = subpath_areas(&shapes[0]);
+        ring.sort();
+        assert_eq!(ring, vec![-1, 9]);
+        assert_eq!(subpath_areas(&shapes[1]), vec![1]);
+    }
+
+    #[test]
+    fn every_mode_covers_each_colour_exactly() {
+        let mut seed = 7u32;
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(9, 7, |_, _| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            [A, B, CLEAR][(seed >> 16) as usize % 3]
+        }));
+        let pixels = img.to_rgba8();
+
+        for mode in [SvgMode::RUNS, SvgMode::RECTANGLES, SvgMode::PATHS] {
+            let output = svg(&img, mode);
+            let colours = distinct_colours(&pixels);
+            let shapes = paths(&output);
+            assert_eq!(shapes.len(), colours.len());
+
+            for (colour, shape) in colours.iter().zip(&shapes) {
+                let count = pixels.pixels().filter(|p| *p == colour).count() as i64;
+                assert_eq!(subpath_areas(shape).iter().sum::<i64>(), count);
+            }
+        }
+    }
+
+    #[test]
+    fn transparency_and_scale_reach_the_markup() {
+        let img = image(&[&[CLEAR, Rgba([255, 0, 0, 128])]]);
+        let output = image_to_svg(&img, &SvgOptions { scale: 4, crisp_edges: false, ..SvgOptions::default() });
+
+        assert!(output.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"8\" height=\"4\" viewBox=\"0 0 2 1\">"));
+        assert!(output.contains(".c0{fill:#FF0000;fill-opacity:0.502}"));
+        assert_eq!(paths(&output), vec!["M1 0H2V1H1z"]);
+    }
+}
@@ -0,0 +1,343 @@
+use std::sync::OnceLock;
+
+use image::{DynamicImage, Rgb, RgbImage, RgbaImage};
+
+use crate::alpha::{restore_alpha, split_alpha};
+use crate::ditherer::{bayer_threshold, generate_bayer_matrix};
+use crate::palette::Palette;
+
+pub const BLUE_NOISE_SIZE: usize = 64;
+const BLUE_NOISE_SIGMA: f32 = 1.5;
+
+// x, y, width, height
+pub type Rectangle = (u32, u32, u32, u32);
+
+// Every mode keeps pixels whose source colour did not change identical between frames.
+#[derive(Copy, Clone)]
+pub enum TemporalDither {
+    // Bayer thresholds of the given order, fixed to screen position.
+    ORDERED(u32),
+    // Void-and-cluster blue noise thresholds, tiled and fixed to screen position.
+    BLUENOISE,
+    // Error diffusion with the given matrix that only runs over pixels whose largest channel difference
+    // from the source their held output was dithered from exceeds the tolerance, every other pixel
+    // repeats the previous output. Slow fades still update once they drift past the tolerance.
+    DIFFUSION(u8, &'static [((i32, i32), f32)]),
+}
+
+// Dithers all frames to the palette, alpha is carried through untouched.
+pub fn dither_frames(frames: &mut [DynamicImage], palette: &Palette, mode: TemporalDither) {
+    if palette.colours.is_empty() {
+        return;
+    }
+    let colours: Vec<[f32; 3]> = palette.colours.iter().map(|c| c.0.map(|v| v as f32)).collect();
+    let spread = palette_spread(&colours);
+
+    match mode {
+        TemporalDither::ORDERED(order) => {
+            let matrix = generate_bayer_matrix(order);
+            let threshold = |x: u32, y: u32| bayer_threshold(&matrix, order, x, y) / 255f32;
+            for frame in frames.iter_mut() {
+                threshold_dither(frame, &colours, spread, &threshold);
+            }
+        },
+        TemporalDither::BLUENOISE => {
+            let noise = blue_noise_texture();
+            let threshold = |x: u32, y: u32| noise[(y as usize % BLUE_NOISE_SIZE) * BLUE_NOISE_SIZE + x as usize % BLUE_NOISE_SIZE];
+            for frame in frames.iter_mut() {
+                threshold_dither(frame, &colours, spread, &threshold);
+            }
+        },
+        TemporalDither::DIFFUSION(tolerance, diff_mat) => {
+            let mut previous: Option<(RgbImage, RgbImage)> = None;
+            for frame in frames.iter_mut() {
+                let (source, alpha) = split_alpha(frame);
+                let (held, dithered) = diffuse_changed(&source.to_rgb8(), previous.as_ref(), &colours, tolerance, diff_mat);
+                *frame = restore_alpha(DynamicImage::ImageRgb8(dithered.clone()), alpha);
+                previous = Some((held, dithered));
+            }
+        },
+    }
+}
+
+// Bounding box of the pixels that differ, None for identical frames.
+pub fn delta_rectangle(previous: &RgbaImage, current: &RgbaImage) -> Option<Rectangle> {
+    let (width, height) = current.dimensions();
+    if previous.dimensions() != (width, height) {
+        return Some((0, 0, width, height));
+    }
+
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (u32::MAX, u32::MAX, 0, 0);
+    for (x, y, pixel) in current.enumerate_pixels() {
+        if previous.get_pixel(x, y) != pixel {
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+        }
+    }
+
+    if min_x == u32::MAX {
+        return None;
+    }
+    return Some((min_x, min_y, max_x - min_x + 1, max_y - min_y + 1));
+}
+
+fn threshold_dither<F: Fn(u32, u32) -> f32>(frame: &mut DynamicImage, colours: &[[f32; 3]], spread: f32, threshold: &F) {
+    let (source, alpha) = split_alpha(frame);
+    let mut pixels = source.to_rgb8();
+
+    for (x, y, pixel) in pixels.enumerate_pixels_mut() {
+        let offset = (threshold(x, y) - 0.5) * spread;
+        let shifted = pixel.0.map(|v| v as f32 + offset);
+        *pixel = to_rgb(colours[nearest(&shifted, colours)]);
+    }
+
+    *frame = restore_alpha(DynamicImage::ImageRgb8(pixels), alpha);
+}
+
+// Previous is the held source and output of the frame before. The held source of a pixel is the source
+// value its output was last dithered from, returned alongside the new output for the next frame.
+fn diffuse_changed(
+    source: &RgbImage,
+    previous: Option<&(RgbImage, RgbImage)>,
+    colours: &[[f32; 3]],
+    tolerance: u8,
+    diff_mat: &[((i32, i32), f32)],
+) -> (RgbImage, RgbImage) {
+    let (width, height) = source.dimensions();
+    let changed = |x: u32, y: u32| match previous {
+        Some((held, _)) if held.dimensions() == (width, height) => {
+            let (a, b) = (source.get_pixel(x, y), held.get_pixel(x, y));
+            (0..3).any(|i| a[i].abs_diff(b[i]) > tolerance)
+        },
+        _ => true,
+    };
+
+    let mut error = vec![[0f32; 3]; (width * height) as usize];
+    let mut output = RgbImage::new(width, height);
+    let mut held = source.clone();
+    for y in 0..height {
+        for x in 0..width {
+            if !changed(x, y) {
+                let (previous_held, previous_output) = previous.unwrap();
+                output.put_pixel(x, y, *previous_output.get_pixel(x, y));
+                held.put_pixel(x, y, *previous_held.get_pixel(x, y));
+                continue;
+            }
+
+            let index = (y * width + x) as usize;
+            let pixel = source.get_pixel(x, y);
+            let wanted = [0, 1, 2].map(|i| pixel[i] as f32 + error[index][i]);
+            let chosen = colours[nearest(&wanted, colours)];
+            output.put_pixel(x, y, to_rgb(chosen));
+
+            // Only changed pixels receive error, so static regions never pick up noise from moving ones.
+            for &((dx, dy), coeff) in diff_mat {
+                let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+                if nx < 0 || ny < 0 || nx >= width as i32 || ny >= height as i32 || !changed(nx as u32, ny as u32) {
+                    continue;
+                }
+                let target = &mut error[(ny as u32 * width + nx as u32) as usize];
+                for i in 0..3 {
+                    target[i] += (wanted[i] - chosen[i]) * coeff;
+                }
+            }
+        }
+    }
+    return (held, output);
+}
+
+fn nearest(colour: &[f32; 3], colours: &[[f32; 3]]) -> usize {
+    let distance = |c: &[f32; 3]| (0..3).map(|i| (colour[i] - c[i]).powi(2)).sum::<f32>();
+    (0..colours.len())
+        .min_by(|a, b| distance(&colours[*a]).total_cmp(&distance(&colours[*b])))
+        .unwrap()
+}
+
+fn to_rgb(colour: [f32; 3]) -> Rgb<u8> {
+    Rgb(colour.map(|v| v.round().clamp(0f32, 255f32) as u8))
+}
+
+// Mean distance from each palette colour to its nearest neighbour, the threshold range that moves a
+// colour about one palette step.
+fn palette_spread(colours: &[[f32; 3]]) -> f32 {
+    if colours.len() < 2 {
+        return 0f32;
+    }
+
+    let total: f32 = colours.iter().enumerate()
+        .map(|(i, a)| {
+            colours.iter().enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(_, b)| (0..3).map(|k| (a[k] - b[k]).powi(2)).sum::<f32>().sqrt())
+                .fold(f32::INFINITY, f32::min)
+        })
+        .sum();
+    return total / colours.len() as f32;
+}
+
+// Computed once per process, the texture is the same on every call.
+pub fn blue_noise_texture() -> &'static [f32] {
+    static TEXTURE: OnceLock<Vec<f32>> = OnceLock::new();
+    TEXTURE.get_or_init(void_and_cluster)
+}
+
+// Ulichney's void-and-cluster method on a torus, returns thresholds in [0, 1).
+fn void_and_cluster() -> Vec<f32> {
+    let size = BLUE_NOISE_SIZE;
+    let n = size * size;
+
+    let mut kernel = vec![0f32; n];
+    for dy in 0..size {
+        for dx in 0..size {
+            let wx = dx.min(size - dx) as f32;
+            let wy = dy.min(size - dy) as f32;
+            kernel[dy * size + dx] = (-(wx * wx + wy * wy) / (2f32 * BLUE_NOISE_SIGMA * BLUE_NOISE_SIGMA)).exp();
+        }
+    }
+    let splat = |energy: &mut [f32], p: usize, sign: f32| {
+        let (px, py) = (p % size, p / size);
+        for y in 0..size {
+            for x in 0..size {
+                let k = ((y + size - py) % size) * size + (x + size - px) % size;
+                energy[y * size + x] += sign * kernel[k];
+            }
+        }
+    };
+    let tightest_cluster = |pattern: &[bool], energy: &[f32]| {
+        (0..n).filter(|i| pattern[*i]).max_by(|a, b| energy[*a].total_cmp(&energy[*b])).unwrap()
+    };
+    let largest_void = |pattern: &[bool], energy: &[f32]| {
+        (0..n).filter(|i| !pattern[*i]).min_by(|a, b| energy[*a].total_cmp(&energy[*b])).unwrap()
+    };
+
+    // Deterministic initial pattern so the texture, and every dithered frame, is reproducible.
+    let mut pattern = vec![false; n];
+    let mut seed: u32 = 0x9E3779B9;
+    let initial = n / 10;
+    let mut placed = 0;
+    while placed < initial {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        let p = seed as usize % n;
+        if !pattern[p] {
+            pattern[p] = true;
+            placed += 1;
+        }
+    }
+
+    let mut energy = vec![0f32; n];
+    for p in (0..n).filter(|p| pattern[*p]) {
+        splat(&mut energy, p, 1f32);
+    }
+    loop {
+        let cluster = tightest_cluster(&pattern, &energy);
+        pattern[cluster] = false;
+        splat(&mut energy, cluster, -1f32);
+        let void = largest_void(&pattern, &energy);
+        pattern[void] = true;
+        splat(&mut energy, void, 1f32);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut rank = vec![0usize; n];
+    let prototype = pattern.clone();
+    let prototype_energy = energy.clone();
+
+    // Ranks below the initial pattern by taking out the tightest clusters.
+    for r in (0..initial).rev() {
+        let cluster = tightest_cluster(&pattern, &energy);
+        pattern[cluster] = false;
+        splat(&mut energy, cluster, -1f32);
+        rank[cluster] = r;
+    }
+
+    // Ranks above it by filling the largest voids.
+    let mut pattern = prototype;
+    let mut energy = prototype_energy;
+    for r in initial..n {
+        let void = largest_void(&pattern, &energy);
+        pattern[void] = true;
+        splat(&mut energy, void, 1f32);
+        rank[void] = r;
+    }
+
+    return rank.into_iter().map(|r| (r as f32 + 0.5) / n as f32).collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    fn flat(width: u32, height: u32) -> RgbaImage {
+        return RgbaImage::from_pixel(width, height, Rgba([40, 80, 120, 255]));
+    }
+
+    #[test]
+    fn identical_frames_have_no_delta() {
+        assert_eq!(delta_rectangle(&flat(6, 4), &flat(6, 4)), None);
+    }
+
+    #[test]
+    fn delta_is_the_bounding_box_of_the_changes() {
+        let mut changed = flat(6, 4);
+        changed.put_pixel(1, 2, Rgba([0, 0, 0, 255]));
+        changed.put_pixel(4, 1, Rgba([40, 80, 120, 0]));
+        assert_eq!(delta_rectangle(&flat(6, 4), &changed), Some((1, 1, 4, 2)));
+
+        let mut corner = flat(6, 4);
+        corner.put_pixel(5, 3, Rgba([41, 80, 120, 255]));
+        assert_eq!(delta_rectangle(&flat(6, 4), &corner), Some((5, 3, 1, 1)));
+    }
+
+    const BLACK_WHITE: [[f32; 3]; 2] = [[0f32; 3], [255f32; 3]];
+    const FLOYD_STEINBERG: [((i32, i32), f32); 4] = [((1, 0), 7f32 / 16f32), ((-1, 1), 3f32 / 16f32), ((0, 1), 5f32 / 16f32), ((1, 1), 1f32 / 16f32)];
+
+    fn grey(level: u8) -> RgbImage {
+        return RgbImage::from_pixel(8, 8, Rgb([level; 3]));
+    }
+
+    fn diffuse(frames: &[RgbImage], tolerance: u8) -> Vec<RgbImage> {
+        let mut previous: Option<(RgbImage, RgbImage)> = None;
+        let mut outputs = Vec::new();
+        for frame in frames {
+            let (held, output) = diffuse_changed(frame, previous.as_ref(), &BLACK_WHITE, tolerance, &FLOYD_STEINBERG);
+            outputs.push(output.clone());
+            previous = Some((held, output));
+        }
+        return outputs;
+    }
+
+    #[test]
+    fn static_clip_repeats_the_first_output() {
+        let outputs = diffuse(&vec![grey(100); 6], 4);
+        assert!(outputs.iter().all(|output| *output == outputs[0]));
+        assert!(outputs[0].pixels().any(|p| p[0] == 255) && outputs[0].pixels().any(|p| p[0] == 0));
+    }
+
+    #[test]
+    fn slow_fade_updates_once_it_drifts_past_the_tolerance() {
+        // One level per frame never differs from the frame before by more than the tolerance.
+        let frames: Vec<RgbImage> = (0..=40).map(|i| grey(100 + i)).collect();
+        let outputs = diffuse(&frames, 4);
+
+        assert_eq!(outputs[4], outputs[0]);
+        assert_ne!(outputs[5], outputs[0]);
+        let white = |output: &RgbImage| output.pixels().filter(|p| p[0] == 255).count();
+        assert!(white(&outputs[40]) > white(&outputs[0]));
+        // The held output still averages close to the final level of 140.
+        let mean = outputs[40].pixels().map(|p| p[0] as f32).sum::<f32>() / 64f32;
+        assert!((mean - 140f32).abs() < 10f32, "{}", mean);
+    }
+
+    #[test]
+    fn resized_frames_are_redrawn_in_full() {
+        assert_eq!(delta_rectangle(&flat(6, 4), &flat(5, 4)), Some((0, 0, 5, 4)));
+    }
+}
@@ -0,0 +1,889 @@
+use image::{DynamicImage, Rgba, RgbaImage};
+
+// Weights of Super-xBR's interpolation along the edge direction.
+const SUPER_XBR_WEIGHT_1: f32 = 0.129633;
+const SUPER_XBR_WEIGHT_2: f32 = 0.175068;
+// xBR colour difference below which two pixels count as the same.
+const XBR_EQUAL_THRESHOLD: f32 = 155f32;
+// Two pixels differ past any of these YUV differences, the thresholds hqx uses.
+const YUV_THRESHOLDS: [f32; 3] = [48f32, 7f32, 6f32];
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Upscaler {
+    // Every pixel becomes a square block, any factor.
+    NEAREST,
+    // Eric's Pixel Expansion, 2x. Copies a neighbour into a corner where the two neighbours beside it match.
+    EPX,
+    // Scale2x and Scale3x, also known as AdvMAME2x and AdvMAME3x. EPX with extra checks so diagonal
+    // lines are not thickened.
+    SCALEX,
+    // 2x, copies a neighbour into a corner where all three pixels around the corner match.
+    EAGLE,
+    // hq2x, hq3x and hq4x. The neighbours that differ from the pixel in YUV make one of 256 patterns,
+    // and the pattern's entry in a table says how each output pixel is mixed from the neighbourhood.
+    HQX,
+    // xBR level 2 at any factor. Edges are found from the 5x5 neighbourhood and filled as shallow,
+    // steep or 45 degree lines with anti-aliased borders.
+    XBR,
+    // Super-xBR, 2x. Three passes of edge directed interpolation with anti-ringing.
+    SUPERXBR,
+}
+
+impl Upscaler {
+    pub fn new(upscaler: &str) -> Result<Upscaler, &'static str> {
+        return match upscaler {
+            "nearest" => Ok(Upscaler::NEAREST),
+            "epx" => Ok(Upscaler::EPX),
+            "scalex" | "scale2x" | "scale3x" | "advmame" => Ok(Upscaler::SCALEX),
+            "eagle" => Ok(Upscaler::EAGLE),
+            "hqx" | "hq2x" | "hq3x" | "hq4x" => Ok(Upscaler::HQX),
+            "xbr" => Ok(Upscaler::XBR),
+            "superxbr" | "super-xbr" => Ok(Upscaler::SUPERXBR),
+            _ => Err("Invalid upscaler"),
+        };
+    }
+
+    pub fn to_string(upscaler: &Upscaler) -> String {
+        return match upscaler {
+            Upscaler::NEAREST => "nearest",
+            Upscaler::EPX => "epx",
+            Upscaler::SCALEX => "scalex",
+            Upscaler::EAGLE => "eagle",
+            Upscaler::HQX => "hqx",
+            Upscaler::XBR => "xbr",
+            Upscaler::SUPERXBR => "superxbr",
+        }.to_string();
+    }
+
+    // Factors the algorithm is defined for, None where any factor works.
+    fn native_factors(&self) -> Option<&'static [u32]> {
+        return match self {
+            Upscaler::NEAREST | Upscaler::XBR => None,
+            Upscaler::EPX | Upscaler::EAGLE | Upscaler::SUPERXBR => Some(&[2]),
+            Upscaler::SCALEX => Some(&[3, 2]),
+            Upscaler::HQX => Some(&[4, 3, 2]),
+        };
+    }
+}
+
+// Scales by a whole factor. Factors the algorithm has no version of are reached by running it more
+// than once, so Scale2x twice gives 4x, and factors that cannot be built that way are an error.
+// NEAREST, EPX, SCALEX and EAGLE only copy pixels, the others blend and add colours between them.
+pub fn upscale_image(img: &DynamicImage, upscaler: Upscaler, factor: u32) -> Result<DynamicImage, String> {
+    let steps = match upscaler.native_factors() {
+        _ if factor == 0 => None,
+        None => Some(vec![factor]),
+        Some(natives) => factor_steps(factor, natives),
+    };
+    let Some(steps) = steps else {
+        return Err(format!("{} cannot scale by {}", Upscaler::to_string(&upscaler), factor));
+    };
+    if img.width().checked_mul(factor).is_none() || img.height().checked_mul(factor).is_none() {
+        return Err(format!("Scaling {}x{} by {} is too large", img.width(), img.height(), factor));
+    }
+
+    let mut pixels = img.to_rgba8();
+    for step in steps.into_iter().filter(|step| *step > 1) {
+        pixels = match upscaler {
+            Upscaler::NEAREST => expand(&pixels, step, |_, _, _| None),
+            Upscaler::EPX => expand(&pixels, step, epx),
+            Upscaler::SCALEX if step == 3 => expand(&pixels, step, scale3x),
+            Upscaler::SCALEX => expand(&pixels, step, scale2x),
+            Upscaler::EAGLE => expand(&pixels, step, eagle),
+            Upscaler::HQX => hqx(&pixels, step),
+            Upscaler::XBR => corner_fill(&pixels, step, xbr_corner),
+            Upscaler::SUPERXBR => super_xbr(&pixels),
+        };
+    }
+
+    return match img.color().has_alpha() {
+        true => Ok(DynamicImage::ImageRgba8(pixels)),
+        false => Ok(DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(pixels).to_rgb8())),
+    };
+}
+
+// Natives whose product is the factor, largest first. None when there are none.
+fn factor_steps(factor: u32, natives: &[u32]) -> Option<Vec<u32>> {
+    if factor == 1 {
+        return Some(Vec::new());
+    }
+    for native in natives {
+        if factor.is_multiple_of(*native) {
+            if let Some(mut steps) = factor_steps(factor / native, natives) {
+                steps.insert(0, *native);
+                return Some(steps);
+            }
+        }
+    }
+    return None;
+}
+
+// Pixel at an offset, edges repeat.
+fn sample(image: &RgbaImage, x: i64, y: i64) -> Rgba<u8> {
+    let x = x.clamp(0, image.width() as i64 - 1) as u32;
+    let y = y.clamp(0, image.height() as i64 - 1) as u32;
+    return *image.get_pixel(x, y);
+}
+
+// The 3x3 neighbourhood in reading order, the centre at 4.
+fn neighbourhood(image: &RgbaImage, x: i64, y: i64) -> [Rgba<u8>; 9] {
+    return std::array::from_fn(|i| sample(image, x + i as i64 % 3 - 1, y + i as i64 / 3 - 1));
+}
+
+// Writes a factor by factor block for every pixel, in reading order. Blocks the rule returns None for
+// are filled with the pixel itself.
+fn expand<F: Fn(&RgbaImage, i64, i64) -> Option<Vec<Rgba<u8>>>>(image: &RgbaImage, factor: u32, block: F) -> RgbaImage {
+    let mut output = RgbaImage::new(image.width() * factor, image.height() * factor);
+    for (x, y, pixel) in image.enumerate_pixels() {
+        let colours = block(image, x as i64, y as i64);
+        for i in 0..factor * factor {
+            let colour = colours.as_ref().map(|colours| colours[i as usize]).unwrap_or(*pixel);
+            output.put_pixel(x * factor + i % factor, y * factor + i / factor, colour);
+        }
+    }
+    return output;
+}
+
+fn epx(image: &RgbaImage, x: i64, y: i64) -> Option<Vec<Rgba<u8>>> {
+    let [_, a, _, c, p, b, _, d, _] = neighbourhood(image, x, y);
+    // Three or more matching sides would fill most of the block, so it is left alone.
+    let sides = [a, b, c, d];
+    if sides.iter().any(|side| sides.iter().filter(|other| *other == side).count() >= 3) {
+        return None;
+    }
+    return Some(vec![
+        if c == a { a } else { p },
+        if a == b { b } else { p },
+        if d == c { c } else { p },
+        if b == d { d } else { p },
+    ]);
+}
+
+fn scale2x(image: &RgbaImage, x: i64, y: i64) -> Option<Vec<Rgba<u8>>> {
+    let [_, b, _, d, e, f, _, h, _] = neighbourhood(image, x, y);
+    if b == h || d == f {
+        return None;
+    }
+    return Some(vec![
+        if d == b { d } else { e },
+        if b == f { f } else { e },
+        if d == h { d } else { e },
+        if h == f { f } else { e },
+    ]);
+}
+
+fn scale3x(image: &RgbaImage, x: i64, y: i64) -> Option<Vec<Rgba<u8>>> {
+    let [a, b, c, d, e, f, g, h, i] = neighbourhood(image, x, y);
+    if b == h || d == f {
+        return None;
+    }
+    return Some(vec![
+        if d == b { d } else { e },
+        if (d == b && e != c) || (b == f && e != a) { b } else { e },
+        if b == f { f } else { e },
+        if (d == b && e != g) || (d == h && e != a) { d } else { e },
+        e,
+        if (b == f && e != i) || (h == f && e != c) { f } else { e },
+        if d == h { d } else { e },
+        if (d == h && e != i) || (h == f && e != g) { h } else { e },
+        if h == f { f } else { e },
+    ]);
+}
+
+fn eagle(image: &RgbaImage, x: i64, y: i64) -> Option<Vec<Rgba<u8>>> {
+    let [a, b, c, d, e, f, g, h, i] = neighbourhood(image, x, y);
+    return Some(vec![
+        if a == b && b == d { b } else { e },
+        if b == c && c == f { c } else { e },
+        if d == g && g == h { g } else { e },
+        if f == i && i == h { i } else { e },
+    ]);
+}
+
+// How a corner of the block is filled with a neighbouring colour. Shapes are given for the bottom
+// right corner, with x and y from 0 to 1 across the block.
+#[derive(Copy, Clone)]
+enum CornerFill {
+    NONE,
+    // A 45 degree cut through the corner, with the coverage scaled by the strength.
+    DIAGONAL(Rgba<u8>, f32),
+    // An edge rising one pixel over two, running along the bottom.
+    SHALLOW(Rgba<u8>),
+    // An edge rising two pixels over one, running down the right side.
+    STEEP(Rgba<u8>),
+    // Both of the above, for an edge that bends around the corner.
+    BENT(Rgba<u8>),
+}
+
+// Fraction of a sub-pixel on the far side of a line, the line blurred over one sub-pixel.
+fn line_coverage(value: f32, threshold: f32, factor: u32) -> f32 {
+    return ((value - threshold) * factor as f32 + 0.5).clamp(0f32, 1f32);
+}
+
+impl CornerFill {
+    fn colour_and_coverage(&self, x: f32, y: f32, factor: u32) -> Option<(Rgba<u8>, f32)> {
+        let diagonal = line_coverage(x + y, 1.5, factor);
+        let shallow = line_coverage(y + 0.5 * x, 1f32, factor);
+        let steep = line_coverage(x + 0.5 * y, 1f32, factor);
+        return match *self {
+            CornerFill::NONE => None,
+            CornerFill::DIAGONAL(colour, strength) => Some((colour, diagonal * strength)),
+            CornerFill::SHALLOW(colour) => Some((colour, shallow)),
+            CornerFill::STEEP(colour) => Some((colour, steep)),
+            CornerFill::BENT(colour) => Some((colour, shallow.max(steep))),
+        };
+    }
+}
+
+// Neighbour at an offset given for the bottom right corner, turned a quarter at a time to reach the
+// bottom left, top left and top right corners.
+fn rotated(image: &RgbaImage, x: i64, y: i64, dx: i64, dy: i64, rotation: usize) -> Rgba<u8> {
+    let (dx, dy) = match rotation {
+        0 => (dx, dy),
+        1 => (-dy, dx),
+        2 => (-dx, -dy),
+        _ => (dy, -dx),
+    };
+    return sample(image, x + dx, y + dy);
+}
+
+// Each pixel starts as itself and takes on the fills of its four corners in turn.
+fn corner_fill<F: Fn(&dyn Fn(i64, i64) -> Rgba<u8>) -> CornerFill>(image: &RgbaImage, factor: u32, corner: F) -> RgbaImage {
+    let mut output = RgbaImage::new(image.width() * factor, image.height() * factor);
+    for (x, y, pixel) in image.enumerate_pixels() {
+        let fills: Vec<CornerFill> = (0..4)
+            .map(|rotation| corner(&|dx, dy| rotated(image, x as i64, y as i64, dx, dy, rotation)))
+            .collect();
+
+        for i in 0..factor * factor {
+            let (sx, sy) = (i % factor, i / factor);
+            let (u, v) = ((sx as f32 + 0.5) / factor as f32 - 0.5, (sy as f32 + 0.5) / factor as f32 - 0.5);
+            let mut colour = *pixel;
+            for (rotation, fill) in fills.iter().enumerate() {
+                // The sub-pixel as seen from the bottom right corner.
+                let (cx, cy) = match rotation {
+                    0 => (u, v),
+                    1 => (v, -u),
+                    2 => (-u, -v),
+                    _ => (-v, u),
+                };
+                if let Some((fill_colour, coverage)) = fill.colour_and_coverage(cx + 0.5, cy + 0.5, factor) {
+                    colour = mix(colour, fill_colour, coverage);
+                }
+            }
+            output.put_pixel(x * factor + sx, y * factor + sy, colour);
+        }
+    }
+    return output;
+}
+
+fn yuv_differs(a: Rgba<u8>, b: Rgba<u8>) -> bool {
+    let (a_yuv, b_yuv) = (yuv(a), yuv(b));
+    return (0..3).any(|c| (a_yuv[c] - b_yuv[c]).abs() > YUV_THRESHOLDS[c])
+        || (a[3] as f32 - b[3] as f32).abs() > YUV_THRESHOLDS[0];
+}
+
+// One way to fill an hqx output pixel, weights over the 3x3 neighbourhood in reading order. A step
+// with two neighbours to compare is only taken when they differ, otherwise the next one is tried.
+#[derive(Clone, PartialEq, Debug)]
+struct HqxStep {
+    differ: Option<(usize, usize)>,
+    weights: [u32; 9],
+}
+
+// The steps for every output pixel of the block in reading order, for each of the 256 patterns.
+type HqxTable = Vec<Vec<Vec<HqxStep>>>;
+
+// Pattern cases shared by the rules, as (mask, value) pairs over the differing neighbours.
+const HQX_DIAGONAL: [(u8, u8); 13] = [
+    (0x6f, 0x2a), (0x5b, 0x0a), (0xbf, 0x3a), (0xdf, 0x5a), (0x9f, 0x8a), (0xcf, 0x8a), (0xef, 0x4e),
+    (0x3f, 0x0e), (0xfb, 0x5a), (0xbb, 0x8a), (0x7f, 0x5a), (0xaf, 0x8a), (0xeb, 0x8a),
+];
+const HQX_CORNER: [(u8, u8); 14] = [
+    (0x0b, 0x08), (0xf9, 0x68), (0xf3, 0x62), (0x6d, 0x6c), (0x67, 0x66), (0x3d, 0x3c), (0x37, 0x36),
+    (0xf9, 0xf8), (0xdd, 0xdc), (0xf3, 0xf2), (0xd7, 0xd6), (0xdd, 0x1c), (0xd7, 0x16), (0x0b, 0x02),
+];
+const HQX_SLOPE: [(u8, u8); 8] = [(0x4f, 0x4b), (0x9f, 0x1b), (0x2f, 0x0b), (0xbe, 0x0a), (0xee, 0x0a), (0x7e, 0x0a), (0xeb, 0x4b), (0x3b, 0x1b)];
+
+// Neighbours in reading order without the centre, the bit each one has in a pattern.
+fn pattern_bit(position: usize) -> usize {
+    return if position > 4 { position - 1 } else { position };
+}
+
+fn hqx_matches(pattern: u8, cases: &[(u8, u8)]) -> bool {
+    return cases.iter().any(|(mask, value)| pattern & mask == *value);
+}
+
+// Collects the rules hqx tries in order for one pattern, up to the first that always applies.
+struct HqxRules {
+    steps: Vec<HqxStep>,
+    done: bool,
+}
+
+impl HqxRules {
+    fn new() -> HqxRules {
+        return HqxRules { steps: Vec::new(), done: false };
+    }
+
+    fn add(mut self, matches: bool, differ: Option<(usize, usize)>, weights: &[(usize, u32)]) -> HqxRules {
+        if matches && !self.done {
+            let mut step = HqxStep { differ, weights: [0; 9] };
+            for (position, weight) in weights {
+                step.weights[*position] += weight;
+            }
+            self.steps.push(step);
+            self.done = differ.is_none();
+        }
+        return self;
+    }
+
+    fn rule(self, matches: bool, weights: &[(usize, u32)]) -> HqxRules {
+        return self.add(matches, None, weights);
+    }
+
+    fn rule_if_differ(self, matches: bool, a: usize, b: usize, weights: &[(usize, u32)]) -> HqxRules {
+        return self.add(matches, Some((a, b)), weights);
+    }
+
+    fn otherwise(self, weights: &[(usize, u32)]) -> Vec<HqxStep> {
+        return self.rule(true, weights).steps;
+    }
+}
+
+// The rules below are for the top left of the block, with the pattern and positions seen from
+// there. They are the 256 case tables of Maxim Stepin's hqx folded into decision lists, as in
+// FFmpeg's hqx filter.
+fn hq2x_corner(k: u8) -> Vec<HqxStep> {
+    let p = |cases: &[(u8, u8)]| hqx_matches(k, cases);
+    return HqxRules::new()
+        .rule_if_differ(p(&[(0xbf, 0x37), (0xdb, 0x13)]), 1, 5, &[(4, 3), (3, 1)])
+        .rule_if_differ(p(&[(0xdb, 0x49), (0xef, 0x6d)]), 7, 3, &[(4, 3), (1, 1)])
+        .rule_if_differ(p(&[(0x0b, 0x0b), (0xfe, 0x4a), (0xfe, 0x1a)]), 3, 1, &[(4, 1)])
+        .rule_if_differ(p(&HQX_DIAGONAL), 3, 1, &[(4, 3), (0, 1)])
+        .rule(p(&[(0x0b, 0x08)]), &[(4, 2), (0, 1), (1, 1)])
+        .rule(p(&[(0x0b, 0x02)]), &[(4, 2), (0, 1), (3, 1)])
+        .rule(p(&[(0x2f, 0x2f)]), &[(4, 14), (3, 1), (1, 1)])
+        .rule(p(&[(0xbf, 0x37), (0xdb, 0x13)]), &[(4, 5), (1, 2), (3, 1)])
+        .rule(p(&[(0xdb, 0x49), (0xef, 0x6d)]), &[(4, 5), (3, 2), (1, 1)])
+        .rule(p(&[(0x1b, 0x03), (0x4f, 0x43), (0x8b, 0x83), (0x6b, 0x43)]), &[(4, 3), (3, 1)])
+        .rule(p(&[(0x4b, 0x09), (0x8b, 0x89), (0x1f, 0x19), (0x3b, 0x19)]), &[(4, 3), (1, 1)])
+        .rule(p(&[(0x7e, 0x2a), (0xef, 0xab), (0xbf, 0x8f), (0x7e, 0x0e)]), &[(4, 2), (3, 3), (1, 3)])
+        .rule(p(&[(0xfb, 0x6a), (0x6f, 0x6e), (0x3f, 0x3e), (0xfb, 0xfa), (0xdf, 0xde), (0xdf, 0x1e)]), &[(4, 3), (0, 1)])
+        .rule(p(&[(0x0a, 0x00)]) || p(&HQX_SLOPE), &[(4, 2), (3, 1), (1, 1)])
+        .otherwise(&[(4, 6), (3, 1), (1, 1)]);
+}
+
+fn hq3x_corner(k: u8) -> Vec<HqxStep> {
+    let p = |cases: &[(u8, u8)]| hqx_matches(k, cases);
+    return HqxRules::new()
+        .rule_if_differ(p(&[(0xdb, 0x49), (0xef, 0x6d)]), 7, 3, &[(4, 3), (1, 1)])
+        .rule_if_differ(p(&[(0xbf, 0x37), (0xdb, 0x13)]), 1, 5, &[(4, 3), (3, 1)])
+        .rule_if_differ(p(&[(0x0b, 0x0b), (0xfe, 0x4a), (0xfe, 0x1a)]), 3, 1, &[(4, 1)])
+        .rule_if_differ(p(&HQX_DIAGONAL), 3, 1, &[(4, 3), (0, 1)])
+        .rule(p(&[(0x4b, 0x09), (0x8b, 0x89), (0x1f, 0x19), (0x3b, 0x19)]), &[(4, 3), (1, 1)])
+        .rule(p(&[(0x1b, 0x03), (0x4f, 0x43), (0x8b, 0x83), (0x6b, 0x43)]), &[(4, 3), (3, 1)])
+        .rule(p(&[(0x7e, 0x2a), (0xef, 0xab), (0xbf, 0x8f), (0x7e, 0x0e)]), &[(3, 1), (1, 1)])
+        .rule(p(&HQX_SLOPE), &[(4, 2), (3, 7), (1, 7)])
+        .rule(p(&HQX_CORNER), &[(4, 3), (0, 1)])
+        .otherwise(&[(4, 2), (3, 1), (1, 1)]);
+}
+
+// The top middle pixel of the 3x3 block.
+fn hq3x_edge(k: u8) -> Vec<HqxStep> {
+    let p = |cases: &[(u8, u8)]| hqx_matches(k, cases);
+    return HqxRules::new()
+        .rule_if_differ(p(&[(0xfe, 0xde), (0x9e, 0x16), (0xda, 0x12), (0x17, 0x16), (0x5b, 0x12), (0xbb, 0x12)]), 1, 5, &[(4, 1)])
+        .rule_if_differ(p(&[(0x0f, 0x0b), (0x5e, 0x0a), (0xfb, 0x7b), (0x3b, 0x0b), (0xbe, 0x0a), (0x7a, 0x0a)]), 3, 1, &[(4, 1)])
+        .rule(p(&[(0xbf, 0x8f), (0x7e, 0x0e), (0xbf, 0x37), (0xdb, 0x13)]), &[(1, 3), (4, 1)])
+        .rule(p(&[(0x02, 0x00), (0x7c, 0x28), (0xed, 0xa9), (0xf5, 0xb4), (0xd9, 0x90)]), &[(4, 3), (1, 1)])
+        .rule(p(&[
+            (0x4f, 0x4b), (0xfb, 0x7b), (0xfe, 0x7e), (0x9f, 0x1b), (0x2f, 0x0b), (0xbe, 0x0a), (0x7e, 0x0a), (0xfb, 0x4b),
+            (0xfb, 0xdb), (0xfe, 0xde), (0xfe, 0x56), (0x57, 0x56), (0x97, 0x16), (0x3f, 0x1e), (0xdb, 0x12), (0xbb, 0x12),
+        ]), &[(4, 7), (1, 1)])
+        .otherwise(&[(4, 1)]);
+}
+
+// Conditions the four hq4x pixels of a quarter share.
+struct Hq4xCases {
+    pattern: u8,
+    up: bool,
+    left: bool,
+    diagonal: bool,
+    steep: bool,
+    shallow: bool,
+    upper: bool,
+    lower: bool,
+    corner: bool,
+    straight: bool,
+}
+
+impl Hq4xCases {
+    fn new(k: u8) -> Hq4xCases {
+        let p = |cases: &[(u8, u8)]| hqx_matches(k, cases);
+        return Hq4xCases {
+            pattern: k,
+            up: p(&[(0xbf, 0x37), (0xdb, 0x13)]),
+            left: p(&[(0xdb, 0x49), (0xef, 0x6d)]),
+            diagonal: p(&HQX_DIAGONAL),
+            steep: p(&[(0x1b, 0x03), (0x4f, 0x43), (0x8b, 0x83), (0x6b, 0x43)]),
+            shallow: p(&[(0x4b, 0x09), (0x8b, 0x89), (0x1f, 0x19), (0x3b, 0x19)]),
+            upper: p(&[(0x7e, 0x2a), (0xef, 0xab)]),
+            lower: p(&[(0xbf, 0x8f), (0x7e, 0x0e)]),
+            corner: p(&HQX_CORNER),
+            straight: p(&[(0x0f, 0x0b), (0x2b, 0x0b), (0xfe, 0x4a), (0xfe, 0x1a)]),
+        };
+    }
+
+    fn p(&self, cases: &[(u8, u8)]) -> bool {
+        return hqx_matches(self.pattern, cases);
+    }
+}
+
+fn hq4x_corner(k: u8) -> Vec<HqxStep> {
+    let c = Hq4xCases::new(k);
+    return HqxRules::new()
+        .rule_if_differ(c.up, 1, 5, &[(4, 5), (3, 3)])
+        .rule_if_differ(c.left, 7, 3, &[(4, 5), (1, 3)])
+        .rule_if_differ(c.p(&[(0x0b, 0x0b), (0xfe, 0x4a), (0xfe, 0x1a)]), 3, 1, &[(4, 1)])
+        .rule_if_differ(c.diagonal, 3, 1, &[(4, 5), (0, 3)])
+        .rule(c.left, &[(4, 3), (3, 1)])
+        .rule(c.up, &[(4, 3), (1, 1)])
+        .rule(c.steep, &[(4, 5), (3, 3)])
+        .rule(c.shallow, &[(4, 5), (1, 3)])
+        .rule(c.p(&[(0x0f, 0x0b), (0x5e, 0x0a), (0x2b, 0x0b), (0xbe, 0x0a), (0x7a, 0x0a), (0xee, 0x0a)]), &[(1, 1), (3, 1)])
+        .rule(c.corner, &[(4, 5), (0, 3)])
+        .otherwise(&[(4, 2), (1, 1), (3, 1)]);
+}
+
+// The pixel right of the corner.
+fn hq4x_top(k: u8) -> Vec<HqxStep> {
+    let c = Hq4xCases::new(k);
+    return HqxRules::new()
+        .rule_if_differ(c.up, 1, 5, &[(4, 7), (3, 1)])
+        .rule_if_differ(c.straight, 3, 1, &[(4, 1)])
+        .rule_if_differ(c.diagonal, 3, 1, &[(4, 3), (0, 1)])
+        .rule_if_differ(c.left, 7, 3, &[(1, 1), (4, 3)])
+        .rule(c.p(&[(0x2f, 0x2f)]), &[(4, 1)])
+        .rule(c.p(&[(0x0a, 0x00)]), &[(4, 5), (1, 2), (3, 1)])
+        .rule(c.p(&[(0x0b, 0x08)]), &[(4, 5), (1, 2), (0, 1)])
+        .rule(c.p(&[(0x0b, 0x09)]), &[(4, 5), (1, 3)])
+        .rule(c.up, &[(1, 3), (4, 1)])
+        .rule(c.upper, &[(1, 2), (4, 1), (3, 1)])
+        .rule(c.lower, &[(1, 5), (3, 3)])
+        .rule(c.steep, &[(4, 7), (3, 1)])
+        .rule(c.p(&[(0xf3, 0x62), (0x67, 0x66), (0x37, 0x36), (0xf3, 0xf2), (0xd7, 0xd6), (0xd7, 0x16), (0x0b, 0x02)]), &[(4, 3), (0, 1)])
+        .rule(c.p(&HQX_SLOPE), &[(1, 1), (4, 1)])
+        .otherwise(&[(4, 3), (1, 1)]);
+}
+
+// The pixel below the corner.
+fn hq4x_left(k: u8) -> Vec<HqxStep> {
+    let c = Hq4xCases::new(k);
+    return HqxRules::new()
+        .rule_if_differ(c.left, 7, 3, &[(4, 7), (1, 1)])
+        .rule_if_differ(c.straight, 3, 1, &[(4, 1)])
+        .rule_if_differ(c.diagonal, 3, 1, &[(4, 3), (0, 1)])
+        .rule_if_differ(c.up, 1, 5, &[(3, 1), (4, 3)])
+        .rule(c.p(&[(0x2f, 0x2f)]), &[(4, 1)])
+        .rule(c.p(&[(0x0a, 0x00)]), &[(4, 5), (3, 2), (1, 1)])
+        .rule(c.p(&[(0x0b, 0x02)]), &[(4, 5), (3, 2), (0, 1)])
+        .rule(c.p(&[(0x0b, 0x03)]), &[(4, 5), (3, 3)])
+        .rule(c.left, &[(3, 3), (4, 1)])
+        .rule(c.lower, &[(3, 2), (4, 1), (1, 1)])
+        .rule(c.upper, &[(3, 5), (1, 3)])
+        .rule(c.shallow, &[(4, 7), (1, 1)])
+        .rule(c.p(&[(0x0b, 0x08), (0xf9, 0x68), (0x6d, 0x6c), (0x3d, 0x3c), (0xf9, 0xf8), (0xdd, 0xdc), (0xdd, 0x1c)]), &[(4, 3), (0, 1)])
+        .rule(c.p(&HQX_SLOPE), &[(3, 1), (4, 1)])
+        .otherwise(&[(4, 3), (3, 1)]);
+}
+
+// The pixel diagonally in from the corner.
+fn hq4x_inner(k: u8) -> Vec<HqxStep> {
+    let c = Hq4xCases::new(k);
+    return HqxRules::new()
+        .rule_if_differ(c.p(&[(0x7f, 0x2b), (0xef, 0xab), (0xbf, 0x8f), (0x7f, 0x0f)]), 3, 1, &[(4, 1)])
+        .rule_if_differ(c.diagonal, 3, 1, &[(4, 7), (0, 1)])
+        .rule(c.p(&[(0x0b, 0x03)]), &[(4, 7), (3, 1)])
+        .rule(c.p(&[(0x0b, 0x09)]), &[(4, 7), (1, 1)])
+        .rule(c.p(&[(0x0a, 0x00)]) || c.upper || c.lower, &[(4, 6), (3, 1), (1, 1)])
+        .rule(c.corner, &[(4, 7), (0, 1)])
+        .otherwise(&[(4, 1)]);
+}
+
+type HqxRule = fn(u8) -> Vec<HqxStep>;
+// Where an offset from the top left's point of view is in the image.
+type HqxFrame = fn(i32, i32) -> (i32, i32);
+
+// Builds the table for one factor. Each rule is worked out for the top left of the block and moved to
+// the other quarters by mirroring, or for hq3x by turning, the neighbourhood.
+fn hqx_table(factor: u32) -> HqxTable {
+    let mirrors: [HqxFrame; 4] = [|x, y| (x, y), |x, y| (-x, y), |x, y| (x, -y), |x, y| (-x, -y)];
+    let turns: [HqxFrame; 4] = [|x, y| (x, y), |x, y| (-y, x), |x, y| (y, -x), |x, y| (-x, -y)];
+    let (frames, rules) = match factor {
+        2 => (mirrors, vec![((0, 0), hq2x_corner as HqxRule)]),
+        3 => (turns, vec![((0, 0), hq3x_corner as HqxRule), ((1, 0), hq3x_edge)]),
+        _ => (mirrors, vec![((0, 0), hq4x_corner as HqxRule), ((1, 0), hq4x_top), ((0, 1), hq4x_left), ((1, 1), hq4x_inner)]),
+    };
+    let last = factor as i32 - 1;
+
+    return (0..256usize).map(|pattern| {
+        // The middle of hq3x's block is always the pixel itself.
+        let mut cells = vec![vec![HqxStep { differ: None, weights: [0, 0, 0, 0, 1, 0, 0, 0, 0] }]; (factor * factor) as usize];
+        for frame in frames {
+            let position: [usize; 9] = std::array::from_fn(|i| {
+                let (x, y) = frame(i as i32 % 3 - 1, i as i32 / 3 - 1);
+                ((y + 1) * 3 + x + 1) as usize
+            });
+            let k = (0..9).filter(|i| *i != 4)
+                .fold(0u8, |k, i| k | (((pattern >> pattern_bit(position[i])) & 1) as u8) << pattern_bit(i));
+
+            for &((x, y), rule) in &rules {
+                let (x, y) = frame(2 * x - last, 2 * y - last);
+                let cell = ((y + last) / 2 * factor as i32 + (x + last) / 2) as usize;
+                cells[cell] = rule(k).into_iter().map(|step| {
+                    let mut weights = [0; 9];
+                    for (i, weight) in step.weights.iter().enumerate() {
+                        weights[position[i]] = *weight;
+                    }
+                    HqxStep { differ: step.differ.map(|(a, b)| (position[a], position[b])), weights }
+                }).collect();
+            }
+        }
+        cells
+    }).collect();
+}
+
+fn hqx(image: &RgbaImage, factor: u32) -> RgbaImage {
+    let table = hqx_table(factor);
+    let mut output = RgbaImage::new(image.width() * factor, image.height() * factor);
+    for (x, y, _) in image.enumerate_pixels() {
+        let w = neighbourhood(image, x as i64, y as i64);
+        let pattern = (0..9).filter(|i| *i != 4 && w[*i] != w[4] && yuv_differs(w[4], w[*i]))
+            .fold(0usize, |pattern, i| pattern | 1 << pattern_bit(i));
+
+        for (i, steps) in table[pattern].iter().enumerate() {
+            let step = steps.iter().find(|step| step.differ.is_none_or(|(a, b)| yuv_differs(w[a], w[b]))).unwrap();
+            output.put_pixel(x * factor + i as u32 % factor, y * factor + i as u32 / factor, hqx_mix(&w, &step.weights));
+        }
+    }
+    return output;
+}
+
+// Weighted sum rounded down like hqx's fixed point mixing, colours weighted by alpha as in mix.
+fn hqx_mix(w: &[Rgba<u8>; 9], weights: &[u32; 9]) -> Rgba<u8> {
+    let total: u32 = weights.iter().sum();
+    let alpha: u32 = (0..9).map(|i| w[i][3] as u32 * weights[i]).sum();
+    let channel = |c: usize| match alpha {
+        0 => (0..9).map(|i| w[i][c] as u32 * weights[i]).sum::<u32>() / total,
+        _ => (0..9).map(|i| w[i][c] as u32 * w[i][3] as u32 * weights[i]).sum::<u32>() / alpha,
+    };
+    return Rgba([channel(0) as u8, channel(1) as u8, channel(2) as u8, (alpha / total) as u8]);
+}
+
+fn xbr_corner(at: &dyn Fn(i64, i64) -> Rgba<u8>) -> CornerFill {
+    let (e, f, h, i) = (at(0, 0), at(1, 0), at(0, 1), at(1, 1));
+    if e == f || e == h {
+        return CornerFill::NONE;
+    }
+    let (b, c, d, g) = (at(0, -1), at(1, -1), at(-1, 0), at(-1, 1));
+    let (f4, i4, h5, i5) = (at(2, 0), at(2, 1), at(0, 2), at(1, 2));
+    let eq = |a: Rgba<u8>, b: Rgba<u8>| xbr_difference(a, b) < XBR_EQUAL_THRESHOLD;
+
+    // Weighted differences across the two diagonals, the smaller one is the direction of the edge.
+    let across = xbr_difference(e, c) + xbr_difference(e, g) + xbr_difference(i, h5) + xbr_difference(i, f4) + 4f32 * xbr_difference(h, f);
+    let along = xbr_difference(h, d) + xbr_difference(h, i5) + xbr_difference(f, i4) + xbr_difference(f, b) + 4f32 * xbr_difference(e, i);
+    let colour = if xbr_difference(e, f) <= xbr_difference(e, h) { f } else { h };
+
+    let corner = (!eq(f, b) && !eq(h, d)) || (eq(e, i) && !eq(f, i4) && !eq(h, i5)) || eq(e, g) || eq(e, c);
+    if across < along && corner {
+        let (ke, ki) = (xbr_difference(f, g), xbr_difference(h, c));
+        let shallow = 2f32 * ke <= ki && e != g && d != g;
+        let steep = 2f32 * ki <= ke && e != c && b != c;
+        return match (shallow, steep) {
+            (true, true) => CornerFill::BENT(colour),
+            (true, false) => CornerFill::SHALLOW(colour),
+            (false, true) => CornerFill::STEEP(colour),
+            (false, false) => CornerFill::DIAGONAL(colour, 1f32),
+        };
+    }
+    if across <= along {
+        return CornerFill::DIAGONAL(colour, 0.5);
+    }
+    return CornerFill::NONE;
+}
+
+// YUV difference with the weights xBR uses, alpha counted like luma.
+fn xbr_difference(a: Rgba<u8>, b: Rgba<u8>) -> f32 {
+    let (a_yuv, b_yuv) = (yuv(a), yuv(b));
+    return 48f32 * (a_yuv[0] - b_yuv[0]).abs()
+        + 7f32 * (a_yuv[1] - b_yuv[1]).abs()
+        + 6f32 * (a_yuv[2] - b_yuv[2]).abs()
+        + 48f32 * (a[3] as f32 - b[3] as f32).abs();
+}
+
+fn yuv(colour: Rgba<u8>) -> [f32; 3] {
+    let (r, g, b) = (colour[0] as f32, colour[1] as f32, colour[2] as f32);
+    return [
+        0.299 * r + 0.587 * g + 0.114 * b,
+        -0.169 * r - 0.331 * g + 0.5 * b,
+        0.5 * r - 0.419 * g - 0.081 * b,
+    ];
+}
+
+// Blend weighted by alpha, so fully transparent pixels add no colour.
+fn mix(a: Rgba<u8>, b: Rgba<u8>, t: f32) -> Rgba<u8> {
+    if t <= 0f32 {
+        return a;
+    }
+    if t >= 1f32 {
+        return b;
+    }
+    let (wa, wb) = (a[3] as f32 * (1f32 - t), b[3] as f32 * t);
+    let alpha = wa + wb;
+    let channel = |c: usize| match alpha > 0f32 {
+        true => (a[c] as f32 * wa + b[c] as f32 * wb) / alpha,
+        false => a[c] as f32 * (1f32 - t) + b[c] as f32 * t,
+    };
+    return Rgba([channel(0).round() as u8, channel(1).round() as u8, channel(2).round() as u8, alpha.round() as u8]);
+}
+
+// Super-xBR at 2x. The first pass fills the centres between four source pixels, the second the
+// points between those and the source pixels, and the third refines every output pixel.
+fn super_xbr(image: &RgbaImage) -> RgbaImage {
+    let (width, height) = (image.width() as i64, image.height() as i64);
+    let (out_width, out_height) = (width * 2, height * 2);
+    let channels = |p: Rgba<u8>| [p[0] as f32, p[1] as f32, p[2] as f32, p[3] as f32];
+    let mut output = vec![[0f32; 4]; (out_width * out_height) as usize];
+    let index = |x: i64, y: i64| (y.clamp(0, out_height - 1) * out_width + x.clamp(0, out_width - 1)) as usize;
+
+    let full = [2f32, 1f32, -1f32, 4f32, -1f32, 1f32];
+    let cross = [2f32, 0f32, 0f32, 0f32, 0f32, 0f32];
+    let (w1, w2) = (-SUPER_XBR_WEIGHT_1, SUPER_XBR_WEIGHT_1 + 0.5);
+    let (w3, w4) = (-SUPER_XBR_WEIGHT_2, SUPER_XBR_WEIGHT_2 + 0.5);
+
+    for y in 0..height {
+        for x in 0..width {
+            let matrix: [[[f32; 4]; 4]; 4] = std::array::from_fn(|sx| {
+                std::array::from_fn(|sy| channels(sample(image, x + sx as i64 - 1, y + sy as i64 - 1)))
+            });
+            let source = channels(sample(image, x, y));
+            output[index(2 * x, 2 * y)] = source;
+            output[index(2 * x + 1, 2 * y)] = source;
+            output[index(2 * x, 2 * y + 1)] = source;
+            output[index(2 * x + 1, 2 * y + 1)] = super_xbr_interpolate(&matrix, &full, w1, w2);
+        }
+    }
+
+    // The neighbourhood is turned 45 degrees, so its rows run along the diagonals of the output.
+    for y in (0..out_height).step_by(2) {
+        for x in (0..out_width).step_by(2) {
+            let matrix: [[[f32; 4]; 4]; 4] = std::array::from_fn(|sx| {
+                std::array::from_fn(|sy| output[index(x + sx as i64 + sy as i64 - 2, y + sx as i64 - sy as i64)])
+            });
+            output[index(x + 1, y)] = super_xbr_interpolate(&matrix, &cross, w3, w4);
+
+            let matrix: [[[f32; 4]; 4]; 4] = std::array::from_fn(|sx| {
+                std::array::from_fn(|sy| output[index(x + sx as i64 + sy as i64 - 3, y + sx as i64 - sy as i64 + 1)])
+            });
+            output[index(x, y + 1)] = super_xbr_interpolate(&matrix, &cross, w3, w4);
+        }
+    }
+
+    for y in (0..out_height).rev() {
+        for x in (0..out_width).rev() {
+            let matrix: [[[f32; 4]; 4]; 4] = std::array::from_fn(|sx| {
+                std::array::from_fn(|sy| output[index(x + sx as i64 - 2, y + sy as i64 - 2)])
+            });
+            output[index(x, y)] = super_xbr_interpolate(&matrix, &full, w1, w2);
+        }
+    }
+
+    return RgbaImage::from_fn(out_width as u32, out_height as u32, |x, y| {
+        let p = output[index(x as i64, y as i64)];
+        Rgba([p[0] as u8, p[1] as u8, p[2] as u8, p[3] as u8])
+    });
+}
+
+// Interpolates along whichever diagonal of the 4x4 matrix the edge runs, clamped to the four centre
+// pixels so edges do not ring.
+fn super_xbr_interpolate(matrix: &[[[f32; 4]; 4]; 4], weights: &[f32; 6], outer: f32, inner: f32) -> [f32; 4] {
+    let luma: [[f32; 4]; 4] = std::array::from_fn(|x| {
+        std::array::from_fn(|y| 0.2126 * matrix[x][y][0] + 0.7152 * matrix[x][y][1] + 0.0722 * matrix[x][y][2])
+    });
+    let edge = super_xbr_diagonal_edge(&luma, weights);
+
+    return std::array::from_fn(|c| {
+        let centre = [matrix[1][1][c], matrix[2][1][c], matrix[1][2][c], matrix[2][2][c]];
+        let (min, max) = (centre.iter().copied().fold(f32::MAX, f32::min), centre.iter().copied().fold(f32::MIN, f32::max));
+        let value = match edge <= 0f32 {
+            true => outer * (matrix[0][3][c] + matrix[3][0][c]) + inner * (matrix[1][2][c] + matrix[2][1][c]),
+            false => outer * (matrix[0][0][c] + matrix[3][3][c]) + inner * (matrix[1][1][c] + matrix[2][2][c]),
+        };
+        value.clamp(min, max).ceil().clamp(0f32, 255f32)
+    });
+}
+
+// Positive when the edge runs from top left to bottom right.
+fn super_xbr_diagonal_edge(m: &[[f32; 4]; 4], w: &[f32; 6]) -> f32 {
+    let d = |a: f32, b: f32| (a - b).abs();
+    let first = w[0] * (d(m[0][2], m[1][1]) + d(m[1][1], m[2][0]) + d(m[1][3], m[2][2]) + d(m[2][2], m[3][1]))
+        + w[1] * (d(m[0][3], m[1][2]) + d(m[2][1], m[3][0]))
+        + w[2] * (d(m[0][3], m[2][1]) + d(m[1][2], m[3][0]))
+        + w[3] * d(m[1][2], m[2][1])
+        + w[4] * (d(m[0][2], m[2][0]) + d(m[1][3], m[3][1]))
+        + w[5] * (d(m[0][1], m[1][0]) + d(m[2][3], m[3][2]));
+    let second = w[0] * (d(m[0][1], m[1][2]) + d(m[1][2], m[2][3]) + d(m[1][0], m[2][1]) + d(m[2][1], m[3][2]))
+        + w[1] * (d(m[0][0], m[1][1]) + d(m[2][2], m[3][3]))
+        + w[2] * (d(m[0][0], m[2][2]) + d(m[1][1], m[3][3]))
+        + w[3] * d(m[1][1], m[2][2])
+        + w[4] * (d(m[1][0], m[3][2]) + d(m[0][1], m[2][3]))
+        + w[5] * (d(m[0][2], m[1][3]) + d(m[2][0], m[3][1]));
+    return first - second;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UPSCALERS: [Upscaler; 7] = [
+        Upscaler::NEAREST,
+        Upscaler::EPX,
+        Upscaler::SCALEX,
+        Upscaler::EAGLE,
+        Upscaler::HQX,
+        Upscaler::XBR,
+        Upscaler::SUPERXBR,
+    ];
+
+    fn checkerboard() -> DynamicImage {
+        return DynamicImage::ImageRgba8(RgbaImage::from_fn(5, 3, |x, y| match (x + y) % 2 {
+            0 => Rgba([20, 20, 20, 255]),
+            _ => Rgba([230, 200, 40, 255]),
+        }));
+    }
+
+    #[test]
+    fn factors_are_built_from_native_steps() {
+        assert_eq!(factor_steps(1, &[2]), Some(vec![]));
+        assert_eq!(factor_steps(2, &[2]), Some(vec![2]));
+        assert_eq!(factor_steps(8, &[2]), Some(vec![2, 2, 2]));
+        assert_eq!(factor_steps(6, &[3, 2]), Some(vec![3, 2]));
+        assert_eq!(factor_steps(9, &[3, 2]), Some(vec![3, 3]));
+        assert_eq!(factor_steps(12, &[4, 3, 2]), Some(vec![4, 3]));
+        assert_eq!(factor_steps(3, &[2]), None);
+        assert_eq!(factor_steps(10, &[3, 2]), None);
+    }
+
+    #[test]
+    fn output_is_the_factor_times_the_input() {
+        for upscaler in UPSCALERS {
+            for factor in [1, 2, 4, 8] {
+                let output = upscale_image(&checkerboard(), upscaler, factor).unwrap();
+                assert_eq!((output.width(), output.height()), (5 * factor, 3 * factor), "{:?} x{}", upscaler, factor);
+            }
+        }
+    }
+
+    #[test]
+    fn unreachable_factors_are_an_error() {
+        assert!(upscale_image(&checkerboard(), Upscaler::NEAREST, 0).is_err());
+        assert!(upscale_image(&checkerboard(), Upscaler::EPX, 3).is_err());
+        assert!(upscale_image(&checkerboard(), Upscaler::SCALEX, 5).is_err());
+        assert!(upscale_image(&checkerboard(), Upscaler::HQX, 3).is_ok());
+        assert!(upscale_image(&checkerboard(), Upscaler::NEAREST, 5).is_ok());
+        assert!(upscale_image(&checkerboard(), Upscaler::XBR, 5).is_ok());
+    }
+
+    #[test]
+    fn copying_upscalers_add_no_colours() {
+        let input: Vec<Rgba<u8>> = checkerboard().to_rgba8().pixels().copied().collect();
+        for upscaler in [Upscaler::NEAREST, Upscaler::EPX, Upscaler::SCALEX, Upscaler::EAGLE] {
+            let output = upscale_image(&checkerboard(), upscaler, 4).unwrap().to_rgba8();
+            assert!(output.pixels().all(|p| input.contains(p)), "{:?}", upscaler);
+        }
+    }
+
+    fn dot(factor: u32) -> RgbaImage {
+        let image = RgbaImage::from_fn(3, 3, |x, y| if (x, y) == (1, 1) { Rgba([0, 0, 0, 255]) } else { Rgba([255, 255, 255, 255]) });
+        return upscale_image(&DynamicImage::ImageRgba8(image), Upscaler::HQX, factor).unwrap().to_rgba8();
+    }
+
+    // The middle block of a lone dark pixel, as grey levels in reading order.
+    fn dot_block(factor: u32) -> Vec<u8> {
+        let output = dot(factor);
+        return (0..factor * factor).map(|i| output.get_pixel(factor + i % factor, factor + i / factor)[0]).collect();
+    }
+
+    #[test]
+    fn hqx_leaves_flat_areas_alone() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 3, Rgba([90, 140, 30, 255])));
+        for factor in [2, 3, 4] {
+            let output = upscale_image(&image, Upscaler::HQX, factor).unwrap().to_rgba8();
+            assert!(output.pixels().all(|p| *p == Rgba([90, 140, 30, 255])), "x{}", factor);
+        }
+    }
+
+    #[test]
+    fn hqx_rounds_off_a_lone_pixel() {
+        // hq2x mixes every pixel as 14:1:1 of itself and the two light sides, hq3x and hq4x only soften
+        // the corners as 2:1:1.
+        assert_eq!(dot_block(2), vec![31, 31, 31, 31]);
+        assert_eq!(dot_block(3), vec![127, 0, 127, 0, 0, 0, 127, 0, 127]);
+        assert_eq!(dot_block(4), vec![127, 0, 0, 127, 0, 0, 0, 0, 0, 0, 0, 0, 127, 0, 0, 127]);
+
+        // The light pixels around it only see it on a side or corner and stay as they are.
+        for factor in [2, 3, 4] {
+            let output = dot(factor);
+            let outside = output.enumerate_pixels().filter(|(x, y, _)| !(factor..2 * factor).contains(x) || !(factor..2 * factor).contains(y));
+            assert!(outside.into_iter().all(|(_, _, p)| *p == Rgba([255, 255, 255, 255])), "x{}", factor);
+        }
+    }
+
+    #[test]
+    fn hqx_smooths_a_staircase() {
+        // A one pixel diagonal line, the rule for a 45 degree edge fills the corners it passes.
+        let image = RgbaImage::from_fn(4, 4, |x, y| if x == y { Rgba([0, 0, 0, 255]) } else { Rgba([255, 255, 255, 255]) });
+        let output = upscale_image(&DynamicImage::ImageRgba8(image), Upscaler::HQX, 2).unwrap().to_rgba8();
+        // Top right and bottom left of the second pixel blend towards the white either side.
+        assert!(output.get_pixel(3, 2)[0] > 0 && output.get_pixel(2, 3)[0] > 0);
+        assert_eq!(output.get_pixel(2, 2), &Rgba([0, 0, 0, 255]));
+        assert_eq!(output.get_pixel(3, 3), &Rgba([0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn hqx_table_entries_are_normalised() {
+        for factor in [2, 3, 4] {
+            let table = hqx_table(factor);
+            assert_eq!(table.len(), 256);
+            for cells in &table {
+                assert_eq!(cells.len(), (factor * factor) as usize);
+                for steps in cells {
+                    // Every list ends in a step without a check and every weight total is a power of two.
+                    assert!(steps.last().unwrap().differ.is_none());
+                    assert!(steps.iter().all(|step| step.weights.iter().sum::<u32>().is_power_of_two()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hqx_is_symmetric() {
+        // Flipping or transposing the input has to do the same to the output, which catches a case
+        // copied into the tables wrong.
+        let colours = [Rgba([20, 20, 20, 255]), Rgba([230, 200, 40, 255]), Rgba([40, 90, 220, 255]), Rgba([235, 205, 45, 255])];
+        let mut seed = 7u32;
+        let image = RgbaImage::from_fn(12, 12, |_, _| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            colours[(seed >> 16) as usize % colours.len()]
+        });
+        let transpose = |image: &RgbaImage| RgbaImage::from_fn(image.height(), image.width(), |x, y| *image.get_pixel(y, x));
+        let upscale = |image: &RgbaImage, factor: u32| upscale_image(&DynamicImage::ImageRgba8(image.clone()), Upscaler::HQX, factor).unwrap().to_rgba8();
+
+        for factor in [2, 3, 4] {
+            let output = upscale(&image, factor);
+            assert_eq!(upscale(&transpose(&image), factor), transpose(&output), "x{} transposed", factor);
+            assert_eq!(upscale(&image::imageops::flip_horizontal(&image), factor), image::imageops::flip_horizontal(&output), "x{} flipped", factor);
+            assert_eq!(upscale(&image::imageops::rotate90(&image), factor), image::imageops::rotate90(&output), "x{} turned", factor);
+        }
+    }
+}
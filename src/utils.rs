@@ -1,9 +1,10 @@
 use std::collections::HashSet;
 use std::thread;
 
-use image::{DynamicImage, GenericImage, GenericImageView, ImageBuffer, Rgb};
+use image::{DynamicImage, ImageBuffer, Rgb};
 use rand::Rng;
 
+use crate::colour::euclidean_distance;
 use crate::ditherer::BlueNoiseThreshold;
 
 pub fn available_threads() -> usize {
@@ -29,7 +30,7 @@ pub fn rgb_to_hex(rgb: Rgb<u8>) -> String {
     format!("{:02X}{:02X}{:02X}", rgb[0], rgb[1], rgb[2])
 }
 
-pub fn sum_fold_and_count(cluster: &Vec<Rgb<u8>>) -> (u32, u32, u32, u32) {
+pub fn sum_fold_and_count(cluster: &[Rgb<u8>]) -> (u32, u32, u32, u32) {
     cluster.iter().fold((0u32, 0u32, 0u32, 0u32), |acc, color| {
         (
             acc.0 + color[0] as u32,
@@ -40,10 +41,10 @@ pub fn sum_fold_and_count(cluster: &Vec<Rgb<u8>>) -> (u32, u32, u32, u32) {
     })
 }
 
-pub fn find_closest_color(color: &Rgb<u8>, palette: &Vec<Rgb<u8>>) -> Rgb<u8> {
+pub fn find_closest_color(color: &Rgb<u8>, palette: &[Rgb<u8>]) -> Rgb<u8> {
     let (r, g, b) = (color[0], color[1], color[2]);
     let mut min_distance = f32::MAX;
-    let pal: Vec<Rgb<u8>> = palette.iter().cloned().collect();
+    let pal: Vec<Rgb<u8>> = palette.to_vec();
     let mut closest_color = Rgb([0, 0, 0]);
 
     for palette_color in pal {
@@ -59,6 +60,21 @@ pub fn find_closest_color(color: &Rgb<u8>, palette: &Vec<Rgb<u8>>) -> Rgb<u8> {
     Rgb([closest_color[0], closest_color[1], closest_color[2]])
 }
 
+pub fn find_closest_index(color: &Rgb<u8>, palette: &[Rgb<u8>]) -> usize {
+    let mut min_distance = f32::MAX;
+    let mut closest = 0;
+
+    for (i, palette_color) in palette.iter().enumerate() {
+        let distance = euclidean_distance(color, palette_color);
+        if distance < min_distance {
+            min_distance = distance;
+            closest = i;
+        }
+    }
+
+    closest
+}
+
 pub fn calculate_error(old_color: &Rgb<u8>, new_color: &Rgb<u8>) -> Rgb<i16> {
     Rgb([
         old_color[0] as i16 - new_color[0] as i16,
@@ -77,11 +93,10 @@ pub fn diffuse_error(x: u32, y: u32, diff_mat: &[((i32, i32), f32)], error: Rgb<
 
         if nx >= 0 && nx < width as i32 && ny >= 0 && ny < height as i32 {
             let pixel = image.get_pixel(nx as u32, ny as u32);
-            let mut new_pixel = image.get_pixel(nx as u32, ny as u32).clone();
+            let mut new_pixel = *image.get_pixel(nx as u32, ny as u32);
 
             for i in 0..3 {
-                new_pixel[i] = pixel[i] + (error[i] as f32 * coeff) as u8;
-                new_pixel[i] = clamp(new_pixel[i] as i16, 0, 255) as u8;
+                new_pixel[i] = clamp(pixel[i] as i16 + (error[i] as f32 * coeff) as i16, 0, 255) as u8;
             }
 
             image.put_pixel(nx as u32, ny as u32, Rgb([
@@ -115,12 +130,35 @@ pub fn generate_raw_palette(img: &DynamicImage) -> HashSet<Rgb<u8>> {
 }
 
 pub fn gen_blue_noise_threshold(threshold: BlueNoiseThreshold) -> u8 {
-    let noise_threshold;
-    let mut rng = rand::thread_rng();
-    match threshold {
-        BlueNoiseThreshold::LOW => { noise_threshold = rng.gen_range(0u8..86u8) }
-        BlueNoiseThreshold::MEDIUM => { noise_threshold = rng.gen_range(86u8..171u8) }
-        BlueNoiseThreshold::HIGH => { noise_threshold = rng.gen_range(171u8..255u8) }
-    }
+    let mut rng = rand::rng();
+    let noise_threshold = match threshold {
+        BlueNoiseThreshold::LOW => { rng.random_range(0u8..86u8) }
+        BlueNoiseThreshold::MEDIUM => { rng.random_range(86u8..171u8) }
+        BlueNoiseThreshold::HIGH => { rng.random_range(171u8..255u8) }
+    };
     return noise_threshold;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diffused_error_saturates_instead_of_wrapping() {
+        let mut image = ImageBuffer::from_pixel(2, 1, Rgb([250u8, 10, 128]));
+        diffuse_error(0, 0, &[((1, 0), 1f32)], Rgb([40, -40, 0]), &mut image);
+        assert_eq!(image.get_pixel(1, 0), &Rgb([255, 0, 128]));
+        assert_eq!(image.get_pixel(0, 0), &Rgb([250, 10, 128]));
+    }
+
+    #[test]
+    fn negative_error_darkens_the_neighbours() {
+        let mut image = ImageBuffer::from_pixel(2, 2, Rgb([100u8, 100, 100]));
+        let floyd_steinberg = [((1, 0), 7f32 / 16f32), ((-1, 1), 3f32 / 16f32), ((0, 1), 5f32 / 16f32), ((1, 1), 1f32 / 16f32)];
+        diffuse_error(0, 0, &floyd_steinberg, Rgb([-80, -16, 32]), &mut image);
+
+        assert_eq!(image.get_pixel(1, 0), &Rgb([65, 93, 114]));
+        assert_eq!(image.get_pixel(0, 1), &Rgb([75, 95, 110]));
+        assert_eq!(image.get_pixel(1, 1), &Rgb([95, 99, 102]));
+    }
+}